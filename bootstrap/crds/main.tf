@@ -60,16 +60,127 @@ resource "kubernetes_manifest" "customresourcedefinition_ogmiosports_demeter_run
               "properties" = {
                 "spec" = {
                   "properties" = {
+                    "additionalAuthTokens" = {
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type" = "array"
+                    }
+                    "allowedCidrs" = {
+                      "items" = {
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type" = "array"
+                    }
                     "authToken" = {
                       "nullable" = true
                       "type" = "string"
                     }
+                    "expiresAt" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
                     "network" = {
                       "type" = "string"
                     }
+                    "readOnlyKey" = {
+                      "nullable" = true
+                      "type" = "boolean"
+                    }
+                    "secretRef" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
                     "throughputTier" = {
                       "type" = "string"
                     }
+                    "tierOverrides" = {
+                      "nullable" = true
+                      "properties" = {
+                        "allowQueryKey" = {
+                          "nullable" = true
+                          "type" = "boolean"
+                        }
+                        "burst" = {
+                          "format" = "uint32"
+                          "minimum" = 1
+                          "nullable" = true
+                          "type" = "integer"
+                        }
+                        "dailyRequests" = {
+                          "format" = "uint64"
+                          "minimum" = 1
+                          "nullable" = true
+                          "type" = "integer"
+                        }
+                        "maxConnections" = {
+                          "format" = "uint32"
+                          "minimum" = 1
+                          "nullable" = true
+                          "type" = "integer"
+                        }
+                        "maxInFlight" = {
+                          "format" = "uint32"
+                          "minimum" = 1
+                          "nullable" = true
+                          "type" = "integer"
+                        }
+                        "monthlyRequests" = {
+                          "format" = "uint64"
+                          "minimum" = 1
+                          "nullable" = true
+                          "type" = "integer"
+                        }
+                        "rates" = {
+                          "items" = {
+                            "properties" = {
+                              "burst" = {
+                                "format" = "uint32"
+                                "minimum" = 1
+                                "nullable" = true
+                                "type" = "integer"
+                              }
+                              "initial" = {
+                                "format" = "uint32"
+                                "minimum" = 0
+                                "nullable" = true
+                                "type" = "integer"
+                              }
+                              "interval" = {
+                                "pattern" = "^[0-9]+[smhd]$"
+                                "type" = "string"
+                              }
+                              "limit" = {
+                                "format" = "uint32"
+                                "minimum" = 1
+                                "type" = "integer"
+                              }
+                              "refill" = {
+                                "format" = "uint32"
+                                "minimum" = 1
+                                "nullable" = true
+                                "type" = "integer"
+                              }
+                              "refillInterval" = {
+                                "nullable" = true
+                                "pattern" = "^[0-9]+[smhd]$"
+                                "type" = "string"
+                              }
+                            }
+                            "required" = [
+                              "interval",
+                              "limit",
+                            ]
+                            "type" = "object"
+                          }
+                          "nullable" = true
+                          "type" = "array"
+                        }
+                      }
+                      "type" = "object"
+                    }
                     "version" = {
                       "format" = "uint8"
                       "minimum" = 0
@@ -89,12 +200,48 @@ resource "kubernetes_manifest" "customresourcedefinition_ogmiosports_demeter_run
                     "authToken" = {
                       "type" = "string"
                     }
+                    "authTokenSecretRef" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "authTokenSecretVersion" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
                     "authenticatedEndpointUrl" = {
                       "type" = "string"
                     }
                     "endpointUrl" = {
                       "type" = "string"
                     }
+                    "previousAuthTokens" = {
+                      "default" = [
+                      ]
+                      "items" = {
+                        "properties" = {
+                          "expiresAt" = {
+                            "type" = "string"
+                          }
+                          "token" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "expiresAt",
+                          "token",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "readOnlyAuthToken" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "trialStartedAt" = {
+                      "nullable" = true
+                      "type" = "string"
+                    }
                   }
                   "required" = [
                     "authToken",
@@ -121,3 +268,346 @@ resource "kubernetes_manifest" "customresourcedefinition_ogmiosports_demeter_run
     }
   }
 }
+
+resource "kubernetes_manifest" "customresourcedefinition_ogmiostiers_demeter_run" {
+  manifest = {
+    "apiVersion" = "apiextensions.k8s.io/v1"
+    "kind" = "CustomResourceDefinition"
+    "metadata" = {
+      "name" = "ogmiostiers.demeter.run"
+    }
+    "spec" = {
+      "group" = "demeter.run"
+      "names" = {
+        "categories" = [
+        ]
+        "kind" = "OgmiosTier"
+        "plural" = "ogmiostiers"
+        "shortNames" = [
+          "otr",
+        ]
+        "singular" = "ogmiostier"
+      }
+      "scope" = "Namespaced"
+      "versions" = [
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.maxConnections"
+              "name" = "Max Connections"
+              "type" = "integer"
+            },
+            {
+              "jsonPath" = ".spec.maxInFlight"
+              "name" = "Max In Flight"
+              "type" = "integer"
+            },
+          ]
+          "name" = "v1alpha1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for OgmiosTierSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "anyOf" = [
+                    {
+                      "required" = [
+                        "extends",
+                      ]
+                    },
+                    {
+                      "required" = [
+                        "maxConnections",
+                        "rates",
+                      ]
+                    },
+                  ]
+                  "description" = "Limits of a throughput tier, named by the resource and referenced by `throughputTier` on the ports. Tiers live in the proxy namespace and are read by the proxy only."
+                  "properties" = {
+                    "allowQueryKey" = {
+                      "nullable" = true
+                      "type" = "boolean"
+                    }
+                    "allowedMethods" = {
+                      "items" = {
+                        "minLength" = 1
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type" = "array"
+                    }
+                    "allowedNetworks" = {
+                      "items" = {
+                        "minLength" = 1
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type" = "array"
+                    }
+                    "bandwidth" = {
+                      "nullable" = true
+                      "properties" = {
+                        "interval" = {
+                          "pattern" = "^[0-9]+[smhd]$"
+                          "type" = "string"
+                        }
+                        "limit" = {
+                          "format" = "uint64"
+                          "minimum" = 1
+                          "type" = "integer"
+                        }
+                      }
+                      "required" = [
+                        "interval",
+                        "limit",
+                      ]
+                      "type" = "object"
+                    }
+                    "compression" = {
+                      "nullable" = true
+                      "type" = "boolean"
+                    }
+                    "costs" = {
+                      "additionalProperties" = {
+                        "format" = "uint32"
+                        "minimum" = 0
+                        "type" = "integer"
+                      }
+                      "nullable" = true
+                      "type" = "object"
+                    }
+                    "dailyRequests" = {
+                      "format" = "uint64"
+                      "minimum" = 1
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "defaultCost" = {
+                      "format" = "uint32"
+                      "minimum" = 1
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "deniedMethods" = {
+                      "items" = {
+                        "minLength" = 1
+                        "type" = "string"
+                      }
+                      "nullable" = true
+                      "type" = "array"
+                    }
+                    "extends" = {
+                      "minLength" = 1
+                      "nullable" = true
+                      "type" = "string"
+                    }
+                    "maxConnections" = {
+                      "format" = "uint32"
+                      "minimum" = 1
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "maxInFlight" = {
+                      "format" = "uint32"
+                      "minimum" = 1
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "maxSessionDuration" = {
+                      "nullable" = true
+                      "pattern" = "^[0-9]+[smhd]$"
+                      "type" = "string"
+                    }
+                    "methods" = {
+                      "additionalProperties" = {
+                        "items" = {
+                          "properties" = {
+                            "burst" = {
+                              "format" = "uint32"
+                              "minimum" = 1
+                              "nullable" = true
+                              "type" = "integer"
+                            }
+                            "initial" = {
+                              "format" = "uint32"
+                              "minimum" = 0
+                              "nullable" = true
+                              "type" = "integer"
+                            }
+                            "interval" = {
+                              "pattern" = "^[0-9]+[smhd]$"
+                              "type" = "string"
+                            }
+                            "limit" = {
+                              "format" = "uint32"
+                              "minimum" = 1
+                              "type" = "integer"
+                            }
+                            "refill" = {
+                              "format" = "uint32"
+                              "minimum" = 1
+                              "nullable" = true
+                              "type" = "integer"
+                            }
+                            "refillInterval" = {
+                              "nullable" = true
+                              "pattern" = "^[0-9]+[smhd]$"
+                              "type" = "string"
+                            }
+                          }
+                          "required" = [
+                            "interval",
+                            "limit",
+                          ]
+                          "type" = "object"
+                        }
+                        "type" = "array"
+                      }
+                      "nullable" = true
+                      "type" = "object"
+                    }
+                    "monthlyRequests" = {
+                      "format" = "uint64"
+                      "minimum" = 1
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "priority" = {
+                      "format" = "uint32"
+                      "minimum" = 1
+                      "nullable" = true
+                      "type" = "integer"
+                    }
+                    "rates" = {
+                      "items" = {
+                        "properties" = {
+                          "burst" = {
+                            "format" = "uint32"
+                            "minimum" = 1
+                            "nullable" = true
+                            "type" = "integer"
+                          }
+                          "initial" = {
+                            "format" = "uint32"
+                            "minimum" = 0
+                            "nullable" = true
+                            "type" = "integer"
+                          }
+                          "interval" = {
+                            "pattern" = "^[0-9]+[smhd]$"
+                            "type" = "string"
+                          }
+                          "limit" = {
+                            "format" = "uint32"
+                            "minimum" = 1
+                            "type" = "integer"
+                          }
+                          "refill" = {
+                            "format" = "uint32"
+                            "minimum" = 1
+                            "nullable" = true
+                            "type" = "integer"
+                          }
+                          "refillInterval" = {
+                            "nullable" = true
+                            "pattern" = "^[0-9]+[smhd]$"
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "interval",
+                          "limit",
+                        ]
+                        "type" = "object"
+                      }
+                      "minItems" = 1
+                      "nullable" = true
+                      "type" = "array"
+                    }
+                    "schedules" = {
+                      "items" = {
+                        "description" = "A time window in which the rates of the tier are multiplied. The window is either daily, between `from` and `to`, or repeats `every` interval from `anchor` for `duration`, eg around epoch boundaries. Every window that matches applies."
+                        "properties" = {
+                          "anchor" = {
+                            "nullable" = true
+                            "type" = "string"
+                          }
+                          "days" = {
+                            "items" = {
+                              "minLength" = 3
+                              "type" = "string"
+                            }
+                            "nullable" = true
+                            "type" = "array"
+                          }
+                          "duration" = {
+                            "nullable" = true
+                            "pattern" = "^[0-9]+[smhd]$"
+                            "type" = "string"
+                          }
+                          "every" = {
+                            "nullable" = true
+                            "pattern" = "^[0-9]+[smhd]$"
+                            "type" = "string"
+                          }
+                          "from" = {
+                            "nullable" = true
+                            "pattern" = "^[0-9]{2}:[0-9]{2}$"
+                            "type" = "string"
+                          }
+                          "multiplier" = {
+                            "format" = "double"
+                            "minimum" = 0.01
+                            "type" = "number"
+                          }
+                          "to" = {
+                            "nullable" = true
+                            "pattern" = "^[0-9]{2}:[0-9]{2}$"
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "multiplier",
+                        ]
+                        "type" = "object"
+                      }
+                      "nullable" = true
+                      "type" = "array"
+                    }
+                    "trial" = {
+                      "nullable" = true
+                      "properties" = {
+                        "duration" = {
+                          "pattern" = "^[0-9]+[smhd]$"
+                          "type" = "string"
+                        }
+                        "postTrialTier" = {
+                          "minLength" = 1
+                          "type" = "string"
+                        }
+                      }
+                      "required" = [
+                        "duration",
+                        "postTrialTier",
+                      ]
+                      "type" = "object"
+                    }
+                  }
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "OgmiosTier"
+              "type" = "object"
+            }
+          }
+          "served" = true
+          "storage" = true
+          "subresources" = {}
+        },
+      ]
+    }
+  }
+}
@@ -24,6 +24,8 @@ resource "kubernetes_deployment_v1" "ogmios_operator" {
         }
       }
       spec {
+        service_account_name = kubernetes_service_account_v1.operator.metadata[0].name
+
         container {
           name              = "main"
           image             = "ghcr.io/demeter-run/ext-cardano-ogmios-operator:${var.operator_image_tag}"
@@ -1,6 +1,14 @@
-resource "kubernetes_cluster_role" "cluster_role" {
+resource "kubernetes_service_account_v1" "operator" {
   metadata {
-    name = var.namespace
+    name      = local.operator_name
+    namespace = var.namespace
+  }
+}
+
+// Only the operator writes the ports and the secrets of their keys.
+resource "kubernetes_cluster_role" "operator" {
+  metadata {
+    name = "${var.namespace}-${local.operator_name}"
   }
 
   rule {
@@ -9,6 +17,18 @@ resource "kubernetes_cluster_role" "cluster_role" {
     verbs      = ["get", "list", "watch", "patch", "update"]
   }
 
+  rule {
+    api_groups = ["demeter.run"]
+    resources  = ["ogmiostiers"]
+    verbs      = ["get", "list", "watch"]
+  }
+
+  rule {
+    api_groups = [""]
+    resources  = ["secrets"]
+    verbs      = ["get", "create", "patch"]
+  }
+
   rule {
     api_groups = ["events.k8s.io"]
     resources  = ["events"]
@@ -16,6 +36,73 @@ resource "kubernetes_cluster_role" "cluster_role" {
   }
 }
 
+resource "kubernetes_cluster_role_binding" "operator" {
+  metadata {
+    name = "${var.namespace}-${local.operator_name}"
+  }
+  role_ref {
+    api_group = "rbac.authorization.k8s.io"
+    kind      = "ClusterRole"
+    name      = kubernetes_cluster_role.operator.metadata[0].name
+  }
+  subject {
+    kind      = "ServiceAccount"
+    name      = kubernetes_service_account_v1.operator.metadata[0].name
+    namespace = var.namespace
+  }
+}
+
+// The operator keeps when each port was first seen in a ConfigMap of its namespace.
+resource "kubernetes_role_v1" "operator" {
+  metadata {
+    name      = local.operator_name
+    namespace = var.namespace
+  }
+
+  rule {
+    api_groups = [""]
+    resources  = ["configmaps"]
+    verbs      = ["get", "create", "patch"]
+  }
+}
+
+resource "kubernetes_role_binding_v1" "operator" {
+  metadata {
+    name      = local.operator_name
+    namespace = var.namespace
+  }
+  role_ref {
+    api_group = "rbac.authorization.k8s.io"
+    kind      = "Role"
+    name      = kubernetes_role_v1.operator.metadata[0].name
+  }
+  subject {
+    kind      = "ServiceAccount"
+    name      = kubernetes_service_account_v1.operator.metadata[0].name
+    namespace = var.namespace
+  }
+}
+
+// The proxy runs with the default service account, it only reads the ports, the tiers and the
+// secrets named by the ports.
+resource "kubernetes_cluster_role" "cluster_role" {
+  metadata {
+    name = var.namespace
+  }
+
+  rule {
+    api_groups = ["demeter.run"]
+    resources  = ["ogmiosports", "ogmiostiers"]
+    verbs      = ["get", "list", "watch"]
+  }
+
+  rule {
+    api_groups = [""]
+    resources  = ["secrets"]
+    verbs      = ["get"]
+  }
+}
+
 resource "kubernetes_cluster_role_binding" "cluster_role_binding" {
   metadata {
     name = var.namespace
@@ -0,0 +1,54 @@
+locals {
+  tiers = {
+    "0" = {
+      maxConnections = 2
+      rates = [
+        {
+          interval = "1m"
+          limit    = 500
+        }
+      ]
+    }
+    "1" = {
+      maxConnections = 5
+      rates = [
+        {
+          interval = "1m"
+          limit    = 500
+        }
+      ]
+    }
+    "2" = {
+      maxConnections = 250
+      rates = [
+        {
+          interval = "1m"
+          limit    = 500
+        }
+      ]
+    }
+    "3" = {
+      maxConnections = 450
+      rates = [
+        {
+          interval = "1m"
+          limit    = 1500
+        }
+      ]
+    }
+  }
+}
+
+resource "kubernetes_manifest" "tier" {
+  for_each = local.tiers
+
+  manifest = {
+    apiVersion = "demeter.run/v1alpha1"
+    kind       = "OgmiosTier"
+    metadata = {
+      name      = each.key
+      namespace = var.namespace
+    }
+    spec = each.value
+  }
+}
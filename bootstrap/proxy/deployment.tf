@@ -46,6 +46,30 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
             protocol       = "TCP"
           }
 
+          port {
+            name           = "health"
+            container_port = local.health_port
+            protocol       = "TCP"
+          }
+
+          liveness_probe {
+            http_get {
+              path = "/livez"
+              port = local.health_port
+            }
+            period_seconds    = 10
+            failure_threshold = 3
+          }
+
+          readiness_probe {
+            http_get {
+              path = "/readyz"
+              port = local.health_port
+            }
+            period_seconds    = 5
+            failure_threshold = 2
+          }
+
           env {
             name  = "NETWORK"
             value = var.network
@@ -62,13 +86,13 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
           }
 
           env {
-            name  = "PROXY_TIERS_PATH"
-            value = "/configs/tiers.toml"
+            name  = "PROMETHEUS_ADDR"
+            value = local.prometheus_addr
           }
 
           env {
-            name  = "PROMETHEUS_ADDR"
-            value = local.prometheus_addr
+            name  = "HEALTH_ADDR"
+            value = local.health_addr
           }
 
           env {
@@ -95,11 +119,6 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
             mount_path = "/certs"
             name       = "certs"
           }
-
-          volume_mount {
-            mount_path = "/configs"
-            name       = "configs"
-          }
         }
 
         volume {
@@ -109,13 +128,6 @@ resource "kubernetes_deployment_v1" "ogmios_proxy" {
           }
         }
 
-        volume {
-          name = "configs"
-          config_map {
-            name = kubernetes_config_map.proxy.metadata.0.name
-          }
-        }
-
         dynamic "toleration" {
           for_each = var.tolerations
           content {
@@ -4,6 +4,8 @@ locals {
 
   prometheus_port = 9187
   prometheus_addr = "0.0.0.0:${local.prometheus_port}"
+  health_port     = 9189
+  health_addr     = "0.0.0.0:${local.health_port}"
   proxy_port      = 8080
   proxy_addr      = "0.0.0.0:${local.proxy_port}"
   proxy_labels    = { role = "${local.role}" }
@@ -14,6 +14,7 @@ pub struct Config {
     pub dns_zone: String,
     pub extension_name: String,
     pub api_key_salt: String,
+    pub api_key_grace_period: Duration,
     pub dcu_per_second: HashMap<String, f64>,
     pub metrics_delay: Duration,
     pub prometheus_url: String,
@@ -24,6 +25,14 @@ impl Config {
         let dns_zone = env::var("DNS_ZONE").unwrap_or("demeter.run".into());
         let extension_name = env::var("EXTENSION_NAME").unwrap_or("ogmios-m1".into());
         let api_key_salt = env::var("API_KEY_SALT").unwrap_or("ogmios-salt".into());
+        let api_key_grace_period = Duration::from_secs(
+            env::var("API_KEY_GRACE_PERIOD")
+                .map(|v| {
+                    v.parse::<u64>()
+                        .expect("API_KEY_GRACE_PERIOD must be a number in seconds")
+                })
+                .unwrap_or(86400),
+        );
 
         // This will be deprecated soon. Naming is like this for compatibility
         let dcu_per_second = env::var("DCU_PER_FRAME")
@@ -51,6 +60,7 @@ impl Config {
             dns_zone,
             extension_name,
             api_key_salt,
+            api_key_grace_period,
             dcu_per_second,
             metrics_delay,
             prometheus_url,
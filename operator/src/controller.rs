@@ -1,4 +1,6 @@
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
+use k8s_openapi::{api::core::v1::Secret, ByteString};
 use kube::{
     api::ListParams,
     runtime::{controller::Action, watcher::Config as WatcherConfig, Controller},
@@ -6,10 +8,13 @@ use kube::{
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 use tracing::{error, info, instrument};
 
-use crate::{build_api_key, build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{
+    apply_secret, build_api_key, build_hostname, build_read_only_api_key, get_config, get_secret,
+    patch_resource_status, trial_started_at, Error, Metrics, OgmiosTierRate, Result, State,
+};
 
 pub static OGMIOS_PORT_FINALIZER: &str = "ogmiosports.demeter.run";
 
@@ -38,6 +43,44 @@ pub struct OgmiosPortSpec {
     // throughput should be 0, 1, 2
     pub throughput_tier: String,
     pub auth_token: Option<String>,
+    // extra keys accepted for the port, eg while clients move to a new one
+    pub additional_auth_tokens: Option<Vec<String>>,
+    // source CIDRs allowed to use the port, any address is allowed when empty
+    pub allowed_cidrs: Option<Vec<String>>,
+    // mints a second key that can't submit or evaluate transactions
+    pub read_only_key: Option<bool>,
+    // secret of the port namespace the keys are written to, instead of the status
+    pub secret_ref: Option<String>,
+    // RFC 3339 timestamp after which every key of the port is refused
+    pub expires_at: Option<String>,
+    // parameters of the throughput tier changed for this port only
+    pub tier_overrides: Option<TierOverrides>,
+}
+impl OgmiosPortSpec {
+    /// When the keys of the port stop working, an error when `expiresAt` isn't a valid timestamp.
+    pub fn expires_at(&self) -> Option<Result<DateTime<Utc>, chrono::ParseError>> {
+        let expires_at = self.expires_at.as_ref()?;
+        Some(DateTime::parse_from_rfc3339(expires_at).map(|at| at.with_timezone(&Utc)))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TierOverrides {
+    #[schemars(range(min = 1))]
+    pub max_connections: Option<u32>,
+    #[schemars(range(min = 1))]
+    pub max_in_flight: Option<u32>,
+    // burst of every rate of the tier, never below their limit
+    #[schemars(range(min = 1))]
+    pub burst: Option<u32>,
+    // replaces the rates of the tier, the method rates are kept
+    pub rates: Option<Vec<OgmiosTierRate>>,
+    #[schemars(range(min = 1))]
+    pub daily_requests: Option<u64>,
+    #[schemars(range(min = 1))]
+    pub monthly_requests: Option<u64>,
+    pub allow_query_key: Option<bool>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
@@ -46,6 +89,124 @@ pub struct OgmiosPortStatus {
     pub endpoint_url: String,
     pub authenticated_endpoint_url: String,
     pub auth_token: String,
+    // keys replaced by the current one, still accepted until they expire
+    #[serde(default)]
+    pub previous_auth_tokens: Vec<PreviousAuthToken>,
+    // key limited to the read-only scope, set when the spec asks for one
+    #[serde(default)]
+    pub read_only_auth_token: Option<String>,
+    // secret holding the keys when the spec has a secretRef, the other key fields are left empty
+    pub auth_token_secret_ref: Option<String>,
+    // resource version of that secret, so watchers of the port see key changes
+    pub auth_token_secret_version: Option<String>,
+    // RFC 3339 timestamp of the first time a port of this name was seen, trials of tiers run from
+    // then even when the port was deleted and created again
+    #[serde(default)]
+    pub trial_started_at: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviousAuthToken {
+    pub token: String,
+    // RFC 3339 timestamp
+    pub expires_at: String,
+}
+impl PreviousAuthToken {
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.expires_at)
+            .ok()
+            .map(|expires_at| expires_at.with_timezone(&Utc))
+    }
+}
+
+/// Keys of a port, kept in its status or in the secret named by its spec.
+#[derive(Clone, Default, Debug)]
+pub struct AuthTokens {
+    pub auth_token: String,
+    pub authenticated_endpoint_url: String,
+    pub previous_auth_tokens: Vec<PreviousAuthToken>,
+    pub read_only_auth_token: Option<String>,
+}
+impl AuthTokens {
+    const AUTH_TOKEN: &'static str = "authToken";
+    const AUTHENTICATED_ENDPOINT_URL: &'static str = "authenticatedEndpointUrl";
+    const PREVIOUS_AUTH_TOKENS: &'static str = "previousAuthTokens";
+    const READ_ONLY_AUTH_TOKEN: &'static str = "readOnlyAuthToken";
+
+    pub fn from_status(status: &OgmiosPortStatus) -> Self {
+        Self {
+            auth_token: status.auth_token.clone(),
+            authenticated_endpoint_url: status.authenticated_endpoint_url.clone(),
+            previous_auth_tokens: status.previous_auth_tokens.clone(),
+            read_only_auth_token: status.read_only_auth_token.clone(),
+        }
+    }
+
+    /// Reads the keys written by [`AuthTokens::to_secret_data`], `None` when the secret has no
+    /// key.
+    pub fn from_secret(secret: &Secret) -> Option<Self> {
+        let data = secret.data.as_ref()?;
+        let get = |name: &str| {
+            data.get(name)
+                .and_then(|value| String::from_utf8(value.0.clone()).ok())
+        };
+
+        Some(Self {
+            auth_token: get(Self::AUTH_TOKEN)?,
+            authenticated_endpoint_url: get(Self::AUTHENTICATED_ENDPOINT_URL).unwrap_or_default(),
+            previous_auth_tokens: get(Self::PREVIOUS_AUTH_TOKENS)
+                .and_then(|value| serde_json::from_str(&value).ok())
+                .unwrap_or_default(),
+            read_only_auth_token: get(Self::READ_ONLY_AUTH_TOKEN),
+        })
+    }
+
+    pub fn to_secret_data(&self) -> BTreeMap<String, ByteString> {
+        let mut data = BTreeMap::new();
+        let mut insert = |name: &str, value: &str| {
+            data.insert(name.to_string(), ByteString(value.as_bytes().to_vec()));
+        };
+
+        insert(Self::AUTH_TOKEN, &self.auth_token);
+        insert(
+            Self::AUTHENTICATED_ENDPOINT_URL,
+            &self.authenticated_endpoint_url,
+        );
+        insert(
+            Self::PREVIOUS_AUTH_TOKENS,
+            &serde_json::to_string(&self.previous_auth_tokens).unwrap_or_default(),
+        );
+        if let Some(read_only_auth_token) = &self.read_only_auth_token {
+            insert(Self::READ_ONLY_AUTH_TOKEN, read_only_auth_token);
+        }
+
+        data
+    }
+}
+
+/// Keeps the key replaced by a rotation valid for the grace period, and drops the ones expired.
+fn rotate_auth_tokens(current: Option<&AuthTokens>, key: &str) -> Vec<PreviousAuthToken> {
+    let now = Utc::now();
+    let mut previous_auth_tokens: Vec<PreviousAuthToken> = current
+        .iter()
+        .flat_map(|current| current.previous_auth_tokens.clone())
+        .filter(|previous| previous.token != key)
+        .filter(|previous| previous.expires_at().is_some_and(|at| at > now))
+        .collect();
+
+    if let Some(current) = current {
+        if !current.auth_token.is_empty() && current.auth_token != key {
+            let grace_period = chrono::Duration::from_std(get_config().api_key_grace_period)
+                .unwrap_or(chrono::Duration::zero());
+            previous_auth_tokens.push(PreviousAuthToken {
+                token: current.auth_token.clone(),
+                expires_at: (now + grace_period).to_rfc3339(),
+            });
+        }
+    }
+
+    previous_auth_tokens
 }
 
 struct Context {
@@ -64,15 +225,63 @@ async fn reconcile(crd: Arc<OgmiosPort>, ctx: Arc<Context>) -> Result<Action> {
         None => build_api_key(&crd).await?,
     };
 
+    let namespace = crd.namespace().unwrap();
     let (hostname, hostname_key) = build_hostname(&crd.spec.network, &crd.spec.version, &key);
 
-    let status = OgmiosPortStatus {
-        endpoint_url: format!("https://{hostname}",),
+    // Ports moving to a secret rotate from the keys of their status.
+    let secret = match &crd.spec.secret_ref {
+        Some(name) => get_secret(ctx.client.clone(), &namespace, name).await?,
+        None => None,
+    };
+    let current = secret
+        .as_ref()
+        .and_then(AuthTokens::from_secret)
+        .or_else(|| crd.status.as_ref().map(AuthTokens::from_status));
+
+    let tokens = AuthTokens {
+        previous_auth_tokens: rotate_auth_tokens(current.as_ref(), &key),
+        read_only_auth_token: match crd.spec.read_only_key {
+            Some(true) => Some(build_read_only_api_key(&crd).await?),
+            _ => None,
+        },
         authenticated_endpoint_url: format!("https://{hostname_key}"),
         auth_token: key,
     };
 
-    let namespace = crd.namespace().unwrap();
+    // Runs again when the first previous key expires, to drop it from the status.
+    let next_expiry = tokens
+        .previous_auth_tokens
+        .iter()
+        .filter_map(|previous| previous.expires_at())
+        .min()
+        .and_then(|at| (at - Utc::now()).to_std().ok());
+
+    let trial_started_at = Some(trial_started_at(ctx.client.clone(), &crd).await?);
+
+    let status = match &crd.spec.secret_ref {
+        Some(name) => {
+            let secret =
+                apply_secret(ctx.client.clone(), &crd, name, tokens.to_secret_data()).await?;
+
+            OgmiosPortStatus {
+                endpoint_url: format!("https://{hostname}",),
+                auth_token_secret_ref: Some(name.clone()),
+                auth_token_secret_version: secret.resource_version(),
+                trial_started_at,
+                ..Default::default()
+            }
+        }
+        None => OgmiosPortStatus {
+            endpoint_url: format!("https://{hostname}",),
+            authenticated_endpoint_url: tokens.authenticated_endpoint_url,
+            auth_token: tokens.auth_token,
+            previous_auth_tokens: tokens.previous_auth_tokens,
+            read_only_auth_token: tokens.read_only_auth_token,
+            trial_started_at,
+            ..Default::default()
+        },
+    };
+
     let ogmios_port = OgmiosPort::api_resource();
 
     patch_resource_status(
@@ -86,7 +295,10 @@ async fn reconcile(crd: Arc<OgmiosPort>, ctx: Arc<Context>) -> Result<Action> {
 
     info!(resource = crd.name_any(), "Reconcile completed");
 
-    Ok(Action::await_change())
+    match next_expiry {
+        Some(next_expiry) => Ok(Action::requeue(next_expiry)),
+        None => Ok(Action::await_change()),
+    }
 }
 
 fn error_policy(crd: Arc<OgmiosPort>, err: &Error, ctx: Arc<Context>) -> Action {
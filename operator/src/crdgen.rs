@@ -1,18 +1,15 @@
 use kube::CustomResourceExt;
-use operator::controller;
+use operator::{controller, tier};
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
+    let crds = [controller::OgmiosPort::crd(), tier::OgmiosTier::crd()];
     if args.len() > 1 && args[1] == "json" {
-        print!(
-            "{}",
-            serde_json::to_string_pretty(&controller::OgmiosPort::crd()).unwrap()
-        );
+        print!("{}", serde_json::to_string_pretty(&crds).unwrap());
         return;
     }
 
-    print!(
-        "{}",
-        serde_yaml::to_string(&controller::OgmiosPort::crd()).unwrap()
-    )
+    for crd in crds {
+        print!("---\n{}", serde_yaml::to_string(&crd).unwrap())
+    }
 }
@@ -1,6 +1,7 @@
 use prometheus::Registry;
 use thiserror::Error;
 
+pub use k8s_openapi;
 pub use kube;
 
 #[derive(Error, Debug)]
@@ -25,6 +26,9 @@ pub enum Error {
 
     #[error("Config Error: {0}")]
     ConfigError(String),
+
+    #[error("Secret Error: {0}")]
+    SecretError(String),
 }
 impl Error {
     pub fn metric_label(&self) -> String {
@@ -82,6 +86,9 @@ pub use crate::controller::*;
 pub mod metrics;
 pub use metrics::*;
 
+pub mod tier;
+pub use tier::*;
+
 mod utils;
 pub use utils::*;
 
@@ -0,0 +1,182 @@
+use kube::CustomResource;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, ObjectValidation, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Limits of a throughput tier, named by the resource and referenced by `throughputTier` on the
+/// ports. Tiers live in the proxy namespace and are read by the proxy only.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "OgmiosTier",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "otr",
+    namespaced,
+    schema = "manual"
+)]
+#[kube(printcolumn = r#"
+        {"name": "Max Connections", "jsonPath": ".spec.maxConnections", "type": "integer"},
+        {"name": "Max In Flight", "jsonPath": ".spec.maxInFlight", "type": "integer"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+pub struct OgmiosTierSpec {
+    // tier of the same namespace whose fields apply when they aren't set here
+    #[schemars(length(min = 1))]
+    pub extends: Option<String>,
+    // required unless the tier extends another one, which then has to set it
+    #[schemars(range(min = 1))]
+    pub max_connections: Option<u32>,
+    #[schemars(length(min = 1))]
+    pub rates: Option<Vec<OgmiosTierRate>>,
+    // the key can be sent as the dmtr-api-key query parameter
+    pub allow_query_key: Option<bool>,
+    // only these JSON-RPC methods are forwarded when set
+    #[schemars(inner(length(min = 1)))]
+    pub allowed_methods: Option<Vec<String>>,
+    // JSON-RPC methods never forwarded, checked after the allowlist
+    #[schemars(inner(length(min = 1)))]
+    pub denied_methods: Option<Vec<String>>,
+    #[schemars(range(min = 1))]
+    pub max_in_flight: Option<u32>,
+    // extra rates applied only to the given JSON-RPC methods
+    pub methods: Option<BTreeMap<String, Vec<OgmiosTierRate>>>,
+    pub bandwidth: Option<OgmiosTierBandwidth>,
+    // requests allowed in each UTC calendar day and month
+    #[schemars(range(min = 1))]
+    pub daily_requests: Option<u64>,
+    #[schemars(range(min = 1))]
+    pub monthly_requests: Option<u64>,
+    // weight of the tier when the upstream is saturated, defaults to 1
+    #[schemars(range(min = 1))]
+    pub priority: Option<u32>,
+    // networks the ports of the tier can use, any of them when unset
+    #[schemars(inner(length(min = 1)))]
+    pub allowed_networks: Option<Vec<String>>,
+    // multipliers of the rates during time windows, eg more requests off-peak
+    pub schedules: Option<Vec<OgmiosTierSchedule>>,
+    // ports using the tier for longer than the trial are moved to another tier
+    pub trial: Option<OgmiosTierTrial>,
+    // units each JSON-RPC method takes from the rates, eg queryLedgerState/utxo: 50
+    pub costs: Option<BTreeMap<String, u32>>,
+    // units of the other messages, defaults to 1
+    #[schemars(range(min = 1))]
+    pub default_cost: Option<u32>,
+    // websocket sessions are closed after this long, a number followed by s, m, h or d, eg: 1h
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub max_session_duration: Option<String>,
+    // websocket messages are compressed with permessage-deflate when the client offers it
+    pub compression: Option<bool>,
+}
+
+/// The derived schema, requiring `maxConnections` and `rates` on the tiers that don't extend
+/// another one.
+impl JsonSchema for OgmiosTier {
+    fn schema_name() -> String {
+        "OgmiosTier".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let required = |fields: &[&str]| {
+            Schema::Object(SchemaObject {
+                object: Some(Box::new(ObjectValidation {
+                    required: fields.iter().map(|field| field.to_string()).collect(),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            })
+        };
+        let mut spec = gen.subschema_for::<OgmiosTierSpec>().into_object();
+        spec.subschemas().any_of = Some(vec![
+            required(&["extends"]),
+            required(&["maxConnections", "rates"]),
+        ]);
+
+        Schema::Object(SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "Auto-generated derived type for OgmiosTierSpec via `CustomResource`"
+                        .to_string(),
+                ),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::Object.into()),
+            object: Some(Box::new(ObjectValidation {
+                properties: [("spec".to_string(), spec.into())].into(),
+                required: ["spec".to_string()].into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OgmiosTierTrial {
+    // time since the port was created, a number followed by s, m, h or d, eg: 14d
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub duration: String,
+    #[schemars(length(min = 1))]
+    pub post_trial_tier: String,
+}
+
+/// A time window in which the rates of the tier are multiplied. The window is either daily,
+/// between `from` and `to`, or repeats `every` interval from `anchor` for `duration`, eg around
+/// epoch boundaries. Every window that matches applies.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OgmiosTierSchedule {
+    #[schemars(range(min = 0.01))]
+    pub multiplier: f64,
+    // UTC times formatted as HH:MM, the window wraps around midnight when `to` is earlier
+    #[schemars(regex(pattern = r"^[0-9]{2}:[0-9]{2}$"))]
+    pub from: Option<String>,
+    #[schemars(regex(pattern = r"^[0-9]{2}:[0-9]{2}$"))]
+    pub to: Option<String>,
+    // days of the week the daily window applies, eg: sat, every day when unset
+    #[schemars(inner(length(min = 3)))]
+    pub days: Option<Vec<String>>,
+    // RFC 3339 start of the first repetition
+    pub anchor: Option<String>,
+    // a number followed by s, m, h or d, eg: 5d
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub every: Option<String>,
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub duration: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OgmiosTierRate {
+    #[schemars(range(min = 1))]
+    pub limit: u32,
+    // requests that can be sent at once, defaults to the limit
+    #[schemars(range(min = 1))]
+    pub burst: Option<u32>,
+    // a number followed by s, m, h or d, eg: 1m
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub interval: String,
+    // requests added to the bucket at each refill, defaults to the limit
+    #[schemars(range(min = 1))]
+    pub refill: Option<u32>,
+    // time between refills, defaults to the interval
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub refill_interval: Option<String>,
+    // requests in the bucket when it's created, defaults to full
+    pub initial: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OgmiosTierBandwidth {
+    // bytes allowed in both directions for each interval
+    #[schemars(range(min = 1))]
+    pub limit: u64,
+    // a number followed by s, m, h or d, eg: 1m
+    #[schemars(regex(pattern = r"^[0-9]+[smhd]$"))]
+    pub interval: String,
+}
@@ -1,13 +1,19 @@
 use argon2::Argon2;
 use base64::{engine::general_purpose, Engine};
 use bech32::ToBase32;
+use chrono::Utc;
+use k8s_openapi::{
+    api::core::v1::{ConfigMap, Secret},
+    ByteString,
+};
 use kube::{
-    api::{Patch, PatchParams},
+    api::{ObjectMeta, Patch, PatchParams, PostParams},
     core::DynamicObject,
     discovery::ApiResource,
-    Api, Client, ResourceExt,
+    Api, Client, Resource, ResourceExt,
 };
 use serde_json::json;
+use std::collections::BTreeMap;
 
 use crate::{get_config, Error, OgmiosPort};
 
@@ -27,6 +33,108 @@ pub async fn patch_resource_status(
     Ok(())
 }
 
+pub async fn get_secret(
+    client: Client,
+    namespace: &str,
+    name: &str,
+) -> Result<Option<Secret>, kube::Error> {
+    let api: Api<Secret> = Api::namespaced(client, namespace);
+    api.get_opt(name).await
+}
+
+/// Writes the secret in the namespace of the port, owned by it so it's removed along with it. A
+/// secret of the same name that the port doesn't own is left alone, the port fails to reconcile
+/// until it names another one.
+pub async fn apply_secret(
+    client: Client,
+    crd: &OgmiosPort,
+    name: &str,
+    data: BTreeMap<String, ByteString>,
+) -> Result<Secret, Error> {
+    let namespace = crd.namespace().unwrap();
+    let api: Api<Secret> = Api::namespaced(client, &namespace);
+
+    if let Some(existing) = api.get_opt(name).await? {
+        let owned = existing
+            .owner_references()
+            .iter()
+            .any(|owner| Some(&owner.uid) == crd.uid().as_ref());
+        if !owned {
+            return Err(Error::SecretError(format!(
+                "secret {namespace}/{name} exists and isn't owned by the port"
+            )));
+        }
+    }
+
+    let secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some(namespace.clone()),
+            owner_references: crd.controller_owner_ref(&()).map(|owner| vec![owner]),
+            ..Default::default()
+        },
+        data: Some(data),
+        ..Default::default()
+    };
+
+    let patch_params = PatchParams::apply("ogmios-operator").force();
+    Ok(api
+        .patch(name, &patch_params, &Patch::Apply(&secret))
+        .await?)
+}
+
+/// ConfigMap of the operator namespace keeping when each port was first seen, by
+/// `NAMESPACE.NAME`. It outlives the ports, so a port created again doesn't start a new trial.
+const TRIALS_CONFIG_MAP: &str = "ogmios-port-trials";
+
+/// When the trial of the port started, kept in its status once known.
+pub async fn trial_started_at(client: Client, crd: &OgmiosPort) -> Result<String, Error> {
+    if let Some(started_at) = crd.status.as_ref().and_then(|s| s.trial_started_at.clone()) {
+        return Ok(started_at);
+    }
+
+    let api: Api<ConfigMap> = Api::default_namespaced(client);
+    let key = format!("{}.{}", crd.namespace().unwrap(), crd.name_any());
+    let current = api.get_opt(TRIALS_CONFIG_MAP).await?;
+    if let Some(started_at) = current
+        .as_ref()
+        .and_then(|config_map| config_map.data.as_ref()?.get(&key))
+    {
+        return Ok(started_at.clone());
+    }
+
+    let started_at = crd
+        .metadata
+        .creation_timestamp
+        .as_ref()
+        .map_or_else(Utc::now, |time| time.0)
+        .to_rfc3339();
+    match current {
+        // Merged so the entries of the other ports are kept.
+        Some(_) => {
+            let patch = json!({ "data": { key: started_at } });
+            api.patch(
+                TRIALS_CONFIG_MAP,
+                &PatchParams::default(),
+                &Patch::Merge(patch),
+            )
+            .await?;
+        }
+        None => {
+            let config_map = ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(TRIALS_CONFIG_MAP.to_string()),
+                    ..Default::default()
+                },
+                data: Some(BTreeMap::from([(key, started_at.clone())])),
+                ..Default::default()
+            };
+            api.create(&PostParams::default(), &config_map).await?;
+        }
+    }
+    Ok(started_at)
+}
+
 pub fn build_hostname(network: &str, version: &u8, key: &str) -> (String, String) {
     let config = get_config();
     let extension_name = &config.extension_name;
@@ -39,8 +147,16 @@ pub fn build_hostname(network: &str, version: &u8, key: &str) -> (String, String
 }
 
 pub async fn build_api_key(crd: &OgmiosPort) -> Result<String, Error> {
+    hash_api_key(crd, &format!("ogmios-auth-{}", &crd.name_any()))
+}
+
+/// Key of the port that can only call the methods not changing the chain or the mempool.
+pub async fn build_read_only_api_key(crd: &OgmiosPort) -> Result<String, Error> {
+    hash_api_key(crd, &format!("ogmios-auth-read-{}", &crd.name_any()))
+}
+
+fn hash_api_key(crd: &OgmiosPort, name: &str) -> Result<String, Error> {
     let namespace = crd.namespace().unwrap();
-    let name = format!("ogmios-auth-{}", &crd.name_any());
 
     let password = format!("{}{}", name, namespace).as_bytes().to_vec();
 
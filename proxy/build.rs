@@ -0,0 +1,36 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Embeds the commit and the compiler version, reported by the build info metric. `GIT_SHA` wins
+/// over the checkout, for builds without the `.git` directory.
+fn main() {
+    // The blocking pool metrics of tokio need `RUSTFLAGS="--cfg tokio_unstable"`.
+    println!("cargo:rustc-check-cfg=cfg(tokio_unstable)");
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    for path in ["../.git/HEAD", "../.git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+
+    let git_sha = std::env::var("GIT_SHA")
+        .ok()
+        .or_else(|| output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or("unknown".into());
+    let rustc = std::env::var("RUSTC").unwrap_or("rustc".into());
+    let rustc_version = output(&rustc, &["--version"])
+        .and_then(|version| version.split_whitespace().nth(1).map(String::from))
+        .unwrap_or("unknown".into());
+
+    println!("cargo:rustc-env=PROXY_GIT_SHA={git_sha}");
+    println!("cargo:rustc-env=PROXY_RUSTC_VERSION={rustc_version}");
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
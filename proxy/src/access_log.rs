@@ -0,0 +1,60 @@
+use hyper::StatusCode;
+use std::time::Duration;
+use tracing::info;
+
+use crate::proxy::ProxyRequest;
+
+/// Emits one access log line when a websocket session ends.
+pub fn log_session(
+    proxy_req: &ProxyRequest,
+    duration: Duration,
+    bytes_received: u64,
+    bytes_sent: u64,
+    close_reason: &str,
+    subprotocol: Option<&str>,
+) {
+    info!(
+        target: "access_log",
+        request_id = proxy_req.request_id,
+        client_ip = proxy_req.client_ip.to_string(),
+        forwarded = proxy_req.forwarded,
+        namespace = proxy_req.consumer.namespace,
+        port_name = proxy_req.consumer.port_name,
+        tier = proxy_req.consumer.tier,
+        network = proxy_req.consumer.network,
+        protocol = proxy_req.protocol.to_string(),
+        duration_ms = duration.as_millis() as u64,
+        bytes_received,
+        bytes_sent,
+        close_reason,
+        subprotocol,
+        "session closed"
+    );
+}
+
+/// Emits one access log line per http request, when enabled.
+pub fn log_request(
+    proxy_req: &ProxyRequest,
+    access_log_requests: bool,
+    duration: Duration,
+    status: StatusCode,
+) {
+    if !access_log_requests {
+        return;
+    }
+
+    info!(
+        target: "access_log",
+        request_id = proxy_req.request_id,
+        client_ip = proxy_req.client_ip.to_string(),
+        forwarded = proxy_req.forwarded,
+        namespace = proxy_req.consumer.namespace,
+        port_name = proxy_req.consumer.port_name,
+        tier = proxy_req.consumer.tier,
+        network = proxy_req.consumer.network,
+        protocol = proxy_req.protocol.to_string(),
+        duration_ms = duration.as_millis() as u64,
+        status = status.as_u16(),
+        "request completed"
+    );
+}
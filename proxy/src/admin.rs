@@ -0,0 +1,338 @@
+use std::sync::Arc;
+use std::time::Duration;
+use std::{net::SocketAddr, str::FromStr};
+
+use http_body_util::BodyExt;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1 as http1_server;
+use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::{error, info, instrument};
+
+use crate::utils::{full, hash_key, secret_eq, ProxyResponse};
+use crate::{health, State};
+
+fn json_response(value: Value) -> Result<ProxyResponse, hyper::Error> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(value.to_string()))
+        .unwrap())
+}
+
+// Api keys are never dumped, consumers are identified by namespace and port name.
+async fn api_get_consumers(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let consumers = state.consumers.read().await;
+    let consumers: Vec<Value> = consumers
+        .values()
+        .map(|consumer| {
+            json!({
+                "consumer": consumer.to_string(),
+                "namespace": consumer.namespace,
+                "port_name": consumer.port_name,
+                "tier": consumer.tier,
+                "network": consumer.network,
+                "version": consumer.version,
+                "allowed_cidrs": consumer
+                    .allowed_cidrs
+                    .iter()
+                    .map(|net| net.to_string())
+                    .collect::<Vec<_>>(),
+                "invalid_cidrs": consumer.invalid_cidrs,
+                "read_only_key": consumer.read_only_key.is_some(),
+                "tier_overrides": consumer.tier_overrides.is_some(),
+                "active_connections": consumer.active_connections,
+            })
+        })
+        .collect();
+
+    json_response(json!(consumers))
+}
+
+async fn api_get_tiers(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let tiers = state.tiers.read().await;
+    let tiers: Vec<Value> = tiers
+        .values()
+        .map(|tier| {
+            let rate_json = |rate: &crate::tiers::TierRate| {
+                json!({
+                    "limit": rate.limit,
+                    "burst": rate.capacity(),
+                    "interval_secs": rate.interval.as_secs_f64(),
+                    "refill": rate.refill_amount(),
+                    "refill_interval_secs": rate.refill_interval().as_secs_f64(),
+                })
+            };
+
+            json!({
+                "name": tier.name,
+                "max_connections": tier.max_connections,
+                "rates": tier.rates.iter().map(rate_json).collect::<Vec<_>>(),
+                "methods": tier
+                    .methods
+                    .iter()
+                    .map(|(method, rates)| (method.clone(), rates.iter().map(rate_json).collect()))
+                    .collect::<serde_json::Map<String, Value>>(),
+                "costs": tier.costs,
+                "default_cost": tier.cost(None),
+                "bandwidth": tier.bandwidth.as_ref().map(|bandwidth| json!({
+                    "limit": bandwidth.limit,
+                    "interval_secs": bandwidth.interval.as_secs_f64(),
+                })),
+            })
+        })
+        .collect();
+
+    json_response(json!(tiers))
+}
+
+async fn api_get_limiters(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let consumers = state.consumers.read().await;
+    let limiters = state.limiter.read().await;
+    let limiters: serde_json::Map<String, Value> = limiters
+        .iter()
+        .map(|(key, limiter)| {
+            let consumer = consumers
+                .get(key)
+                .map(|consumer| consumer.to_string())
+                .unwrap_or("deleted".into());
+            (consumer, limiter.to_json())
+        })
+        .collect();
+
+    json_response(Value::Object(limiters))
+}
+
+async fn api_get_connections(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let consumers = state.consumers.read().await;
+    let connections: serde_json::Map<String, Value> = consumers
+        .values()
+        .filter(|consumer| consumer.active_connections > 0)
+        .map(|consumer| (consumer.to_string(), json!(consumer.active_connections)))
+        .collect();
+
+    json_response(json!({
+        "sessions": state.active_sessions(),
+        "consumers": connections,
+    }))
+}
+
+async fn api_disconnect_consumer(state: &State, key: &str) -> Result<ProxyResponse, hyper::Error> {
+    let consumer = match state.get_consumer(&hash_key(key)).await {
+        Some(consumer) => consumer,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(full("Consumer not found"))
+                .unwrap())
+        }
+    };
+
+    // Nobody is subscribed when the consumer has no open sessions, that's not an error.
+    let _ = state.disconnect.send(consumer.key.clone());
+    info!(
+        consumer = consumer.to_string(),
+        active_connections = consumer.active_connections,
+        "consumer disconnected by admin"
+    );
+
+    json_response(json!({
+        "consumer": consumer.to_string(),
+        "disconnected": consumer.active_connections,
+    }))
+}
+
+fn maintenance_json(state: &State) -> Value {
+    let message = state.maintenance();
+    json!({ "enabled": message.is_some(), "message": message })
+}
+
+// The body can carry the message shown to clients, eg: {"message": "Upgrading to Ogmios v6.5"}.
+async fn api_enable_maintenance(
+    state: &State,
+    req: Request<Incoming>,
+) -> Result<ProxyResponse, hyper::Error> {
+    let body = req.into_body().collect().await?.to_bytes();
+    let message = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|body| body["message"].as_str().map(String::from))
+        .unwrap_or(state.config().proxy_maintenance_message.clone());
+
+    state.set_maintenance(Some(message.clone()));
+    info!(message, "maintenance mode enabled");
+
+    json_response(maintenance_json(state))
+}
+
+async fn api_disable_maintenance(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    state.set_maintenance(None);
+    info!("maintenance mode disabled");
+
+    json_response(maintenance_json(state))
+}
+
+async fn api_get_switches(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let switches = state.switches.read().unwrap().clone();
+    json_response(json!(switches))
+}
+
+// Body: {"endpoint": "ogmios-green:1337", "drain_secs": 300}. Without an endpoint the network goes
+// back to the configured instances, without drain_secs the open sessions are left alone.
+async fn api_switch_upstream(
+    state: &State,
+    network: &str,
+    req: Request<Incoming>,
+) -> Result<ProxyResponse, hyper::Error> {
+    if !state.config().networks.iter().any(|n| n == network) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(full("Network not found"))
+            .unwrap());
+    }
+
+    let body = req.into_body().collect().await?.to_bytes();
+    // An empty body switches back, a body that isn't JSON is refused rather than taken as one.
+    let body = match body.is_empty() {
+        true => Value::Null,
+        false => match serde_json::from_slice::<Value>(&body) {
+            Ok(body) => body,
+            Err(err) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(full(format!("Invalid body: {err}")))
+                    .unwrap());
+            }
+        },
+    };
+    let endpoint = body["endpoint"].as_str().map(String::from);
+    let drain = body["drain_secs"].as_u64().map(Duration::from_secs);
+
+    state.switch_upstream(network, endpoint.clone(), drain);
+    info!(
+        network,
+        endpoint,
+        drain_secs = drain.map(|d| d.as_secs()),
+        "upstream switched by admin"
+    );
+
+    json_response(json!({
+        "network": network,
+        "endpoint": endpoint,
+        "drain_secs": drain.map(|d| d.as_secs()),
+    }))
+}
+
+/// Read actions are open when no `ADMIN_TOKEN` is configured, write actions always require it.
+fn is_authorized(req: &Request<Incoming>, token: Option<&str>, write: bool) -> bool {
+    match token {
+        Some(token) => req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|bearer| secret_eq(bearer, token)),
+        None => !write,
+    }
+}
+
+async fn routes_match(
+    req: Request<Incoming>,
+    state: Arc<State>,
+) -> Result<ProxyResponse, hyper::Error> {
+    let write = req.method() != Method::GET;
+    if !is_authorized(&req, state.config().admin_token.as_deref(), write) {
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(full("Unauthorized"))
+            .unwrap());
+    }
+
+    let path = req.uri().path();
+    if let Some(key) = path
+        .strip_prefix("/admin/consumers/")
+        .and_then(|path| path.strip_suffix("/disconnect"))
+    {
+        if req.method() == Method::POST {
+            return api_disconnect_consumer(&state, key).await;
+        }
+    }
+
+    if let Some(network) = path
+        .strip_prefix("/admin/networks/")
+        .and_then(|path| path.strip_suffix("/switch"))
+    {
+        if req.method() == Method::POST {
+            let network = network.to_string();
+            return api_switch_upstream(&state, &network, req).await;
+        }
+    }
+
+    match (req.method(), path) {
+        (&Method::GET, "/admin/switches") => api_get_switches(&state).await,
+        (&Method::POST, "/admin/maintenance") => api_enable_maintenance(&state, req).await,
+        (&Method::DELETE, "/admin/maintenance") => api_disable_maintenance(&state).await,
+        (&Method::GET, "/admin/maintenance") => json_response(maintenance_json(&state)),
+        (&Method::GET, "/admin/consumers") => api_get_consumers(&state).await,
+        (&Method::GET, "/admin/tiers") => api_get_tiers(&state).await,
+        (&Method::GET, "/admin/limiters") => api_get_limiters(&state).await,
+        (&Method::GET, "/admin/connections") => api_get_connections(&state).await,
+        (&Method::GET, "/admin/status") => health::handle_status(&state).await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(full("Not Found"))
+            .unwrap()),
+    }
+}
+
+/// Serves the admin api when `ADMIN_ADDR` is set. It should only be bound to localhost or an
+/// address reachable from inside the cluster.
+#[instrument("admin server", skip_all)]
+pub async fn start(state: Arc<State>) {
+    let Some(admin_addr) = state.config().admin_addr.clone() else {
+        return std::future::pending().await;
+    };
+
+    let addr_result = SocketAddr::from_str(&admin_addr);
+    if let Err(err) = addr_result {
+        error!(error = err.to_string(), "invalid admin addr");
+        std::process::exit(1);
+    }
+    let addr = addr_result.unwrap();
+
+    let listener_result = TcpListener::bind(addr).await;
+    if let Err(err) = listener_result {
+        error!(
+            error = err.to_string(),
+            "fail to bind tcp admin server listener"
+        );
+        std::process::exit(1);
+    }
+    let listener = listener_result.unwrap();
+
+    info!(addr = admin_addr, "admin listening");
+
+    loop {
+        let state = state.clone();
+
+        let accept_result = listener.accept().await;
+        if let Err(err) = accept_result {
+            error!(error = err.to_string(), "accept client admin server");
+            continue;
+        }
+        let (stream, _) = accept_result.unwrap();
+
+        let io = TokioIo::new(stream);
+
+        tokio::task::spawn(async move {
+            let service = service_fn(move |req| routes_match(req, state.clone()));
+
+            if let Err(err) = http1_server::Builder::new()
+                .serve_connection(io, service)
+                .await
+            {
+                error!(error = err.to_string(), "failed admin server connection");
+            }
+        });
+    }
+}
@@ -0,0 +1,57 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::proxy::ProxyRequest;
+
+/// Emits the audit line of a session or request opened by an operator as a consumer.
+pub fn log_impersonation_started(proxy_req: &ProxyRequest, operator: &str) {
+    info!(
+        target: "audit",
+        request_id = proxy_req.request_id,
+        client_ip = proxy_req.client_ip.to_string(),
+        operator,
+        consumer = proxy_req.consumer.to_string(),
+        protocol = proxy_req.protocol.to_string(),
+        "impersonation started"
+    );
+}
+
+/// Emits the audit line closing an impersonated session or request, `outcome` is the close reason
+/// of websocket sessions and the status of http requests.
+pub fn log_impersonation_ended(proxy_req: &ProxyRequest, duration: Duration, outcome: &str) {
+    let Some(operator) = &proxy_req.impersonated_by else {
+        return;
+    };
+
+    info!(
+        target: "audit",
+        request_id = proxy_req.request_id,
+        client_ip = proxy_req.client_ip.to_string(),
+        operator,
+        consumer = proxy_req.consumer.to_string(),
+        protocol = proxy_req.protocol.to_string(),
+        duration_ms = duration.as_millis() as u64,
+        outcome,
+        "impersonation ended"
+    );
+}
+
+/// Emits the audit line of an impersonation attempt that was refused.
+pub fn log_impersonation_refused(
+    request_id: &str,
+    client_ip: &IpAddr,
+    operator: Option<&str>,
+    consumer: &str,
+    reason: &str,
+) {
+    warn!(
+        target: "audit",
+        request_id,
+        client_ip = client_ip.to_string(),
+        operator,
+        consumer,
+        reason,
+        "impersonation refused"
+    );
+}
@@ -1,16 +1,24 @@
 use futures_util::TryStreamExt;
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use operator::{
+    k8s_openapi::api::core::v1::Secret,
     kube::{
         runtime::watcher::{self, Config, Event},
         Api, Client, ResourceExt,
     },
-    OgmiosPort,
+    AuthTokens, OgmiosPort,
 };
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::{collections::HashMap, sync::Arc};
 use tokio::pin;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
-use crate::{Consumer, State};
+use crate::limiter;
+use crate::{Consumer, Revocation, State};
 
 #[instrument("auth background service", skip_all)]
 pub fn start(state: Arc<State>) {
@@ -29,35 +37,146 @@ pub fn start(state: Arc<State>) {
                 // Stream restart, also run on startup.
                 Ok(Some(Event::Restarted(crds))) => {
                     info!("auth: Watcher restarted, reseting consumers");
-                    let consumers: HashMap<String, Consumer> = crds
-                        .iter()
-                        .map(|crd| {
-                            let consumer = Consumer::from(crd);
-                            (consumer.key.clone(), consumer)
-                        })
-                        .collect();
-                    *state.consumers.write().await = consumers;
+                    let mut consumers: HashMap<String, Consumer> = HashMap::new();
+                    for crd in &crds {
+                        if let Some(consumer) = load_consumer(&client, crd).await {
+                            consumers.insert(consumer.key.clone(), consumer);
+                        }
+                    }
+                    *state.key_aliases.write().await = key_aliases(consumers.values());
+
+                    // Ports deleted or rotated while the watcher was down. Webhook consumers
+                    // don't come from ports, they're kept until their decision expires.
+                    let mut current = state.consumers.write().await;
+                    let previous = std::mem::replace(&mut *current, consumers);
+                    for (key, consumer) in previous.iter().filter(|(_, c)| c.external) {
+                        current
+                            .entry(key.clone())
+                            .or_insert_with(|| consumer.clone());
+                    }
+                    // Open sessions are still counted, on the new key of their port when it was
+                    // rotated in the meantime.
+                    for previous in previous.values().filter(|c| !c.external) {
+                        let port_consumer = current
+                            .values_mut()
+                            .find(|consumer| consumer.is_same_port(previous));
+                        if let Some(consumer) = port_consumer {
+                            consumer.active_connections += previous.active_connections;
+                        }
+                    }
+                    drop(current);
+                    for previous in previous.values().filter(|c| !c.external) {
+                        let current = state
+                            .get_port_consumer(&previous.namespace, &previous.port_name)
+                            .await;
+                        if current.is_none() {
+                            state.requests.write().await.remove(&previous.to_string());
+                        }
+                        revoke(&state, previous, current.as_ref());
+                    }
+                    state.rejected_keys.clear();
 
                     // When the watcher is restarted, we reset the limiter because a user
                     // could have changed the tier on the watcher restart.
                     state.limiter.write().await.clear();
+                    for previous in previous.values().filter(|c| !c.external) {
+                        if state.consumers.read().await.contains_key(&previous.key) {
+                            continue;
+                        }
+                        match state
+                            .get_port_consumer(&previous.namespace, &previous.port_name)
+                            .await
+                        {
+                            Some(current) => move_key(&state, &previous.key, &current.key).await,
+                            None => forget_key(&state, &previous.key).await,
+                        }
+                    }
+                    for crd in &crds {
+                        let namespace = crd.namespace().unwrap_or_default();
+                        let _ = state.update.send(format!("{namespace}.{}", crd.name_any()));
+                    }
+                    state.consumers_synced.store(true, Ordering::Relaxed);
                 }
                 // New port created or updated.
-                Ok(Some(Event::Applied(crd))) => match crd.status {
-                    Some(_) => {
+                Ok(Some(Event::Applied(crd))) => match load_consumer(&client, &crd).await {
+                    Some(mut consumer) => {
                         info!("auth: Adding new consumer: {}", crd.name_any());
-                        let consumer = Consumer::from(&crd);
-                        state.limiter.write().await.remove(&consumer.key);
-                        state
+                        let previous = state
                             .consumers
-                            .write()
+                            .read()
                             .await
-                            .insert(consumer.key.clone(), consumer);
+                            .get(&consumer.key)
+                            .map(|current| (current.tier.clone(), current.tier_overrides.clone()));
+                        match previous {
+                            // Updates that keep the tier, eg a status change, keep the balance.
+                            Some((tier, overrides))
+                                if tier == consumer.tier
+                                    && overrides == consumer.tier_overrides => {}
+                            Some((tier, _)) => {
+                                info!(
+                                    consumer = consumer.to_string(),
+                                    from = tier,
+                                    to = consumer.tier,
+                                    "auth: Tier changed, rebuilding limiter"
+                                );
+                                limiter::rebuild(&state, &consumer).await;
+                            }
+                            None => {
+                                state.limiter.write().await.remove(&consumer.key);
+                            }
+                        }
+
+                        // Keep the live connection count so the tier cap still applies to
+                        // sessions opened before the update.
+                        let mut consumers = state.consumers.write().await;
+                        if let Some(current) = consumers.get(&consumer.key) {
+                            consumer.active_connections = current.active_connections;
+                        }
+
+                        // The key was rotated, sessions opened with the previous key are still
+                        // counted against the port, on its new key.
+                        let mut rotated = Vec::new();
+                        let mut rotated_connections = 0;
+                        consumers.retain(|key, current| {
+                            let same_port = current.is_same_port(&consumer);
+                            if same_port {
+                                revoke(&state, current, Some(&consumer));
+                            }
+                            let keep = *key == consumer.key || !same_port;
+                            if !keep {
+                                rotated.push(key.clone());
+                                rotated_connections += current.active_connections;
+                            }
+                            keep
+                        });
+                        consumer.active_connections += rotated_connections;
+
+                        // Keys of the port may have been tried before it existed.
+                        let port_aliases = key_aliases([&consumer]);
+                        state
+                            .rejected_keys
+                            .remove(port_aliases.keys().chain([&consumer.key]));
+                        state
+                            .revoked_keys
+                            .remove(port_aliases.keys().chain([&consumer.key]));
+
+                        let mut aliases = state.key_aliases.write().await;
+                        aliases.retain(|_, primary| consumers.contains_key(primary));
+                        aliases.extend(port_aliases);
+                        let port = consumer.to_string();
+                        let primary = consumer.key.clone();
+                        consumers.insert(consumer.key.clone(), consumer);
+                        drop(aliases);
+                        drop(consumers);
+                        let _ = state.update.send(port);
+                        for key in &rotated {
+                            move_key(&state, key, &primary).await;
+                        }
                     }
                     None => {
                         // New ports are created without status. When the status is added, a new
                         // Applied event is triggered.
-                        info!("auth: New port created: {}", crd.name_any());
+                        info!("auth: Port without keys yet: {}", crd.name_any());
                     }
                 },
                 // Port deleted.
@@ -66,9 +185,21 @@ pub fn start(state: Arc<State>) {
                         "auth: Port deleted, removing from state: {}",
                         crd.name_any()
                     );
-                    let consumer = Consumer::from(&crd);
+                    // The keys of the port may be gone with its secret, it's found by name.
+                    let namespace = crd.namespace().unwrap_or_default();
+                    let Some(consumer) = state.get_port_consumer(&namespace, &crd.name_any()).await
+                    else {
+                        continue;
+                    };
                     state.consumers.write().await.remove(&consumer.key);
-                    state.limiter.write().await.remove(&consumer.key);
+                    state
+                        .key_aliases
+                        .write()
+                        .await
+                        .retain(|_, primary| *primary != consumer.key);
+                    forget_key(&state, &consumer.key).await;
+                    state.requests.write().await.remove(&consumer.to_string());
+                    revoke(&state, &consumer, None);
                 }
                 // Empty response from stream. Should never happen.
                 Ok(None) => {
@@ -84,3 +215,235 @@ pub fn start(state: Arc<State>) {
         }
     });
 }
+
+/// Returns the consumer named by a verified client certificate or an introspected token, whose
+/// identity has the form `PORT.NAMESPACE`.
+pub async fn authenticate_identity(state: &State, identity: &str) -> Option<Consumer> {
+    // Namespaces are DNS labels without dots, port names can have some.
+    let (port_name, namespace) = identity.rsplit_once('.')?;
+    state.get_port_consumer(namespace, port_name).await
+}
+
+/// Drops what's kept by key for a consumer that's gone.
+async fn forget_key(state: &State, key: &str) {
+    state.limiter.write().await.remove(key);
+    state.bandwidth.write().await.remove(key);
+    state.in_flight.write().await.remove(key);
+}
+
+/// Moves what's kept by key for a consumer to the new key of its port after a rotation, so the
+/// sessions opened with the previous key keep their balances.
+async fn move_key(state: &State, from: &str, to: &str) {
+    let mut limiters = state.limiter.write().await;
+    if let Some(limiter) = limiters.remove(from) {
+        limiters.entry(to.to_string()).or_insert(limiter);
+    }
+    drop(limiters);
+    let mut bandwidth = state.bandwidth.write().await;
+    if let Some(usage) = bandwidth.remove(from) {
+        bandwidth.entry(to.to_string()).or_insert(usage);
+    }
+    drop(bandwidth);
+    let mut in_flight = state.in_flight.write().await;
+    if let Some(semaphore) = in_flight.remove(from) {
+        in_flight.entry(to.to_string()).or_insert(semaphore);
+    }
+}
+
+/// Closes the sessions whose key the port doesn't accept anymore, or all of them when the port
+/// is gone.
+fn revoke(state: &State, previous: &Consumer, current: Option<&Consumer>) {
+    // Nobody is subscribed when there are no open sessions, that's not an error.
+    let Some(current) = current else {
+        for key in previous.accepted_keys() {
+            state.revoked_keys.insert(&key);
+        }
+        let _ = state.revoke.send(Revocation::Port(previous.to_string()));
+        return;
+    };
+
+    let accepted = current.accepted_keys();
+    for key in previous.accepted_keys() {
+        if !accepted.contains(&key) {
+            info!(consumer = previous.to_string(), "auth: Key revoked.");
+            state.revoked_keys.insert(&key);
+            let _ = state.revoke.send(Revocation::Key(key));
+        }
+    }
+}
+
+/// Builds the consumer of a port once the operator filled its keys, in its status or in the secret
+/// it names.
+async fn load_consumer(client: &Client, crd: &OgmiosPort) -> Option<Consumer> {
+    let status = crd.status.as_ref()?;
+    let Some(secret_name) = &status.auth_token_secret_ref else {
+        return Some(Consumer::new(crd, &AuthTokens::from_status(status)));
+    };
+
+    let namespace = crd.namespace()?;
+    let api = Api::<Secret>::namespaced(client.clone(), &namespace);
+    match api.get_opt(secret_name).await {
+        Ok(Some(secret)) => {
+            let tokens = AuthTokens::from_secret(&secret)?;
+            Some(Consumer::new(crd, &tokens))
+        }
+        Ok(None) => {
+            warn!(
+                namespace,
+                secret_name, "auth: Secret of the port not found."
+            );
+            None
+        }
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                namespace, secret_name, "auth: Failed to read the secret of the port."
+            );
+            None
+        }
+    }
+}
+
+/// Maps the alias and read-only keys of the consumers to their current key.
+fn key_aliases<'a>(consumers: impl IntoIterator<Item = &'a Consumer>) -> HashMap<String, String> {
+    consumers
+        .into_iter()
+        .flat_map(|consumer| {
+            consumer
+                .alias_keys
+                .keys()
+                .chain(&consumer.read_only_key)
+                .map(|alias| (alias.clone(), consumer.key.clone()))
+        })
+        .collect()
+}
+
+/// Claims of the JWTs accepted as credentials. They name the OgmiosPort the token was issued for.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    namespace: String,
+    port: String,
+    /// When set, it has to be the current tier of the port, so tokens issued before a downgrade
+    /// stop working.
+    tier: Option<String>,
+}
+
+async fn fetch_jwks(url: &str) -> Result<JwkSet, reqwest::Error> {
+    reqwest::get(url).await?.error_for_status()?.json().await
+}
+
+/// Keeps the signing keys of `PROXY_JWKS_URL` up to date. JWT authentication is disabled when
+/// the url isn't set.
+#[instrument("jwks background service", skip_all)]
+pub fn start_jwks(state: Arc<State>) {
+    let Some(url) = state.config().proxy_jwks_url.clone() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            match fetch_jwks(&url).await {
+                Ok(jwks) => {
+                    info!(keys = jwks.keys.len(), "auth: JWKS refreshed");
+                    *state.jwks.write().await = Some(jwks);
+                }
+                // The keys fetched previously are kept until the next refresh.
+                Err(err) => error!(error = err.to_string(), "auth: Failed to fetch JWKS."),
+            }
+            tokio::time::sleep(state.config().proxy_jwks_refresh_interval).await;
+        }
+    });
+}
+
+/// Algorithms a signing key verifies: the one it names, or else the asymmetric ones of its key
+/// type. The header of the token only picks among them, it can't switch the key to another one.
+fn jwk_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(algorithm) = &jwk.common.key_algorithm {
+        return Algorithm::from_str(&algorithm.to_string())
+            .into_iter()
+            .collect();
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => vec![],
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        // Shared secrets have no place in a published key set.
+        AlgorithmParameters::OctetKey(_) => vec![],
+    }
+}
+
+/// Why a bearer token wasn't accepted as a JWT.
+pub enum JwtRejection {
+    /// Not a JWT signed by one of the keys, or its claims don't name a port.
+    Invalid,
+    /// Signed by one of the keys, but past its `exp`.
+    Expired,
+}
+
+/// Validates a JWT against the signing keys and returns the consumer of the port named by its
+/// claims. The token is refused when its key id isn't in the JWKS, when its algorithm isn't one
+/// of the key, when it's expired, or when the issuer or audience don't match the configured ones.
+pub async fn authenticate_jwt(state: &State, token: &str) -> Result<Consumer, JwtRejection> {
+    let header = decode_header(token).map_err(|_| JwtRejection::Invalid)?;
+    let (decoding_key, algorithms) = {
+        let jwks = state.jwks.read().await;
+        let jwk = jwks
+            .as_ref()
+            .zip(header.kid.as_deref())
+            .and_then(|(jwks, kid)| jwks.find(kid))
+            .ok_or(JwtRejection::Invalid)?;
+        let decoding_key = DecodingKey::from_jwk(jwk).map_err(|_| JwtRejection::Invalid)?;
+        (decoding_key, jwk_algorithms(jwk))
+    };
+    if !algorithms.contains(&header.alg) {
+        warn!(
+            algorithm = format!("{:?}", header.alg),
+            "auth: JWT algorithm doesn't match its key."
+        );
+        return Err(JwtRejection::Invalid);
+    }
+
+    let config = state.config();
+    let mut validation = Validation::new(header.alg);
+    if let Some(issuer) = &config.proxy_jwt_issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    match &config.proxy_jwt_audience {
+        Some(audience) => validation.set_audience(&[audience]),
+        None => validation.validate_aud = false,
+    }
+
+    // The expiry is only checked once the signature is, so an unsigned token can't pass for an
+    // expired one in the failures metric.
+    let claims = match decode::<Claims>(token, &decoding_key, &validation) {
+        Ok(data) => data.claims,
+        Err(err) if *err.kind() == ErrorKind::ExpiredSignature => {
+            return Err(JwtRejection::Expired)
+        }
+        Err(err) => {
+            warn!(error = err.to_string(), "auth: Invalid JWT.");
+            return Err(JwtRejection::Invalid);
+        }
+    };
+
+    let consumer = state
+        .get_port_consumer(&claims.namespace, &claims.port)
+        .await
+        .ok_or(JwtRejection::Invalid)?;
+    if claims.tier.is_some_and(|tier| tier != consumer.tier) {
+        return Err(JwtRejection::Invalid);
+    }
+
+    Ok(consumer)
+}
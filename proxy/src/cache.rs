@@ -0,0 +1,64 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::jsonrpc::JsonRpcRequest;
+
+struct CacheEntry {
+    expires_at: Instant,
+    response: Value,
+}
+
+/// Short lived cache for the responses of read-only JSON-RPC calls, keyed by network, method and
+/// params. Only the methods configured as cacheable are stored.
+pub struct ResponseCache {
+    ttl: Duration,
+    methods: Vec<String>,
+    entries: RwLock<HashMap<String, CacheEntry>>,
+}
+impl ResponseCache {
+    pub fn new(ttl: Duration, methods: Vec<String>) -> Self {
+        Self {
+            ttl,
+            methods,
+            entries: Default::default(),
+        }
+    }
+
+    /// Cache key for the request, or `None` when the request can't be cached.
+    pub fn key(&self, network: &str, request: &JsonRpcRequest) -> Option<String> {
+        if self.ttl.is_zero() || !self.methods.contains(&request.method) {
+            return None;
+        }
+
+        let params = request.params.clone().unwrap_or_default();
+        Some(format!("{network}.{}.{params}", request.method))
+    }
+
+    /// Returns the cached response with the id of the current request.
+    pub fn get(&self, key: &str, id: Option<&Value>) -> Option<Value> {
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(key).filter(|e| e.expires_at > Instant::now())?;
+
+        let mut response = entry.response.clone();
+        if let Some(object) = response.as_object_mut() {
+            object.insert("id".into(), id.cloned().unwrap_or_default());
+        }
+        Some(response)
+    }
+
+    pub fn insert(&self, key: String, response: Value) {
+        let now = Instant::now();
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|_, e| e.expires_at > now);
+        entries.insert(
+            key,
+            CacheEntry {
+                expires_at: now + self.ttl,
+                response,
+            },
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy)]
+enum CircuitState {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+/// Fast-fails new connections to a network and version while its upstream is unavailable, the
+/// other routes keep their own circuit. After the cooldown a single probe is let through, its
+/// result decides whether the circuit closes again or stays open.
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    cooldown: Duration,
+    inner: Mutex<HashMap<(String, String), (CircuitState, usize)>>,
+}
+impl CircuitBreaker {
+    pub fn new(failure_threshold: usize, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            inner: Default::default(),
+        }
+    }
+
+    /// Returns the time the client should wait before retrying when the circuit is open.
+    pub fn allow(&self, network: &str, version: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let Some(circuit) = inner.get_mut(&(network.to_string(), version.to_string())) else {
+            return Ok(());
+        };
+
+        match circuit.0 {
+            CircuitState::Closed => Ok(()),
+            CircuitState::Open { until } if now < until => Err(until - now),
+            CircuitState::HalfOpen { probe_started } if now < probe_started + self.cooldown => {
+                Err(probe_started + self.cooldown - now)
+            }
+            // Cooldown expired, or the previous probe never reported back.
+            _ => {
+                circuit.0 = CircuitState::HalfOpen { probe_started: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self, network: &str, version: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(circuit) = inner.remove(&(network.to_string(), version.to_string())) else {
+            return;
+        };
+        if !matches!(circuit.0, CircuitState::Closed) {
+            info!(network, version, "upstream circuit closed");
+        }
+    }
+
+    pub fn record_failure(&self, network: &str, version: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let circuit = inner
+            .entry((network.to_string(), version.to_string()))
+            .or_insert((CircuitState::Closed, 0));
+        circuit.1 += 1;
+
+        let failures = circuit.1;
+        if matches!(circuit.0, CircuitState::HalfOpen { .. }) || failures >= self.failure_threshold
+        {
+            self.open(circuit, network, version);
+        }
+    }
+
+    /// Opens the circuit right away, used when the health check deems the upstream unhealthy.
+    pub fn trip(&self, network: &str, version: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let circuit = inner
+            .entry((network.to_string(), version.to_string()))
+            .or_insert((CircuitState::Closed, 0));
+        if matches!(circuit.0, CircuitState::Closed) {
+            self.open(circuit, network, version);
+        }
+    }
+
+    fn open(&self, circuit: &mut (CircuitState, usize), network: &str, version: &str) {
+        if !matches!(circuit.0, CircuitState::Open { .. }) {
+            warn!(
+                network,
+                version,
+                failures = circuit.1,
+                "upstream circuit opened"
+            );
+        }
+        circuit.0 = CircuitState::Open {
+            until: Instant::now() + self.cooldown,
+        };
+    }
+}
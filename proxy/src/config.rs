@@ -1,66 +1,683 @@
-use std::{env, path::PathBuf, time::Duration};
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, time::Duration};
+
+use crate::jsonrpc::OGMIOS_METHODS;
+use crate::metrics::ConsumerLevel;
+use crate::proxy::SlowClientPolicy;
+use crate::upstream::UpstreamStrategy;
+use crate::usage::UsageSinkFormat;
+use crate::utils::handle_legacy_networks;
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub proxy_addr: String,
+    pub proxy_addrs: Vec<String>,
+    pub proxy_acceptors: usize,
+    pub proxy_protocol: bool,
+    pub proxy_trusted_proxies: Vec<IpNet>,
     pub proxy_namespace: String,
-    pub proxy_tiers_path: PathBuf,
-    pub proxy_tiers_poll_interval: Duration,
+    pub proxy_host_regex: String,
+    pub proxy_host_regex_key_group: usize,
+    pub proxy_tiers_path: Option<PathBuf>,
+    pub proxy_default_tier: Option<String>,
+    pub proxy_quota_state_path: Option<PathBuf>,
+    pub proxy_quota_flush_interval: Duration,
+    pub proxy_trial_check_interval: Duration,
     pub prometheus_addr: String,
+    pub health_addr: Option<String>,
+    pub admin_addr: Option<String>,
+    pub proxy_jwks_url: Option<String>,
+    pub proxy_jwks_refresh_interval: Duration,
+    pub proxy_jwt_issuer: Option<String>,
+    pub proxy_jwt_audience: Option<String>,
+    pub proxy_auth_webhook_url: Option<String>,
+    pub proxy_auth_webhook_timeout: Duration,
+    pub proxy_usage_sink_url: Option<String>,
+    pub proxy_usage_sink_format: UsageSinkFormat,
+    pub proxy_usage_sink_token: Option<String>,
+    pub proxy_usage_sink_timeout: Duration,
+    pub proxy_usage_export_interval: Duration,
+    pub proxy_auth_webhook_cache_ttl: Duration,
+    pub proxy_introspection_url: Option<String>,
+    pub proxy_introspection_client_id: Option<String>,
+    pub proxy_introspection_client_secret: Option<String>,
+    pub proxy_introspection_claim: String,
+    pub proxy_introspection_cache_ttl: Duration,
+    pub admin_token: Option<String>,
+    pub proxy_impersonation_token: Option<String>,
+    pub proxy_maintenance: bool,
+    pub proxy_maintenance_message: String,
+    pub access_log_requests: bool,
+    pub metrics_methods: Vec<String>,
+    pub metrics_consumer_level: ConsumerLevel,
+    pub metrics_max_consumers: Option<usize>,
+    pub metrics_limiter_balances: bool,
+    pub metrics_snapshot_interval: Option<Duration>,
+    pub metrics_token: Option<String>,
+    pub metrics_basic_auth: Option<String>,
+    pub metrics_allowed_cidrs: Vec<IpNet>,
     pub ogmios_port: u16,
     pub ogmios_dns: String,
-    pub ssl_crt_path: PathBuf,
-    pub ssl_key_path: PathBuf,
-    pub network: String,
+    pub ogmios_instance_template: String,
+    pub ogmios_versions: Option<Vec<String>>,
+    pub ogmios_endpoints: BTreeMap<String, String>,
+    pub ogmios_upstreams: BTreeMap<String, Vec<String>>,
+    pub ogmios_fallbacks: BTreeMap<String, Vec<String>>,
+    pub ogmios_upstream_strategy: UpstreamStrategy,
+    pub ogmios_tls: bool,
+    pub ogmios_tls_ca_path: Option<PathBuf>,
+    pub ogmios_tls_server_name: Option<String>,
+    pub proxy_dns_ttl: Duration,
+    pub proxy_http_pool_max_idle: usize,
+    pub proxy_http_pool_idle_timeout: Duration,
+    pub ssl_crt_path: Option<PathBuf>,
+    pub ssl_key_path: Option<PathBuf>,
+    pub ssl_poll_interval: Duration,
+    pub proxy_client_ca_path: Option<PathBuf>,
+    pub networks: Vec<String>,
+    pub proxy_shutdown_grace_period: Duration,
+    pub proxy_ws_ping_interval: Duration,
+    pub proxy_ws_keepalive_timeout: Duration,
+    pub proxy_idle_timeout: Option<Duration>,
+    pub proxy_reconnect_token_ttl: Option<Duration>,
+    pub proxy_ws_max_client_message_size: usize,
+    pub proxy_ws_max_instance_message_size: usize,
+    pub proxy_ws_client_buffer_size: usize,
+    pub proxy_ws_session_memory_limit: Option<usize>,
+    pub proxy_slow_client_policy: SlowClientPolicy,
+    pub proxy_circuit_failure_threshold: usize,
+    pub proxy_circuit_cooldown: Duration,
+    pub proxy_auth_failure_threshold: u32,
+    pub proxy_auth_ban_duration: Duration,
+    pub proxy_auth_ban_max_duration: Duration,
+    pub proxy_auth_negative_cache_ttl: Duration,
+    pub proxy_cors_allowed_origins: Vec<String>,
+    pub proxy_cache_ttl: Duration,
+    pub proxy_cache_methods: Vec<String>,
+    pub proxy_rate_limit_max_wait: Option<Duration>,
+    pub proxy_rate_limit_delay_threshold: Duration,
+    pub proxy_upstream_capacity: Option<usize>,
+    pub proxy_scheduler_max_wait: Duration,
 
     // Health endpoint
     pub health_poll_interval: std::time::Duration,
+    pub health_min_synchronization: f64,
+    pub health_max_tip_age: Duration,
+    pub health_timeout: Duration,
+    pub health_rise: usize,
+    pub health_fall: usize,
 }
 
 impl Config {
-    pub fn new() -> Self {
-        Self {
-            network: env::var("NETWORK").expect("NETWORK must be set"),
-            proxy_addr: env::var("PROXY_ADDR").expect("PROXY_ADDR must be set"),
-            proxy_namespace: env::var("PROXY_NAMESPACE").unwrap_or("ftr-ogmios-v1".into()),
-            proxy_tiers_path: env::var("PROXY_TIERS_PATH")
-                .map(|v| v.into())
-                .expect("PROXY_TIERS_PATH must be set"),
-            proxy_tiers_poll_interval: env::var("PROXY_TIERS_POLL_INTERVAL")
-                .map(|v| {
-                    Duration::from_secs(
-                        v.parse::<u64>()
-                            .expect("PROXY_TIERS_POLL_INTERVAL must be a number in seconds. eg: 2"),
-                    )
-                })
+    /// Reads the environment, with the env file at `PROXY_CONFIG_PATH` on top when set. The
+    /// process environment is never modified, so a key removed from the file falls back to it.
+    pub fn load() -> Result<Self, ConfigError> {
+        let file = match env::var("PROXY_CONFIG_PATH") {
+            Ok(path) => read_env_file(Path::new(&path))
+                .map_err(|err| ConfigError(format!("fail to read {path}: {err}")))?,
+            Err(_) => HashMap::new(),
+        };
+        Self::from_source(&|key| file.get(key).cloned().or_else(|| env::var(key).ok()))
+    }
+
+    /// Builds the configuration from the settings `source` returns by name, refusing the first
+    /// one that's invalid.
+    pub fn from_source(source: &impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let vars = Vars(source);
+        Ok(Self {
+            networks: vars
+                .required("NETWORK")?
+                .split(',')
+                .map(handle_legacy_networks)
+                .collect(),
+            proxy_addrs: vars
+                .required("PROXY_ADDR")?
+                .split(',')
+                .map(|addr| addr.trim().to_string())
+                .collect(),
+            proxy_acceptors: vars
+                .positive(
+                    "PROXY_ACCEPTORS",
+                    "PROXY_ACCEPTORS must be a number of sockets above 0. eg: 4",
+                )?
+                .unwrap_or(1),
+            proxy_protocol: vars.flag("PROXY_PROTOCOL"),
+            proxy_trusted_proxies: vars.cidrs(
+                "PROXY_TRUSTED_PROXIES",
+                "PROXY_TRUSTED_PROXIES must be a list of CIDRs. eg: 10.0.0.0/8",
+            )?,
+            proxy_namespace: vars
+                .get("PROXY_NAMESPACE")
+                .unwrap_or("ftr-ogmios-v1".into()),
+            proxy_host_regex: vars
+                .get("PROXY_HOST_REGEX")
+                .unwrap_or(r"([dmtr_]?[\w\d-]+)?\.?.+".into()),
+            proxy_host_regex_key_group: vars
+                .parse(
+                    "PROXY_HOST_REGEX_KEY_GROUP",
+                    "PROXY_HOST_REGEX_KEY_GROUP must be a capture group number. eg: 1",
+                )?
+                .unwrap_or(1),
+            proxy_tiers_path: vars.get("PROXY_TIERS_PATH").map(|v| v.into()),
+            proxy_default_tier: vars.get("PROXY_DEFAULT_TIER"),
+            proxy_quota_state_path: vars.get("PROXY_QUOTA_STATE_PATH").map(|v| v.into()),
+            proxy_quota_flush_interval: vars
+                .positive_secs(
+                    "PROXY_QUOTA_FLUSH_INTERVAL",
+                    "PROXY_QUOTA_FLUSH_INTERVAL must be a number of seconds above 0. eg: 10",
+                )?
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_trial_check_interval: vars
+                .positive_secs(
+                    "PROXY_TRIAL_CHECK_INTERVAL",
+                    "PROXY_TRIAL_CHECK_INTERVAL must be a number of seconds above 0. eg: 60",
+                )?
+                .unwrap_or(Duration::from_secs(60)),
+            proxy_shutdown_grace_period: vars
+                .secs(
+                    "PROXY_SHUTDOWN_GRACE_PERIOD",
+                    "PROXY_SHUTDOWN_GRACE_PERIOD must be a number in seconds. eg: 30",
+                )?
+                .unwrap_or(Duration::from_secs(30)),
+            proxy_ws_ping_interval: vars
+                .positive_secs(
+                    "PROXY_WS_PING_INTERVAL",
+                    "PROXY_WS_PING_INTERVAL must be a number of seconds above 0. eg: 30",
+                )?
+                .unwrap_or(Duration::from_secs(30)),
+            proxy_ws_keepalive_timeout: vars
+                .secs(
+                    "PROXY_WS_KEEPALIVE_TIMEOUT",
+                    "PROXY_WS_KEEPALIVE_TIMEOUT must be a number in seconds. eg: 90",
+                )?
+                .unwrap_or(Duration::from_secs(90)),
+            proxy_idle_timeout: vars.secs(
+                "PROXY_IDLE_TIMEOUT",
+                "PROXY_IDLE_TIMEOUT must be a number in seconds. eg: 600",
+            )?,
+            proxy_reconnect_token_ttl: vars.secs(
+                "PROXY_RECONNECT_TOKEN_TTL",
+                "PROXY_RECONNECT_TOKEN_TTL must be a number in seconds. eg: 60",
+            )?,
+            proxy_ws_max_client_message_size: vars
+                .parse(
+                    "PROXY_WS_MAX_CLIENT_MESSAGE_SIZE",
+                    "PROXY_WS_MAX_CLIENT_MESSAGE_SIZE must be a number in bytes",
+                )?
+                .unwrap_or(1 << 20),
+            proxy_ws_max_instance_message_size: vars
+                .parse(
+                    "PROXY_WS_MAX_INSTANCE_MESSAGE_SIZE",
+                    "PROXY_WS_MAX_INSTANCE_MESSAGE_SIZE must be a number in bytes",
+                )?
+                .unwrap_or(64 << 20),
+            proxy_ws_client_buffer_size: vars
+                .parse(
+                    "PROXY_WS_CLIENT_BUFFER_SIZE",
+                    "PROXY_WS_CLIENT_BUFFER_SIZE must be a number of messages. eg: 64",
+                )?
+                .unwrap_or(64),
+            proxy_ws_session_memory_limit: vars.parse(
+                "PROXY_WS_SESSION_MEMORY_LIMIT",
+                "PROXY_WS_SESSION_MEMORY_LIMIT must be a number in bytes",
+            )?,
+            proxy_slow_client_policy: vars
+                .parse(
+                    "PROXY_SLOW_CLIENT_POLICY",
+                    "PROXY_SLOW_CLIENT_POLICY must be pause or disconnect",
+                )?
+                .unwrap_or(SlowClientPolicy::Pause),
+            proxy_circuit_failure_threshold: vars
+                .parse(
+                    "PROXY_CIRCUIT_FAILURE_THRESHOLD",
+                    "PROXY_CIRCUIT_FAILURE_THRESHOLD must be a number. eg: 5",
+                )?
+                .unwrap_or(5),
+            proxy_circuit_cooldown: vars
+                .secs(
+                    "PROXY_CIRCUIT_COOLDOWN",
+                    "PROXY_CIRCUIT_COOLDOWN must be a number in seconds. eg: 10",
+                )?
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_auth_failure_threshold: vars
+                .parse(
+                    "PROXY_AUTH_FAILURE_THRESHOLD",
+                    "PROXY_AUTH_FAILURE_THRESHOLD must be a number of failures. eg: 20",
+                )?
+                .unwrap_or(20),
+            proxy_auth_ban_duration: vars
+                .secs(
+                    "PROXY_AUTH_BAN_DURATION",
+                    "PROXY_AUTH_BAN_DURATION must be a number in seconds. eg: 60",
+                )?
+                .unwrap_or(Duration::from_secs(60)),
+            proxy_auth_ban_max_duration: vars
+                .secs(
+                    "PROXY_AUTH_BAN_MAX_DURATION",
+                    "PROXY_AUTH_BAN_MAX_DURATION must be a number in seconds. eg: 3600",
+                )?
+                .unwrap_or(Duration::from_secs(3600)),
+            proxy_auth_negative_cache_ttl: vars
+                .secs(
+                    "PROXY_AUTH_NEGATIVE_CACHE_TTL",
+                    "PROXY_AUTH_NEGATIVE_CACHE_TTL must be a number in seconds. eg: 30",
+                )?
+                .unwrap_or(Duration::from_secs(30)),
+            proxy_cors_allowed_origins: vars
+                .get("PROXY_CORS_ALLOWED_ORIGINS")
+                .map(|v| v.split(',').map(String::from).collect())
+                .unwrap_or_default(),
+            proxy_cache_ttl: vars
+                .secs(
+                    "PROXY_CACHE_TTL",
+                    "PROXY_CACHE_TTL must be a number in seconds. eg: 2",
+                )?
+                .unwrap_or(Duration::from_secs(0)),
+            proxy_cache_methods: vars
+                .get("PROXY_CACHE_METHODS")
+                .unwrap_or("queryNetwork/tip,queryNetwork/genesisConfiguration,queryLedgerState/protocolParameters".into())
+                .split(',')
+                .map(String::from)
+                .collect(),
+            proxy_upstream_capacity: vars.parse(
+                "PROXY_UPSTREAM_CAPACITY",
+                "PROXY_UPSTREAM_CAPACITY must be a number of requests. eg: 1000",
+            )?,
+            proxy_scheduler_max_wait: vars
+                .secs(
+                    "PROXY_SCHEDULER_MAX_WAIT",
+                    "PROXY_SCHEDULER_MAX_WAIT must be a number in seconds. eg: 5",
+                )?
+                .unwrap_or(Duration::from_secs(5)),
+            proxy_rate_limit_max_wait: vars.secs(
+                "PROXY_RATE_LIMIT_MAX_WAIT",
+                "PROXY_RATE_LIMIT_MAX_WAIT must be a number in seconds. eg: 5",
+            )?,
+            proxy_rate_limit_delay_threshold: vars
+                .secs(
+                    "PROXY_RATE_LIMIT_DELAY_THRESHOLD",
+                    "PROXY_RATE_LIMIT_DELAY_THRESHOLD must be a number in seconds. eg: 1",
+                )?
+                .unwrap_or(Duration::from_secs(1)),
+            prometheus_addr: vars.required("PROMETHEUS_ADDR")?,
+            health_addr: vars.get("HEALTH_ADDR"),
+            admin_addr: vars.get("ADMIN_ADDR"),
+            proxy_jwks_url: vars.get("PROXY_JWKS_URL"),
+            proxy_jwks_refresh_interval: vars
+                .secs(
+                    "PROXY_JWKS_REFRESH_INTERVAL",
+                    "PROXY_JWKS_REFRESH_INTERVAL must be a number in seconds. eg: 300",
+                )?
+                .unwrap_or(Duration::from_secs(300)),
+            proxy_jwt_issuer: vars.get("PROXY_JWT_ISSUER"),
+            proxy_jwt_audience: vars.get("PROXY_JWT_AUDIENCE"),
+            proxy_auth_webhook_url: vars.get("PROXY_AUTH_WEBHOOK_URL"),
+            proxy_auth_webhook_timeout: vars
+                .secs(
+                    "PROXY_AUTH_WEBHOOK_TIMEOUT",
+                    "PROXY_AUTH_WEBHOOK_TIMEOUT must be a number in seconds. eg: 2",
+                )?
                 .unwrap_or(Duration::from_secs(2)),
-            prometheus_addr: env::var("PROMETHEUS_ADDR").expect("PROMETHEUS_ADDR must be set"),
-            ssl_crt_path: env::var("SSL_CRT_PATH")
-                .map(|e| e.into())
-                .expect("SSL_CRT_PATH must be set"),
-            ssl_key_path: env::var("SSL_KEY_PATH")
-                .map(|e| e.into())
-                .expect("SSL_KEY_PATH must be set"),
-            ogmios_port: env::var("OGMIOS_PORT")
-                .expect("OGMIOS_PORT must be set")
-                .parse()
-                .expect("OGMIOS_PORT must a number"),
-            ogmios_dns: env::var("OGMIOS_DNS").expect("OGMIOS_DNS must be set"),
-            health_poll_interval: env::var("HEALTH_POLL_INTERVAL")
-                .map(|v| {
-                    Duration::from_secs(
-                        v.parse::<u64>()
-                            .expect("HEALTH_POLL_INTERVAL must be a number in seconds. eg: 2"),
-                    )
-                })
+            proxy_usage_sink_url: vars.get("PROXY_USAGE_SINK_URL"),
+            proxy_usage_sink_format: vars
+                .parse(
+                    "PROXY_USAGE_SINK_FORMAT",
+                    "PROXY_USAGE_SINK_FORMAT must be json or kafka-rest",
+                )?
+                .unwrap_or(UsageSinkFormat::Json),
+            proxy_usage_sink_token: vars.get("PROXY_USAGE_SINK_TOKEN"),
+            proxy_usage_sink_timeout: vars
+                .secs(
+                    "PROXY_USAGE_SINK_TIMEOUT",
+                    "PROXY_USAGE_SINK_TIMEOUT must be a number in seconds. eg: 10",
+                )?
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_usage_export_interval: vars
+                .positive_secs(
+                    "PROXY_USAGE_EXPORT_INTERVAL",
+                    "PROXY_USAGE_EXPORT_INTERVAL must be a number of seconds above 0. eg: 60",
+                )?
+                .unwrap_or(Duration::from_secs(60)),
+            proxy_auth_webhook_cache_ttl: vars
+                .secs(
+                    "PROXY_AUTH_WEBHOOK_CACHE_TTL",
+                    "PROXY_AUTH_WEBHOOK_CACHE_TTL must be a number in seconds. eg: 60",
+                )?
+                .unwrap_or(Duration::from_secs(60)),
+            proxy_introspection_url: vars.get("PROXY_INTROSPECTION_URL"),
+            proxy_introspection_client_id: vars.get("PROXY_INTROSPECTION_CLIENT_ID"),
+            proxy_introspection_client_secret: vars.get("PROXY_INTROSPECTION_CLIENT_SECRET"),
+            proxy_introspection_claim: vars
+                .get("PROXY_INTROSPECTION_CLAIM")
+                .unwrap_or("sub".into()),
+            proxy_introspection_cache_ttl: vars
+                .secs(
+                    "PROXY_INTROSPECTION_CACHE_TTL",
+                    "PROXY_INTROSPECTION_CACHE_TTL must be a number in seconds. eg: 60",
+                )?
+                .unwrap_or(Duration::from_secs(60)),
+            admin_token: vars.get("ADMIN_TOKEN"),
+            proxy_impersonation_token: vars.get("PROXY_IMPERSONATION_TOKEN"),
+            proxy_maintenance: vars.flag("PROXY_MAINTENANCE"),
+            proxy_maintenance_message: vars
+                .get("PROXY_MAINTENANCE_MESSAGE")
+                .unwrap_or("The service is under maintenance, please retry later".into()),
+            access_log_requests: vars.flag("ACCESS_LOG_REQUESTS"),
+            metrics_methods: vars
+                .get("METRICS_METHODS")
+                .map(|v| v.split(',').map(String::from).collect())
+                .unwrap_or(OGMIOS_METHODS.iter().map(|m| m.to_string()).collect()),
+            metrics_consumer_level: vars
+                .parse(
+                    "METRICS_CONSUMER_LEVEL",
+                    "METRICS_CONSUMER_LEVEL must be consumer, namespace or tier",
+                )?
+                .unwrap_or(ConsumerLevel::Consumer),
+            metrics_max_consumers: vars.parse(
+                "METRICS_MAX_CONSUMERS",
+                "METRICS_MAX_CONSUMERS must be a number. eg: 1000",
+            )?,
+            metrics_limiter_balances: vars.flag("METRICS_LIMITER_BALANCES"),
+            metrics_snapshot_interval: vars.positive_secs(
+                "METRICS_SNAPSHOT_INTERVAL",
+                "METRICS_SNAPSHOT_INTERVAL must be a number of seconds above 0. eg: 15",
+            )?,
+            metrics_token: vars.get("METRICS_TOKEN"),
+            metrics_basic_auth: vars.get("METRICS_BASIC_AUTH"),
+            metrics_allowed_cidrs: vars.cidrs(
+                "METRICS_ALLOWED_CIDRS",
+                "METRICS_ALLOWED_CIDRS must be a list of CIDRs. eg: 10.0.0.0/8",
+            )?,
+            ssl_crt_path: vars.get("SSL_CRT_PATH").map(|e| e.into()),
+            ssl_key_path: vars.get("SSL_KEY_PATH").map(|e| e.into()),
+            ssl_poll_interval: vars
+                .secs(
+                    "SSL_POLL_INTERVAL",
+                    "SSL_POLL_INTERVAL must be a number in seconds. eg: 10",
+                )?
+                .unwrap_or(Duration::from_secs(10)),
+            proxy_client_ca_path: vars.get("PROXY_CLIENT_CA_PATH").map(|v| v.into()),
+            ogmios_port: vars
+                .parse("OGMIOS_PORT", "OGMIOS_PORT must a number")?
+                .ok_or_else(|| ConfigError("OGMIOS_PORT must be set".into()))?,
+            ogmios_dns: vars.required("OGMIOS_DNS")?,
+            ogmios_instance_template: vars
+                .get("OGMIOS_INSTANCE_TEMPLATE")
+                .unwrap_or("ogmios-{network}-{version}".into()),
+            ogmios_versions: vars
+                .get("OGMIOS_VERSIONS")
+                .map(|v| v.split(',').map(String::from).collect()),
+            ogmios_endpoints: vars
+                .get("OGMIOS_ENDPOINTS")
+                .map(|v| parse_endpoints(&v))
+                .transpose()?
+                .unwrap_or_default(),
+            ogmios_upstreams: vars
+                .get("OGMIOS_UPSTREAMS")
+                .map(|v| parse_upstreams(&v, "OGMIOS_UPSTREAMS"))
+                .transpose()?
+                .unwrap_or_default(),
+            ogmios_fallbacks: vars
+                .get("OGMIOS_FALLBACKS")
+                .map(|v| parse_upstreams(&v, "OGMIOS_FALLBACKS"))
+                .transpose()?
+                .unwrap_or_default(),
+            ogmios_upstream_strategy: vars
+                .parse(
+                    "OGMIOS_UPSTREAM_STRATEGY",
+                    "OGMIOS_UPSTREAM_STRATEGY must be round-robin or least-connections",
+                )?
+                .unwrap_or(UpstreamStrategy::RoundRobin),
+            ogmios_tls: vars.flag("OGMIOS_TLS"),
+            ogmios_tls_ca_path: vars.get("OGMIOS_TLS_CA_PATH").map(|v| v.into()),
+            ogmios_tls_server_name: vars.get("OGMIOS_TLS_SERVER_NAME"),
+            proxy_dns_ttl: vars
+                .secs(
+                    "PROXY_DNS_TTL",
+                    "PROXY_DNS_TTL must be a number in seconds. eg: 30",
+                )?
+                .unwrap_or(Duration::from_secs(30)),
+            proxy_http_pool_max_idle: vars
+                .parse(
+                    "PROXY_HTTP_POOL_MAX_IDLE",
+                    "PROXY_HTTP_POOL_MAX_IDLE must be a number of connections. eg: 32",
+                )?
+                .unwrap_or(32),
+            proxy_http_pool_idle_timeout: vars
+                .secs(
+                    "PROXY_HTTP_POOL_IDLE_TIMEOUT",
+                    "PROXY_HTTP_POOL_IDLE_TIMEOUT must be a number in seconds. eg: 90",
+                )?
+                .unwrap_or(Duration::from_secs(90)),
+            health_poll_interval: vars
+                .secs(
+                    "HEALTH_POLL_INTERVAL",
+                    "HEALTH_POLL_INTERVAL must be a number in seconds. eg: 2",
+                )?
                 .unwrap_or(Duration::from_secs(10)),
+            health_min_synchronization: vars
+                .parse(
+                    "HEALTH_MIN_SYNCHRONIZATION",
+                    "HEALTH_MIN_SYNCHRONIZATION must be a number between 0 and 1. eg: 0.999",
+                )?
+                .unwrap_or(0.999),
+            health_max_tip_age: vars
+                .secs(
+                    "HEALTH_MAX_TIP_AGE",
+                    "HEALTH_MAX_TIP_AGE must be a number in seconds. eg: 300",
+                )?
+                .unwrap_or(Duration::from_secs(300)),
+            health_timeout: vars
+                .positive_secs(
+                    "HEALTH_TIMEOUT",
+                    "HEALTH_TIMEOUT must be a number of seconds above 0. eg: 5",
+                )?
+                .unwrap_or(Duration::from_secs(5)),
+            health_rise: vars
+                .parse("HEALTH_RISE", "HEALTH_RISE must be a number. eg: 2")?
+                .unwrap_or(1),
+            health_fall: vars
+                .parse("HEALTH_FALL", "HEALTH_FALL must be a number. eg: 3")?
+                .unwrap_or(1),
+        })
+    }
+
+    /// The instance of a network and version. Networks with an entry in `OGMIOS_ENDPOINTS` use
+    /// it, with `{version}` replaced, the others are built from the DNS template.
+    pub fn instance(&self, network: &str, version: &str) -> String {
+        if let Some(endpoint) = self.ogmios_endpoints.get(network) {
+            return endpoint.replace("{version}", version);
+        }
+
+        let name = self
+            .ogmios_instance_template
+            .replace("{network}", network)
+            .replace("{version}", version);
+
+        format!("{}.{}:{}", name, self.ogmios_dns, self.ogmios_port)
+    }
+
+    /// Whether the proxy routes the version. Every version is routed when none are configured.
+    pub fn serves_version(&self, version: &str) -> bool {
+        self.ogmios_versions
+            .as_ref()
+            .map(|versions| versions.iter().any(|v| v == version))
+            .unwrap_or(true)
+    }
+
+    /// Versions checked by the health loop, v6 unless configured otherwise.
+    pub fn health_versions(&self) -> Vec<String> {
+        self.ogmios_versions.clone().unwrap_or(vec!["6".into()])
+    }
+
+    /// All the replicas serving a network and version. Upstream lists can be keyed by
+    /// `NETWORK/VERSION` or only by `VERSION`. Falls back to the single DNS based instance when no
+    /// explicit upstream list is configured.
+    pub fn instances(&self, network: &str, version: &str) -> Vec<String> {
+        let instances = self
+            .ogmios_upstreams
+            .get(&format!("{network}/{version}"))
+            .or_else(|| self.ogmios_upstreams.get(version));
+
+        match instances {
+            Some(instances) if !instances.is_empty() => instances.clone(),
+            _ => vec![self.instance(network, version)],
+        }
+    }
+
+    /// Endpoints tried in order when the selected instance can't be reached, keyed like the
+    /// upstream lists.
+    pub fn fallbacks(&self, network: &str, version: &str) -> Vec<String> {
+        self.ogmios_fallbacks
+            .get(&format!("{network}/{version}"))
+            .or_else(|| self.ogmios_fallbacks.get(version))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Short hash of the effective configuration, the same for replicas configured alike. Maps
+    /// are ordered so the debug output is stable. Secrets are left out, a short hash of a weak
+    /// one could be brute forced from the metrics.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(format!("{:?}", self.without_secrets()));
+        digest[..8]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// The configuration with its secrets emptied, only whether each one is set is kept.
+    fn without_secrets(&self) -> Config {
+        let set = |secret: &Option<String>| secret.as_ref().map(|_| String::new());
+        Config {
+            proxy_usage_sink_token: set(&self.proxy_usage_sink_token),
+            proxy_introspection_client_secret: set(&self.proxy_introspection_client_secret),
+            admin_token: set(&self.admin_token),
+            proxy_impersonation_token: set(&self.proxy_impersonation_token),
+            metrics_token: set(&self.metrics_token),
+            metrics_basic_auth: set(&self.metrics_basic_auth),
+            ..self.clone()
+        }
+    }
+}
+
+// Format: [NETWORK/]VERSION=HOST:PORT|HOST:PORT,[NETWORK/]VERSION=HOST:PORT
+fn parse_upstreams(value: &str, name: &str) -> Result<BTreeMap<String, Vec<String>>, ConfigError> {
+    value
+        .split(',')
+        .map(|pair| {
+            let (version, instances) = pair.split_once('=').ok_or_else(|| {
+                ConfigError(format!(
+                    "{name} must be [NETWORK/]VERSION=HOST:PORT|HOST:PORT"
+                ))
+            })?;
+            let instances = instances.split('|').map(String::from).collect();
+
+            Ok((version.into(), instances))
+        })
+        .collect()
+}
+
+// Format: NETWORK=HOST:PORT,NETWORK=HOST:PORT
+fn parse_endpoints(value: &str) -> Result<BTreeMap<String, String>, ConfigError> {
+    value
+        .split(',')
+        .map(|pair| {
+            let (network, endpoint) = pair.split_once('=').ok_or_else(|| {
+                ConfigError("OGMIOS_ENDPOINTS must be NETWORK=HOST:PORT,NETWORK=HOST:PORT".into())
+            })?;
+
+            Ok((
+                handle_legacy_networks(network.trim()),
+                endpoint.trim().into(),
+            ))
+        })
+        .collect()
+}
+
+/// Reads an env file, `KEY=VALUE` per line. Empty lines and comments are skipped, and values can
+/// be quoted.
+fn read_env_file(path: &Path) -> std::io::Result<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().to_string(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect())
+}
+
+/// A setting that's missing or can't be parsed, with what it should look like.
+#[derive(Debug)]
+pub struct ConfigError(String);
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+impl Error for ConfigError {}
+
+/// The settings by name, as handed to `Config::from_source`.
+struct Vars<'a, F: Fn(&str) -> Option<String>>(&'a F);
+impl<F: Fn(&str) -> Option<String>> Vars<'_, F> {
+    fn get(&self, key: &str) -> Option<String> {
+        (self.0)(key)
+    }
+
+    fn required(&self, key: &str) -> Result<String, ConfigError> {
+        self.get(key)
+            .ok_or_else(|| ConfigError(format!("{key} must be set")))
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        self.get(key).is_some_and(|v| v == "true")
+    }
+
+    fn parse<T: FromStr>(&self, key: &str, message: &str) -> Result<Option<T>, ConfigError> {
+        self.get(key)
+            .map(|v| v.trim().parse().map_err(|_| ConfigError(message.into())))
+            .transpose()
+    }
+
+    /// Numbers that can't be 0, eg intervals.
+    fn positive<T: FromStr + PartialEq + Default>(
+        &self,
+        key: &str,
+        message: &str,
+    ) -> Result<Option<T>, ConfigError> {
+        match self.parse(key, message)? {
+            Some(value) if value == T::default() => Err(ConfigError(message.into())),
+            value => Ok(value),
         }
     }
 
-    pub fn instance(&self, version: &str) -> String {
-        format!(
-            "ogmios-{}-{}.{}:{}",
-            self.network, version, self.ogmios_dns, self.ogmios_port
-        )
+    fn secs(&self, key: &str, message: &str) -> Result<Option<Duration>, ConfigError> {
+        Ok(self.parse(key, message)?.map(Duration::from_secs))
+    }
+
+    /// Periods of timers, which can't tick every 0 seconds.
+    fn positive_secs(&self, key: &str, message: &str) -> Result<Option<Duration>, ConfigError> {
+        Ok(self.positive(key, message)?.map(Duration::from_secs))
+    }
+
+    fn cidrs(&self, key: &str, message: &str) -> Result<Vec<IpNet>, ConfigError> {
+        match self.get(key) {
+            Some(v) => v
+                .split(',')
+                .map(|cidr| cidr.trim().parse().map_err(|_| ConfigError(message.into())))
+                .collect(),
+            None => Ok(vec![]),
+        }
     }
 }
@@ -0,0 +1,47 @@
+use hyper::body::Incoming;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::{Method, Request, Response, StatusCode};
+
+use crate::config::Config;
+use crate::utils::{full, ProxyResponse, DMTR_API_KEY};
+
+/// Origin to echo back when the request comes from an allowed origin. CORS is disabled when no
+/// origins are configured.
+pub fn allowed_origin(config: &Config, req: &Request<Incoming>) -> Option<HeaderValue> {
+    let origin = req.headers().get(ORIGIN)?;
+    let allowed = config
+        .proxy_cors_allowed_origins
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes());
+
+    allowed.then(|| origin.clone())
+}
+
+pub fn is_preflight(req: &Request<Incoming>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+pub fn preflight(origin: HeaderValue) -> ProxyResponse {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, OPTIONS")
+        .header(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            format!("authorization, content-type, {DMTR_API_KEY}"),
+        )
+        .header(ACCESS_CONTROL_MAX_AGE, "86400")
+        .body(full(""))
+        .unwrap();
+    apply(&mut response, origin);
+    response
+}
+
+pub fn apply(response: &mut ProxyResponse, origin: HeaderValue) {
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+}
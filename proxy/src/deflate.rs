@@ -0,0 +1,712 @@
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use hyper::header::HeaderValue;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Name of the extension in `Sec-WebSocket-Extensions`, also the offer sent to the instances.
+pub const EXTENSION: &str = "permessage-deflate";
+
+/// Removed from the end of every compressed message and added back before inflating it.
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const MASKED: u8 = 0x80;
+/// Response headers longer than this aren't looked at, the handshake fails on its own then.
+const MAX_HANDSHAKE_SIZE: usize = 64 * 1024;
+
+/// What was agreed with the peer of a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Negotiated {
+    /// Whether the messages sent are compressed. The compressor always uses the full window, a
+    /// peer asking for a smaller one only gets uncompressed messages.
+    pub compress: bool,
+    /// The compressor is reset after every message sent.
+    pub no_context_takeover: bool,
+}
+
+/// Splits an extension parameter into its name and value.
+fn param(param: &str) -> (&str, Option<&str>) {
+    match param.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+        None => (param.trim(), None),
+    }
+}
+
+fn is_window_bits(value: &str) -> bool {
+    value
+        .parse::<u8>()
+        .is_ok_and(|bits| (8..=15).contains(&bits))
+}
+
+/// Picks the first offer of the client the proxy can honour, returning what was agreed and the
+/// value of the `Sec-WebSocket-Extensions` response header. Offers limiting the window of the
+/// server are declined.
+pub fn accept<'a>(
+    offers: impl IntoIterator<Item = &'a HeaderValue>,
+) -> Option<(Negotiated, HeaderValue)> {
+    let offers = offers
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for offer in offers {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next() != Some(EXTENSION) {
+            continue;
+        }
+
+        let mut negotiated = Negotiated {
+            compress: true,
+            no_context_takeover: false,
+        };
+        let supported = params.all(|value| match param(value) {
+            ("server_no_context_takeover", None) => {
+                negotiated.no_context_takeover = true;
+                true
+            }
+            ("client_no_context_takeover", None) | ("client_max_window_bits", None) => true,
+            ("client_max_window_bits", Some(bits)) => is_window_bits(bits),
+            ("server_max_window_bits", Some(bits)) => bits == "15",
+            _ => false,
+        });
+        if !supported {
+            continue;
+        }
+
+        let response = match negotiated.no_context_takeover {
+            true => HeaderValue::from_static("permessage-deflate; server_no_context_takeover"),
+            false => HeaderValue::from_static(EXTENSION),
+        };
+        return Some((negotiated, response));
+    }
+    None
+}
+
+/// What the instance agreed to in its handshake response, `None` when it declined the offer.
+fn accepted(head: &[u8]) -> Option<Negotiated> {
+    let head = std::str::from_utf8(head).ok()?;
+    let values = head
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-extensions"))
+        .flat_map(|(_, value)| value.split(','));
+    for extension in values {
+        let mut params = extension.split(';').map(str::trim);
+        if params.next() != Some(EXTENSION) {
+            continue;
+        }
+
+        let mut negotiated = Negotiated {
+            compress: true,
+            no_context_takeover: false,
+        };
+        for value in params {
+            match param(value) {
+                ("client_no_context_takeover", _) => negotiated.no_context_takeover = true,
+                ("client_max_window_bits", Some(bits)) if bits != "15" => {
+                    negotiated.compress = false
+                }
+                _ => {}
+            }
+        }
+        return Some(negotiated);
+    }
+    None
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Header of a websocket frame, `size` being its length in bytes.
+struct Header {
+    first: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+    size: usize,
+}
+impl Header {
+    /// `None` until the whole header was received.
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let (&first, rest) = buf.split_first()?;
+        let (&second, rest) = rest.split_first()?;
+        let (len, rest) = match second & 0x7f {
+            126 => {
+                let (len, rest) = rest.split_first_chunk::<2>()?;
+                (u16::from_be_bytes(*len) as u64, rest)
+            }
+            127 => {
+                let (len, rest) = rest.split_first_chunk::<8>()?;
+                (u64::from_be_bytes(*len), rest)
+            }
+            len => (len as u64, rest),
+        };
+        let (mask, rest) = match second & MASKED != 0 {
+            true => {
+                let (mask, rest) = rest.split_first_chunk::<4>()?;
+                (Some(*mask), rest)
+            }
+            false => (None, rest),
+        };
+        Some(Self {
+            first,
+            mask,
+            len,
+            size: buf.len() - rest.len(),
+        })
+    }
+
+    fn opcode(&self) -> u8 {
+        self.first & 0x0f
+    }
+
+    fn is_control(&self) -> bool {
+        self.opcode() >= 8
+    }
+
+    fn is_final(&self) -> bool {
+        self.first & FIN != 0
+    }
+
+    /// Writes the frame with this header and the payload given, masked with the same key.
+    fn write(&self, first: u8, mut payload: Vec<u8>, out: &mut BytesMut) {
+        let masked = if self.mask.is_some() { MASKED } else { 0 };
+        out.put_u8(first);
+        match payload.len() {
+            len if len < 126 => out.put_u8(masked | len as u8),
+            len if len <= u16::MAX as usize => {
+                out.put_u8(masked | 126);
+                out.put_u16(len as u16);
+            }
+            len => {
+                out.put_u8(masked | 127);
+                out.put_u64(len as u64);
+            }
+        }
+        if let Some(mask) = self.mask {
+            out.put_slice(&mask);
+            apply_mask(&mut payload, mask);
+        }
+        out.put_slice(&payload);
+    }
+}
+
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i & 3];
+    }
+}
+
+fn compress(compressor: &mut Compress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() / 4 + 64);
+    let start = compressor.total_in();
+    loop {
+        if output.len() == output.capacity() {
+            output.reserve(output.capacity());
+        }
+        let consumed = (compressor.total_in() - start) as usize;
+        compressor.compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)?;
+        let consumed = (compressor.total_in() - start) as usize;
+        // The flush is complete once it didn't fill the output.
+        if consumed == input.len() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+    }
+}
+
+/// Inflates `input` at the end of `output`, refusing to grow it past `limit`.
+fn inflate(
+    inflater: &mut Decompress,
+    input: &[u8],
+    limit: usize,
+    output: &mut Vec<u8>,
+) -> io::Result<()> {
+    let start = inflater.total_in();
+    loop {
+        if output.len() == output.capacity() {
+            let room = limit.saturating_sub(output.len()) + 1;
+            output.reserve(output.capacity().max(4096).min(room));
+        }
+        let progress = (inflater.total_in(), inflater.total_out());
+        let consumed = (inflater.total_in() - start) as usize;
+        let status = inflater.decompress_vec(&input[consumed..], output, FlushDecompress::Sync)?;
+        if output.len() > limit {
+            return Err(invalid("inflated message too big"));
+        }
+
+        let consumed = (inflater.total_in() - start) as usize;
+        let room = output.len() < output.capacity();
+        if status == Status::StreamEnd || (consumed == input.len() && room) {
+            return Ok(());
+        }
+        if progress == (inflater.total_in(), inflater.total_out()) && room {
+            return Err(invalid("invalid compressed message"));
+        }
+    }
+}
+
+enum Mode {
+    /// Not negotiated, the bytes go through as they are.
+    Off,
+    /// The handshake response of the instance is being read, with what was read of it so far.
+    Handshake(Vec<u8>),
+    On(Negotiated),
+}
+
+/// permessage-deflate (RFC 7692) underneath tungstenite, which doesn't implement it. Compressed
+/// frames read are inflated before tungstenite sees them and the data frames it writes are
+/// compressed, masked with the key it picked when it masks them.
+pub struct Deflate<S> {
+    inner: S,
+    mode: Mode,
+    /// Size of a message once inflated.
+    limit: usize,
+    /// Read from the inner stream and not parsed yet.
+    raw: BytesMut,
+    /// Ready to be read by tungstenite.
+    ready: BytesMut,
+    /// What's left of the payload of an uncompressed frame, passed through as it's read.
+    passthrough: u64,
+    /// Whether the message being read is compressed, and what it inflated to so far.
+    inflating: bool,
+    inflated: usize,
+    eof: bool,
+    inflater: Option<Decompress>,
+    /// Written by tungstenite and not a complete frame yet.
+    pending: BytesMut,
+    /// Frames to write to the inner stream.
+    out: BytesMut,
+    compressor: Option<Compress>,
+}
+impl<S> Deflate<S> {
+    fn with_mode(inner: S, mode: Mode, limit: usize) -> Self {
+        Self {
+            inner,
+            mode,
+            limit,
+            raw: BytesMut::new(),
+            ready: BytesMut::new(),
+            passthrough: 0,
+            inflating: false,
+            inflated: 0,
+            eof: false,
+            inflater: None,
+            pending: BytesMut::new(),
+            out: BytesMut::new(),
+            compressor: None,
+        }
+    }
+
+    /// A connection whose handshake is done, compressed when the extension was negotiated.
+    pub fn new(inner: S, negotiated: Option<Negotiated>, limit: usize) -> Self {
+        let mode = match negotiated {
+            Some(negotiated) => Mode::On(negotiated),
+            None => Mode::Off,
+        };
+        Self::with_mode(inner, mode, limit)
+    }
+
+    /// A connection to an instance, whose handshake response tells whether the extension it was
+    /// `offered` is used.
+    pub fn client(inner: S, offered: bool, limit: usize) -> Self {
+        let mode = match offered {
+            true => Mode::Handshake(Vec::new()),
+            false => Mode::Off,
+        };
+        Self::with_mode(inner, mode, limit)
+    }
+
+    /// Takes what was read from the inner stream during the handshake, switching to the frames
+    /// once the response headers are complete.
+    fn read_handshake(&mut self, chunk: &[u8]) {
+        let Mode::Handshake(head) = &mut self.mode else {
+            return;
+        };
+        let start = head.len();
+        head.extend_from_slice(chunk);
+        let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") else {
+            self.ready.extend_from_slice(chunk);
+            if head.len() > MAX_HANDSHAKE_SIZE {
+                self.mode = Mode::Off;
+            }
+            return;
+        };
+
+        // The terminator wasn't in what was read before, so it ends in this chunk.
+        let split = end + 4 - start;
+        self.ready.extend_from_slice(&chunk[..split]);
+        self.mode = match accepted(&head[..end]) {
+            Some(negotiated) => {
+                self.raw.extend_from_slice(&chunk[split..]);
+                Mode::On(negotiated)
+            }
+            None => {
+                self.ready.extend_from_slice(&chunk[split..]);
+                Mode::Off
+            }
+        };
+    }
+
+    /// Moves what can be from the raw bytes to the ones tungstenite reads, false when more has to
+    /// be read first.
+    fn decode(&mut self) -> io::Result<bool> {
+        if self.passthrough > 0 {
+            let len = self.raw.len().min(self.passthrough as usize);
+            if len == 0 {
+                return Ok(false);
+            }
+            self.ready.extend_from_slice(&self.raw.split_to(len));
+            self.passthrough -= len as u64;
+            return Ok(true);
+        }
+        let Some(header) = Header::parse(&self.raw) else {
+            return Ok(false);
+        };
+
+        let rsv1 = header.first & RSV1 != 0;
+        if rsv1 && (header.is_control() || header.opcode() == 0) {
+            return Err(invalid("compressed control or continuation frame"));
+        }
+        let compressed = rsv1 || (header.opcode() == 0 && self.inflating);
+        if !compressed {
+            if !header.is_control() && header.opcode() != 0 {
+                self.inflating = false;
+            }
+            self.ready
+                .extend_from_slice(&self.raw.split_to(header.size));
+            self.passthrough = header.len;
+            return Ok(true);
+        }
+
+        // Compressed frames are inflated whole.
+        if header.len > self.limit as u64 {
+            return Err(invalid("compressed frame too big"));
+        }
+        let size = header.size + header.len as usize;
+        if self.raw.len() < size {
+            self.raw.reserve(size - self.raw.len());
+            return Ok(false);
+        }
+        let frame = self.raw.split_to(size);
+        let mut payload = frame[header.size..].to_vec();
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+        if header.is_final() {
+            payload.extend_from_slice(&TAIL);
+        }
+        if header.opcode() != 0 {
+            self.inflating = true;
+            self.inflated = 0;
+        }
+
+        let inflater = self.inflater.get_or_insert_with(|| Decompress::new(false));
+        let mut inflated = Vec::new();
+        inflate(
+            inflater,
+            &payload,
+            self.limit - self.inflated,
+            &mut inflated,
+        )?;
+        self.inflated += inflated.len();
+        if header.is_final() {
+            self.inflating = false;
+        }
+        header.write(header.first & !RSV1, inflated, &mut self.ready);
+        Ok(true)
+    }
+
+    /// Compresses the complete data frames written by tungstenite.
+    fn encode(&mut self, negotiated: Negotiated) -> io::Result<()> {
+        while let Some(header) = Header::parse(&self.pending) {
+            let size = header.size as u64 + header.len;
+            if (self.pending.len() as u64) < size {
+                self.pending
+                    .reserve((size - self.pending.len() as u64) as usize);
+                return Ok(());
+            }
+            let frame = self.pending.split_to(size as usize);
+            if header.is_control() || !negotiated.compress {
+                self.out.extend_from_slice(&frame);
+                continue;
+            }
+
+            let mut payload = frame[header.size..].to_vec();
+            if let Some(mask) = header.mask {
+                apply_mask(&mut payload, mask);
+            }
+            // Chain-sync sessions stream a lot, the fastest level keeps the cpu cost low.
+            let compressor = self
+                .compressor
+                .get_or_insert_with(|| Compress::new(Compression::fast(), false));
+            let mut compressed = compress(compressor, &payload)?;
+            let mut first = header.first;
+            if header.opcode() != 0 {
+                first |= RSV1;
+            }
+            if header.is_final() {
+                if compressed.ends_with(&TAIL) {
+                    compressed.truncate(compressed.len() - TAIL.len());
+                }
+                if negotiated.no_context_takeover {
+                    compressor.reset();
+                }
+            }
+            header.write(first, compressed, &mut self.out);
+        }
+        Ok(())
+    }
+}
+impl<S: AsyncWrite + Unpin> Deflate<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+impl<S: AsyncRead + Unpin> AsyncRead for Deflate<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.ready.is_empty() {
+                let len = this.ready.len().min(buf.remaining());
+                buf.put_slice(&this.ready.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+            match this.mode {
+                Mode::Off if this.raw.is_empty() => {
+                    return Pin::new(&mut this.inner).poll_read(cx, buf)
+                }
+                Mode::Off => {
+                    let raw = this.raw.split();
+                    this.ready.extend_from_slice(&raw);
+                    continue;
+                }
+                Mode::On(_) => {
+                    if this.decode()? {
+                        continue;
+                    }
+                }
+                Mode::Handshake(_) => {}
+            }
+            if this.eof {
+                // A truncated frame is left to tungstenite to report.
+                let raw = this.raw.split();
+                this.ready.extend_from_slice(&raw);
+                if this.ready.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+
+            let mut chunk = [0u8; 8192];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            let chunk = chunk_buf.filled();
+            if chunk.is_empty() {
+                this.eof = true;
+                continue;
+            }
+            match this.mode {
+                Mode::Handshake(_) => this.read_handshake(chunk),
+                _ => this.raw.extend_from_slice(chunk),
+            }
+        }
+    }
+}
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflate<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // What was compressed already goes first, so a slow peer holds tungstenite back.
+        ready!(this.poll_drain(cx))?;
+        let Mode::On(negotiated) = this.mode else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        this.pending.extend_from_slice(buf);
+        this.encode(negotiated)?;
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+    use tokio_tungstenite::tungstenite::{protocol::Role, Message};
+    use tokio_tungstenite::WebSocketStream;
+
+    fn offer(value: &'static str) -> Option<Negotiated> {
+        accept([&HeaderValue::from_static(value)]).map(|(negotiated, _)| negotiated)
+    }
+
+    #[test]
+    fn offers_are_accepted_unless_they_limit_the_server_window() {
+        let negotiated = Negotiated {
+            compress: true,
+            no_context_takeover: false,
+        };
+        assert_eq!(offer("permessage-deflate"), Some(negotiated));
+        assert_eq!(
+            offer("permessage-deflate; client_max_window_bits"),
+            Some(negotiated)
+        );
+        assert_eq!(
+            offer("permessage-deflate; server_no_context_takeover"),
+            Some(Negotiated {
+                no_context_takeover: true,
+                ..negotiated
+            })
+        );
+        assert_eq!(offer("permessage-deflate; server_max_window_bits=10"), None);
+        assert_eq!(offer("x-webkit-deflate-frame"), None);
+        // The next offer is tried when the first one can't be honoured.
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=10, permessage-deflate"),
+            Some(negotiated)
+        );
+    }
+
+    #[test]
+    fn responses_of_the_instances_are_read() {
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            sec-websocket-extensions: permessage-deflate; client_no_context_takeover";
+        assert_eq!(
+            accepted(response),
+            Some(Negotiated {
+                compress: true,
+                no_context_takeover: true,
+            })
+        );
+        let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=9";
+        assert!(accepted(response).is_some_and(|negotiated| !negotiated.compress));
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket";
+        assert_eq!(accepted(response), None);
+    }
+
+    #[tokio::test]
+    async fn messages_go_through_compressed_both_ways() {
+        let (server, client) = tokio::io::duplex(1024);
+        let negotiated = Negotiated {
+            compress: true,
+            no_context_takeover: false,
+        };
+        let server = Deflate::new(server, Some(negotiated), 1 << 20);
+        let client = Deflate::new(client, Some(negotiated), 1 << 20);
+        let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        let block = r#"{"jsonrpc":"2.0","method":"nextBlock","result":{"direction":"forward"}}"#;
+        let large = block.repeat(2000);
+        for message in [block.to_string(), large, String::new()] {
+            let sent = Message::text(message.clone());
+            let (_, received) = tokio::join!(server.send(sent), client.next());
+            assert_eq!(received.unwrap().unwrap(), Message::text(message.clone()));
+
+            let sent = Message::text(message.clone());
+            let (_, received) = tokio::join!(client.send(sent), server.next());
+            assert_eq!(received.unwrap().unwrap(), Message::text(message));
+        }
+
+        let (_, received) = tokio::join!(server.send(Message::Ping(vec![1])), client.next());
+        assert_eq!(received.unwrap().unwrap(), Message::Ping(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn inflated_messages_over_the_limit_are_refused() {
+        let (server, client) = tokio::io::duplex(1024);
+        let negotiated = Negotiated {
+            compress: true,
+            no_context_takeover: false,
+        };
+        let server = Deflate::new(server, Some(negotiated), 1 << 20);
+        let client = Deflate::new(client, Some(negotiated), 1024);
+        let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+
+        let (_, received) =
+            tokio::join!(server.send(Message::text("a".repeat(4096))), client.next());
+        assert!(received.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn the_instance_response_switches_compression_on() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_tungstenite::client_async;
+
+        let (mut instance, client) = tokio::io::duplex(1024);
+        let instance = async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(instance.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap();
+            let key = request
+                .lines()
+                .filter_map(|line| line.split_once(": "))
+                .find(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))
+                .map(|(_, key)| key)
+                .unwrap();
+            let response = format!(
+                "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n",
+                derive_accept_key(key.as_bytes())
+            );
+            instance.write_all(response.as_bytes()).await.unwrap();
+
+            let negotiated = Negotiated {
+                compress: true,
+                no_context_takeover: false,
+            };
+            let instance = Deflate::new(instance, Some(negotiated), 1 << 20);
+            let mut instance = WebSocketStream::from_raw_socket(instance, Role::Server, None).await;
+            let message = instance.next().await.unwrap().unwrap();
+            instance.send(message).await.unwrap();
+        };
+
+        let client = async move {
+            let client = Deflate::client(client, true, 1 << 20);
+            let request = "ws://instance/".into_client_request().unwrap();
+            let (mut client, _) = client_async(request, client).await.unwrap();
+            let message = Message::text("nextBlock".repeat(100));
+            client.send(message.clone()).await.unwrap();
+            assert_eq!(client.next().await.unwrap().unwrap(), message);
+            assert!(client.get_ref().compressor.is_some());
+        };
+        tokio::join!(instance, client);
+    }
+}
@@ -1,22 +1,152 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use http_body_util::{BodyExt, Full};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1 as http1_server;
+use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tracing::{error, info, warn};
+use std::time::Duration;
+use std::{net::SocketAddr, str::FromStr};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tracing::{error, info, instrument, warn};
 
+use crate::config::Config;
+use crate::utils::{full, ProxyResponse};
 use crate::State;
 
-async fn get_health(state: &State) -> bool {
-    let client = match reqwest::Client::builder().build() {
-        Ok(client) => client,
-        Err(err) => {
-            warn!(error = err.to_string(), "Failed to build reqwest client");
-            return false;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy,
+}
+impl HealthStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Unhealthy => "unhealthy",
         }
-    };
+    }
+
+    fn from_healthy(healthy: bool) -> Self {
+        if healthy {
+            Self::Healthy
+        } else {
+            Self::Unhealthy
+        }
+    }
+}
+
+/// Health of each network and version the proxy checks.
+pub type UpstreamHealth = HashMap<(String, String), HealthStatus>;
+
+/// The last check of the instances of a network and version, `streak` being how many checks in a
+/// row had the same result. The route is healthy while one of its instances is.
+#[derive(Debug, Clone)]
+pub struct UpstreamCheck {
+    instances: BTreeMap<String, HealthStatus>,
+    result: HealthStatus,
+    streak: usize,
+    checked_at: DateTime<Utc>,
+    latency: Duration,
+}
+
+pub type UpstreamChecks = HashMap<(String, String), UpstreamCheck>;
+
+/// Checks the instances serving each network and version, the replicas or the endpoint it was
+/// switched to. They're all checked at once, so one that hangs doesn't hold back the others.
+async fn get_health(state: &State) -> UpstreamChecks {
+    let config = state.config();
+    let mut routes = Vec::new();
+    for network in &config.networks {
+        for version in config.health_versions() {
+            let instances = state.instances(network, &version);
+            routes.push(((network.clone(), version), instances));
+        }
+    }
 
-    let response = match client
-        .get(format!("http://{}/health", state.config.instance("6")))
-        .send()
+    let checks = routes.into_iter().map(|(route, instances)| async move {
+        let checked_at = Utc::now();
+        let started_at = Instant::now();
+        let instances = join_all(instances.into_iter().map(|instance| async move {
+            let healthy = check_instance(state, &instance).await;
+            (instance, HealthStatus::from_healthy(healthy))
+        }))
+        .await;
+
+        let instances: BTreeMap<_, _> = instances.into_iter().collect();
+        let healthy = instances
+            .values()
+            .any(|status| *status == HealthStatus::Healthy);
+        let check = UpstreamCheck {
+            instances,
+            result: HealthStatus::from_healthy(healthy),
+            streak: 1,
+            checked_at,
+            latency: started_at.elapsed(),
+        };
+        (route, check)
+    });
+
+    join_all(checks).await.into_iter().collect()
+}
+
+/// An instance that doesn't answer within `HEALTH_TIMEOUT` failed its check.
+async fn check_instance(state: &State, instance: &str) -> bool {
+    let timeout = state.config().health_timeout;
+    match tokio::time::timeout(timeout, get_instance_health(state, instance)).await {
+        Ok(healthy) => healthy,
+        Err(_) => {
+            warn!(instance, "Health request timed out");
+            false
+        }
+    }
+}
+
+/// Whether connections to the network and version can be forwarded. Routes that aren't checked
+/// are assumed healthy, and so are routes with fallbacks, the failover takes care of them.
+pub async fn is_upstream_healthy(state: &State, network: &str, version: &str) -> bool {
+    let status = state
+        .upstream_health
+        .read()
         .await
-    {
+        .get(&(network.to_string(), version.to_string()))
+        .copied();
+    status != Some(HealthStatus::Unhealthy)
+        || !state.config().fallbacks(network, version).is_empty()
+}
+
+/// The proxy is healthy while at least one of the upstreams it checks is.
+async fn is_healthy(state: &State) -> bool {
+    state
+        .upstream_health
+        .read()
+        .await
+        .values()
+        .any(|status| *status == HealthStatus::Healthy)
+}
+
+/// The part of the Ogmios `/health` document the check looks at.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OgmiosHealth {
+    network_synchronization: Option<f64>,
+    last_tip_update: Option<DateTime<Utc>>,
+}
+
+// The request goes through the pooled client, so it uses the resolver and the upstream TLS settings.
+async fn get_instance_health(state: &State, instance: &str) -> bool {
+    let request = Request::get(format!("http://{}/health", instance))
+        .body(Full::new(Bytes::new()))
+        .unwrap();
+
+    let response = match state.http_client.request(request).await {
         Ok(response) => response,
         Err(err) => {
             warn!(error = err.to_string(), "Failed to perform health request");
@@ -26,30 +156,437 @@ async fn get_health(state: &State) -> bool {
 
     let status = response.status();
     if status.is_client_error() || status.is_server_error() {
-        error!(status = status.to_string(), "Health request failed");
+        error!(
+            status = status.to_string(),
+            instance, "Health request failed"
+        );
+        return false;
+    }
+    if status != 200 {
+        return false;
+    }
+
+    let health = match response.into_body().collect().await {
+        Ok(body) => serde_json::from_slice::<OgmiosHealth>(&body.to_bytes()),
+        Err(err) => {
+            warn!(
+                error = err.to_string(),
+                instance, "Failed to read health response"
+            );
+            return false;
+        }
+    };
+    let health = match health {
+        Ok(health) => health,
+        Err(err) => {
+            warn!(error = err.to_string(), instance, "Invalid health response");
+            return false;
+        }
+    };
+    is_synced(&state.config(), instance, &health)
+}
+
+/// An instance answering isn't enough, it has to be close to the tip of the network and the tip
+/// has to move.
+fn is_synced(config: &Config, instance: &str, health: &OgmiosHealth) -> bool {
+    let synchronization = health.network_synchronization.unwrap_or_default();
+    if synchronization < config.health_min_synchronization {
+        warn!(
+            instance,
+            synchronization, "Upstream is lagging behind the network"
+        );
         return false;
     }
 
-    status == 200
+    let tip_age = health
+        .last_tip_update
+        .map(|last_tip_update| (Utc::now() - last_tip_update).to_std().unwrap_or_default());
+    match tip_age {
+        Some(tip_age) if tip_age <= config.health_max_tip_age => true,
+        Some(tip_age) => {
+            warn!(
+                instance,
+                tip_age_secs = tip_age.as_secs(),
+                "Upstream tip is stuck"
+            );
+            false
+        }
+        None => {
+            warn!(instance, "Upstream has no tip yet");
+            false
+        }
+    }
+}
+
+/// The status of an upstream once `HEALTH_RISE` successes or `HEALTH_FALL` failures in a row
+/// went against it. The first check of an upstream applies right away.
+fn debounce(
+    config: &Config,
+    current: Option<HealthStatus>,
+    result: HealthStatus,
+    streak: usize,
+) -> HealthStatus {
+    let threshold = match result {
+        HealthStatus::Healthy => config.health_rise,
+        HealthStatus::Unhealthy => config.health_fall,
+    };
+    match current {
+        Some(current) if current != result && streak < threshold => current,
+        _ => result,
+    }
 }
 
 async fn update_health(state: &State) {
-    let current_health = *state.upstream_health.read().await;
+    let config = state.config();
+    let mut checks = get_health(state).await;
+
+    let previous_checks = state.upstream_checks.read().await.clone();
+    let current_health = state.upstream_health.read().await.clone();
+    let mut new_health = UpstreamHealth::new();
+    for (route, check) in checks.iter_mut() {
+        if let Some(previous) = previous_checks.get(route) {
+            if previous.result == check.result {
+                check.streak = previous.streak + 1;
+            }
+        }
 
-    let new_health = get_health(state).await;
+        let current = current_health.get(route).copied();
+        let status = debounce(&config, current, check.result, check.streak);
+        let (network, version) = route;
+        match (current, status) {
+            (None | Some(HealthStatus::Unhealthy), HealthStatus::Healthy) => info!(
+                network,
+                version, "Upstream is now healthy, ready to proxy requests."
+            ),
+            (None | Some(HealthStatus::Healthy), HealthStatus::Unhealthy) => {
+                warn!(network, version, "Upstream is now deamed unhealthy.")
+            }
+            _ => {}
+        }
+        if current.is_some_and(|current| current != status) {
+            state.metrics.count_upstream_health_transition(
+                &config.proxy_namespace,
+                network,
+                version,
+                status,
+            );
+        }
+        new_health.insert(route.clone(), status);
+    }
 
-    match (current_health, new_health) {
-        (false, true) => info!("Upstream is now healthy, ready to proxy requests."),
-        (true, false) => warn!("Upstream is now deamed unhealthy."),
-        _ => {}
+    // A dead network doesn't stop the others, only its own circuit opens, unless it has
+    // fallbacks to fail over to.
+    for ((network, version), status) in &new_health {
+        if *status == HealthStatus::Unhealthy && config.fallbacks(network, version).is_empty() {
+            state.circuit.trip(network, version);
+        }
     }
 
     *state.upstream_health.write().await = new_health;
+    *state.upstream_checks.write().await = checks;
 }
 
 pub async fn start(state: Arc<State>) {
     loop {
         update_health(&state).await;
-        tokio::time::sleep(state.config.health_poll_interval).await;
+        tokio::time::sleep(state.config().health_poll_interval).await;
+    }
+}
+
+pub async fn handle_healthz(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    if is_healthy(state).await {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(full("OK"))
+            .unwrap())
+    } else {
+        Ok(Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(full(""))
+            .unwrap())
+    }
+}
+
+/// The process is up and serving, whatever the state of the upstreams.
+pub async fn handle_livez() -> Result<ProxyResponse, hyper::Error> {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(full("OK"))
+        .unwrap())
+}
+
+/// Ready once the ports and tiers were loaded, so no client is refused for a key the proxy
+/// doesn't know yet, and while some network can be served, by a healthy instance or through its
+/// fallbacks. A network that's down is answered with a 503 on its own, it doesn't take the other
+/// networks of the replica out of the service.
+async fn is_ready(state: &State) -> bool {
+    if !state.consumers_synced.load(Ordering::Relaxed)
+        || !state.tiers_synced.load(Ordering::Relaxed)
+    {
+        return false;
+    }
+
+    let config = state.config();
+    let health = state.upstream_health.read().await;
+    health.iter().any(|((network, version), status)| {
+        *status == HealthStatus::Healthy || !config.fallbacks(network, version).is_empty()
+    })
+}
+
+pub async fn handle_readyz(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    if is_ready(state).await {
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .body(full("OK"))
+            .unwrap())
+    } else {
+        Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(full(""))
+            .unwrap())
+    }
+}
+
+/// Everything the health checks know, for humans and monitors that need more than a status code.
+/// It names the instances, so it's only served on the admin api.
+pub async fn handle_status(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let health = state.upstream_health.read().await.clone();
+    // Ordered by network and version, so the document reads the same on every call.
+    let checks: BTreeMap<_, _> = state
+        .upstream_checks
+        .read()
+        .await
+        .clone()
+        .into_iter()
+        .collect();
+    let upstreams = checks
+        .iter()
+        .map(|(route, check)| {
+            let (network, version) = route;
+            json!({
+                "network": network,
+                "version": version,
+                "instances": check
+                    .instances
+                    .iter()
+                    .map(|(instance, status)| (instance.clone(), status.as_str()))
+                    .collect::<BTreeMap<_, _>>(),
+                "status": health.get(route).map(HealthStatus::as_str),
+                "last_result": check.result.as_str(),
+                "consecutive": check.streak,
+                "checked_at": check.checked_at.to_rfc3339(),
+                "latency_ms": check.latency.as_millis() as u64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let body = json!({
+        "healthy": is_healthy(state).await,
+        "ready": is_ready(state).await,
+        "consumers": {
+            "synced": state.consumers_synced.load(Ordering::Relaxed),
+            "count": state.consumers.read().await.len(),
+        },
+        "tiers": {
+            "synced": state.tiers_synced.load(Ordering::Relaxed),
+            "count": state.tiers.read().await.len(),
+        },
+        "upstreams": upstreams,
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(body.to_string()))
+        .unwrap())
+}
+
+async fn routes_match(
+    req: Request<Incoming>,
+    state: Arc<State>,
+) -> Result<ProxyResponse, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => handle_healthz(&state).await,
+        (&Method::GET, "/livez") => handle_livez().await,
+        (&Method::GET, "/readyz") => handle_readyz(&state).await,
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(full("Not Found"))
+            .unwrap()),
+    }
+}
+
+/// Serves `/healthz`, `/livez` and `/readyz` on their own listener when `HEALTH_ADDR` is set, so
+/// load balancer health checks don't need a port that also exposes the metrics.
+#[instrument("health server", skip_all)]
+pub async fn serve(state: Arc<State>) {
+    let Some(health_addr) = state.config().health_addr.clone() else {
+        return std::future::pending().await;
+    };
+
+    let addr_result = SocketAddr::from_str(&health_addr);
+    if let Err(err) = addr_result {
+        error!(error = err.to_string(), "invalid health addr");
+        std::process::exit(1);
+    }
+    let addr = addr_result.unwrap();
+
+    let listener_result = TcpListener::bind(addr).await;
+    if let Err(err) = listener_result {
+        error!(
+            error = err.to_string(),
+            "fail to bind tcp health server listener"
+        );
+        std::process::exit(1);
+    }
+    let listener = listener_result.unwrap();
+
+    info!(addr = health_addr, "health listening");
+
+    loop {
+        let state = state.clone();
+
+        let accept_result = listener.accept().await;
+        if let Err(err) = accept_result {
+            error!(error = err.to_string(), "accept client health server");
+            continue;
+        }
+        let (stream, _) = accept_result.unwrap();
+
+        let io = TokioIo::new(stream);
+
+        tokio::task::spawn(async move {
+            let service = service_fn(move |req| routes_match(req, state.clone()));
+
+            if let Err(err) = http1_server::Builder::new()
+                .serve_connection(io, service)
+                .await
+            {
+                error!(error = err.to_string(), "failed health server connection");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(vars: &[(&str, &str)]) -> Config {
+        let required = [
+            ("NETWORK", "mainnet"),
+            ("PROXY_ADDR", "0.0.0.0:80"),
+            ("PROMETHEUS_ADDR", "0.0.0.0:9187"),
+            ("OGMIOS_DNS", "ogmios"),
+            ("OGMIOS_PORT", "1337"),
+        ];
+        let vars: HashMap<String, String> = required
+            .iter()
+            .chain(vars)
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Config::from_source(&|key| vars.get(key).cloned()).unwrap()
+    }
+
+    #[test]
+    fn first_check_applies_right_away() {
+        let config = config(&[("HEALTH_RISE", "2"), ("HEALTH_FALL", "3")]);
+
+        let status = debounce(&config, None, HealthStatus::Unhealthy, 1);
+        assert_eq!(status, HealthStatus::Unhealthy);
+        let status = debounce(&config, None, HealthStatus::Healthy, 1);
+        assert_eq!(status, HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn healthy_upstreams_fall_after_health_fall_failures() {
+        let config = config(&[("HEALTH_RISE", "2"), ("HEALTH_FALL", "3")]);
+        let current = Some(HealthStatus::Healthy);
+
+        for streak in 1..3 {
+            let status = debounce(&config, current, HealthStatus::Unhealthy, streak);
+            assert_eq!(status, HealthStatus::Healthy);
+        }
+        let status = debounce(&config, current, HealthStatus::Unhealthy, 3);
+        assert_eq!(status, HealthStatus::Unhealthy);
+    }
+
+    #[test]
+    fn unhealthy_upstreams_rise_after_health_rise_successes() {
+        let config = config(&[("HEALTH_RISE", "2"), ("HEALTH_FALL", "3")]);
+        let current = Some(HealthStatus::Unhealthy);
+
+        let status = debounce(&config, current, HealthStatus::Healthy, 1);
+        assert_eq!(status, HealthStatus::Unhealthy);
+        let status = debounce(&config, current, HealthStatus::Healthy, 2);
+        assert_eq!(status, HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn thresholds_of_one_apply_every_check() {
+        let config = config(&[("HEALTH_RISE", "1"), ("HEALTH_FALL", "1")]);
+
+        let status = debounce(
+            &config,
+            Some(HealthStatus::Healthy),
+            HealthStatus::Unhealthy,
+            1,
+        );
+        assert_eq!(status, HealthStatus::Unhealthy);
+        let status = debounce(
+            &config,
+            Some(HealthStatus::Unhealthy),
+            HealthStatus::Healthy,
+            1,
+        );
+        assert_eq!(status, HealthStatus::Healthy);
+    }
+
+    fn health(synchronization: Option<f64>, tip_age_secs: Option<i64>) -> OgmiosHealth {
+        OgmiosHealth {
+            network_synchronization: synchronization,
+            last_tip_update: tip_age_secs.map(|secs| Utc::now() - chrono::Duration::seconds(secs)),
+        }
+    }
+
+    #[test]
+    fn instances_close_to_a_moving_tip_are_synced() {
+        let config = config(&[
+            ("HEALTH_MIN_SYNCHRONIZATION", "0.999"),
+            ("HEALTH_MAX_TIP_AGE", "300"),
+        ]);
+
+        assert!(is_synced(&config, "ogmios", &health(Some(1.0), Some(10))));
+        assert!(is_synced(
+            &config,
+            "ogmios",
+            &health(Some(0.999), Some(290))
+        ));
+    }
+
+    #[test]
+    fn instances_behind_the_network_are_not_synced() {
+        let config = config(&[
+            ("HEALTH_MIN_SYNCHRONIZATION", "0.999"),
+            ("HEALTH_MAX_TIP_AGE", "300"),
+        ]);
+
+        assert!(!is_synced(
+            &config,
+            "ogmios",
+            &health(Some(0.998), Some(10))
+        ));
+        // Instances that don't report it are still syncing.
+        assert!(!is_synced(&config, "ogmios", &health(None, Some(10))));
+    }
+
+    #[test]
+    fn instances_with_a_stuck_tip_are_not_synced() {
+        let config = config(&[
+            ("HEALTH_MIN_SYNCHRONIZATION", "0.999"),
+            ("HEALTH_MAX_TIP_AGE", "300"),
+        ]);
+
+        assert!(!is_synced(&config, "ogmios", &health(Some(1.0), Some(310))));
+        assert!(!is_synced(&config, "ogmios", &health(Some(1.0), None)));
     }
 }
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::{Consumer, State};
+
+#[derive(Debug)]
+pub struct InFlightExceeded;
+impl Display for InFlightExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Too many requests in flight")
+    }
+}
+impl Error for InFlightExceeded {}
+
+/// Reserves a slot for one outstanding JSON-RPC request of the consumer, released when the
+/// returned permit is dropped. No permit is needed when the tier doesn't cap in-flight requests.
+pub async fn try_acquire(
+    state: &State,
+    consumer: &Consumer,
+) -> Result<Option<OwnedSemaphorePermit>, InFlightExceeded> {
+    let max_in_flight = state
+        .consumer_tier(consumer)
+        .await
+        .and_then(|tier| tier.max_in_flight);
+    let Some(max_in_flight) = max_in_flight else {
+        return Ok(None);
+    };
+
+    let key = state.current_key(consumer).await;
+    let semaphore = {
+        let mut in_flight = state.in_flight.write().await;
+        let (max, semaphore) = in_flight
+            .entry(key)
+            .or_insert_with(|| (max_in_flight, Arc::new(Semaphore::new(max_in_flight))));
+
+        // The tier changed, requests already in flight keep their permits on the old semaphore.
+        if *max != max_in_flight {
+            *max = max_in_flight;
+            *semaphore = Arc::new(Semaphore::new(max_in_flight));
+        }
+        semaphore.clone()
+    };
+
+    semaphore
+        .try_acquire_owned()
+        .map(Some)
+        .map_err(|_| InFlightExceeded)
+}
+
+/// A request sent over a websocket session, with its method and when it was sent to time the
+/// response.
+struct Sent {
+    _permit: Option<OwnedSemaphorePermit>,
+    method: Option<String>,
+    sent_at: Instant,
+}
+
+/// Requests sent over a websocket session that are waiting for their response, keyed by id,
+/// along with the in-flight permit they hold.
+#[derive(Default)]
+pub struct Pending(std::sync::Mutex<HashMap<String, Sent>>);
+impl Pending {
+    pub fn insert(&self, id: String, method: Option<&str>, permit: Option<OwnedSemaphorePermit>) {
+        let sent = Sent {
+            _permit: permit,
+            method: method.map(String::from),
+            sent_at: Instant::now(),
+        };
+        self.0.lock().unwrap().insert(id, sent);
+    }
+
+    /// Whether a request with this id is still waiting for its response, a second one couldn't
+    /// be told apart from it.
+    pub fn contains(&self, id: &str) -> bool {
+        self.0.lock().unwrap().contains_key(id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Releases what the request held, returning its method and how long its response took.
+    pub fn remove(&self, id: &str) -> Option<(Option<String>, Duration)> {
+        let sent = self.0.lock().unwrap().remove(id)?;
+        Some((sent.method, sent.sent_at.elapsed()))
+    }
+}
@@ -0,0 +1,114 @@
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tracing::error;
+
+use crate::auth;
+use crate::{Consumer, State};
+
+/// Answer of an RFC 7662 introspection endpoint. Only `active` and `exp` are standard, the claim
+/// naming the port is configured.
+#[derive(Debug, Deserialize)]
+struct Introspection {
+    active: bool,
+    #[serde(default)]
+    exp: Option<u64>,
+    #[serde(flatten)]
+    claims: Map<String, Value>,
+}
+
+/// Ports the introspected tokens were issued for as `PORT.NAMESPACE`, by token hash, `None` for
+/// inactive tokens.
+#[derive(Default)]
+pub struct IntrospectedTokens(Mutex<HashMap<String, (Instant, Option<String>)>>);
+impl IntrospectedTokens {
+    fn get(&self, token_hash: &str) -> Option<Option<String>> {
+        let tokens = self.0.lock().unwrap();
+        let (expires_at, port) = tokens.get(token_hash)?;
+        (*expires_at > Instant::now()).then(|| port.clone())
+    }
+
+    fn insert(&self, token_hash: &str, port: Option<String>, ttl: Duration) {
+        let now = Instant::now();
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|_, (expires_at, _)| *expires_at > now);
+        tokens.insert(token_hash.to_string(), (now + ttl, port));
+    }
+}
+
+/// What the introspection endpoint made of a token.
+pub enum Introspected {
+    /// Active and issued for an existing port.
+    Valid(Box<Consumer>),
+    /// Inactive or unknown to the endpoint, or not issued for a port, it won't become valid.
+    Invalid,
+    /// The endpoint couldn't be reached or the port named isn't loaded, it may work later.
+    Unknown,
+}
+
+async fn introspect(state: &State, url: &str, token: &str) -> reqwest::Result<Introspection> {
+    let config = state.config();
+    let mut request = state
+        .webhook_client
+        .post(url)
+        .timeout(config.proxy_auth_webhook_timeout)
+        .form(&[("token", token), ("token_type_hint", "access_token")]);
+    if let Some(client_id) = &config.proxy_introspection_client_id {
+        request = request.basic_auth(client_id, config.proxy_introspection_client_secret.as_ref());
+    }
+
+    request.send().await?.error_for_status()?.json().await
+}
+
+/// Validates an opaque bearer token against `PROXY_INTROSPECTION_URL`. The token has to be active
+/// and its `PROXY_INTROSPECTION_CLAIM` claim must name an existing port as `PORT.NAMESPACE`.
+/// Answers are cached for `PROXY_INTROSPECTION_CACHE_TTL`, never past the expiry of the token.
+pub async fn authenticate(state: &State, token: &str, token_hash: &str) -> Introspected {
+    let config = state.config();
+    let Some(url) = config.proxy_introspection_url.as_ref() else {
+        return Introspected::Invalid;
+    };
+
+    let port = match state.introspection.get(token_hash) {
+        Some(port) => port,
+        None => {
+            let introspection = match introspect(state, url, token).await {
+                Ok(introspection) => introspection,
+                Err(err) => {
+                    error!(error = err.to_string(), "auth: Failed to introspect token.");
+                    return Introspected::Unknown;
+                }
+            };
+
+            let port = introspection
+                .claims
+                .get(&config.proxy_introspection_claim)
+                .and_then(|identity| identity.as_str())
+                .map(String::from)
+                .filter(|_| introspection.active);
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let ttl = introspection
+                .exp
+                .map(|exp| Duration::from_secs(exp).saturating_sub(now))
+                .map_or(config.proxy_introspection_cache_ttl, |left| {
+                    left.min(config.proxy_introspection_cache_ttl)
+                });
+            state.introspection.insert(token_hash, port.clone(), ttl);
+            port
+        }
+    };
+
+    let Some(port) = port else {
+        return Introspected::Invalid;
+    };
+    match auth::authenticate_identity(state, &port).await {
+        Some(consumer) => Introspected::Valid(Box::new(consumer)),
+        None => Introspected::Unknown,
+    }
+}
@@ -0,0 +1,147 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::{Response, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::utils::{full, ProxyResponse};
+
+/// Methods exposed by Ogmios v6, used as the default allowlist of method metric labels.
+pub const OGMIOS_METHODS: &[&str] = &[
+    "findIntersection",
+    "nextBlock",
+    "submitTransaction",
+    "evaluateTransaction",
+    "acquireLedgerState",
+    "releaseLedgerState",
+    "queryLedgerState/constitution",
+    "queryLedgerState/constitutionalCommittee",
+    "queryLedgerState/epoch",
+    "queryLedgerState/eraStart",
+    "queryLedgerState/eraSummaries",
+    "queryLedgerState/liveStakeDistribution",
+    "queryLedgerState/projectedRewards",
+    "queryLedgerState/protocolParameters",
+    "queryLedgerState/proposedProtocolParameters",
+    "queryLedgerState/rewardAccountSummaries",
+    "queryLedgerState/rewardsProvenance",
+    "queryLedgerState/stakePools",
+    "queryLedgerState/tip",
+    "queryLedgerState/utxo",
+    "queryNetwork/blockHeight",
+    "queryNetwork/genesisConfiguration",
+    "queryNetwork/startTime",
+    "queryNetwork/tip",
+    "acquireMempool",
+    "nextTransaction",
+    "hasTransaction",
+    "sizeOfMempool",
+    "releaseMempool",
+];
+
+/// Methods that change the chain or the mempool, or cost the instance as much as doing so. Read-only
+/// keys can't call them.
+pub const WRITE_METHODS: &[&str] = &["submitTransaction", "evaluateTransaction"];
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JsonRpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Option<Value>,
+    #[serde(default)]
+    pub id: Option<Value>,
+}
+impl JsonRpcRequest {
+    pub fn from_slice(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Self::from_slice(text.as_bytes()),
+            Message::Binary(data) => Self::from_slice(data),
+            _ => None,
+        }
+    }
+}
+
+/// Only the id, method and error code of responses are read, to match them with the request
+/// they answer and count the errors.
+#[derive(Debug, Deserialize)]
+pub struct JsonRpcResponse {
+    #[serde(default)]
+    pub id: Option<Value>,
+    /// Ogmios echoes the method of the request in its responses.
+    #[serde(default)]
+    pub method: Option<String>,
+    #[serde(default)]
+    pub error: Option<JsonRpcError>,
+}
+impl JsonRpcResponse {
+    pub fn from_slice(data: &[u8]) -> Option<Self> {
+        serde_json::from_slice(data).ok()
+    }
+
+    pub fn from_message(message: &Message) -> Option<Self> {
+        match message {
+            Message::Text(text) => Self::from_slice(text.as_bytes()),
+            Message::Binary(data) => Self::from_slice(data),
+            _ => None,
+        }
+    }
+
+    /// Whether the message can hold an error, checked before parsing it so the results streamed
+    /// during a chain-sync aren't all parsed.
+    pub fn may_be_error(message: &Message) -> bool {
+        let data: &[u8] = match message {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(data) => data,
+            _ => return false,
+        };
+        data.windows(7).any(|window| window == b"\"error\"")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JsonRpcError {
+    pub code: i64,
+}
+
+// Error codes returned by the proxy itself, in the implementation defined server error range so
+// they don't clash with the ones from Ogmios.
+pub const UNAUTHORIZED: i64 = -32001;
+pub const KEY_BINDING_MISMATCH: i64 = -32002;
+pub const FORBIDDEN: i64 = -32003;
+pub const METHOD_NOT_ALLOWED: i64 = -32004;
+pub const PAYLOAD_TOO_LARGE: i64 = -32013;
+pub const LIMIT_EXCEEDED: i64 = -32029;
+pub const QUOTA_EXCEEDED: i64 = -32030;
+pub const UPSTREAM_UNAVAILABLE: i64 = -32050;
+pub const MAINTENANCE: i64 = -32051;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const INTERNAL_ERROR: i64 = -32603;
+
+pub fn error_response(code: i64, message: &str, id: Option<&Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id.cloned().unwrap_or_default(),
+    })
+}
+
+pub fn error_http_response(
+    status: StatusCode,
+    code: i64,
+    message: &str,
+    id: Option<&Value>,
+) -> ProxyResponse {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(error_response(code, message, id).to_string()))
+        .unwrap()
+}
+
+pub fn error_message(code: i64, message: &str, id: Option<&Value>) -> Message {
+    Message::Text(error_response(code, message, id).to_string())
+}
@@ -1,70 +1,343 @@
+use chrono::Utc;
 use futures_util::future::join_all;
 use leaky_bucket::RateLimiter;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{error::Error, fmt::Display};
+use tokio::time::{timeout, Instant};
 
-use crate::{tiers::Tier, Consumer, State};
+use crate::jsonrpc;
+use crate::tiers::{Tier, TierRate};
+use crate::{Consumer, State};
+
+#[derive(Clone, Default)]
+pub struct Limiter {
+    rates: Vec<Arc<RateLimiter>>,
+    methods: HashMap<String, Vec<Arc<RateLimiter>>>,
+    /// Multiplier of the schedules of the tier the rates were built with.
+    multiplier: f64,
+}
+impl Limiter {
+    /// The rates a message goes through with the permits it takes from each: the general rates
+    /// are charged the cost of the method, the method rates count messages.
+    fn rates_for(&self, method: Option<&str>, cost: usize) -> Vec<(Arc<RateLimiter>, usize)> {
+        let method_rates = method
+            .and_then(|method| self.methods.get(method))
+            .into_iter()
+            .flatten()
+            .map(|r| (r.clone(), 1));
+
+        self.rates
+            .iter()
+            .map(|r| (r.clone(), cost))
+            .chain(method_rates)
+            .collect()
+    }
+
+    /// Current token balance of every rate, for the admin api.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rates": self.rates.iter().map(|r| rate_json(r)).collect::<Vec<_>>(),
+            "methods": self
+                .methods
+                .iter()
+                .map(|(method, rates)| {
+                    (method.clone(), rates.iter().map(|r| rate_json(r)).collect())
+                })
+                .collect::<serde_json::Map<String, Value>>(),
+        })
+    }
+}
+
+fn rate_json(rate: &RateLimiter) -> Value {
+    json!({
+        "balance": rate.balance(),
+        "max": rate.max(),
+        "refill": rate.refill(),
+        "interval_secs": rate.interval().as_secs_f64(),
+    })
+}
+
+/// State of the tightest rate after a message was let through, surfaced to http clients.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitStatus {
+    pub limit: usize,
+    pub remaining: usize,
+    /// Set when the message had to wait for the rate to refill.
+    pub retry_after: Option<Duration>,
+}
 
 #[derive(Debug)]
 pub enum LimiterError {
     PortDeleted,
     InvalidTier,
+    RateLimited(Duration),
 }
 impl Display for LimiterError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             LimiterError::PortDeleted => f.write_str("Port was deleted"),
             LimiterError::InvalidTier => f.write_str("Tier is invalid"),
+            LimiterError::RateLimited(retry_after) => write!(
+                f,
+                "Rate limit exceeded, retry after {}s",
+                retry_after.as_secs().max(1)
+            ),
         }
     }
 }
 impl Error for LimiterError {}
+impl LimiterError {
+    pub fn json_rpc_code(&self) -> i64 {
+        match self {
+            LimiterError::PortDeleted => jsonrpc::UNAUTHORIZED,
+            LimiterError::InvalidTier => jsonrpc::INTERNAL_ERROR,
+            LimiterError::RateLimited(_) => jsonrpc::LIMIT_EXCEEDED,
+        }
+    }
+}
 
-async fn has_limiter(state: &State, consumer: &Consumer) -> bool {
+async fn has_limiter(state: &State, key: &str) -> bool {
     let rate_limiter_map = state.limiter.read().await;
-    rate_limiter_map.get(&consumer.key).is_some()
+    rate_limiter_map.get(key).is_some()
 }
 
-async fn add_limiter(state: &State, consumer: &Consumer, tier: &Tier) {
-    let rates = tier
-        .rates
+/// Buckets for the rates multiplied by `multiplier`. Buckets replacing `previous` ones start as
+/// full as those were, in proportion of their size, so a schedule change doesn't refill them.
+fn build_rates(
+    rates: &[TierRate],
+    multiplier: f64,
+    previous: Option<&[Arc<RateLimiter>]>,
+) -> Vec<Arc<RateLimiter>> {
+    rates
         .iter()
-        .map(|r| {
+        .enumerate()
+        .map(|(i, r)| {
+            let r = r.scaled(multiplier);
+            let initial = match previous.and_then(|previous| previous.get(i)) {
+                Some(previous) => {
+                    let ratio = r.capacity() as f64 / previous.max().max(1) as f64;
+                    (previous.balance() as f64 * ratio).round() as usize
+                }
+                None => r.initial(),
+            };
             Arc::new(
                 RateLimiter::builder()
-                    .initial(r.limit)
-                    .interval(r.interval)
-                    .refill(r.limit)
+                    .max(r.capacity())
+                    .initial(initial)
+                    .interval(r.refill_interval())
+                    .refill(r.refill_amount())
                     .build(),
             )
         })
-        .collect();
+        .collect()
+}
 
-    state
-        .limiter
-        .write()
-        .await
-        .insert(consumer.key.clone(), rates);
+fn build_limiter(tier: &Tier, multiplier: f64, previous: Option<&Limiter>) -> Limiter {
+    Limiter {
+        rates: build_rates(
+            &tier.rates,
+            multiplier,
+            previous.map(|previous| previous.rates.as_slice()),
+        ),
+        methods: tier
+            .methods
+            .iter()
+            .map(|(method, rates)| {
+                let previous = previous
+                    .and_then(|previous| previous.methods.get(method))
+                    .map(Vec::as_slice);
+                (method.clone(), build_rates(rates, multiplier, previous))
+            })
+            .collect(),
+        multiplier,
+    }
+}
+
+async fn add_limiter(state: &State, key: &str, tier: &Tier) {
+    state.limiter.write().await.insert(
+        key.to_string(),
+        build_limiter(tier, tier.multiplier_at(Utc::now()), None),
+    );
+}
+
+/// Swaps the limiter of a consumer whose port moved to another tier for one with the new rates,
+/// so its open sessions are limited by the new tier from their next message. Consumers without a
+/// limiter get one on their first message anyway.
+pub async fn rebuild(state: &State, consumer: &Consumer) {
+    let tier = state
+        .resolve_tier(
+            &consumer.tier,
+            consumer.tier_overrides.as_ref(),
+            consumer.created_at,
+        )
+        .await;
+    let mut limiters = state.limiter.write().await;
+    if !limiters.contains_key(&consumer.key) {
+        return;
+    }
+    match tier {
+        Some(tier) => {
+            let multiplier = tier.multiplier_at(Utc::now());
+            limiters.insert(consumer.key.clone(), build_limiter(&tier, multiplier, None))
+        }
+        None => limiters.remove(&consumer.key),
+    };
 }
 
-pub async fn limiter(state: Arc<State>, consumer: &Consumer) -> Result<(), LimiterError> {
-    if !has_limiter(&state, consumer).await {
+/// Rebuilds the limiter of the consumer when a schedule of its tier started or ended since it
+/// was built, so the multiplied rates apply from this message on.
+async fn apply_schedules(state: &State, key: &str, tier: &Tier) {
+    let multiplier = tier.multiplier_at(Utc::now());
+    let stale = |limiter: &Limiter| limiter.multiplier != multiplier;
+    if !state.limiter.read().await.get(key).is_some_and(stale) {
+        return;
+    }
+
+    let mut limiters = state.limiter.write().await;
+    if let Some(current) = limiters.get(key).filter(|limiter| stale(limiter)) {
+        let limiter = build_limiter(tier, multiplier, Some(current));
+        limiters.insert(key.to_string(), limiter);
+    }
+}
+
+/// Permits left on the general and the method specific rates of the consumer, in the order of the
+/// tier. None until a first message built the limiter, every rate is full then.
+pub async fn balances(
+    state: &State,
+    consumer: &Consumer,
+) -> Option<(Vec<usize>, HashMap<String, Vec<usize>>)> {
+    let limiters = state.limiter.read().await;
+    let limiter = limiters.get(&consumer.key)?;
+    let balances = |rates: &[Arc<RateLimiter>]| rates.iter().map(|r| r.balance()).collect();
+
+    Some((
+        balances(&limiter.rates),
+        limiter
+            .methods
+            .iter()
+            .map(|(method, rates)| (method.clone(), balances(rates)))
+            .collect(),
+    ))
+}
+
+/// Level of one bucket of a consumer, `rate` being its position in the general or the method
+/// rates of the tier.
+pub struct BucketBalance {
+    pub consumer: Consumer,
+    pub method: Option<String>,
+    pub rate: usize,
+    pub remaining: usize,
+    pub capacity: usize,
+}
+
+/// Levels of the buckets of every consumer with a limiter, read as they are right now.
+pub async fn sample(state: &State) -> Vec<BucketBalance> {
+    let consumers = state.consumers.read().await;
+    let limiters = state.limiter.read().await;
+    let mut balances = vec![];
+    for (key, limiter) in limiters.iter() {
+        let Some(consumer) = consumers.get(key) else {
+            continue;
+        };
+        let methods = limiter
+            .methods
+            .iter()
+            .map(|(method, rates)| (Some(method), rates));
+        for (method, rates) in std::iter::once((None, &limiter.rates)).chain(methods) {
+            for (rate, bucket) in rates.iter().enumerate() {
+                balances.push(BucketBalance {
+                    consumer: consumer.clone(),
+                    method: method.cloned(),
+                    rate,
+                    remaining: bucket.balance(),
+                    capacity: bucket.max(),
+                });
+            }
+        }
+    }
+    balances
+}
+
+/// Waits until the consumer has capacity for one more message, which takes the cost of its method
+/// from the general rates. When the message is a JSON-RPC call, the method specific rates of the
+/// tier are applied on top of the general ones. The message
+/// is rejected instead when the wait would exceed `PROXY_RATE_LIMIT_MAX_WAIT`.
+pub async fn limiter(
+    state: Arc<State>,
+    consumer: &Consumer,
+    method: Option<&str>,
+) -> Result<Option<RateLimitStatus>, LimiterError> {
+    // Sessions opened before a key rotation share the limiter of the new key.
+    let key = state.current_key(consumer).await;
+    let tier = if !has_limiter(&state, &key).await {
         let consumers = state.consumers.read().await.clone();
-        let refreshed_consumer = match consumers.get(&consumer.key) {
+        let refreshed_consumer = match consumers.get(&key) {
             Some(consumer) => consumer,
             None => return Err(LimiterError::PortDeleted),
         };
-        let tiers = state.tiers.read().await.clone();
-        let tier = match tiers.get(&refreshed_consumer.tier) {
+        let tier = match state.consumer_tier(refreshed_consumer).await {
             Some(tier) => tier,
             None => return Err(LimiterError::InvalidTier),
         };
-        add_limiter(&state, refreshed_consumer, tier).await;
-    }
+        add_limiter(&state, &key, &tier).await;
+        Some(tier)
+    } else {
+        let tier = state.consumer_tier(consumer).await;
+        if let Some(tier) = &tier {
+            apply_schedules(&state, &key, tier).await;
+        }
+        tier
+    };
+    let cost = tier.as_ref().map(|tier| tier.cost(method)).unwrap_or(1);
+    let tier_name = tier.map(|tier| tier.name).unwrap_or(consumer.tier.clone());
+
+    let rates = state
+        .limiter
+        .read()
+        .await
+        .get(&key)
+        .map(|limiter| limiter.rates_for(method, cost))
+        .unwrap_or_default();
 
-    let rate_limiter_map = state.limiter.read().await.clone();
-    let rates = rate_limiter_map.get(&consumer.key).unwrap();
+    // The rate with the fewest permits left is the one slowing the consumer down.
+    let tightest = rates
+        .iter()
+        .min_by_key(|(r, permits)| r.balance() as isize - *permits as isize);
+    let delayed = tightest.is_some_and(|(r, permits)| r.balance() < *permits);
+    let retry_after = tightest.map(|(r, _)| r.interval()).unwrap_or_default();
+
+    let acquire = join_all(
+        rates
+            .iter()
+            .map(|(r, permits)| async { r.acquire(*permits).await }),
+    );
+    let namespace = &state.config().proxy_namespace;
+    let started_at = Instant::now();
+    match state.config().proxy_rate_limit_max_wait {
+        Some(max_wait) => {
+            if timeout(max_wait, acquire).await.is_err() {
+                state
+                    .metrics
+                    .count_rate_limited(namespace, consumer, &tier_name, "rejected");
+                return Err(LimiterError::RateLimited(retry_after));
+            }
+        }
+        None => {
+            acquire.await;
+        }
+    }
+    if delayed && started_at.elapsed() >= state.config().proxy_rate_limit_delay_threshold {
+        state
+            .metrics
+            .count_rate_limited(namespace, consumer, &tier_name, "delayed");
+    }
 
-    join_all(rates.iter().map(|r| async { r.acquire_one().await })).await;
-    Ok(())
+    Ok(tightest.map(|(r, _)| RateLimitStatus {
+        limit: r.max(),
+        remaining: r.balance(),
+        retry_after: delayed.then_some(retry_after),
+    }))
 }
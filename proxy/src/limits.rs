@@ -0,0 +1,152 @@
+use chrono::Utc;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::jsonrpc::{error_http_response, INTERNAL_ERROR};
+use crate::proxy::ProxyRequest;
+use crate::quota::{self, QuotaError};
+use crate::tiers::TierRate;
+use crate::utils::{full, ProxyResponse};
+use crate::{limiter, State};
+
+pub const LIMITS_PATH: &str = "/dmtr/limits";
+
+/// The rates as currently enforced, multiplied by the schedules of the tier.
+fn rates_json(rates: &[TierRate], multiplier: f64, balances: Option<&Vec<usize>>) -> Value {
+    rates
+        .iter()
+        .map(|rate| rate.scaled(multiplier))
+        .enumerate()
+        .map(|(i, rate)| {
+            let remaining = balances
+                .and_then(|balances| balances.get(i))
+                .copied()
+                .unwrap_or(rate.initial());
+            json!({
+                "limit": rate.limit,
+                "burst": rate.capacity(),
+                "interval_secs": rate.interval.as_secs_f64(),
+                "refill": rate.refill_amount(),
+                "refill_interval_secs": rate.refill_interval().as_secs_f64(),
+                "remaining": remaining,
+            })
+        })
+        .collect()
+}
+
+fn calendar_json(limit: Option<u64>, used: u64, window: QuotaError) -> Value {
+    match limit {
+        Some(limit) => json!({
+            "limit": limit,
+            "used": used,
+            "remaining": limit.saturating_sub(used),
+            "reset_secs": window.retry_after().map(|reset| reset.as_secs()),
+        }),
+        None => Value::Null,
+    }
+}
+
+/// Answers `GET /dmtr/limits` with the limits resolved for the key and what's left of them, so
+/// users can tell why they're throttled. Nothing is forwarded nor counted against the limits.
+pub async fn handle_limits(
+    state: &State,
+    proxy_req: &ProxyRequest,
+) -> Result<ProxyResponse, hyper::Error> {
+    let consumer = state
+        .get_consumer(&proxy_req.consumer.key)
+        .await
+        .unwrap_or_else(|| proxy_req.consumer.clone());
+    let Some(tier) = state.consumer_tier(&consumer).await else {
+        return Ok(error_http_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            INTERNAL_ERROR,
+            "Invalid tier value. Contact support team for more information.",
+            None,
+        ));
+    };
+
+    let port_tier = state.tiers.read().await.get(&consumer.tier).cloned();
+    let trial_ends_at = port_tier
+        .as_ref()
+        .and_then(|port_tier| port_tier.trial.as_ref())
+        .zip(consumer.created_at)
+        .map(|(trial, created_at)| trial.ends_at(created_at).to_rfc3339());
+    let multiplier = tier.multiplier_at(Utc::now());
+    let (rate_balances, method_balances) = limiter::balances(state, &consumer).await.unzip();
+    let (daily, monthly) = quota::request_usage(state, &consumer).await;
+    let bandwidth = match &tier.bandwidth {
+        Some(bandwidth) => {
+            let (used, reset) = quota::bandwidth_usage(state, &consumer, bandwidth.interval).await;
+            json!({
+                "limit": bandwidth.limit,
+                "used": used,
+                "remaining": bandwidth.limit.saturating_sub(used),
+                "interval_secs": bandwidth.interval.as_secs_f64(),
+                "reset_secs": reset.as_secs(),
+            })
+        }
+        None => Value::Null,
+    };
+    let in_flight = match tier.max_in_flight {
+        Some(max) => {
+            let in_use = state
+                .in_flight
+                .read()
+                .await
+                .get(&consumer.key)
+                .map(|(_, semaphore)| max.saturating_sub(semaphore.available_permits()))
+                .unwrap_or_default();
+            json!({ "max": max, "in_use": in_use })
+        }
+        None => Value::Null,
+    };
+
+    let body = json!({
+        "consumer": consumer.to_string(),
+        "network": consumer.network,
+        "version": consumer.version,
+        "tier": tier.name,
+        "port_tier": consumer.tier,
+        "fallback_tier": port_tier.is_none(),
+        "trial_ends_at": trial_ends_at,
+        "tier_overrides": consumer.tier_overrides.is_some(),
+        "expires_at": consumer.expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        "connections": {
+            "max": tier.max_connections,
+            "active": consumer.active_connections,
+        },
+        "in_flight": in_flight,
+        "max_session_duration_secs": tier
+            .max_session_duration
+            .map(|duration| duration.as_secs()),
+        "rates": rates_json(&tier.rates, multiplier, rate_balances.as_ref()),
+        "methods": tier
+            .methods
+            .iter()
+            .map(|(method, rates)| {
+                let balances = method_balances
+                    .as_ref()
+                    .and_then(|balances| balances.get(method));
+                (method.clone(), rates_json(rates, multiplier, balances))
+            })
+            .collect::<serde_json::Map<String, Value>>(),
+        "allowed_methods": tier.allowed_methods,
+        "denied_methods": tier.denied_methods,
+        "allowed_networks": tier.allowed_networks,
+        "costs": tier.costs,
+        "default_cost": tier.cost(None),
+        "priority": tier.priority.unwrap_or(1),
+        "multiplier": multiplier,
+        "quotas": {
+            "daily_requests": calendar_json(tier.daily_requests, daily, QuotaError::DailyRequests),
+            "monthly_requests": calendar_json(tier.monthly_requests, monthly, QuotaError::MonthlyRequests),
+            "bandwidth": bandwidth,
+        },
+    });
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(full(body.to_string()))
+        .unwrap())
+}
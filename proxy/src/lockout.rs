@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Why a request couldn't be authenticated, used as the `reason` label of the failures metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    MissingHost,
+    /// No key nor token was sent.
+    MissingKey,
+    UnknownKey,
+    /// The key belonged to a port that was deleted or rotated it.
+    RevokedKey,
+    /// The bearer token is a JWT past its expiry.
+    ExpiredToken,
+    QueryKeyNotAllowed,
+    NotServed,
+    /// The tier of the port doesn't give access to its network.
+    NetworkNotAllowed,
+    RouteMismatch,
+    /// The credentials are valid but the port expired.
+    Expired,
+    /// The hostname names a key that isn't one of the consumer's.
+    KeyBindingMismatch,
+    /// Refused without checking the credentials, the source or the key is banned.
+    Banned(Duration),
+}
+impl AuthFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::MissingHost => "missing_host",
+            Self::MissingKey => "missing_key",
+            Self::UnknownKey => "unknown_key",
+            Self::RevokedKey => "revoked_key",
+            Self::ExpiredToken => "expired_token",
+            Self::QueryKeyNotAllowed => "query_key_not_allowed",
+            Self::NotServed => "not_served",
+            Self::NetworkNotAllowed => "network_not_allowed",
+            Self::RouteMismatch => "route_mismatch",
+            Self::Expired => "expired",
+            Self::KeyBindingMismatch => "key_binding_mismatch",
+            Self::Banned(_) => "banned",
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Failed authentications by source ip and by hash of the key sent. Keys are tracked whole, a
+/// prefix is part of the public hostname and would let anyone lock its owner out. Once a source reaches the threshold it
+/// is banned, and each failure after the ban expires doubles the next one, up to the max
+/// duration. Sources are forgotten after failing nothing for the max duration.
+pub struct Lockout {
+    threshold: u32,
+    ban: Duration,
+    max_ban: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+impl Lockout {
+    pub fn new(threshold: u32, ban: Duration, max_ban: Duration) -> Self {
+        Self {
+            threshold,
+            ban,
+            max_ban,
+            failures: Default::default(),
+        }
+    }
+
+    fn sources(ip: &IpAddr, key_hash: Option<&str>) -> Vec<String> {
+        let mut sources = vec![format!("ip:{ip}")];
+        if let Some(key_hash) = key_hash.filter(|key_hash| !key_hash.is_empty()) {
+            sources.push(format!("key:{key_hash}"));
+        }
+        sources
+    }
+
+    /// Time left on the longest ban applying to the ip or the key.
+    pub fn banned(&self, ip: &IpAddr, key_hash: Option<&str>) -> Option<Duration> {
+        if self.threshold == 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        Self::sources(ip, key_hash)
+            .iter()
+            .filter_map(|source| failures.get(source)?.banned_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    pub fn record_failure(&self, ip: &IpAddr, key_hash: Option<&str>) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, f| now.duration_since(f.last_failure) < self.max_ban);
+
+        for source in Self::sources(ip, key_hash) {
+            let entry = failures.entry(source).or_insert(Failures {
+                count: 0,
+                last_failure: now,
+                banned_until: None,
+            });
+            entry.count += 1;
+            entry.last_failure = now;
+
+            if entry.count >= self.threshold {
+                let exponent = (entry.count - self.threshold).min(31);
+                let ban = self
+                    .ban
+                    .saturating_mul(2u32.saturating_pow(exponent))
+                    .min(self.max_ban);
+                entry.banned_until = Some(now + ban);
+            }
+        }
+    }
+}
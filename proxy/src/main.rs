@@ -1,80 +1,602 @@
+use bytes::Bytes;
+use cache::ResponseCache;
+use chrono::{DateTime, Utc};
+use circuit::CircuitBreaker;
 use config::Config;
 use dotenv::dotenv;
-use leaky_bucket::RateLimiter;
+use health::{UpstreamChecks, UpstreamHealth};
+use http_body_util::Full;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use introspection::IntrospectedTokens;
+use ipnet::IpNet;
+use jsonrpc::WRITE_METHODS;
+use jsonwebtoken::jwk::JwkSet;
+use limiter::Limiter;
+use lockout::Lockout;
 use metrics::Metrics;
-use operator::{kube::ResourceExt, OgmiosPort};
+use negative_cache::RejectedKeys;
+use operator::{kube::ResourceExt, AuthTokens, OgmiosPort};
 use prometheus::Registry;
+use quota::{RequestUsage, Usage};
+use reconnect::ReconnectTokens;
 use regex::Regex;
+use resolver::{Resolver, UpstreamConnector};
+use scheduler::Scheduler;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tiers::Tier;
-use tokio::sync::RwLock;
-use tracing::Level;
+use std::time::Duration;
+use tiers::{Tier, TierOverrides};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, watch, RwLock, Semaphore};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use upstream::Upstreams;
+use usage::UsageAccounting;
+use webhook::WebhookDecisions;
 
-use crate::utils::handle_legacy_networks;
+use crate::utils::{handle_legacy_networks, hash_key};
 
+mod access_log;
+mod admin;
+mod audit;
 mod auth;
+mod cache;
+mod circuit;
 mod config;
+mod cors;
+mod deflate;
 mod health;
+mod inflight;
+mod introspection;
+mod jsonrpc;
 mod limiter;
+mod limits;
+mod lockout;
 mod metrics;
+mod negative_cache;
 mod proxy;
+mod proxy_protocol;
+mod quota;
+mod reconnect;
+mod resolver;
+mod scheduler;
+mod telemetry;
 mod tiers;
+mod tls;
+mod trial;
+mod upstream;
+mod usage;
 mod utils;
+mod webhook;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
 
-    tracing_subscriber::fmt().with_max_level(Level::INFO).init();
+    let fmt_layer = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => tracing_subscriber::fmt::layer().json().boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(fmt_layer)
+        .with(telemetry::layer()?)
+        .init();
 
     let state = Arc::new(State::try_new()?);
 
     auth::start(state.clone());
+    auth::start_jwks(state.clone());
     tiers::start(state.clone());
+    quota::start(state.clone());
+    trial::start(state.clone());
+    webhook::start(state.clone());
+    usage::start(state.clone());
 
+    let meter_provider = telemetry::meter_provider(state.clone())?;
     let metrics = metrics::start(state.clone());
+    let admin = admin::start(state.clone());
     let proxy_server = proxy::start(state.clone());
     let healthloop = health::start(state.clone());
+    let health_server = health::serve(state.clone());
 
-    tokio::join!(metrics, proxy_server, healthloop);
+    tokio::spawn(shutdown_signal(state.clone()));
+    tokio::spawn(reload_signal(state.clone()));
+
+    // The proxy server only returns once a shutdown was requested and sessions were drained.
+    tokio::select! {
+        _ = metrics => {},
+        _ = admin => {},
+        _ = proxy_server => {},
+        _ = healthloop => {},
+        _ = health_server => {},
+    }
+
+    quota::flush_requests(&state).await;
+    usage::flush(&state).await;
+    telemetry::shutdown(meter_provider);
 
     Ok(())
 }
 
+/// Loads the variables of an env file, overriding the ones already set so changes apply on
+/// reload.
+async fn reload_signal(state: Arc<State>) {
+    let mut sighup = signal(SignalKind::hangup()).expect("failed to listen to SIGHUP");
+
+    while sighup.recv().await.is_some() {
+        info!("reload signal received");
+        state.reload_config();
+    }
+}
+
+async fn shutdown_signal(state: Arc<State>) {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen to SIGTERM");
+
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = tokio::signal::ctrl_c() => {},
+    }
+
+    info!("shutdown signal received, draining connections");
+    state.shutdown.send_replace(true);
+}
+
+/// How long the keys of deleted or rotated ports are told apart from unknown keys when they're
+/// refused.
+const REVOKED_KEYS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub struct State {
-    config: Config,
+    config: std::sync::RwLock<Arc<Config>>,
     metrics: Metrics,
     host_regex: Regex,
+    host_key_group: usize,
     consumers: RwLock<HashMap<String, Consumer>>,
     tiers: RwLock<HashMap<String, Tier>>,
-    limiter: RwLock<HashMap<String, Vec<Arc<RateLimiter>>>>,
-    upstream_health: RwLock<bool>,
+    limiter: RwLock<HashMap<String, Limiter>>,
+    bandwidth: RwLock<HashMap<String, Usage>>,
+    /// Calendar request usage by consumer name, persisted across restarts.
+    requests: RwLock<HashMap<String, RequestUsage>>,
+    in_flight: RwLock<HashMap<String, (usize, Arc<Semaphore>)>>,
+    upstream_health: RwLock<UpstreamHealth>,
+    upstream_checks: RwLock<UpstreamChecks>,
+    /// Set once the first list of the ports, and of the tiers, has been loaded.
+    consumers_synced: AtomicBool,
+    tiers_synced: AtomicBool,
+    upstreams: Upstreams,
+    resolver: Arc<Resolver>,
+    http_client: Client<UpstreamConnector, Full<Bytes>>,
+    cache: ResponseCache,
+    circuit: CircuitBreaker,
+    scheduler: Arc<Scheduler>,
+    lockout: Lockout,
+    rejected_keys: RejectedKeys,
+    /// Keys no port accepts anymore, only used to label the failures.
+    revoked_keys: RejectedKeys,
+    usage: UsageAccounting,
+    webhook: WebhookDecisions,
+    webhook_client: reqwest::Client,
+    introspection: IntrospectedTokens,
+    shutdown: watch::Sender<bool>,
+    disconnect: broadcast::Sender<String>,
+    revoke: broadcast::Sender<Revocation>,
+    /// Ports created or updated, as `NAMESPACE.PORT`.
+    update: broadcast::Sender<String>,
+    sessions: AtomicUsize,
+    maintenance: std::sync::RwLock<Option<String>>,
+    reconnect: ReconnectTokens,
+    switches: std::sync::RwLock<HashMap<String, String>>,
+    jwks: RwLock<Option<JwkSet>>,
+    key_aliases: RwLock<HashMap<String, String>>,
+    migrate: broadcast::Sender<(String, Duration)>,
 }
 impl State {
     pub fn try_new() -> Result<Self, Box<dyn Error>> {
-        let config = Config::new();
-        let metrics = Metrics::try_new(Registry::default())?;
-        let host_regex = Regex::new(r"([dmtr_]?[\w\d-]+)?\.?.+")?;
+        let config = Config::load()?;
+        let metrics = Metrics::try_new(Registry::default(), &config)?;
+        let host_regex = Regex::new(&config.proxy_host_regex)?;
+        if config.proxy_host_regex_key_group >= host_regex.captures_len() {
+            return Err(format!(
+                "PROXY_HOST_REGEX has no capture group {}",
+                config.proxy_host_regex_key_group
+            )
+            .into());
+        }
+        let host_key_group = config.proxy_host_regex_key_group;
         let consumers = Default::default();
         let tiers = Default::default();
         let limiter = Default::default();
+        let bandwidth = Default::default();
+        let requests = match &config.proxy_quota_state_path {
+            Some(path) => quota::load_requests(path),
+            None => Default::default(),
+        };
+        let upstreams = Default::default();
+        let upstream_tls = tls::build_upstream_tls(&config)?;
+        let resolver = Arc::new(Resolver::new(config.proxy_dns_ttl, upstream_tls));
+        let http_client = Client::builder(TokioExecutor::new())
+            .pool_timer(TokioTimer::new())
+            .pool_idle_timeout(config.proxy_http_pool_idle_timeout)
+            .pool_max_idle_per_host(config.proxy_http_pool_max_idle)
+            .http1_preserve_header_case(true)
+            .http1_title_case_headers(true)
+            .build(UpstreamConnector(resolver.clone()));
+        let maintenance = config
+            .proxy_maintenance
+            .then(|| config.proxy_maintenance_message.clone());
+        let rejected_keys = RejectedKeys::new(config.proxy_auth_negative_cache_ttl);
+        let revoked_keys = RejectedKeys::new(REVOKED_KEYS_TTL);
+        let usage = UsageAccounting::new(config.proxy_usage_sink_url.is_some());
+        let lockout = Lockout::new(
+            config.proxy_auth_failure_threshold,
+            config.proxy_auth_ban_duration,
+            config.proxy_auth_ban_max_duration,
+        );
+        let circuit = CircuitBreaker::new(
+            config.proxy_circuit_failure_threshold,
+            config.proxy_circuit_cooldown,
+        );
+        let scheduler = Scheduler::new(
+            config.proxy_upstream_capacity,
+            config.proxy_scheduler_max_wait,
+        );
+        let cache = ResponseCache::new(config.proxy_cache_ttl, config.proxy_cache_methods.clone());
 
         Ok(Self {
-            config,
+            config: std::sync::RwLock::new(Arc::new(config)),
             metrics,
             host_regex,
+            host_key_group,
             consumers,
             tiers,
             limiter,
-            upstream_health: RwLock::new(false),
+            bandwidth,
+            requests: RwLock::new(requests),
+            in_flight: Default::default(),
+            upstream_health: Default::default(),
+            upstream_checks: Default::default(),
+            consumers_synced: Default::default(),
+            tiers_synced: Default::default(),
+            upstreams,
+            resolver,
+            http_client,
+            cache,
+            circuit,
+            scheduler,
+            lockout,
+            rejected_keys,
+            revoked_keys,
+            usage,
+            webhook: Default::default(),
+            webhook_client: reqwest::Client::new(),
+            introspection: Default::default(),
+            shutdown: watch::Sender::new(false),
+            disconnect: broadcast::channel(16).0,
+            revoke: broadcast::channel(64).0,
+            update: broadcast::channel(64).0,
+            sessions: AtomicUsize::new(0),
+            maintenance: std::sync::RwLock::new(maintenance),
+            reconnect: Default::default(),
+            switches: Default::default(),
+            jwks: Default::default(),
+            key_aliases: Default::default(),
+            migrate: broadcast::channel(16).0,
         })
     }
 
-    pub async fn get_consumer(&self, key: &str) -> Option<Consumer> {
-        self.consumers.read().await.clone().get(key).cloned()
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-reads the configuration and swaps it in place. Sessions keep running and pick up the
+    /// new values on their next lookup. An invalid configuration is rejected and the current one
+    /// is kept.
+    pub fn reload_config(&self) {
+        match Config::load() {
+            Ok(config) => {
+                self.metrics.set_config_info(&config);
+                info!(fingerprint = config.fingerprint(), "proxy config reloaded");
+                *self.config.write().unwrap() = Arc::new(config);
+            }
+            Err(err) => error!(
+                error = err.to_string(),
+                "invalid proxy config, keeping the current one"
+            ),
+        }
+    }
+
+    /// Resolves once a shutdown was requested. Safe to call any number of times.
+    pub async fn wait_shutdown(&self) {
+        let mut receiver = self.shutdown.subscribe();
+        let _ = receiver.wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Resolves once an admin asked to drop the sessions of the consumer.
+    pub async fn wait_disconnect(&self, consumer: &Consumer) {
+        let mut receiver = self.disconnect.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(disconnected) if disconnected == self.current_key(consumer).await => return,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+            }
+        }
+    }
+
+    /// Resolves once the key of the session stopped being accepted, or its port was deleted.
+    pub async fn wait_revocation(&self, consumer: &Consumer) {
+        let mut receiver = self.revoke.subscribe();
+        loop {
+            match receiver.recv().await {
+                Ok(Revocation::Key(key)) if consumer.session_key.as_ref() == Some(&key) => return,
+                // Webhook consumers are revoked by key, they may be named like a port.
+                Ok(Revocation::Port(port))
+                    if !consumer.external && port == consumer.to_string() =>
+                {
+                    return
+                }
+                // Revocations were missed, the session is checked against the current keys.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    if !self.is_still_accepted(consumer).await {
+                        return;
+                    }
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+            }
+        }
+    }
+
+    /// Whether the port of the session still exists and accepts the key it was opened with.
+    async fn is_still_accepted(&self, consumer: &Consumer) -> bool {
+        let current = match consumer.external {
+            true => self.consumers.read().await.get(&consumer.key).cloned(),
+            false => {
+                self.get_port_consumer(&consumer.namespace, &consumer.port_name)
+                    .await
+            }
+        };
+        match (current, &consumer.session_key) {
+            (Some(current), Some(key)) => current.accepted_keys().contains(key),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// The tier of the consumer, with the overrides of its port merged in. Sessions hold the
+    /// consumer they were opened with, so the tier is read from the current port when it still
+    /// exists, and a tier change applies to open sessions right away.
+    pub async fn consumer_tier(&self, consumer: &Consumer) -> Option<Tier> {
+        let key = self.current_key(consumer).await;
+        let current = self.consumers.read().await.get(&key).map(|current| {
+            (
+                current.tier.clone(),
+                current.tier_overrides.clone(),
+                current.created_at,
+            )
+        });
+        match current {
+            Some((tier, overrides, created_at)) => {
+                self.resolve_tier(&tier, overrides.as_ref(), created_at)
+                    .await
+            }
+            None => {
+                self.resolve_tier(
+                    &consumer.tier,
+                    consumer.tier_overrides.as_ref(),
+                    consumer.created_at,
+                )
+                .await
+            }
+        }
+    }
+
+    /// The tier named, with the overrides merged in, without looking up a port. A tier that
+    /// doesn't exist falls back to `PROXY_DEFAULT_TIER`, and ports whose trial ended use the
+    /// post-trial tier.
+    pub async fn resolve_tier(
+        &self,
+        name: &str,
+        overrides: Option<&TierOverrides>,
+        created_at: Option<DateTime<Utc>>,
+    ) -> Option<Tier> {
+        let tiers = self.tiers.read().await;
+        let default_tier = || tiers.get(self.config().proxy_default_tier.as_ref()?);
+        let mut tier = tiers.get(name).or_else(default_tier)?;
+        if let Some(trial) = &tier.trial {
+            let ended =
+                created_at.is_some_and(|created_at| trial.ends_at(created_at) <= Utc::now());
+            if ended {
+                tier = tiers
+                    .get(&trial.post_trial_tier)
+                    .or_else(default_tier)
+                    .unwrap_or(tier);
+            }
+        }
+        Some(match overrides {
+            Some(overrides) => tier.with_overrides(overrides),
+            None => tier.clone(),
+        })
+    }
+
+    /// Open websocket sessions of the consumers by network, version and tier, including the
+    /// routes without sessions so their series stay at zero.
+    pub async fn active_connections(&self) -> HashMap<(String, String, String), usize> {
+        let mut connections: HashMap<(String, String, String), usize> = HashMap::new();
+        for consumer in self.consumers.read().await.values() {
+            let route = (
+                consumer.network.clone(),
+                consumer.version.clone(),
+                consumer.tier.clone(),
+            );
+            *connections.entry(route).or_default() += consumer.active_connections;
+        }
+        connections
+    }
+
+    /// Consumers referencing a tier that doesn't exist, by the name of that tier.
+    pub async fn missing_tiers(&self) -> HashMap<String, usize> {
+        let tiers = self.tiers.read().await;
+        let mut missing: HashMap<String, usize> = HashMap::new();
+        for consumer in self.consumers.read().await.values() {
+            if !tiers.contains_key(&consumer.tier) {
+                *missing.entry(consumer.tier.clone()).or_default() += 1;
+            }
+        }
+        missing
+    }
+
+    /// Resolves once the port of the session expired. The expiry is read again when it's reached
+    /// and whenever the port is updated, since it may have been extended or brought forward in the
+    /// meantime.
+    pub async fn wait_expiry(&self, consumer: &Consumer) {
+        let mut updates = self.update.subscribe();
+        let mut expires_at = consumer.expires_at;
+        loop {
+            let expiry = async {
+                match expires_at {
+                    Some(at) => {
+                        tokio::time::sleep((at - Utc::now()).to_std().unwrap_or_default()).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = expiry => {}
+                update = updates.recv() => match update {
+                    Ok(port) if port == consumer.to_string() => {}
+                    // Updates were missed, the expiry is read again in case one was for this port.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+                },
+            }
+
+            // Deleted ports are handled by the revocation.
+            let current = self
+                .get_port_consumer(&consumer.namespace, &consumer.port_name)
+                .await;
+            let Some(current) = current else {
+                return std::future::pending().await;
+            };
+            if current.is_expired() {
+                return;
+            }
+            expires_at = current.expires_at;
+        }
+    }
+
+    /// The message returned to new requests while the maintenance mode is on.
+    pub fn maintenance(&self) -> Option<String> {
+        self.maintenance.read().unwrap().clone()
+    }
+
+    pub fn set_maintenance(&self, message: Option<String>) {
+        *self.maintenance.write().unwrap() = message;
+    }
+
+    /// Instances serving a network and version. A network switched through the admin api is
+    /// routed to its new endpoint instead of the configured ones.
+    pub fn instances(&self, network: &str, version: &str) -> Vec<String> {
+        match self.switches.read().unwrap().get(network) {
+            Some(endpoint) => vec![endpoint.replace("{version}", version)],
+            None => self.config().instances(network, version),
+        }
+    }
+
+    /// Resolves once the sessions of the network were asked to move to a switched endpoint,
+    /// after a random delay within the drain window so they don't all reconnect at once.
+    pub async fn wait_migration(&self, network: &str) {
+        let mut receiver = self.migrate.subscribe();
+        let window = loop {
+            match receiver.recv().await {
+                Ok((switched, window)) if switched == network => break window,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+            }
+        };
+
+        let delay = window.mul_f64(rand::random::<f64>());
+        tokio::time::sleep(delay).await;
+    }
+
+    /// Routes the new sessions of the network to the endpoint. With a drain window, the sessions
+    /// already open are asked to reconnect within it.
+    pub fn switch_upstream(
+        &self,
+        network: &str,
+        endpoint: Option<String>,
+        drain: Option<Duration>,
+    ) {
+        match endpoint {
+            Some(endpoint) => self
+                .switches
+                .write()
+                .unwrap()
+                .insert(network.to_string(), endpoint),
+            None => self.switches.write().unwrap().remove(network),
+        };
+
+        if let Some(drain) = drain {
+            // Nobody is subscribed when the network has no open sessions.
+            let _ = self.migrate.send((network.to_string(), drain));
+        }
+    }
+
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+
+    /// Looks up the consumer of the port, for credentials naming the port instead of its key.
+    pub async fn get_port_consumer(&self, namespace: &str, port_name: &str) -> Option<Consumer> {
+        self.consumers
+            .read()
+            .await
+            .values()
+            .find(|c| !c.external && c.namespace == namespace && c.port_name == port_name)
+            .cloned()
+    }
+
+    /// The key the consumer of a session is tracked under now. Sessions keep the key their port had
+    /// when they were opened, after a rotation the limits and connections are kept on the new one.
+    pub async fn current_key(&self, consumer: &Consumer) -> String {
+        let consumers = self.consumers.read().await;
+        if consumer.external || consumers.contains_key(&consumer.key) {
+            return consumer.key.clone();
+        }
+        if let Some(primary) = self.key_aliases.read().await.get(&consumer.key) {
+            return primary.clone();
+        }
+        // The previous key is no longer accepted, the session is being revoked.
+        consumers
+            .values()
+            .find(|current| consumer.is_same_port(current))
+            .map(|current| current.key.clone())
+            .unwrap_or_else(|| consumer.key.clone())
+    }
+
+    /// Looks up the consumer of a key hash, either of its current key or of one of its aliases.
+    /// The returned consumer is always keyed by the current key, so limits are shared across keys.
+    pub async fn get_consumer(&self, key_hash: &str) -> Option<Consumer> {
+        let consumers = self.consumers.read().await;
+        if let Some(consumer) = consumers.get(key_hash).filter(|c| !c.external) {
+            return Some(consumer.clone().with_key(key_hash));
+        }
+
+        let primary = self.key_aliases.read().await.get(key_hash).cloned()?;
+        consumers
+            .get(&primary)
+            .filter(|consumer| consumer.accepts_alias(key_hash))
+            .cloned()
+            .map(|consumer| consumer.with_key(key_hash))
     }
 }
 
@@ -83,24 +605,120 @@ pub struct Consumer {
     namespace: String,
     port_name: String,
     tier: String,
+    /// Hash of the api key, see [`hash_key`]. The other keys are hashed the same way.
     key: String,
     network: String,
     version: String,
+    allowed_cidrs: Vec<IpNet>,
+    /// Some of the allowed cidrs couldn't be parsed, every address is refused rather than all of
+    /// them allowed.
+    invalid_cidrs: bool,
+    /// Other keys accepted for the port, with the time they stop working for rotated ones.
+    alias_keys: HashMap<String, Option<DateTime<Utc>>>,
+    read_only_key: Option<String>,
+    /// What the key used by the request can call.
+    scope: KeyScope,
+    /// Hash of the key used by the request, none for JWTs and client certificates.
+    session_key: Option<String>,
+    /// Allowed by the auth webhook, it's only looked up through its cached decision.
+    external: bool,
+    /// When the keys of the port stop working.
+    expires_at: Option<DateTime<Utc>>,
+    /// Parameters of the tier changed for this port only.
+    tier_overrides: Option<TierOverrides>,
+    /// When the port was first created, trials of tiers run from then.
+    created_at: Option<DateTime<Utc>>,
     active_connections: usize,
 }
+/// Sessions to close because their credentials are no longer valid.
+#[derive(Debug, Clone)]
+pub enum Revocation {
+    /// Sessions opened with the key hash.
+    Key(String),
+    /// Every session of the port, by its `namespace.port_name`.
+    Port(String),
+}
+
+/// Calls allowed by a key, on top of the tier restrictions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyScope {
+    #[default]
+    Full,
+    /// Can't submit or evaluate transactions. Messages that aren't JSON-RPC calls are refused,
+    /// since their method can't be checked.
+    ReadOnly,
+}
+impl KeyScope {
+    pub fn is_method_allowed(&self, method: Option<&str>) -> bool {
+        match self {
+            Self::Full => true,
+            Self::ReadOnly => method.is_some_and(|method| !WRITE_METHODS.contains(&method)),
+        }
+    }
+}
+
 impl Display for Consumer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}.{}", self.namespace, self.port_name)
     }
 }
-impl From<&OgmiosPort> for Consumer {
-    fn from(value: &OgmiosPort) -> Self {
+impl Consumer {
+    /// Builds the consumer of a port with its keys, read from its status or its secret.
+    pub fn new(value: &OgmiosPort, tokens: &AuthTokens) -> Self {
         let network = handle_legacy_networks(&value.spec.network);
         let version = value.spec.version.to_string();
         let tier = value.spec.throughput_tier.to_string();
-        let key = value.status.as_ref().unwrap().auth_token.clone();
+        let key = hash_key(&tokens.auth_token);
         let namespace = value.metadata.namespace.as_ref().unwrap().clone();
         let port_name = value.name_any();
+        let mut invalid_cidrs = false;
+        let allowed_cidrs = value
+            .spec
+            .allowed_cidrs
+            .iter()
+            .flatten()
+            .filter_map(|cidr| match cidr.parse::<IpNet>() {
+                Ok(net) => Some(net),
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        cidr, "invalid allowed cidr, refusing every address"
+                    );
+                    invalid_cidrs = true;
+                    None
+                }
+            })
+            .collect();
+        let additional_keys = value
+            .spec
+            .additional_auth_tokens
+            .iter()
+            .flatten()
+            .map(|key| (hash_key(key), None));
+        let previous_keys = tokens.previous_auth_tokens.iter().map(|previous| {
+            // A rotated key whose expiry can't be read is taken as expired already.
+            let expires_at = previous.expires_at().unwrap_or(DateTime::<Utc>::MIN_UTC);
+            (hash_key(&previous.token), Some(expires_at))
+        });
+        let alias_keys = additional_keys
+            .chain(previous_keys)
+            .filter(|(alias, _)| *alias != key)
+            .collect();
+        let read_only_key = tokens.read_only_auth_token.as_deref().map(hash_key);
+        let expires_at = value.spec.expires_at().map(|expires_at| {
+            expires_at.unwrap_or_else(|err| {
+                warn!(
+                    error = err.to_string(),
+                    "refusing the keys of a port with an invalid expiry"
+                );
+                DateTime::<Utc>::MIN_UTC
+            })
+        });
+        let tier_overrides = value.spec.tier_overrides.as_ref().and_then(|overrides| {
+            TierOverrides::try_from(overrides)
+                .inspect_err(|err| warn!(error = err, "ignoring invalid tier overrides"))
+                .ok()
+        });
 
         Self {
             namespace,
@@ -109,33 +727,111 @@ impl From<&OgmiosPort> for Consumer {
             key,
             network,
             version,
+            allowed_cidrs,
+            invalid_cidrs,
+            alias_keys,
+            read_only_key,
+            scope: KeyScope::Full,
+            session_key: None,
+            external: false,
+            expires_at,
+            tier_overrides,
+            // The first time the operator saw a port of this name, the creation of this one
+            // until it's reconciled.
+            created_at: value
+                .status
+                .as_ref()
+                .and_then(|status| status.trial_started_at.as_deref())
+                .and_then(|started_at| DateTime::parse_from_rfc3339(started_at).ok())
+                .map(|started_at| started_at.with_timezone(&Utc))
+                .or_else(|| {
+                    value
+                        .metadata
+                        .creation_timestamp
+                        .as_ref()
+                        .map(|time| time.0)
+                }),
             active_connections: 0,
         }
     }
-}
-impl Consumer {
-    pub async fn inc_connections(&self, state: Arc<State>) {
-        state
-            .consumers
-            .write()
-            .await
-            .entry(self.key.clone())
-            .and_modify(|consumer| consumer.active_connections += 1);
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    /// Whether the alias is still accepted. Rotated keys without a valid expiry are refused.
+    pub fn accepts_alias(&self, alias: &str) -> bool {
+        self.read_only_key.as_deref() == Some(alias)
+            || self
+                .alias_keys
+                .get(alias)
+                .is_some_and(|expires_at| expires_at.is_none_or(|at| at > Utc::now()))
+    }
+
+    /// The consumer as seen through one of its keys, which narrows the scope for read-only keys.
+    fn with_key(mut self, key: &str) -> Self {
+        if self.read_only_key.as_deref() == Some(key) {
+            self.scope = KeyScope::ReadOnly;
+        }
+        self.session_key = Some(key.to_string());
+        self
+    }
+
+    /// Whether both are the consumer of the same port, webhook consumers belonging to none.
+    pub fn is_same_port(&self, other: &Consumer) -> bool {
+        !self.external
+            && !other.external
+            && self.namespace == other.namespace
+            && self.port_name == other.port_name
+    }
+
+    /// Hashes of the keys currently accepted for the port.
+    pub fn accepted_keys(&self) -> Vec<String> {
+        let aliases = self
+            .alias_keys
+            .keys()
+            .chain(&self.read_only_key)
+            .filter(|alias| self.accepts_alias(alias));
+        std::iter::once(&self.key).chain(aliases).cloned().collect()
+    }
+
+    pub fn is_ip_allowed(&self, ip: &IpAddr) -> bool {
+        !self.invalid_cidrs
+            && (self.allowed_cidrs.is_empty()
+                || self.allowed_cidrs.iter().any(|net| net.contains(ip)))
+    }
+
+    /// Reserves a connection slot for the consumer, failing when the tier allowance is already in
+    /// use. Check and increment happen under the same lock, so concurrent upgrades can't overshoot.
+    pub async fn try_inc_connections(&self, state: Arc<State>, max_connections: usize) -> bool {
+        let key = state.current_key(self).await;
+        let mut consumers = state.consumers.write().await;
+        match consumers.get_mut(&key) {
+            Some(consumer) if consumer.active_connections < max_connections => {
+                consumer.active_connections += 1;
+                true
+            }
+            _ => false,
+        }
     }
     pub async fn dec_connections(&self, state: Arc<State>) {
+        let key = state.current_key(self).await;
         state
             .consumers
             .write()
             .await
-            .entry(self.key.clone())
-            .and_modify(|consumer| consumer.active_connections -= 1);
+            .entry(key)
+            .and_modify(|consumer| {
+                consumer.active_connections = consumer.active_connections.saturating_sub(1)
+            });
     }
     pub async fn get_active_connections(&self, state: Arc<State>) -> usize {
+        let key = state.current_key(self).await;
         state
             .consumers
             .read()
             .await
-            .get(&self.key)
+            .get(&key)
             .map(|consumer| consumer.active_connections)
             .unwrap_or_default()
     }
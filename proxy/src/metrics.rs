@@ -1,34 +1,127 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::sync::Arc;
-use std::{net::SocketAddr, str::FromStr};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hyper::header::{AUTHORIZATION, WWW_AUTHENTICATE};
 use hyper::server::conn::http1 as http1_server;
 use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use prometheus::{opts, Encoder, IntCounterVec, IntGaugeVec, Registry, TextEncoder};
+use prometheus::core::Collector;
+use prometheus::{
+    histogram_opts, opts, CounterVec, Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Registry, TextEncoder,
+};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tracing::{error, info, instrument};
 
-use crate::proxy::ProxyRequest;
-use crate::utils::{full, ProxyResponse};
-use crate::State;
+use crate::config::Config;
+use crate::health::HealthStatus;
+use crate::limiter::{self, BucketBalance};
+use crate::lockout::AuthFailure;
+use crate::proxy::{DisconnectReason, ProxyRequest};
+use crate::telemetry::Exemplars;
+use crate::utils::{full, secret_eq, ProxyResponse};
+use crate::{Consumer, State};
+
+/// What the `consumer` label holds. Dropping the port keeps large installs from having a series
+/// per port on every metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsumerLevel {
+    /// The port, as `namespace.port`.
+    Consumer,
+    /// The namespace of the port only.
+    Namespace,
+    /// Nothing, series are only split by the `tier` label.
+    Tier,
+}
+impl FromStr for ConsumerLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "consumer" => Ok(Self::Consumer),
+            "namespace" => Ok(Self::Namespace),
+            "tier" => Ok(Self::Tier),
+            _ => Err(format!("invalid consumer level: {s}")),
+        }
+    }
+}
+
+const OVERFLOW_CONSUMER: &str = "overflow";
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
     registry: Registry,
+    methods: HashSet<String>,
+    consumer_level: ConsumerLevel,
+    max_consumers: Option<usize>,
+    consumers: Arc<Mutex<HashSet<String>>>,
+    /// Exposition rendered by the background task when `METRICS_SNAPSHOT_INTERVAL` is set.
+    snapshot: Arc<Mutex<Option<Bytes>>>,
+    pub exemplars: Arc<Exemplars>,
     pub ws_total_frame: IntCounterVec,
+    pub listener_total_connection: IntCounterVec,
+    pub listener_active_connection: IntGaugeVec,
     pub ws_total_connection: IntGaugeVec,
+    pub ws_buffered_bytes: IntGaugeVec,
+    pub buffered_frames: IntGaugeVec,
+    pub ws_total_idle_timeout: IntCounterVec,
+    pub slow_client_disconnects_total: IntCounterVec,
+    pub disconnects_total: IntCounterVec,
     pub http_total_request: IntCounterVec,
+    pub upstream_total_failover: IntCounterVec,
+    pub client_total_request: IntCounterVec,
+    pub bytes_sent_total: IntCounterVec,
+    pub bytes_received_total: IntCounterVec,
+    pub total_method_request: IntCounterVec,
+    pub auth_failures_total: IntCounterVec,
+    pub tier_fallback_consumers: IntGaugeVec,
+    pub scheduler_queue_wait: HistogramVec,
+    pub scheduler_shed_total: IntCounterVec,
+    pub trial_expirations_total: IntCounterVec,
+    pub upstream_health_transitions_total: IntCounterVec,
+    pub upstream_latency: HistogramVec,
+    pub active_connections: IntGaugeVec,
+    pub rate_limited_total: IntCounterVec,
+    pub session_duration: HistogramVec,
+    pub upstream_errors_total: IntCounterVec,
+    pub config_info: IntGaugeVec,
+    pub limiter_remaining: IntGaugeVec,
+    pub limiter_capacity: IntGaugeVec,
+    pub runtime: RuntimeMetrics,
 }
 
 impl Metrics {
-    pub fn try_new(registry: Registry) -> Result<Self, Box<dyn Error>> {
+    pub fn try_new(registry: Registry, config: &Config) -> Result<Self, Box<dyn Error>> {
         let ws_total_frame = IntCounterVec::new(
             opts!("ogmios_proxy_ws_total_frame", "total of websocket frame",),
             &["namespace", "instance", "route", "consumer", "tier"],
         )
         .unwrap();
 
+        let listener_total_connection = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_listener_total_connection",
+                "total of tcp connections accepted by each listener",
+            ),
+            &["listener"],
+        )
+        .unwrap();
+
+        let listener_active_connection = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_listener_active_connection",
+                "tcp connections currently open on each listener",
+            ),
+            &["listener"],
+        )
+        .unwrap();
+
         let ws_total_connection = IntGaugeVec::new(
             opts!(
                 "ogmios_proxy_total_connections",
@@ -38,6 +131,58 @@ impl Metrics {
         )
         .unwrap();
 
+        let ws_buffered_bytes = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_ws_buffered_bytes",
+                "bytes held by websocket sessions, handshake headers and frames queued for the client",
+            ),
+            &["namespace", "instance", "route", "consumer", "tier"],
+        )
+        .unwrap();
+
+        let buffered_frames = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_buffered_frames",
+                "frames from the instances queued for websocket clients, summed over the sessions of each tier",
+            ),
+            &["namespace", "network", "tier"],
+        )
+        .unwrap();
+
+        let ws_total_idle_timeout = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_ws_total_idle_timeout",
+                "total of websocket connections closed for being idle",
+            ),
+            &["namespace", "instance", "route", "consumer", "tier"],
+        )
+        .unwrap();
+
+        let slow_client_disconnects_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_slow_client_disconnects_total",
+                "total of websocket connections closed for not keeping up with the instance",
+            ),
+            &["namespace", "instance", "route", "consumer", "tier"],
+        )
+        .unwrap();
+
+        let disconnects_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_disconnects_total",
+                "total of websocket connections closed by reason",
+            ),
+            &[
+                "namespace",
+                "instance",
+                "route",
+                "consumer",
+                "tier",
+                "reason",
+            ],
+        )
+        .unwrap();
+
         let http_total_request = IntCounterVec::new(
             opts!("ogmios_proxy_http_total_request", "total of http request",),
             &[
@@ -52,18 +197,325 @@ impl Metrics {
         )
         .unwrap();
 
+        let upstream_total_failover = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_upstream_total_failover",
+                "total of connections failed over to a fallback instance",
+            ),
+            &["namespace", "network", "instance"],
+        )
+        .unwrap();
+
+        // Client addresses are aggregated by how they were resolved to keep cardinality bounded.
+        let client_total_request = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_client_total_request",
+                "total of requests by how the client address was resolved",
+            ),
+            &["namespace", "source"],
+        )
+        .unwrap();
+
+        let bytes_sent_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_bytes_sent_total",
+                "total of bytes sent to the clients",
+            ),
+            &["namespace", "consumer", "tier", "network"],
+        )
+        .unwrap();
+
+        let bytes_received_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_bytes_received_total",
+                "total of bytes received from the clients",
+            ),
+            &["namespace", "consumer", "tier", "network"],
+        )
+        .unwrap();
+
+        let total_method_request = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_total_method_request",
+                "total of json-rpc requests by method",
+            ),
+            &[
+                "namespace",
+                "instance",
+                "route",
+                "protocol",
+                "consumer",
+                "tier",
+                "method",
+            ],
+        )
+        .unwrap();
+
+        let auth_failures_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_auth_failures_total",
+                "total of requests refused by authentication, by reason",
+            ),
+            &["namespace", "reason"],
+        )
+        .unwrap();
+
+        let tier_fallback_consumers = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_tier_fallback_consumers",
+                "consumers whose tier doesn't exist, running on the default tier when one is set",
+            ),
+            &["namespace", "tier", "fallback"],
+        )
+        .unwrap();
+
+        let scheduler_queue_wait = HistogramVec::new(
+            histogram_opts!(
+                "ogmios_proxy_scheduler_queue_wait_seconds",
+                "time requests waited for a slot while the upstream was saturated",
+            ),
+            &["namespace", "tier"],
+        )
+        .unwrap();
+
+        let scheduler_shed_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_scheduler_shed_total",
+                "total of requests shed after waiting too long for a slot",
+            ),
+            &["namespace", "tier"],
+        )
+        .unwrap();
+
+        let upstream_health_transitions_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_upstream_health_transitions_total",
+                "total of upstreams turning healthy or unhealthy, after the rise and fall thresholds",
+            ),
+            &["namespace", "network", "version", "status"],
+        )
+        .unwrap();
+
+        let trial_expirations_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_trial_expirations_total",
+                "total of consumers moved to the post-trial tier when their trial ended",
+            ),
+            &["namespace", "consumer", "tier", "post_trial_tier"],
+        )
+        .unwrap();
+
+        let upstream_latency = HistogramVec::new(
+            histogram_opts!(
+                "ogmios_proxy_upstream_latency_seconds",
+                "time from forwarding a JSON-RPC request to the instance to its response",
+            ),
+            &["namespace", "network", "method"],
+        )
+        .unwrap();
+
+        let active_connections = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_active_connections",
+                "websocket sessions currently open, summed over the consumers of each route and tier",
+            ),
+            &["namespace", "network", "version", "tier"],
+        )
+        .unwrap();
+
+        let limiter_remaining = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_limiter_remaining",
+                "permits left in the buckets of the consumers, the emptiest one when the consumer label is shared",
+            ),
+            &["namespace", "consumer", "tier", "method", "rate"],
+        )
+        .unwrap();
+
+        let limiter_capacity = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_limiter_capacity",
+                "permits the buckets of the consumers hold when full",
+            ),
+            &["namespace", "consumer", "tier", "method", "rate"],
+        )
+        .unwrap();
+
+        let rate_limited_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_rate_limited_total",
+                "total of messages delayed past the threshold or rejected by the rate limiter",
+            ),
+            &["namespace", "consumer", "tier", "outcome"],
+        )
+        .unwrap();
+
+        let upstream_errors_total = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_upstream_errors_total",
+                "total of JSON-RPC error responses from the instances, by method and error code",
+            ),
+            &["namespace", "network", "method", "code"],
+        )
+        .unwrap();
+
+        let build_info = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_build_info",
+                "always 1, labelled with the version, commit and compiler of the running binary",
+            ),
+            &["version", "git_sha", "rustc"],
+        )
+        .unwrap();
+        build_info
+            .with_label_values(&[
+                env!("CARGO_PKG_VERSION"),
+                env!("PROXY_GIT_SHA"),
+                env!("PROXY_RUSTC_VERSION"),
+            ])
+            .set(1);
+
+        let config_info = IntGaugeVec::new(
+            opts!(
+                "ogmios_proxy_config_info",
+                "always 1, labelled with the fingerprint of the configuration in use",
+            ),
+            &["hash"],
+        )
+        .unwrap();
+        config_info
+            .with_label_values(&[&config.fingerprint()])
+            .set(1);
+
+        // Sessions last from a single query to days of chain-sync.
+        let session_duration = HistogramVec::new(
+            histogram_opts!(
+                "ogmios_proxy_session_duration_seconds",
+                "time websocket sessions were open, observed when they end",
+                vec![1.0, 10.0, 60.0, 300.0, 900.0, 3600.0, 14400.0, 43200.0, 86400.0, 259200.0]
+            ),
+            &["namespace", "network", "tier"],
+        )
+        .unwrap();
+
         registry.register(Box::new(ws_total_frame.clone()))?;
         registry.register(Box::new(ws_total_connection.clone()))?;
+        registry.register(Box::new(listener_total_connection.clone()))?;
+        registry.register(Box::new(listener_active_connection.clone()))?;
+        registry.register(Box::new(ws_buffered_bytes.clone()))?;
+        registry.register(Box::new(buffered_frames.clone()))?;
+        registry.register(Box::new(ws_total_idle_timeout.clone()))?;
+        registry.register(Box::new(slow_client_disconnects_total.clone()))?;
+        registry.register(Box::new(disconnects_total.clone()))?;
         registry.register(Box::new(http_total_request.clone()))?;
+        registry.register(Box::new(upstream_total_failover.clone()))?;
+        registry.register(Box::new(client_total_request.clone()))?;
+        registry.register(Box::new(bytes_sent_total.clone()))?;
+        registry.register(Box::new(bytes_received_total.clone()))?;
+        registry.register(Box::new(total_method_request.clone()))?;
+        registry.register(Box::new(auth_failures_total.clone()))?;
+        registry.register(Box::new(tier_fallback_consumers.clone()))?;
+        registry.register(Box::new(scheduler_queue_wait.clone()))?;
+        registry.register(Box::new(scheduler_shed_total.clone()))?;
+        registry.register(Box::new(trial_expirations_total.clone()))?;
+        registry.register(Box::new(upstream_health_transitions_total.clone()))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        registry.register(Box::new(active_connections.clone()))?;
+        registry.register(Box::new(limiter_remaining.clone()))?;
+        registry.register(Box::new(limiter_capacity.clone()))?;
+        registry.register(Box::new(rate_limited_total.clone()))?;
+        registry.register(Box::new(session_duration.clone()))?;
+        registry.register(Box::new(upstream_errors_total.clone()))?;
+        // Set once, only the registry holds it.
+        registry.register(Box::new(build_info))?;
+        registry.register(Box::new(config_info.clone()))?;
+        let runtime = RuntimeMetrics::try_new(&registry)?;
 
         Ok(Metrics {
             registry,
+            methods: config.metrics_methods.iter().cloned().collect(),
+            consumer_level: config.metrics_consumer_level,
+            max_consumers: config.metrics_max_consumers,
+            consumers: Default::default(),
+            snapshot: Default::default(),
+            exemplars: Default::default(),
             ws_total_frame,
             ws_total_connection,
+            listener_total_connection,
+            listener_active_connection,
+            ws_buffered_bytes,
+            buffered_frames,
+            ws_total_idle_timeout,
+            slow_client_disconnects_total,
+            disconnects_total,
             http_total_request,
+            upstream_total_failover,
+            client_total_request,
+            bytes_sent_total,
+            bytes_received_total,
+            total_method_request,
+            auth_failures_total,
+            tier_fallback_consumers,
+            scheduler_queue_wait,
+            scheduler_shed_total,
+            trial_expirations_total,
+            upstream_health_transitions_total,
+            upstream_latency,
+            active_connections,
+            rate_limited_total,
+            session_duration,
+            upstream_errors_total,
+            config_info,
+            limiter_remaining,
+            limiter_capacity,
+            runtime,
         })
     }
 
+    /// Label for a JSON-RPC method. Methods outside the allowlist are grouped to cap cardinality.
+    fn method_label<'a>(&self, method: Option<&'a str>) -> &'a str {
+        match method {
+            Some(method) if self.methods.contains(method) => method,
+            Some(_) => "other",
+            None => "none",
+        }
+    }
+
+    /// Label for a consumer at the configured level. Once `max_consumers` values are tracked, the
+    /// next ones share the `overflow` value; a value keeps its label for the life of the process,
+    /// so gauges are decremented on the series they were incremented on.
+    pub fn consumer_label(&self, consumer: &Consumer) -> String {
+        let label = match self.consumer_level {
+            ConsumerLevel::Consumer => consumer.to_string(),
+            ConsumerLevel::Namespace => consumer.namespace.clone(),
+            ConsumerLevel::Tier => return String::new(),
+        };
+        let Some(max_consumers) = self.max_consumers else {
+            return label;
+        };
+
+        let mut consumers = self.consumers.lock().unwrap();
+        if consumers.contains(&label) {
+            return label;
+        }
+        if consumers.len() < max_consumers {
+            consumers.insert(label.clone());
+            return label;
+        }
+        OVERFLOW_CONSUMER.to_string()
+    }
+
+    /// Records the current trace as the exemplar of the series of `collector`, `value` being the
+    /// observation or the increment.
+    fn record_exemplar(&self, collector: &impl Collector, values: &[&str], value: f64) {
+        if !self.exemplars.enabled() {
+            return;
+        }
+        let desc = collector.desc()[0];
+        self.exemplars
+            .record(&desc.fq_name, &desc.variable_labels, values, value);
+    }
+
     pub fn metrics_collected(&self) -> Vec<prometheus::proto::MetricFamily> {
         self.registry.gather()
     }
@@ -74,7 +526,7 @@ impl Metrics {
                 &proxy_req.namespace,
                 &proxy_req.instance,
                 &proxy_req.host,
-                &proxy_req.consumer.to_string(),
+                &proxy_req.consumer_label,
                 &proxy_req.consumer.tier,
             ])
             .inc()
@@ -86,7 +538,7 @@ impl Metrics {
                 &proxy_req.namespace,
                 &proxy_req.instance,
                 &proxy_req.host,
-                &proxy_req.consumer.to_string(),
+                &proxy_req.consumer_label,
                 &proxy_req.consumer.tier,
             ])
             .inc()
@@ -98,42 +550,588 @@ impl Metrics {
                 &proxy_req.namespace,
                 &proxy_req.instance,
                 &proxy_req.host,
-                &proxy_req.consumer.to_string(),
+                &proxy_req.consumer_label,
                 &proxy_req.consumer.tier,
             ])
             .dec()
     }
 
-    pub fn count_http_total_request(&self, proxy_req: &ProxyRequest, status_code: StatusCode) {
-        self.http_total_request
+    /// Replaces the fingerprint once the configuration is reloaded.
+    pub fn set_config_info(&self, config: &Config) {
+        self.config_info.reset();
+        self.config_info
+            .with_label_values(&[&config.fingerprint()])
+            .set(1);
+    }
+
+    pub fn inc_listener_connection(&self, listener: &str) {
+        self.listener_total_connection
+            .with_label_values(&[listener])
+            .inc();
+        self.listener_active_connection
+            .with_label_values(&[listener])
+            .inc()
+    }
+
+    pub fn dec_listener_connection(&self, listener: &str) {
+        self.listener_active_connection
+            .with_label_values(&[listener])
+            .dec()
+    }
+
+    pub fn add_ws_buffered_bytes(&self, proxy_req: &ProxyRequest, bytes: i64) {
+        self.ws_buffered_bytes
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.instance,
+                &proxy_req.host,
+                &proxy_req.consumer_label,
+                &proxy_req.consumer.tier,
+            ])
+            .add(bytes)
+    }
+
+    pub fn add_buffered_frames(&self, proxy_req: &ProxyRequest, frames: i64) {
+        self.buffered_frames
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.consumer.network,
+                &proxy_req.consumer.tier,
+            ])
+            .add(frames)
+    }
+
+    pub fn count_ws_total_idle_timeout(&self, proxy_req: &ProxyRequest) {
+        self.ws_total_idle_timeout
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.instance,
+                &proxy_req.host,
+                &proxy_req.consumer_label,
+                &proxy_req.consumer.tier,
+            ])
+            .inc()
+    }
+
+    pub fn count_slow_client_disconnect(&self, proxy_req: &ProxyRequest) {
+        self.slow_client_disconnects_total
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.instance,
+                &proxy_req.host,
+                &proxy_req.consumer_label,
+                &proxy_req.consumer.tier,
+            ])
+            .inc()
+    }
+
+    pub fn count_disconnect(&self, proxy_req: &ProxyRequest, reason: DisconnectReason) {
+        let consumer = &proxy_req.consumer_label;
+        let labels = [
+            proxy_req.namespace.as_str(),
+            &proxy_req.instance,
+            &proxy_req.host,
+            consumer,
+            &proxy_req.consumer.tier,
+            reason.as_str(),
+        ];
+        self.disconnects_total.with_label_values(&labels).inc();
+        if reason != DisconnectReason::ClientClosed {
+            self.record_exemplar(&self.disconnects_total, &labels, 1.0);
+        }
+    }
+
+    pub fn observe_session_duration(&self, proxy_req: &ProxyRequest, duration: Duration) {
+        self.session_duration
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.consumer.network,
+                &proxy_req.consumer.tier,
+            ])
+            .observe(duration.as_secs_f64())
+    }
+
+    pub fn count_auth_failure(&self, namespace: &str, failure: AuthFailure) {
+        let labels = [namespace, failure.as_str()];
+        self.auth_failures_total.with_label_values(&labels).inc();
+        self.record_exemplar(&self.auth_failures_total, &labels, 1.0);
+    }
+
+    /// Replaces the consumers counted on a fallback, tiers fixed since the last call are dropped.
+    pub fn set_tier_fallbacks(
+        &self,
+        namespace: &str,
+        fallback: Option<&str>,
+        missing: &HashMap<String, usize>,
+    ) {
+        self.tier_fallback_consumers.reset();
+        for (tier, consumers) in missing {
+            self.tier_fallback_consumers
+                .with_label_values(&[namespace, tier, fallback.unwrap_or("none")])
+                .set(*consumers as i64);
+        }
+    }
+
+    /// Replaces the open sessions by network, version and tier, routes without consumers left
+    /// are dropped.
+    pub fn set_active_connections(
+        &self,
+        namespace: &str,
+        connections: &HashMap<(String, String, String), usize>,
+    ) {
+        self.active_connections.reset();
+        for ((network, version, tier), active) in connections {
+            self.active_connections
+                .with_label_values(&[namespace, network, version, tier])
+                .set(*active as i64);
+        }
+    }
+
+    /// Replaces the bucket levels with a new sample. Consumers sharing a label, past
+    /// `METRICS_MAX_CONSUMERS` or above the consumer level, report the bucket closest to empty.
+    pub fn set_limiter_balances(&self, namespace: &str, balances: &[BucketBalance]) {
+        let mut tightest: HashMap<[String; 4], (usize, usize)> = HashMap::new();
+        for balance in balances {
+            let labels = [
+                self.consumer_label(&balance.consumer),
+                balance.consumer.tier.clone(),
+                balance.method.clone().unwrap_or_default(),
+                balance.rate.to_string(),
+            ];
+            let level = (balance.remaining, balance.capacity);
+            let ratio =
+                |(remaining, capacity): (usize, usize)| remaining as f64 / capacity.max(1) as f64;
+            tightest
+                .entry(labels)
+                .and_modify(|current| {
+                    if ratio(level) < ratio(*current) {
+                        *current = level
+                    }
+                })
+                .or_insert(level);
+        }
+
+        self.limiter_remaining.reset();
+        self.limiter_capacity.reset();
+        for ([consumer, tier, method, rate], (remaining, capacity)) in tightest {
+            let labels = [namespace, &consumer, &tier, &method, &rate];
+            self.limiter_remaining
+                .with_label_values(&labels)
+                .set(remaining as i64);
+            self.limiter_capacity
+                .with_label_values(&labels)
+                .set(capacity as i64);
+        }
+    }
+
+    pub fn observe_scheduler_wait(&self, namespace: &str, tier: &str, waited: Duration) {
+        let labels = [namespace, tier];
+        self.scheduler_queue_wait
+            .with_label_values(&labels)
+            .observe(waited.as_secs_f64());
+        self.record_exemplar(&self.scheduler_queue_wait, &labels, waited.as_secs_f64());
+    }
+
+    pub fn count_scheduler_shed(&self, namespace: &str, tier: &str) {
+        self.scheduler_shed_total
+            .with_label_values(&[namespace, tier])
+            .inc()
+    }
+
+    pub fn observe_upstream_latency(
+        &self,
+        proxy_req: &ProxyRequest,
+        method: Option<&str>,
+        latency: Duration,
+    ) {
+        let labels = [
+            proxy_req.namespace.as_str(),
+            &proxy_req.consumer.network,
+            self.method_label(method),
+        ];
+        self.upstream_latency
+            .with_label_values(&labels)
+            .observe(latency.as_secs_f64());
+        self.record_exemplar(&self.upstream_latency, &labels, latency.as_secs_f64());
+    }
+
+    pub fn count_upstream_error(&self, proxy_req: &ProxyRequest, method: Option<&str>, code: i64) {
+        let code = code.to_string();
+        let labels = [
+            proxy_req.namespace.as_str(),
+            &proxy_req.consumer.network,
+            self.method_label(method),
+            &code,
+        ];
+        self.upstream_errors_total.with_label_values(&labels).inc();
+        self.record_exemplar(&self.upstream_errors_total, &labels, 1.0);
+    }
+
+    /// `outcome` is `delayed` or `rejected`.
+    pub fn count_rate_limited(
+        &self,
+        namespace: &str,
+        consumer: &Consumer,
+        tier: &str,
+        outcome: &str,
+    ) {
+        let consumer = self.consumer_label(consumer);
+        let labels = [namespace, &consumer, tier, outcome];
+        self.rate_limited_total.with_label_values(&labels).inc();
+        self.record_exemplar(&self.rate_limited_total, &labels, 1.0);
+    }
+
+    pub fn count_trial_expiration(
+        &self,
+        namespace: &str,
+        consumer: &Consumer,
+        post_trial_tier: &str,
+    ) {
+        self.trial_expirations_total
+            .with_label_values(&[
+                namespace,
+                &self.consumer_label(consumer),
+                &consumer.tier,
+                post_trial_tier,
+            ])
+            .inc()
+    }
+
+    pub fn count_upstream_health_transition(
+        &self,
+        namespace: &str,
+        network: &str,
+        version: &str,
+        status: HealthStatus,
+    ) {
+        self.upstream_health_transitions_total
+            .with_label_values(&[namespace, network, version, status.as_str()])
+            .inc()
+    }
+
+    pub fn count_upstream_total_failover(&self, proxy_req: &ProxyRequest, instance: &str) {
+        self.upstream_total_failover
+            .with_label_values(&[&proxy_req.namespace, &proxy_req.consumer.network, instance])
+            .inc()
+    }
+
+    pub fn count_client_total_request(&self, proxy_req: &ProxyRequest) {
+        let source = if proxy_req.forwarded {
+            "forwarded"
+        } else {
+            "direct"
+        };
+        self.client_total_request
+            .with_label_values(&[&proxy_req.namespace, source])
+            .inc()
+    }
+
+    pub fn count_bytes_sent(&self, proxy_req: &ProxyRequest, bytes: usize) {
+        self.bytes_sent_total
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.consumer_label,
+                &proxy_req.consumer.tier,
+                &proxy_req.consumer.network,
+            ])
+            .inc_by(bytes as u64)
+    }
+
+    pub fn count_bytes_received(&self, proxy_req: &ProxyRequest, bytes: usize) {
+        self.bytes_received_total
+            .with_label_values(&[
+                &proxy_req.namespace,
+                &proxy_req.consumer_label,
+                &proxy_req.consumer.tier,
+                &proxy_req.consumer.network,
+            ])
+            .inc_by(bytes as u64)
+    }
+
+    pub fn count_total_method_request(&self, proxy_req: &ProxyRequest, method: Option<&str>) {
+        self.total_method_request
             .with_label_values(&[
                 &proxy_req.namespace,
                 &proxy_req.instance,
                 &proxy_req.host,
-                &status_code.as_u16().to_string(),
                 &proxy_req.protocol.to_string(),
-                &proxy_req.consumer.to_string(),
+                &proxy_req.consumer_label,
                 &proxy_req.consumer.tier,
+                self.method_label(method),
             ])
             .inc()
     }
+
+    pub fn count_http_total_request(&self, proxy_req: &ProxyRequest, status_code: StatusCode) {
+        let status = status_code.as_u16().to_string();
+        let protocol = proxy_req.protocol.to_string();
+        let consumer = &proxy_req.consumer_label;
+        let labels = [
+            proxy_req.namespace.as_str(),
+            &proxy_req.instance,
+            &proxy_req.host,
+            &status,
+            &protocol,
+            consumer,
+            &proxy_req.consumer.tier,
+        ];
+        self.http_total_request.with_label_values(&labels).inc();
+        if status_code.is_client_error() || status_code.is_server_error() {
+            self.record_exemplar(&self.http_total_request, &labels, 1.0);
+        }
+    }
 }
 
-async fn api_get_metrics(state: &State) -> Result<ProxyResponse, hyper::Error> {
+/// Instrumentation of the tokio runtime, to tell a saturated runtime from a slow upstream. The
+/// counters of tokio are cumulative already, they're advanced by the difference on each update.
+#[derive(Debug, Clone)]
+pub struct RuntimeMetrics {
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    worker_busy_seconds: CounterVec,
+    worker_parks: IntCounterVec,
+    #[cfg(tokio_unstable)]
+    blocking_threads: IntGauge,
+    #[cfg(tokio_unstable)]
+    idle_blocking_threads: IntGauge,
+    #[cfg(tokio_unstable)]
+    blocking_queue_depth: IntGauge,
+    #[cfg(tokio_unstable)]
+    spawned_tasks: prometheus::IntCounter,
+}
+impl RuntimeMetrics {
+    fn try_new(registry: &Registry) -> Result<Self, Box<dyn Error>> {
+        let workers = IntGauge::with_opts(opts!(
+            "ogmios_proxy_tokio_workers",
+            "worker threads of the tokio runtime",
+        ))?;
+        let alive_tasks = IntGauge::with_opts(opts!(
+            "ogmios_proxy_tokio_alive_tasks",
+            "tasks spawned on the tokio runtime and not finished yet",
+        ))?;
+        let global_queue_depth = IntGauge::with_opts(opts!(
+            "ogmios_proxy_tokio_global_queue_depth",
+            "tasks waiting in the global queue of the tokio runtime for a worker",
+        ))?;
+        let worker_busy_seconds = CounterVec::new(
+            opts!(
+                "ogmios_proxy_tokio_worker_busy_seconds_total",
+                "time each tokio worker spent running tasks",
+            ),
+            &["worker"],
+        )?;
+        let worker_parks = IntCounterVec::new(
+            opts!(
+                "ogmios_proxy_tokio_worker_parks_total",
+                "times each tokio worker parked for lack of tasks",
+            ),
+            &["worker"],
+        )?;
+
+        registry.register(Box::new(workers.clone()))?;
+        registry.register(Box::new(alive_tasks.clone()))?;
+        registry.register(Box::new(global_queue_depth.clone()))?;
+        registry.register(Box::new(worker_busy_seconds.clone()))?;
+        registry.register(Box::new(worker_parks.clone()))?;
+
+        #[cfg(tokio_unstable)]
+        let (blocking_threads, idle_blocking_threads, blocking_queue_depth, spawned_tasks) = {
+            let blocking_threads = IntGauge::with_opts(opts!(
+                "ogmios_proxy_tokio_blocking_threads",
+                "threads of the tokio blocking pool",
+            ))?;
+            let idle_blocking_threads = IntGauge::with_opts(opts!(
+                "ogmios_proxy_tokio_idle_blocking_threads",
+                "threads of the tokio blocking pool waiting for work",
+            ))?;
+            let blocking_queue_depth = IntGauge::with_opts(opts!(
+                "ogmios_proxy_tokio_blocking_queue_depth",
+                "tasks waiting for a thread of the tokio blocking pool",
+            ))?;
+            let spawned_tasks = prometheus::IntCounter::with_opts(opts!(
+                "ogmios_proxy_tokio_spawned_tasks_total",
+                "tasks spawned on the tokio runtime",
+            ))?;
+            registry.register(Box::new(blocking_threads.clone()))?;
+            registry.register(Box::new(idle_blocking_threads.clone()))?;
+            registry.register(Box::new(blocking_queue_depth.clone()))?;
+            registry.register(Box::new(spawned_tasks.clone()))?;
+            (
+                blocking_threads,
+                idle_blocking_threads,
+                blocking_queue_depth,
+                spawned_tasks,
+            )
+        };
+
+        Ok(Self {
+            workers,
+            alive_tasks,
+            global_queue_depth,
+            worker_busy_seconds,
+            worker_parks,
+            #[cfg(tokio_unstable)]
+            blocking_threads,
+            #[cfg(tokio_unstable)]
+            idle_blocking_threads,
+            #[cfg(tokio_unstable)]
+            blocking_queue_depth,
+            #[cfg(tokio_unstable)]
+            spawned_tasks,
+        })
+    }
+
+    pub fn update(&self, metrics: &tokio::runtime::RuntimeMetrics) {
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+        for worker in 0..metrics.num_workers() {
+            let label = worker.to_string();
+            let busy = self.worker_busy_seconds.with_label_values(&[&label]);
+            let total = metrics.worker_total_busy_duration(worker).as_secs_f64();
+            busy.inc_by((total - busy.get()).max(0.0));
+            let parks = self.worker_parks.with_label_values(&[&label]);
+            parks.inc_by(
+                metrics
+                    .worker_park_count(worker)
+                    .saturating_sub(parks.get()),
+            );
+        }
+
+        #[cfg(tokio_unstable)]
+        {
+            self.blocking_threads
+                .set(metrics.num_blocking_threads() as i64);
+            self.idle_blocking_threads
+                .set(metrics.num_idle_blocking_threads() as i64);
+            self.blocking_queue_depth
+                .set(metrics.blocking_queue_depth() as i64);
+            self.spawned_tasks.inc_by(
+                metrics
+                    .spawned_tasks_count()
+                    .saturating_sub(self.spawned_tasks.get()),
+            );
+        }
+    }
+}
+
+/// Sets the metrics computed from the state rather than counted as requests go, before they're
+/// scraped or pushed. Consumers and tiers are watched separately.
+pub async fn update_computed(state: &State) {
+    let config = state.config();
+    state
+        .metrics
+        .runtime
+        .update(&tokio::runtime::Handle::current().metrics());
+    state.metrics.set_tier_fallbacks(
+        &config.proxy_namespace,
+        config.proxy_default_tier.as_deref(),
+        &state.missing_tiers().await,
+    );
+    state
+        .metrics
+        .set_active_connections(&config.proxy_namespace, &state.active_connections().await);
+    if config.metrics_limiter_balances {
+        state
+            .metrics
+            .set_limiter_balances(&config.proxy_namespace, &limiter::sample(state).await);
+    }
+}
+
+async fn render(state: &State) -> Bytes {
+    update_computed(state).await;
     let metrics = state.metrics.metrics_collected();
 
     let encoder = TextEncoder::new();
     let mut buffer = vec![];
     encoder.encode(&metrics, &mut buffer).unwrap();
+    buffer.into()
+}
+
+/// Serves the last snapshot when they're rendered in the background, scrapes before the first
+/// one render the metrics themselves.
+async fn api_get_metrics(state: &State) -> Result<ProxyResponse, hyper::Error> {
+    let snapshot = state.metrics.snapshot.lock().unwrap().clone();
+    let buffer = match snapshot {
+        Some(snapshot) => snapshot,
+        None => render(state).await,
+    };
 
     let res = Response::builder().body(full(buffer)).unwrap();
     Ok(res)
 }
 
+/// Renders the metrics every `METRICS_SNAPSHOT_INTERVAL`, so the cost of a scrape doesn't grow
+/// with the consumers. The cached bytes are at most that old.
+fn start_snapshots(state: Arc<State>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let snapshot = render(&state).await;
+            *state.metrics.snapshot.lock().unwrap() = Some(snapshot);
+        }
+    });
+}
+
+/// The endpoint is open when neither `METRICS_TOKEN` nor `METRICS_BASIC_AUTH` is configured,
+/// otherwise either of them is accepted.
+fn is_authorized(req: &Request<Incoming>, token: Option<&str>, basic_auth: Option<&str>) -> bool {
+    if token.is_none() && basic_auth.is_none() {
+        return true;
+    }
+
+    let Some(authorization) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+    else {
+        return false;
+    };
+    if let Some(bearer) = authorization.strip_prefix("Bearer ") {
+        return token.is_some_and(|token| secret_eq(bearer, token));
+    }
+    if let Some(credentials) = authorization.strip_prefix("Basic ") {
+        return basic_auth.is_some_and(|basic_auth| {
+            STANDARD
+                .decode(credentials.trim())
+                .is_ok_and(|credentials| secret_eq(credentials, basic_auth))
+        });
+    }
+    false
+}
+
 async fn routes_match(
     req: Request<Incoming>,
     state: Arc<State>,
+    client_ip: IpAddr,
 ) -> Result<ProxyResponse, hyper::Error> {
+    let config = state.config();
+    if !config.metrics_allowed_cidrs.is_empty()
+        && !config
+            .metrics_allowed_cidrs
+            .iter()
+            .any(|cidr| cidr.contains(&client_ip))
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(full("Forbidden"))
+            .unwrap());
+    }
+    if !is_authorized(
+        &req,
+        config.metrics_token.as_deref(),
+        config.metrics_basic_auth.as_deref(),
+    ) {
+        let mut res = Response::builder().status(StatusCode::UNAUTHORIZED);
+        if config.metrics_basic_auth.is_some() {
+            res = res.header(WWW_AUTHENTICATE, r#"Basic realm="metrics""#);
+        }
+        return Ok(res.body(full("Unauthorized")).unwrap());
+    }
+
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/metrics") => api_get_metrics(&state).await,
         _ => Ok(Response::builder()
@@ -145,7 +1143,7 @@ async fn routes_match(
 
 #[instrument("metrics server", skip_all)]
 pub async fn start(state: Arc<State>) {
-    let addr_result = SocketAddr::from_str(&state.config.prometheus_addr);
+    let addr_result = SocketAddr::from_str(&state.config().prometheus_addr);
     if let Err(err) = addr_result {
         error!(error = err.to_string(), "invalid prometheus addr");
         std::process::exit(1);
@@ -162,7 +1160,11 @@ pub async fn start(state: Arc<State>) {
     }
     let listener = listener_result.unwrap();
 
-    info!(addr = state.config.prometheus_addr, "metrics listening");
+    if let Some(interval) = state.config().metrics_snapshot_interval {
+        start_snapshots(state.clone(), interval);
+    }
+
+    info!(addr = state.config().prometheus_addr, "metrics listening");
 
     loop {
         let state = state.clone();
@@ -172,12 +1174,12 @@ pub async fn start(state: Arc<State>) {
             error!(error = err.to_string(), "accept client prometheus server");
             continue;
         }
-        let (stream, _) = accept_result.unwrap();
+        let (stream, peer) = accept_result.unwrap();
 
         let io = TokioIo::new(stream);
 
         tokio::task::spawn(async move {
-            let service = service_fn(move |req| routes_match(req, state.clone()));
+            let service = service_fn(move |req| routes_match(req, state.clone(), peer.ip()));
 
             if let Err(err) = http1_server::Builder::new()
                 .serve_connection(io, service)
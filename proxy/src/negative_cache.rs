@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Entries kept at most, so a flood of random credentials can't grow the cache without bound.
+/// Once full, new rejections aren't cached until older ones expire.
+const MAX_ENTRIES: usize = 100_000;
+
+/// Hashes of credentials rejected recently. They are refused again without looking them up until
+/// the ttl expires, or until a port using them is applied.
+pub struct RejectedKeys {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Instant>>,
+}
+impl RejectedKeys {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    pub fn contains(&self, key_hash: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key_hash)
+            .is_some_and(|expires_at| *expires_at > Instant::now())
+    }
+
+    pub fn insert(&self, key_hash: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, expires_at| *expires_at > now);
+        }
+        if entries.len() < MAX_ENTRIES {
+            entries.insert(key_hash.to_string(), now + self.ttl);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn remove<'a>(&self, key_hashes: impl IntoIterator<Item = &'a String>) {
+        let mut entries = self.entries.lock().unwrap();
+        for key_hash in key_hashes {
+            entries.remove(key_hash);
+        }
+    }
+}
@@ -1,136 +1,492 @@
-use futures_util::future::select;
+use bytes::Bytes;
+use futures_util::future::join_all;
 use futures_util::SinkExt;
 use futures_util::StreamExt;
-use futures_util::TryStreamExt;
-use http_body_util::{combinators::BoxBody, BodyExt};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, Limited};
 use hyper::body::Incoming;
-use hyper::client::conn::http1 as http1_client;
 use hyper::header::{
-    HeaderValue, CONNECTION, HOST, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+    HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, RETRY_AFTER,
+    SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL,
+    UPGRADE,
 };
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response, StatusCode};
+use hyper::upgrade::Upgraded;
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri};
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto::Builder;
-use rustls::ServerConfig;
-use rustls_pki_types::{CertificateDer, PrivateKeyDer};
-use std::error::Error;
+use serde_json::Value;
+use socket2::{Domain, Socket, Type};
 use std::fmt::Display;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::Arc;
-use std::{fs, io};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::pin;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{timeout, Instant};
 use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
-use tokio_tungstenite::tungstenite::protocol::Role;
-use tokio_tungstenite::{connect_async, WebSocketStream};
-use tracing::{error, info};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{client_async_with_config, WebSocketStream};
+use tracing::{error, info, info_span, warn, Instrument};
 use url::Url;
+use uuid::Uuid;
 
-use crate::limiter::limiter;
-use crate::utils::{full, get_header, ProxyResponse, DMTR_API_KEY};
+use crate::access_log::{log_request, log_session};
+use crate::audit;
+use crate::auth::{self, JwtRejection};
+use crate::cors;
+use crate::deflate::{self, Deflate, Negotiated};
+use crate::health;
+use crate::inflight::{self, Pending};
+use crate::introspection::{self, Introspected};
+use crate::jsonrpc::{
+    error_http_response, error_message, JsonRpcRequest, JsonRpcResponse, FORBIDDEN, INTERNAL_ERROR,
+    INVALID_REQUEST, KEY_BINDING_MISMATCH, LIMIT_EXCEEDED, MAINTENANCE, METHOD_NOT_ALLOWED,
+    PAYLOAD_TOO_LARGE, QUOTA_EXCEEDED, UNAUTHORIZED, UPSTREAM_UNAVAILABLE,
+};
+use crate::limiter::{limiter, LimiterError};
+use crate::limits::{self, LIMITS_PATH};
+use crate::lockout::AuthFailure;
+use crate::proxy_protocol::read_header;
+use crate::quota::{consume_bandwidth, consume_request};
+use crate::resolver::UpstreamStream;
+use crate::scheduler::{Slot, UpstreamSaturated};
+use crate::telemetry;
+use crate::tls::{build_tls_acceptor, client_identity};
+use crate::utils::{
+    full, get_header, hash_key, parse_host_route, resolve_client_ip, secret_eq, split_path_key,
+    split_query_key, ProxyResponse, DMTR_API_KEY, DMTR_IMPERSONATE, DMTR_IMPERSONATION_TOKEN,
+    DMTR_OPERATOR, DMTR_RECONNECT_TOKEN, DMTR_REQUEST_ID,
+};
+use crate::webhook;
 use crate::{Consumer, State};
 
+const LISTEN_BACKLOG: i32 = 1024;
+const PROXY_PROTOCOL_TIMEOUT: Duration = Duration::from_secs(5);
+const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+
 pub async fn start(state: Arc<State>) {
-    let addr_result = SocketAddr::from_str(&state.config.proxy_addr);
-    if let Err(err) = addr_result {
-        error!(error = err.to_string(), "invalid proxy addr");
+    let tls_result = build_tls_acceptor(&state);
+    if let Err(err) = tls_result {
+        error!(error = err.to_string(), "fail to load tls");
         std::process::exit(1);
     }
-    let addr = addr_result.unwrap();
+    // The certificate watcher has to live as long as the listeners.
+    let (tls_acceptor, _tls_watcher) = tls_result.unwrap().unzip();
 
-    let listener_result = TcpListener::bind(addr).await;
-    if let Err(err) = listener_result {
-        error!(error = err.to_string(), "fail to bind tcp server listener");
-        std::process::exit(1);
+    // With several acceptors, each address gets that many sockets sharing the port through
+    // SO_REUSEPORT, and the kernel spreads the incoming connections between their accept tasks.
+    let acceptors = state.config().proxy_acceptors;
+    let mut listeners = Vec::new();
+    for proxy_addr in &state.config().proxy_addrs {
+        let addr_result = SocketAddr::from_str(proxy_addr);
+        if let Err(err) = addr_result {
+            error!(
+                error = err.to_string(),
+                addr = proxy_addr,
+                "invalid proxy addr"
+            );
+            std::process::exit(1);
+        }
+        let addr = addr_result.unwrap();
+
+        for _ in 0..acceptors {
+            let listener_result = bind(addr, acceptors > 1);
+            if let Err(err) = listener_result {
+                error!(
+                    error = err.to_string(),
+                    addr = proxy_addr,
+                    "fail to bind tcp server listener"
+                );
+                std::process::exit(1);
+            }
+            let listener = listener_result.unwrap();
+
+            listeners.push(tokio::spawn(accept_loop(
+                listener,
+                proxy_addr.clone(),
+                tls_acceptor.clone(),
+                state.clone(),
+            )));
+        }
+
+        info!(
+            addr = proxy_addr,
+            tls = tls_acceptor.is_some(),
+            acceptors,
+            "proxy listening"
+        );
     }
-    let listener = listener_result.unwrap();
 
-    let tls_acceptor_result = build_tls_acceptor(&state);
-    if let Err(err) = tls_acceptor_result {
-        error!(error = err.to_string(), "fail to load tls");
-        std::process::exit(1);
+    join_all(listeners).await;
+
+    drain(&state).await;
+}
+
+/// Binds a listener. IPv6 sockets only accept IPv6, so an IPv4 and an IPv6 address can share the
+/// same port when both are configured.
+fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
     }
-    let tls_acceptor = tls_acceptor_result.unwrap();
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
 
-    info!(addr = state.config.proxy_addr, "proxy listening");
+    TcpListener::from_std(socket.into())
+}
 
+async fn accept_loop(
+    listener: TcpListener,
+    listener_addr: String,
+    tls_acceptor: Option<TlsAcceptor>,
+    state: Arc<State>,
+) {
     loop {
         let state = state.clone();
-        let accept_result = listener.accept().await;
+        let accept_result = tokio::select! {
+            result = listener.accept() => result,
+            _ = state.wait_shutdown() => break,
+        };
         if let Err(err) = accept_result {
             error!(error = err.to_string(), "fail to accept client");
             continue;
         }
-        let (stream, _) = accept_result.unwrap();
+        let (mut stream, mut client_addr) = accept_result.unwrap();
+        state.metrics.inc_listener_connection(&listener_addr);
 
         let tls_acceptor = tls_acceptor.clone();
+        let listener_addr = listener_addr.clone();
 
         tokio::spawn(async move {
-            let tls_stream = match tls_acceptor.accept(stream).await {
-                Ok(tls_stream) => tls_stream,
-                Err(err) => {
-                    error!(error = err.to_string(), "failed to perform tls handshake");
-                    return;
-                }
-            };
-
-            let io = TokioIo::new(tls_stream);
+            let _session = SessionGuard::new(state.clone());
 
-            let service = service_fn(move |req| handle(req, state.clone()));
+            async {
+                if state.config().proxy_protocol {
+                    let header = timeout(PROXY_PROTOCOL_TIMEOUT, read_header(&mut stream)).await;
+                    match header {
+                        Ok(Ok(Some(addr))) => client_addr = addr,
+                        Ok(Ok(None)) => {}
+                        Ok(Err(err)) => {
+                            error!(
+                                error = err.to_string(),
+                                "failed to read proxy protocol header"
+                            );
+                            return;
+                        }
+                        Err(_) => {
+                            error!("timeout reading proxy protocol header");
+                            return;
+                        }
+                    }
+                }
 
-            if let Err(err) = Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(io, service)
-                .await
-            {
-                error!(error = err.to_string(), "failed proxy server connection");
+                match tls_acceptor {
+                    Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                        Ok(tls_stream) => {
+                            let identity =
+                                client_identity(tls_stream.get_ref().1.peer_certificates());
+                            serve(tls_stream, client_addr, identity, state.clone()).await
+                        }
+                        Err(err) => {
+                            error!(error = err.to_string(), "failed to perform tls handshake");
+                        }
+                    },
+                    None => serve(stream, client_addr, None, state.clone()).await,
+                }
             }
+            .await;
+
+            state.metrics.dec_listener_connection(&listener_addr);
         });
     }
 }
 
+/// `identity` names the verified client certificate of the connection, when it presented one.
+async fn serve<I>(stream: I, client_addr: SocketAddr, identity: Option<String>, state: Arc<State>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+
+    let service_state = state.clone();
+    let service =
+        service_fn(move |req| handle(req, client_addr, identity.clone(), service_state.clone()));
+
+    let builder = Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(io, service);
+    pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = state.wait_shutdown() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        error!(error = err.to_string(), "failed proxy server connection");
+    }
+}
+
+/// Waits for in-flight connections and websocket sessions to finish, up to the configured grace
+/// period.
+async fn drain(state: &State) {
+    let grace_period = state.config().proxy_shutdown_grace_period;
+    info!(
+        sessions = state.active_sessions(),
+        grace_period = grace_period.as_secs(),
+        "proxy stopped accepting connections, draining sessions"
+    );
+
+    let deadline = Instant::now() + grace_period;
+    while state.active_sessions() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    match state.active_sessions() {
+        0 => info!("all sessions drained"),
+        sessions => warn!(
+            sessions,
+            "grace period expired, dropping remaining sessions"
+        ),
+    }
+}
+
+/// Tracks a live connection or websocket session so shutdown can wait for it to finish.
+struct SessionGuard(Arc<State>);
+impl SessionGuard {
+    fn new(state: Arc<State>) -> Self {
+        state.sessions.fetch_add(1, Ordering::SeqCst);
+        Self(state)
+    }
+}
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 async fn handle(
     mut hyper_req: Request<Incoming>,
+    client_addr: SocketAddr,
+    identity: Option<String>,
+    state: Arc<State>,
+) -> Result<ProxyResponse, hyper::Error> {
+    // Any id sent by the client is replaced, so the ones in the proxy and Ogmios logs are always
+    // generated here.
+    let request_id = HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap();
+    hyper_req
+        .headers_mut()
+        .insert(DMTR_REQUEST_ID, request_id.clone());
+
+    let origin = cors::allowed_origin(&state.config(), &hyper_req);
+
+    // Preflights never carry the api key, they are answered before authenticating.
+    if let Some(origin) = &origin {
+        if cors::is_preflight(&hyper_req) {
+            return Ok(cors::preflight(origin.clone()));
+        }
+    }
+
+    let span = info_span!("request", request_id = request_id.to_str().unwrap());
+    let mut response = handle_request(hyper_req, client_addr, identity, state)
+        .instrument(span)
+        .await?;
+    response.headers_mut().insert(DMTR_REQUEST_ID, request_id);
+    if let Some(origin) = origin {
+        cors::apply(&mut response, origin);
+    }
+    Ok(response)
+}
+
+async fn handle_request(
+    mut hyper_req: Request<Incoming>,
+    client_addr: SocketAddr,
+    identity: Option<String>,
     state: Arc<State>,
 ) -> Result<ProxyResponse, hyper::Error> {
     match (hyper_req.method(), hyper_req.uri().path()) {
-        (&Method::GET, "/healthz") => handle_healthz(&state).await,
+        (&Method::GET, "/healthz") => health::handle_healthz(&state).await,
+        (&Method::GET, "/livez") => health::handle_livez().await,
+        (&Method::GET, "/readyz") => health::handle_readyz(&state).await,
         _ => {
-            let proxy_req_result = ProxyRequest::new(&mut hyper_req, &state).await;
-            if proxy_req_result.is_none() {
-                return Ok(Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .body(full("Unauthorized"))
-                    .unwrap());
+            // Sessions already open keep running, only new ones are refused.
+            if let Some(message) = state.maintenance() {
+                return Ok(error_http_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    MAINTENANCE,
+                    &message,
+                    None,
+                ));
             }
 
-            let proxy_req = proxy_req_result.unwrap();
+            let started_at = Instant::now();
+            let (client_ip, forwarded) = resolve_client_ip(
+                client_addr.ip(),
+                hyper_req.headers(),
+                &state.config().proxy_trusted_proxies,
+            );
+            let client_addr = SocketAddr::new(client_ip, client_addr.port());
+            let proxy_req_result =
+                ProxyRequest::new(&mut hyper_req, client_addr, forwarded, identity, &state)
+                    .instrument(info_span!("auth"))
+                    .await;
+            let proxy_req = match proxy_req_result {
+                Ok(proxy_req) => proxy_req,
+                Err(failure) => {
+                    state
+                        .metrics
+                        .count_auth_failure(&state.config().proxy_namespace, failure);
+                    if let AuthFailure::Banned(retry_after) = failure {
+                        let mut response = error_http_response(
+                            StatusCode::TOO_MANY_REQUESTS,
+                            LIMIT_EXCEEDED,
+                            "Too many failed authentication attempts",
+                            None,
+                        );
+                        response
+                            .headers_mut()
+                            .insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
+                        return Ok(response);
+                    }
+                    if let AuthFailure::Expired = failure {
+                        return Ok(error_http_response(
+                            StatusCode::UNAUTHORIZED,
+                            UNAUTHORIZED,
+                            "API key expired",
+                            None,
+                        ));
+                    }
+                    if let AuthFailure::NetworkNotAllowed = failure {
+                        return Ok(error_http_response(
+                            StatusCode::FORBIDDEN,
+                            FORBIDDEN,
+                            "Network not allowed for this tier",
+                            None,
+                        ));
+                    }
+                    if let AuthFailure::KeyBindingMismatch = failure {
+                        return Ok(error_http_response(
+                            StatusCode::FORBIDDEN,
+                            KEY_BINDING_MISMATCH,
+                            "API key not valid for this hostname",
+                            None,
+                        ));
+                    }
+                    return Ok(error_http_response(
+                        StatusCode::UNAUTHORIZED,
+                        UNAUTHORIZED,
+                        "Unauthorized",
+                        None,
+                    ));
+                }
+            };
+            state.metrics.count_client_total_request(&proxy_req);
+
+            if !proxy_req.consumer.is_ip_allowed(&proxy_req.client_ip) {
+                warn!(
+                    consumer = proxy_req.consumer.to_string(),
+                    client_ip = proxy_req.client_ip.to_string(),
+                    "client ip not allowed"
+                );
+                return Ok(error_http_response(
+                    StatusCode::FORBIDDEN,
+                    FORBIDDEN,
+                    "Client address not allowed",
+                    None,
+                ));
+            }
+
+            // Answered by the proxy itself, so it works while the upstream is down.
+            if hyper_req.method() == Method::GET && hyper_req.uri().path() == LIMITS_PATH {
+                return limits::handle_limits(&state, &proxy_req).await;
+            }
+
+            if let Err(retry_after) = state
+                .circuit
+                .allow(&proxy_req.consumer.network, &proxy_req.consumer.version)
+            {
+                let mut response = error_http_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    UPSTREAM_UNAVAILABLE,
+                    "Upstream unavailable",
+                    None,
+                );
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
+                state
+                    .metrics
+                    .count_http_total_request(&proxy_req, response.status());
+                return Ok(response);
+            }
+            if !health::is_upstream_healthy(
+                &state,
+                &proxy_req.consumer.network,
+                &proxy_req.consumer.version,
+            )
+            .await
+            {
+                let mut response = error_http_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    UPSTREAM_UNAVAILABLE,
+                    "Upstream unavailable",
+                    None,
+                );
+                response.headers_mut().insert(
+                    RETRY_AFTER,
+                    state.config().health_poll_interval.as_secs().max(1).into(),
+                );
+                state
+                    .metrics
+                    .count_http_total_request(&proxy_req, response.status());
+                return Ok(response);
+            }
             let response_result = match proxy_req.protocol {
-                Protocol::Http => handle_http(hyper_req, &proxy_req).await,
+                Protocol::Http => handle_http(hyper_req, &proxy_req, state.clone()).await,
                 Protocol::Websocket => {
                     // Before handling the websocket connection, check if consumer has available
                     // connections.
-                    let tiers = state.tiers.read().await.clone();
-                    match tiers.get(&proxy_req.consumer.tier) {
+                    match state.consumer_tier(&proxy_req.consumer).await {
                         Some(tier) => {
-                            if proxy_req.consumer.active_connections >= tier.max_connections {
-                                Ok(Response::builder()
-                                    .status(StatusCode::TOO_MANY_REQUESTS)
-                                    .body(full("Connection limit exceeded"))
-                                    .unwrap())
-                            } else {
+                            if proxy_req
+                                .consumer
+                                .try_inc_connections(state.clone(), tier.max_connections)
+                                .await
+                            {
                                 handle_websocket(hyper_req, &proxy_req, state.clone()).await
+                            } else {
+                                Ok(error_http_response(
+                                    StatusCode::TOO_MANY_REQUESTS,
+                                    LIMIT_EXCEEDED,
+                                    "Connection limit exceeded",
+                                    None,
+                                ))
                             }
                         }
-                        None => Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(full(
-                                "Invalid tier value. Contact support team for more information.",
-                            ))
-                            .unwrap()),
+                        None => Ok(error_http_response(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            INTERNAL_ERROR,
+                            "Invalid tier value. Contact support team for more information.",
+                            None,
+                        )),
                     }
                 }
             };
@@ -140,10 +496,30 @@ async fn handle(
                     state
                         .metrics
                         .count_http_total_request(&proxy_req, response.status());
+                    if let Protocol::Http = proxy_req.protocol {
+                        log_request(
+                            &proxy_req,
+                            state.config().access_log_requests,
+                            started_at.elapsed(),
+                            response.status(),
+                        );
+                        audit::log_impersonation_ended(
+                            &proxy_req,
+                            started_at.elapsed(),
+                            response.status().as_str(),
+                        );
+                    }
                 }
                 Err(err) => {
                     error!(error = err.to_string(), "Failed to handle request");
-                    todo!("send error to prometheus");
+                    state
+                        .metrics
+                        .count_http_total_request(&proxy_req, StatusCode::BAD_GATEWAY);
+                    audit::log_impersonation_ended(
+                        &proxy_req,
+                        started_at.elapsed(),
+                        StatusCode::BAD_GATEWAY.as_str(),
+                    );
                 }
             };
 
@@ -155,24 +531,347 @@ async fn handle(
 async fn handle_http(
     hyper_req: Request<Incoming>,
     proxy_req: &ProxyRequest,
+    state: Arc<State>,
 ) -> Result<ProxyResponse, hyper::Error> {
-    let stream = TcpStream::connect(&proxy_req.instance).await.unwrap();
-    let io: TokioIo<TcpStream> = TokioIo::new(stream);
+    let (parts, body) = hyper_req.into_parts();
+    let limit = state.config().proxy_ws_max_client_message_size;
+    let body = match Limited::new(body, limit).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            warn!(error = err.to_string(), "failed to read http request body");
+            return Ok(error_http_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                PAYLOAD_TOO_LARGE,
+                "Request body too large",
+                None,
+            ));
+        }
+    };
+    state.metrics.count_bytes_received(proxy_req, body.len());
+    state
+        .usage
+        .add_bytes_received(&proxy_req.consumer, body.len());
+    if let Err(err) = consume_bandwidth(&state, &proxy_req.consumer, body.len()).await {
+        return Ok(error_http_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            QUOTA_EXCEEDED,
+            &err.to_string(),
+            None,
+        ));
+    }
 
-    let (mut sender, conn) = http1_client::Builder::new()
-        .preserve_header_case(true)
-        .title_case_headers(true)
-        .handshake(io)
-        .await?;
+    let rpc_request = JsonRpcRequest::from_slice(&body);
+    let method = rpc_request.as_ref().map(|r| r.method.as_str());
+    let mut hyper_req = Request::from_parts(parts, Full::new(body));
+    telemetry::inject_context(hyper_req.headers_mut());
+
+    state.metrics.count_total_method_request(proxy_req, method);
 
-    tokio::task::spawn(async move {
-        if let Err(err) = conn.await {
-            println!("Connection failed: {:?}", err);
+    if !is_method_allowed(&state, &proxy_req.consumer, method).await {
+        return Ok(error_http_response(
+            StatusCode::FORBIDDEN,
+            METHOD_NOT_ALLOWED,
+            "Method not allowed for this tier",
+            rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+        ));
+    }
+
+    let rate_limit = match limiter(state.clone(), &proxy_req.consumer, method)
+        .instrument(info_span!("limiter"))
+        .await
+    {
+        Ok(rate_limit) => rate_limit,
+        Err(err @ LimiterError::RateLimited(retry_after)) => {
+            let mut response = error_http_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                err.json_rpc_code(),
+                &err.to_string(),
+                rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+            );
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
+            return Ok(response);
         }
-    });
+        Err(err) => {
+            error!(error = err.to_string(), "Failed to run limiter.");
+            return Ok(error_http_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.json_rpc_code(),
+                &err.to_string(),
+                rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+            ));
+        }
+    };
+
+    let _in_flight = match inflight::try_acquire(&state, &proxy_req.consumer).await {
+        Ok(permit) => permit,
+        Err(err) => {
+            return Ok(error_http_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                LIMIT_EXCEEDED,
+                &err.to_string(),
+                rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+            ));
+        }
+    };
+
+    let _slot = match schedule(&state, proxy_req).await {
+        Ok(slot) => slot,
+        Err(err) => {
+            let mut response = error_http_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                UPSTREAM_UNAVAILABLE,
+                &err.to_string(),
+                rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+            );
+            response.headers_mut().insert(RETRY_AFTER, 1.into());
+            return Ok(response);
+        }
+    };
+
+    // Only requests that passed the limits count against the quotas.
+    if let Err(err) = consume_request(&state, &proxy_req.consumer).await {
+        let mut response = error_http_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            QUOTA_EXCEEDED,
+            &err.to_string(),
+            rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+        );
+        if let Some(retry_after) = err.retry_after() {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
+        }
+        return Ok(response);
+    }
+    state.usage.add_request(&proxy_req.consumer);
 
-    let resp = sender.send_request(hyper_req).await?;
-    Ok(resp.map(|b| b.boxed()))
+    let mut response = forward_http(hyper_req, rpc_request, proxy_req, &state).await?;
+    if let Some(rate_limit) = rate_limit {
+        let headers = response.headers_mut();
+        headers.insert(X_RATELIMIT_LIMIT, rate_limit.limit.into());
+        headers.insert(X_RATELIMIT_REMAINING, rate_limit.remaining.into());
+        if let Some(retry_after) = rate_limit.retry_after {
+            headers.insert(RETRY_AFTER, retry_after.as_secs().max(1).into());
+        }
+    }
+    Ok(response)
+}
+
+/// Http responses up to this length are read to count the errors, Ogmios errors are a few hundred
+/// bytes.
+const MAX_ERROR_RESPONSE_LENGTH: usize = 16 * 1024;
+
+async fn forward_http(
+    hyper_req: Request<Full<Bytes>>,
+    rpc_request: Option<JsonRpcRequest>,
+    proxy_req: &ProxyRequest,
+    state: &State,
+) -> Result<ProxyResponse, hyper::Error> {
+    let cache_key = rpc_request
+        .as_ref()
+        .and_then(|r| state.cache.key(&proxy_req.consumer.network, r));
+    if let Some(key) = &cache_key {
+        let id = rpc_request.as_ref().and_then(|r| r.id.as_ref());
+        if let Some(response) = state.cache.get(key, id) {
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(full(response.to_string()))
+                .unwrap());
+        }
+    }
+
+    // Connections are reused from the pool, only a failure to open a new one triggers the
+    // failover. The request is rebuilt for each attempt with the address of the instance.
+    let (parts, body) = hyper_req.into_parts();
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let sent_at = Instant::now();
+    let result = connect_with_failover(proxy_req, state, |instance| {
+        let mut upstream_req = Request::builder()
+            .method(parts.method.clone())
+            .uri(format!("http://{instance}{path_and_query}"))
+            .body(body.clone())
+            .unwrap();
+        *upstream_req.headers_mut() = parts.headers.clone();
+
+        async move {
+            let _upstream = state.upstreams.connect(&instance);
+            match state
+                .http_client
+                .request(upstream_req)
+                .instrument(info_span!("upstream_request", instance))
+                .await
+            {
+                Err(err) if err.is_connect() => Err(err),
+                result => Ok(result),
+            }
+        }
+    })
+    .await;
+    let resp = match result {
+        Ok((_, Ok(resp))) => {
+            state
+                .circuit
+                .record_success(&proxy_req.consumer.network, &proxy_req.consumer.version);
+            let method = rpc_request.as_ref().map(|r| r.method.as_str());
+            state
+                .metrics
+                .observe_upstream_latency(proxy_req, method, sent_at.elapsed());
+            resp
+        }
+        Ok((_, Err(err))) => {
+            error!(error = err.to_string(), "upstream http request failed");
+            return Ok(error_http_response(
+                StatusCode::BAD_GATEWAY,
+                UPSTREAM_UNAVAILABLE,
+                "Upstream unavailable",
+                rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+            ));
+        }
+        Err(err) => {
+            error!(error = err.to_string(), "fail to connect to the instance");
+            state
+                .circuit
+                .record_failure(&proxy_req.consumer.network, &proxy_req.consumer.version);
+            return Ok(error_http_response(
+                StatusCode::BAD_GATEWAY,
+                UPSTREAM_UNAVAILABLE,
+                "Upstream unavailable",
+                rpc_request.as_ref().and_then(|r| r.id.as_ref()),
+            ));
+        }
+    };
+
+    // The response is streamed, so it's accounted by its declared length. An exhausted quota is
+    // enforced on the next request.
+    let response_length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or_default();
+    state.metrics.count_bytes_sent(proxy_req, response_length);
+    state
+        .usage
+        .add_bytes_sent(&proxy_req.consumer, response_length);
+    let _ = consume_bandwidth(state, &proxy_req.consumer, response_length).await;
+
+    // Bodies are only read for the cache, or when they're small enough to be an error worth
+    // counting, the others are streamed as they come.
+    let cache_key = cache_key.filter(|_| resp.status() == StatusCode::OK);
+    let small =
+        resp.headers().contains_key(CONTENT_LENGTH) && response_length <= MAX_ERROR_RESPONSE_LENGTH;
+    if cache_key.is_none() && !small {
+        return Ok(resp.map(|b| b.boxed()));
+    }
+
+    let (parts, body) = resp.into_parts();
+    let body = body.collect().await?.to_bytes();
+    if let Ok(response) = serde_json::from_slice::<Value>(&body) {
+        match response.get("error") {
+            Some(error) => {
+                let method = rpc_request.as_ref().map(|r| r.method.as_str());
+                if let Some(code) = error.get("code").and_then(Value::as_i64) {
+                    state.metrics.count_upstream_error(proxy_req, method, code);
+                }
+            }
+            // Errors are never cached, the next request has to reach the upstream again.
+            None => {
+                if let Some(key) = cache_key {
+                    state.cache.insert(key, response);
+                }
+            }
+        }
+    }
+
+    Ok(Response::from_parts(parts, full(body)))
+}
+
+/// Waits for a slot to forward the request to the instances, when `PROXY_UPSTREAM_CAPACITY` is
+/// set. Requests are delayed in favour of the tiers with a higher priority, and shed once they
+/// waited `PROXY_SCHEDULER_MAX_WAIT`.
+async fn schedule(
+    state: &State,
+    proxy_req: &ProxyRequest,
+) -> Result<Option<Slot>, UpstreamSaturated> {
+    let (tier, weight) = match state.consumer_tier(&proxy_req.consumer).await {
+        Some(tier) => (tier.name, tier.priority.unwrap_or(1)),
+        None => (proxy_req.consumer.tier.clone(), 1),
+    };
+
+    match state.scheduler.acquire(&tier, weight).await {
+        Ok((None, _)) => Ok(None),
+        Ok((Some(slot), waited)) => {
+            state
+                .metrics
+                .observe_scheduler_wait(&proxy_req.namespace, &tier, waited);
+            Ok(Some(slot))
+        }
+        Err(err) => {
+            warn!(
+                consumer = proxy_req.consumer.to_string(),
+                tier, "request shed, upstream saturated"
+            );
+            state
+                .metrics
+                .count_scheduler_shed(&proxy_req.namespace, &tier);
+            Err(err)
+        }
+    }
+}
+
+async fn is_method_allowed(state: &State, consumer: &Consumer, method: Option<&str>) -> bool {
+    if !consumer.scope.is_method_allowed(method) {
+        return false;
+    }
+
+    state
+        .consumer_tier(consumer)
+        .await
+        .map(|tier| tier.is_method_allowed(method))
+        .unwrap_or(true)
+}
+
+/// Connects to the selected instance, then to the fallbacks configured for the network and
+/// version in order, until one of them accepts the connection.
+async fn connect_with_failover<T, E, F, Fut>(
+    proxy_req: &ProxyRequest,
+    state: &State,
+    connect: F,
+) -> Result<(String, T), E>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let fallbacks = state
+        .config()
+        .fallbacks(&proxy_req.consumer.network, &proxy_req.consumer.version);
+
+    let mut instance = proxy_req.instance.clone();
+    let mut result = connect(instance.clone()).await;
+    for fallback in fallbacks {
+        let Err(err) = &result else {
+            break;
+        };
+        warn!(
+            error = err.to_string(),
+            instance, fallback, "fail to connect to the instance, failing over"
+        );
+        state
+            .metrics
+            .count_upstream_total_failover(proxy_req, &fallback);
+        instance = fallback;
+        result = connect(instance.clone()).await;
+    }
+
+    result.map(|connection| (instance, connection))
 }
 
 async fn handle_websocket(
@@ -186,112 +885,687 @@ async fn handle_websocket(
     let key = headers.get(SEC_WEBSOCKET_KEY);
     let derived = key.map(|k| derive_accept_key(k.as_bytes()));
     let version = hyper_req.version();
+    let requested_protocol = headers.get(SEC_WEBSOCKET_PROTOCOL).cloned();
+    // Both legs negotiate compression on their own, the proxy inflating and deflating in between.
+    let compression = state
+        .consumer_tier(&proxy_req.consumer)
+        .await
+        .is_some_and(|tier| tier.compression);
+    let client_deflate = compression
+        .then(|| deflate::accept(headers.get_all(SEC_WEBSOCKET_EXTENSIONS)))
+        .flatten();
+
+    // The instance is connected before answering the client, so the subprotocol it selected can
+    // be sent back in the handshake response.
+    let instance_config = WebSocketConfig {
+        max_message_size: Some(state.config().proxy_ws_max_instance_message_size),
+        max_frame_size: Some(state.config().proxy_ws_max_instance_message_size),
+        ..Default::default()
+    };
+    let uri = hyper_req.uri();
+    let requested_protocol = &requested_protocol;
+    let state_ref = state.as_ref();
+    let connection_result = connect_with_failover(proxy_req, state_ref, |instance| async move {
+        let url = Url::parse(&format!("ws://{}{}", instance, uri)).unwrap();
+        let mut instance_req = url.into_client_request().unwrap();
+        if let Ok(request_id) = HeaderValue::from_str(&proxy_req.request_id) {
+            instance_req
+                .headers_mut()
+                .insert(DMTR_REQUEST_ID, request_id);
+        }
+        if let Some(protocol) = requested_protocol {
+            instance_req
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+        }
+        if compression {
+            instance_req.headers_mut().insert(
+                SEC_WEBSOCKET_EXTENSIONS,
+                HeaderValue::from_static(deflate::EXTENSION),
+            );
+        }
+        telemetry::inject_context(instance_req.headers_mut());
+
+        async {
+            let stream = state_ref.resolver.connect(&instance).await?;
+            let limit = state_ref.config().proxy_ws_max_instance_message_size;
+            let stream = Deflate::client(stream, compression, limit);
+            client_async_with_config(instance_req, stream, Some(instance_config)).await
+        }
+        .instrument(info_span!("upstream_connect", instance))
+        .await
+    })
+    .await;
+    let (instance, (instance_stream, instance_res)) = match connection_result {
+        Ok(connection) => {
+            state
+                .circuit
+                .record_success(&proxy_req.consumer.network, &proxy_req.consumer.version);
+            connection
+        }
+        Err(err) => {
+            error!(error = err.to_string(), "fail to connect to the instance");
+            state
+                .circuit
+                .record_failure(&proxy_req.consumer.network, &proxy_req.consumer.version);
+            proxy_req.consumer.dec_connections(state.clone()).await;
+            return Ok(error_http_response(
+                StatusCode::BAD_GATEWAY,
+                UPSTREAM_UNAVAILABLE,
+                "Upstream unavailable",
+                None,
+            ));
+        }
+    };
+    let selected_protocol = instance_res.headers().get(SEC_WEBSOCKET_PROTOCOL).cloned();
+    // The headers of both handshakes stay allocated for as long as the session runs.
+    let handshake_size = headers_size(hyper_req.headers()) + headers_size(instance_res.headers());
+
+    let reconnect_ttl = state.config().proxy_reconnect_token_ttl;
+    let reconnect_token = reconnect_ttl.map(|_| {
+        state.reconnect.issue(
+            proxy_req
+                .consumer
+                .session_key
+                .as_ref()
+                .unwrap_or(&proxy_req.consumer.key),
+            proxy_req.consumer.scope,
+            &instance,
+        )
+    });
+
+    let released_token = reconnect_token.clone();
 
     let proxy_req = proxy_req.clone();
     let state = state.clone();
+    let negotiated = client_deflate.as_ref().map(|(negotiated, _)| *negotiated);
+    let subprotocol = selected_protocol
+        .as_ref()
+        .and_then(|p| p.to_str().ok())
+        .map(String::from);
+
+    tokio::task::spawn(
+        async move {
+            let _session = SessionGuard::new(state.clone());
+            let _upstream = state.upstreams.connect(&instance);
 
-    tokio::task::spawn(async move {
-        match hyper::upgrade::on(&mut hyper_req).await {
-            Ok(upgraded) => {
-                let upgraded = TokioIo::new(upgraded);
-                let client_stream =
-                    WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
-                let (client_outgoing, mut client_incoming) = client_stream.split();
-
-                let url =
-                    Url::parse(&format!("ws://{}{}", proxy_req.instance, hyper_req.uri())).unwrap();
-                let connection_result = connect_async(url).await;
-                if let Err(err) = connection_result {
-                    error!(error = err.to_string(), "fail to connect to the instance");
-                    return;
+            let upgrade = hyper::upgrade::on(&mut hyper_req)
+                .instrument(info_span!("handshake"))
+                .await;
+            match upgrade {
+                Ok(upgraded) => {
+                    websocket_session(
+                        upgraded,
+                        instance_stream,
+                        subprotocol.as_deref(),
+                        negotiated,
+                        handshake_size,
+                        &proxy_req,
+                        &state,
+                    )
+                    .await
                 }
-                let (instance_stream, _) = connection_result.unwrap();
-                let (mut instance_outgoing, instance_incoming) = instance_stream.split();
+                Err(err) => error!(error = err.to_string(), "upgrade error"),
+            }
 
-                state.metrics.inc_ws_total_connection(&proxy_req);
-                proxy_req.consumer.inc_connections(state.clone()).await;
+            if let (Some(token), Some(ttl)) = (released_token, reconnect_ttl) {
+                state.reconnect.release(&token, ttl);
+            }
+            proxy_req.consumer.dec_connections(state.clone()).await;
+        }
+        .in_current_span(),
+    );
 
-                let active_connections = proxy_req
-                    .consumer
-                    .get_active_connections(state.clone())
-                    .await;
-                info!(
-                    consumer = proxy_req.consumer.to_string(),
-                    active_connections, "client connected"
-                );
+    let mut res = Response::new(BoxBody::default());
+    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    *res.version_mut() = version;
+    res.headers_mut().append(CONNECTION, upgrade);
+    res.headers_mut().append(UPGRADE, websocket);
+    res.headers_mut()
+        .append(SEC_WEBSOCKET_ACCEPT, derived.unwrap().parse().unwrap());
+    if let Some(protocol) = selected_protocol {
+        res.headers_mut().append(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    if let Some((_, extensions)) = client_deflate {
+        res.headers_mut()
+            .append(SEC_WEBSOCKET_EXTENSIONS, extensions);
+    }
+    if let Some(token) = reconnect_token.as_deref() {
+        res.headers_mut()
+            .insert(DMTR_RECONNECT_TOKEN, HeaderValue::from_str(token).unwrap());
+    }
 
-                let client_in = async {
-                    while let Some(result) = client_incoming.next().await {
-                        match result {
-                            Ok(data) => {
-                                if let Err(err) = limiter(state.clone(), &proxy_req.consumer).await
-                                {
-                                    error!(error = err.to_string(), "Failed to run limiter.");
-                                    break;
-                                };
-                                if let Err(err) = instance_outgoing.send(data).await {
-                                    error!(
-                                        error = err.to_string(),
-                                        "fail to send data to instance"
-                                    );
+    Ok(res)
+}
+
+async fn websocket_session(
+    upgraded: Upgraded,
+    instance_stream: WebSocketStream<Deflate<UpstreamStream>>,
+    subprotocol: Option<&str>,
+    negotiated: Option<Negotiated>,
+    handshake_size: usize,
+    proxy_req: &ProxyRequest,
+    state: &Arc<State>,
+) {
+    let upgraded = Deflate::new(
+        TokioIo::new(upgraded),
+        negotiated,
+        state.config().proxy_ws_max_client_message_size,
+    );
+    let client_config = WebSocketConfig {
+        max_message_size: Some(state.config().proxy_ws_max_client_message_size),
+        max_frame_size: Some(state.config().proxy_ws_max_client_message_size),
+        ..Default::default()
+    };
+    let client_stream =
+        WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(client_config)).await;
+    let (mut client_outgoing, mut client_incoming) = client_stream.split();
+    let (mut instance_outgoing, mut instance_incoming) = instance_stream.split();
+
+    state.metrics.inc_ws_total_connection(proxy_req);
+    state
+        .usage
+        .session_opened(&proxy_req.request_id, &proxy_req.consumer);
+
+    let active_connections = proxy_req
+        .consumer
+        .get_active_connections(state.clone())
+        .await;
+    info!(
+        consumer = proxy_req.consumer.to_string(),
+        active_connections, "client connected"
+    );
+
+    // Everything sent to the client goes through this channel, so frames from the instance and
+    // the keepalive pings share a single writer. Its bound is what holds a slow client back.
+    let slow_client_policy = state.config().proxy_slow_client_policy;
+    let (client_tx, mut client_rx) =
+        mpsc::channel::<Message>(state.config().proxy_ws_client_buffer_size);
+    let last_seen = Mutex::new(Instant::now());
+    // Unlike last_seen, only data frames count as activity, the keepalive doesn't keep an
+    // abandoned session open.
+    let last_activity = Mutex::new(Instant::now());
+    let started_at = Instant::now();
+    let bytes_received = AtomicU64::new(0);
+    let bytes_sent = AtomicU64::new(0);
+    let pending = Pending::default();
+
+    // Frames count against the memory budget of the session until they're written to the client.
+    let memory_limit = state.config().proxy_ws_session_memory_limit;
+    let buffered = AtomicUsize::new(handshake_size);
+    state
+        .metrics
+        .add_ws_buffered_bytes(proxy_req, handshake_size as i64);
+    let buffered_frames = AtomicUsize::new(0);
+    let reserve = |message: &Message| {
+        buffered.fetch_add(message.len(), Ordering::Relaxed);
+        buffered_frames.fetch_add(1, Ordering::Relaxed);
+        state
+            .metrics
+            .add_ws_buffered_bytes(proxy_req, message.len() as i64);
+        state.metrics.add_buffered_frames(proxy_req, 1);
+    };
+    let queue = |message: Message| {
+        reserve(&message);
+        client_tx.send(message)
+    };
+    // Error answering the frame that closes the session. It's written right before the close
+    // frame, the writer of the queue is dropped along with what it didn't flush yet.
+    let closing_error = Mutex::new(None::<Message>);
+
+    let client_in = async {
+        while let Some(result) = client_incoming.next().await {
+            match result {
+                Ok(data) => {
+                    *last_seen.lock().unwrap() = Instant::now();
+
+                    // Pongs answer the proxy keepalive, the instance doesn't expect them.
+                    if data.is_pong() {
+                        continue;
+                    }
+                    if !data.is_ping() {
+                        *last_activity.lock().unwrap() = Instant::now();
+                    }
+
+                    bytes_received.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    state.metrics.count_bytes_received(proxy_req, data.len());
+                    state
+                        .usage
+                        .add_bytes_received(&proxy_req.consumer, data.len());
+                    if let Err(err) =
+                        consume_bandwidth(state, &proxy_req.consumer, data.len()).await
+                    {
+                        *closing_error.lock().unwrap() =
+                            Some(error_message(QUOTA_EXCEEDED, &err.to_string(), None));
+                        return Some((DisconnectReason::QuotaExceeded, err.to_string()));
+                    }
+
+                    let rpc_request = JsonRpcRequest::from_message(&data);
+                    let method = rpc_request.as_ref().map(|r| r.method.as_str());
+                    state.metrics.count_total_method_request(proxy_req, method);
+                    if !is_method_allowed(state, &proxy_req.consumer, method).await {
+                        let id = rpc_request.as_ref().and_then(|r| r.id.as_ref());
+                        let error = error_message(
+                            METHOD_NOT_ALLOWED,
+                            "Method not allowed for this tier",
+                            id,
+                        );
+                        if queue(error).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    if let Some(id) = rpc_request.as_ref().and_then(|r| r.id.as_ref()) {
+                        if pending.contains(&id.to_string()) {
+                            let error = error_message(
+                                INVALID_REQUEST,
+                                "Request id already in flight",
+                                Some(id),
+                            );
+                            if queue(error).await.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+                    }
+                    if let Err(err) = limiter(state.clone(), &proxy_req.consumer, method)
+                        .instrument(info_span!("limiter"))
+                        .await
+                    {
+                        error!(error = err.to_string(), "Failed to run limiter.");
+                        let id = rpc_request.as_ref().and_then(|r| r.id.as_ref());
+                        *closing_error.lock().unwrap() =
+                            Some(error_message(err.json_rpc_code(), &err.to_string(), id));
+                        return Some((DisconnectReason::from(&err), err.to_string()));
+                    };
+
+                    // Calls without an id can't be matched with their response, they aren't
+                    // counted as in flight. The scheduler slot is freed once the frame is written,
+                    // a session waiting on a long call doesn't hold the capacity of the others.
+                    let id = rpc_request.as_ref().and_then(|r| r.id.as_ref());
+                    let permit = match id {
+                        Some(id) => match inflight::try_acquire(state, &proxy_req.consumer).await {
+                            Ok(permit) => permit,
+                            Err(err) => {
+                                let error =
+                                    error_message(LIMIT_EXCEEDED, &err.to_string(), Some(id));
+                                if queue(error).await.is_err() {
                                     break;
                                 }
+                                continue;
                             }
-                            Err(err) => {
-                                error!(error = err.to_string(), "stream client incoming");
+                        },
+                        None => None,
+                    };
+                    let slot = match schedule(state, proxy_req).await {
+                        Ok(slot) => slot,
+                        Err(err) => {
+                            let error = error_message(UPSTREAM_UNAVAILABLE, &err.to_string(), id);
+                            if queue(error).await.is_err() {
                                 break;
                             }
+                            continue;
                         }
+                    };
+                    // Only calls that passed the limits count against the quotas.
+                    if let Err(err) = consume_request(state, &proxy_req.consumer).await {
+                        *closing_error.lock().unwrap() =
+                            Some(error_message(QUOTA_EXCEEDED, &err.to_string(), id));
+                        return Some((DisconnectReason::QuotaExceeded, err.to_string()));
+                    }
+                    state.usage.add_request(&proxy_req.consumer);
+                    if let Some(id) = id {
+                        pending.insert(id.to_string(), method, permit);
                     }
-                };
-                pin!(client_in);
 
-                let instance_in = instance_incoming
-                    .inspect_ok(|_| state.metrics.count_ws_total_frame(&proxy_req))
-                    .forward(client_outgoing);
+                    if let Err(err) = instance_outgoing.send(data).await {
+                        error!(error = err.to_string(), "fail to send data to instance");
+                        break;
+                    }
+                    drop(slot);
+                }
+                Err(WsError::Capacity(err)) => {
+                    warn!(error = err.to_string(), "client message too big");
+                    return Some((DisconnectReason::MessageTooBig, "message too big".into()));
+                }
+                Err(err) => {
+                    error!(error = err.to_string(), "stream client incoming");
+                    break;
+                }
+            }
+        }
+        None
+    };
+
+    let instance_in = async {
+        while let Some(result) = instance_incoming.next().await {
+            match result {
+                Ok(data) => {
+                    state.metrics.count_ws_total_frame(proxy_req);
+                    if !data.is_ping() && !data.is_pong() {
+                        *last_activity.lock().unwrap() = Instant::now();
+                    }
+                    let response = (!pending.is_empty() || JsonRpcResponse::may_be_error(&data))
+                        .then(|| JsonRpcResponse::from_message(&data))
+                        .flatten();
+                    if let Some(response) = response {
+                        let sent = response
+                            .id
+                            .as_ref()
+                            .and_then(|id| pending.remove(&id.to_string()));
+                        if let Some((method, latency)) = &sent {
+                            state.metrics.observe_upstream_latency(
+                                proxy_req,
+                                method.as_deref(),
+                                *latency,
+                            );
+                        }
+                        if let Some(error) = &response.error {
+                            let method = sent.and_then(|(method, _)| method).or(response.method);
+                            state.metrics.count_upstream_error(
+                                proxy_req,
+                                method.as_deref(),
+                                error.code,
+                            );
+                        }
+                    }
+                    bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    state.metrics.count_bytes_sent(proxy_req, data.len());
+                    state.usage.add_bytes_sent(&proxy_req.consumer, data.len());
+                    if let Err(err) =
+                        consume_bandwidth(state, &proxy_req.consumer, data.len()).await
+                    {
+                        return Some((DisconnectReason::QuotaExceeded, err.to_string()));
+                    }
+                    if let Some(limit) = memory_limit {
+                        let total = buffered.load(Ordering::Relaxed) + data.len();
+                        if total > limit {
+                            warn!(
+                                consumer = proxy_req.consumer.to_string(),
+                                buffered = total,
+                                "session memory limit exceeded"
+                            );
+                            return Some((
+                                DisconnectReason::MemoryLimit,
+                                "session memory limit exceeded".into(),
+                            ));
+                        }
+                    }
+                    reserve(&data);
+                    match slow_client_policy {
+                        SlowClientPolicy::Pause => {
+                            if client_tx.send(data).await.is_err() {
+                                break;
+                            }
+                        }
+                        SlowClientPolicy::Disconnect => match client_tx.try_send(data) {
+                            Ok(()) => {}
+                            Err(TrySendError::Full(_)) => {
+                                warn!(
+                                    consumer = proxy_req.consumer.to_string(),
+                                    "client can't keep up with the instance"
+                                );
+                                state.metrics.count_slow_client_disconnect(proxy_req);
+                                return Some((
+                                    DisconnectReason::SlowClient,
+                                    "client too slow".into(),
+                                ));
+                            }
+                            Err(TrySendError::Closed(_)) => break,
+                        },
+                    }
+                }
+                Err(WsError::Capacity(err)) => {
+                    warn!(error = err.to_string(), "instance message too big");
+                    return Some((DisconnectReason::MessageTooBig, "response too big".into()));
+                }
+                Err(err) => {
+                    error!(error = err.to_string(), "stream instance incoming");
+                    break;
+                }
+            }
+        }
+        None
+    };
 
-                select(client_in, instance_in).await;
+    let client_out = async {
+        while let Some(data) = client_rx.recv().await {
+            let size = data.len();
+            if let Err(err) = client_outgoing.send(data).await {
+                error!(error = err.to_string(), "fail to send data to client");
+                break;
+            }
+            buffered.fetch_sub(size, Ordering::Relaxed);
+            buffered_frames.fetch_sub(1, Ordering::Relaxed);
+            state
+                .metrics
+                .add_ws_buffered_bytes(proxy_req, -(size as i64));
+            state.metrics.add_buffered_frames(proxy_req, -1);
+        }
+    };
 
-                state.metrics.dec_ws_total_connection(&proxy_req);
-                proxy_req.consumer.dec_connections(state.clone()).await;
+    let keepalive = async {
+        let mut interval = tokio::time::interval(state.config().proxy_ws_ping_interval);
+        interval.tick().await;
 
-                let active_connections = proxy_req
-                    .consumer
-                    .get_active_connections(state.clone())
-                    .await;
-                info!(
+        loop {
+            interval.tick().await;
+
+            if last_seen.lock().unwrap().elapsed() > state.config().proxy_ws_keepalive_timeout {
+                warn!(
                     consumer = proxy_req.consumer.to_string(),
-                    active_connections, "client disconnected"
+                    "client keepalive timeout"
                 );
+                break;
             }
-            Err(err) => {
-                error!(error = err.to_string(), "upgrade error");
+            if client_tx.send(Message::Ping(Vec::new())).await.is_err() {
+                break;
             }
         }
-    });
+    };
 
-    let mut res = Response::new(BoxBody::default());
-    *res.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
-    *res.version_mut() = version;
-    res.headers_mut().append(CONNECTION, upgrade);
-    res.headers_mut().append(UPGRADE, websocket);
-    res.headers_mut()
-        .append(SEC_WEBSOCKET_ACCEPT, derived.unwrap().parse().unwrap());
+    let idle = async {
+        let Some(idle_timeout) = state.config().proxy_idle_timeout else {
+            return std::future::pending().await;
+        };
 
-    Ok(res)
+        loop {
+            let deadline = *last_activity.lock().unwrap() + idle_timeout;
+            if Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+
+        warn!(
+            consumer = proxy_req.consumer.to_string(),
+            "client idle timeout"
+        );
+        state.metrics.count_ws_total_idle_timeout(proxy_req);
+    };
+
+    // Read when the session opens, tier changes apply to the next sessions.
+    let max_session = async {
+        let max_session_duration = state
+            .consumer_tier(&proxy_req.consumer)
+            .await
+            .and_then(|tier| tier.max_session_duration);
+        match max_session_duration {
+            Some(max) => tokio::time::sleep_until(started_at + max).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let (reason, close_reason) = tokio::select! {
+        close = client_in => close.unwrap_or((DisconnectReason::ClientClosed, "client closed".into())),
+        close = instance_in => {
+            close.unwrap_or((DisconnectReason::InstanceClosed, "instance closed".into()))
+        }
+        _ = client_out => (DisconnectReason::ClientClosed, "client closed".into()),
+        _ = keepalive => (DisconnectReason::KeepaliveTimeout, "keepalive timeout".into()),
+        _ = idle => (DisconnectReason::IdleTimeout, "idle timeout".into()),
+        _ = state.wait_shutdown() => (DisconnectReason::Shutdown, "proxy is shutting down".into()),
+        _ = state.wait_disconnect(&proxy_req.consumer) => {
+            (DisconnectReason::Administrator, "disconnected by an administrator".into())
+        }
+        _ = state.wait_revocation(&proxy_req.consumer) => {
+            (DisconnectReason::AuthRevoked, "api key revoked".into())
+        }
+        _ = state.wait_expiry(&proxy_req.consumer) => {
+            (DisconnectReason::CredentialExpired, "api key expired".into())
+        }
+        _ = state.wait_migration(&proxy_req.consumer.network) => {
+            (DisconnectReason::Migrated, "upstream switched, please reconnect".into())
+        }
+        _ = max_session => {
+            (DisconnectReason::SessionDuration, "session duration limit reached, please reconnect".into())
+        }
+    };
+
+    if let Some(error) = closing_error.into_inner().unwrap() {
+        let _ = client_outgoing.send(error).await;
+    }
+    if let Some(code) = reason.close_code() {
+        let close = Message::Close(Some(CloseFrame {
+            code,
+            reason: close_reason.clone().into(),
+        }));
+        let _ = client_outgoing.send(close).await;
+        let _ = instance_outgoing.close().await;
+    }
+
+    state.metrics.count_disconnect(proxy_req, reason);
+    state
+        .metrics
+        .observe_session_duration(proxy_req, started_at.elapsed());
+    state
+        .metrics
+        .add_ws_buffered_bytes(proxy_req, -(buffered.load(Ordering::Relaxed) as i64));
+    state
+        .metrics
+        .add_buffered_frames(proxy_req, -(buffered_frames.load(Ordering::Relaxed) as i64));
+    state.metrics.dec_ws_total_connection(proxy_req);
+    state.usage.session_closed(&proxy_req.request_id);
+
+    log_session(
+        proxy_req,
+        started_at.elapsed(),
+        bytes_received.load(Ordering::Relaxed),
+        bytes_sent.load(Ordering::Relaxed),
+        &close_reason,
+        subprotocol,
+    );
+    audit::log_impersonation_ended(proxy_req, started_at.elapsed(), &close_reason);
+
+    // The slot is released by the caller, the count logged here still includes this session.
+    let active_connections = proxy_req
+        .consumer
+        .get_active_connections(state.clone())
+        .await;
+    info!(
+        consumer = proxy_req.consumer.to_string(),
+        active_connections, "client disconnected"
+    );
+}
+
+fn headers_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+/// What to do when the client doesn't read frames as fast as the instance produces them and its
+/// buffer fills up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowClientPolicy {
+    /// Stop reading from the instance until the client catches up.
+    Pause,
+    /// Close the session.
+    Disconnect,
 }
+impl FromStr for SlowClientPolicy {
+    type Err = String;
 
-async fn handle_healthz(state: &State) -> Result<ProxyResponse, hyper::Error> {
-    if *state.upstream_health.read().await {
-        Ok(Response::builder()
-            .status(StatusCode::OK)
-            .body(full("OK"))
-            .unwrap())
-    } else {
-        Ok(Response::builder()
-            .status(StatusCode::BAD_GATEWAY)
-            .body(full(""))
-            .unwrap())
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(Self::Pause),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!("invalid slow client policy: {s}")),
+        }
+    }
+}
+
+/// Why a websocket session ended, labelled in the disconnect metric.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+    ClientClosed,
+    InstanceClosed,
+    KeepaliveTimeout,
+    IdleTimeout,
+    RateLimited,
+    QuotaExceeded,
+    MessageTooBig,
+    SlowClient,
+    AuthRevoked,
+    CredentialExpired,
+    Administrator,
+    Shutdown,
+    Migrated,
+    SessionDuration,
+    MemoryLimit,
+    InternalError,
+}
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ClientClosed => "client_closed",
+            Self::InstanceClosed => "instance_closed",
+            Self::KeepaliveTimeout => "keepalive_timeout",
+            Self::IdleTimeout => "idle_timeout",
+            Self::RateLimited => "rate_limited",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::MessageTooBig => "message_too_big",
+            Self::SlowClient => "slow_client",
+            Self::AuthRevoked => "auth_revoked",
+            Self::CredentialExpired => "credential_expired",
+            Self::Administrator => "admin_disconnect",
+            Self::Shutdown => "shutdown",
+            Self::Migrated => "migrated",
+            Self::SessionDuration => "max_session_duration",
+            Self::MemoryLimit => "memory_limit",
+            Self::InternalError => "internal_error",
+        }
+    }
+
+    /// Close code sent to the client. No close frame is sent when the client closed the session
+    /// itself.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        match self {
+            Self::ClientClosed => None,
+            Self::InstanceClosed | Self::KeepaliveTimeout | Self::IdleTimeout | Self::Shutdown => {
+                Some(CloseCode::Away)
+            }
+            Self::RateLimited
+            | Self::QuotaExceeded
+            | Self::SlowClient
+            | Self::AuthRevoked
+            | Self::CredentialExpired
+            | Self::Administrator
+            | Self::MemoryLimit => Some(CloseCode::Policy),
+            Self::MessageTooBig => Some(CloseCode::Size),
+            Self::Migrated | Self::SessionDuration => Some(CloseCode::Restart),
+            Self::InternalError => Some(CloseCode::Error),
+        }
+    }
+}
+impl From<&LimiterError> for DisconnectReason {
+    fn from(err: &LimiterError) -> Self {
+        match err {
+            LimiterError::PortDeleted => Self::AuthRevoked,
+            LimiterError::InvalidTier => Self::InternalError,
+            LimiterError::RateLimited(_) => Self::RateLimited,
+        }
     }
 }
 
@@ -309,17 +1583,123 @@ impl Display for Protocol {
     }
 }
 
+/// Looks up the consumer of a key, skipping the ones rejected recently. Missing keys aren't
+/// cached, requests without one usually authenticate some other way.
+async fn authenticate_key(state: &State, key_hash: &str, present: bool) -> Option<Consumer> {
+    if state.rejected_keys.contains(key_hash) {
+        return None;
+    }
+
+    let consumer = state.get_consumer(key_hash).await;
+    if consumer.is_none() && present {
+        state.rejected_keys.insert(key_hash);
+    }
+    consumer
+}
+
+/// Why a key no consumer accepts was refused: none was sent, it was revoked, or it's unknown.
+/// Keys rotated out stay aliases of their port once the grace period is over.
+async fn key_failure(state: &State, token: &str, key_hash: &str) -> AuthFailure {
+    if token.is_empty() {
+        return AuthFailure::MissingKey;
+    }
+    if state.revoked_keys.contains(key_hash)
+        || state.key_aliases.read().await.contains_key(key_hash)
+    {
+        return AuthFailure::RevokedKey;
+    }
+    AuthFailure::UnknownKey
+}
+
+/// Consumer an operator opens the session as, named like on the admin api as `NAMESPACE.PORT`,
+/// along with the operator. The headers are stripped so the instance never sees the token, and
+/// impersonation is disabled when `PROXY_IMPERSONATION_TOKEN` is unset.
+async fn impersonate(
+    state: &State,
+    hyper_req: &mut Request<Incoming>,
+    request_id: &str,
+    client_ip: &IpAddr,
+) -> Result<Option<(Consumer, String)>, ()> {
+    let headers = hyper_req.headers_mut();
+    let target = headers.remove(DMTR_IMPERSONATE);
+    let token = headers.remove(DMTR_IMPERSONATION_TOKEN);
+    let operator = headers.remove(DMTR_OPERATOR);
+    let Some(target) = target else {
+        return Ok(None);
+    };
+
+    let value = |header: Option<HeaderValue>| {
+        header
+            .and_then(|h| h.to_str().ok().map(String::from))
+            .filter(|v| !v.is_empty())
+    };
+    let target = value(Some(target)).unwrap_or_default();
+    let operator = value(operator);
+    let refuse = |reason: &str| {
+        audit::log_impersonation_refused(
+            request_id,
+            client_ip,
+            operator.as_deref(),
+            &target,
+            reason,
+        );
+    };
+
+    let authorized = state
+        .config()
+        .proxy_impersonation_token
+        .as_ref()
+        .is_some_and(|expected| value(token).is_some_and(|token| secret_eq(&token, expected)));
+    if !authorized {
+        refuse("invalid_token");
+        return Err(());
+    }
+    let Some(operator) = operator.clone() else {
+        refuse("missing_operator");
+        return Err(());
+    };
+
+    // Namespaces are DNS labels without dots, the port name is everything after the first one.
+    let consumer = match target.split_once('.') {
+        Some((namespace, port_name)) => state.get_port_consumer(namespace, port_name).await,
+        None => None,
+    };
+    match consumer {
+        Some(consumer) => Ok(Some((consumer, operator))),
+        None => {
+            refuse("unknown_consumer");
+            Err(())
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ProxyRequest {
+    pub request_id: String,
+    pub client_ip: IpAddr,
+    /// Whether the client ip was taken from the forwarding headers of a trusted proxy.
+    pub forwarded: bool,
     pub namespace: String,
     pub host: String,
     pub instance: String,
     pub consumer: Consumer,
+    /// Metrics label of the consumer, taken once for the session.
+    pub consumer_label: String,
     pub protocol: Protocol,
+    /// Operator that opened the request as the consumer, through the impersonation headers.
+    pub impersonated_by: Option<String>,
 }
 impl ProxyRequest {
-    pub async fn new(hyper_req: &mut Request<Incoming>, state: &State) -> Option<Self> {
-        let namespace = state.config.proxy_namespace.clone();
+    pub async fn new(
+        hyper_req: &mut Request<Incoming>,
+        client_addr: SocketAddr,
+        forwarded: bool,
+        identity: Option<String>,
+        state: &State,
+    ) -> Result<Self, AuthFailure> {
+        let client_ip = client_addr.ip();
+        let namespace = state.config().proxy_namespace.clone();
+        let request_id = get_header(hyper_req, DMTR_REQUEST_ID).unwrap_or_default();
 
         let protocol = get_header(hyper_req, UPGRADE.as_str())
             .map(|h| {
@@ -331,53 +1711,218 @@ impl ProxyRequest {
             })
             .unwrap_or(Protocol::Http);
 
-        let host = get_header(hyper_req, HOST.as_str())?;
-        let captures = state.host_regex.captures(&host)?;
+        let host = get_header(hyper_req, HOST.as_str()).ok_or(AuthFailure::MissingHost)?;
+        let Some(captures) = state.host_regex.captures(&host) else {
+            state.lockout.record_failure(&client_ip, None);
+            return Err(AuthFailure::UnknownKey);
+        };
+
+        // Keys in the path or the query are stripped, the instance never sees them.
+        let path_key = split_path_key(hyper_req.uri().path());
+        let query_key = hyper_req.uri().query().and_then(split_query_key);
+        if path_key.is_some() || query_key.is_some() {
+            let path = path_key
+                .as_ref()
+                .map(|(_, path)| path.as_str())
+                .unwrap_or(hyper_req.uri().path());
+            let query = query_key
+                .as_ref()
+                .map(|(_, query)| query.as_str())
+                .or(hyper_req.uri().query())
+                .filter(|query| !query.is_empty());
+            let path_and_query = match query {
+                Some(query) => format!("{path}?{query}"),
+                None => path.to_string(),
+            };
+            let mut parts = hyper_req.uri().clone().into_parts();
+            parts.path_and_query = path_and_query.parse().ok();
+            *hyper_req.uri_mut() = Uri::from_parts(parts).map_err(|_| AuthFailure::UnknownKey)?;
+        }
 
-        let token = get_header(hyper_req, DMTR_API_KEY)
-            .or_else(|| captures.get(1).map(|v| v.as_str().to_string()))
+        // A websocket client resuming a dropped session is identified by its reconnect token.
+        let resumed = match protocol {
+            Protocol::Websocket => get_header(hyper_req, DMTR_RECONNECT_TOKEN)
+                .and_then(|token| state.reconnect.take(&token)),
+            Protocol::Http => None,
+        };
+
+        let host_key = captures
+            .get(state.host_key_group)
+            .map(|v| v.as_str().to_string());
+        let header_key = get_header(hyper_req, DMTR_API_KEY);
+        let from_query = identity.is_none()
+            && resumed.is_none()
+            && header_key.is_none()
+            && path_key.is_none()
+            && query_key.is_some();
+        let token = resumed
+            .as_ref()
+            .map(|resumed| resumed.key.clone())
+            .or(header_key)
+            .or(path_key.map(|(key, _)| key))
+            .or(query_key.map(|(key, _)| key))
+            .or(host_key.clone())
             .unwrap_or_default();
+        // Reconnect tokens are issued for the key hash already.
+        let key_hash = match resumed {
+            Some(_) => token.clone(),
+            None => hash_key(&token),
+        };
+
+        // Banned sources are refused before looking up the key, so guesses get no answer.
+        // Keys are tracked by their hash, the empty key of requests sending none isn't tracked.
+        let lockout_key = (!token.is_empty()).then_some(key_hash.as_str());
+        if let Some(retry_after) = state.lockout.banned(&client_ip, lockout_key) {
+            return Err(AuthFailure::Banned(retry_after));
+        }
+        let fail = |reason: AuthFailure| {
+            state.lockout.record_failure(&client_ip, lockout_key);
+            reason
+        };
+
+        // Operators can open a session as any consumer, the credentials sent are ignored then.
+        let impersonation = impersonate(state, hyper_req, &request_id, &client_ip)
+            .await
+            .map_err(|_| fail(AuthFailure::UnknownKey))?;
+        let impersonated_by = impersonation.as_ref().map(|(_, operator)| operator.clone());
+
+        // A client certificate names the port by itself, the key is ignored. Keys unknown to the
+        // proxy go to the auth webhook, and platforms can also send a short-lived JWT instead.
+        let mut consumer = match (impersonation, identity) {
+            (Some((consumer, _)), _) => consumer,
+            (None, Some(identity)) => auth::authenticate_identity(state, &identity)
+                .await
+                .ok_or_else(|| fail(AuthFailure::UnknownKey))?,
+            (None, None) => match authenticate_key(state, &key_hash, !token.is_empty()).await {
+                Some(consumer) => consumer,
+                None => {
+                    // Resumed sessions only carry the hash, the webhook decision has to be cached.
+                    let credential = if resumed.is_some() { "" } else { &token };
+                    match webhook::authenticate(state, credential, &key_hash, &host).await {
+                        Some(consumer) => consumer,
+                        None => {
+                            let Some(jwt) = get_header(hyper_req, AUTHORIZATION.as_str())
+                                .and_then(|h| h.strip_prefix("Bearer ").map(String::from))
+                            else {
+                                let failure = key_failure(state, &token, &key_hash).await;
+                                return Err(fail(failure));
+                            };
+                            let jwt_hash = hash_key(&jwt);
+                            if state.rejected_keys.contains(&jwt_hash) {
+                                return Err(fail(AuthFailure::UnknownKey));
+                            }
+                            // Bearer tokens that aren't JWTs can be opaque OAuth2 tokens. Only
+                            // tokens the endpoint turned down are remembered as rejected, not the
+                            // ones it couldn't answer for. Expired JWTs are told apart once their
+                            // signature was checked, and aren't remembered.
+                            match auth::authenticate_jwt(state, &jwt).await {
+                                Ok(consumer) => consumer,
+                                Err(JwtRejection::Expired) => {
+                                    return Err(fail(AuthFailure::ExpiredToken))
+                                }
+                                Err(JwtRejection::Invalid) => {
+                                    match introspection::authenticate(state, &jwt, &jwt_hash).await
+                                    {
+                                        Introspected::Valid(consumer) => *consumer,
+                                        Introspected::Invalid => {
+                                            state.rejected_keys.insert(&jwt_hash);
+                                            return Err(fail(AuthFailure::UnknownKey));
+                                        }
+                                        Introspected::Unknown => {
+                                            return Err(fail(AuthFailure::UnknownKey))
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+        };
+        // Expired keys are valid credentials, they aren't counted as failures for the lockout.
+        if consumer.is_expired() {
+            return Err(AuthFailure::Expired);
+        }
+
+        // A key in the hostname binds it to its port, credentials sent some other way have to be
+        // for the same port, so a key of one project can't be used on the hostname of another.
+        if let Some(host_key) = host_key
+            .filter(|key| key.starts_with("dmtr_") && *key != token)
+            .filter(|_| impersonated_by.is_none())
+        {
+            if !consumer.accepted_keys().contains(&hash_key(&host_key)) {
+                warn!(
+                    consumer = consumer.to_string(),
+                    host, "auth: Hostname key bound to another consumer."
+                );
+                return Err(fail(AuthFailure::KeyBindingMismatch));
+            }
+        }
 
-        let consumer = state.get_consumer(&token).await?;
+        // Resumed sessions keep the scope of the key they were opened with.
+        if let Some(resumed) = resumed
+            .as_ref()
+            .filter(|r| consumer.session_key.as_ref() == Some(&r.key))
+        {
+            consumer.scope = resumed.scope;
+        }
 
-        if consumer.network != state.config.network {
-            return None;
+        // Query strings end up in browser history and intermediary logs, so the tier has to opt in.
+        if from_query
+            && !state
+                .consumer_tier(&consumer)
+                .await
+                .is_some_and(|tier| tier.allow_query_key)
+        {
+            return Err(fail(AuthFailure::QueryKeyNotAllowed));
+        }
+
+        let config = state.config();
+        if !config.networks.contains(&consumer.network) || !config.serves_version(&consumer.version)
+        {
+            return Err(AuthFailure::NotServed);
+        }
+        if !state
+            .consumer_tier(&consumer)
+            .await
+            .is_none_or(|tier| tier.is_network_allowed(&consumer.network))
+        {
+            return Err(AuthFailure::NetworkNotAllowed);
+        }
+
+        // When the hostname names a network and version, they have to be the ones the port was
+        // created for.
+        if let Some((network, version)) = parse_host_route(&host) {
+            if network != consumer.network || version != consumer.version {
+                return Err(AuthFailure::RouteMismatch);
+            }
         }
 
-        let instance = state.config.instance(&consumer.version);
+        // Resumed sessions go back to the same instance, as long as it's still serving the port.
+        let instances = state.instances(&consumer.network, &consumer.version);
+        let instance = match resumed {
+            Some(resumed) if instances.contains(&resumed.instance) => resumed.instance,
+            _ => state
+                .upstreams
+                .select(config.ogmios_upstream_strategy, &instances),
+        };
 
-        Some(Self {
+        let consumer_label = state.metrics.consumer_label(&consumer);
+        let proxy_req = Self {
+            request_id,
+            client_ip,
+            forwarded,
             namespace,
             instance,
             consumer,
+            consumer_label,
             protocol,
             host,
-        })
+            impersonated_by,
+        };
+        if let Some(operator) = &proxy_req.impersonated_by {
+            audit::log_impersonation_started(&proxy_req, operator);
+        }
+        Ok(proxy_req)
     }
 }
-
-fn build_tls_acceptor(state: &State) -> Result<TlsAcceptor, Box<dyn Error>> {
-    let certs = load_certs(&state.config.ssl_crt_path)?;
-
-    let key = load_private_key(&state.config.ssl_key_path)?;
-
-    let server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .unwrap();
-
-    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
-    Ok(tls_acceptor)
-}
-
-fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
-    let cert_file = fs::File::open(path)?;
-    let mut reader = io::BufReader::new(cert_file);
-    rustls_pemfile::certs(&mut reader).collect()
-}
-
-fn load_private_key(path: &PathBuf) -> io::Result<PrivateKeyDer<'static>> {
-    let key_file = fs::File::open(path)?;
-    let mut reader = io::BufReader::new(key_file);
-    rustls_pemfile::private_key(&mut reader).map(|key| key.unwrap())
-}
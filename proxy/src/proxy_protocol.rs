@@ -0,0 +1,195 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+const COMMAND_LOCAL: u8 = 0x20;
+const COMMAND_PROXY: u8 = 0x21;
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the PROXY protocol v2 header sent by the load balancer before any client data, returning
+/// the address of the client. `None` is returned for health checks of the load balancer itself
+/// (LOCAL command) and for address families other than TCP.
+pub async fn read_header<R>(stream: &mut R) -> io::Result<Option<SocketAddr>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header).await?;
+    if header[..12] != SIGNATURE {
+        return Err(invalid("missing proxy protocol v2 signature"));
+    }
+
+    let command = header[12];
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+
+    // The addresses are followed by optional TLVs, they are read to drain the header but ignored.
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        COMMAND_LOCAL => return Ok(None),
+        COMMAND_PROXY => {}
+        _ => return Err(invalid("unknown proxy protocol command")),
+    }
+
+    match family {
+        FAMILY_TCP4 if length >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        FAMILY_TCP6 if length >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        FAMILY_TCP4 | FAMILY_TCP6 => Err(invalid("truncated proxy protocol addresses")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(command: u8, family: u8, length: u16, payload: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend(length.to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    async fn read(bytes: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let mut stream = bytes;
+        read_header(&mut stream).await
+    }
+
+    #[tokio::test]
+    async fn bad_signature_is_refused() {
+        let mut bytes = header(COMMAND_PROXY, FAMILY_TCP4, 0, &[]);
+        bytes[0] = b'G';
+        let err = read(&bytes).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = read(b"GET / HTTP/1.1\r\n").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn local_command_has_no_address() {
+        let bytes = header(COMMAND_LOCAL, 0x00, 0, &[]);
+        assert_eq!(read(&bytes).await.unwrap(), None);
+
+        // The addresses a load balancer may still send are drained.
+        let payload = [10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0x00, 0x50];
+        let mut bytes = header(COMMAND_LOCAL, FAMILY_TCP4, 12, &payload);
+        bytes.extend(b"GET");
+        let mut stream = bytes.as_slice();
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn unknown_command_is_refused() {
+        let bytes = header(0x22, FAMILY_TCP4, 0, &[]);
+        let err = read(&bytes).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn tcp4_source_address_is_read() {
+        let payload = [203, 0, 113, 7, 10, 0, 0, 2, 0xd4, 0x31, 0x00, 0x50];
+        let mut bytes = header(COMMAND_PROXY, FAMILY_TCP4, 12, &payload);
+        bytes.extend(b"GET");
+        let mut stream = bytes.as_slice();
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("203.0.113.7:54321".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn tcp6_source_address_is_read() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut payload = source.octets().to_vec();
+        payload.extend(destination.octets());
+        payload.extend(443u16.to_be_bytes());
+        payload.extend(80u16.to_be_bytes());
+        let bytes = header(COMMAND_PROXY, FAMILY_TCP6, 36, &payload);
+        assert_eq!(
+            read(&bytes).await.unwrap(),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn tlvs_after_the_addresses_are_skipped() {
+        let mut payload = vec![203, 0, 113, 7, 10, 0, 0, 2, 0xd4, 0x31, 0x00, 0x50];
+        payload.extend([0x04, 0x00, 0x02, 0xab, 0xcd]);
+        let mut bytes = header(COMMAND_PROXY, FAMILY_TCP4, payload.len() as u16, &payload);
+        bytes.extend(b"GET");
+        let mut stream = bytes.as_slice();
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("203.0.113.7:54321".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET");
+    }
+
+    #[tokio::test]
+    async fn length_too_short_for_the_addresses_is_refused() {
+        let payload = [203, 0, 113, 7, 10, 0];
+        let bytes = header(COMMAND_PROXY, FAMILY_TCP4, 6, &payload);
+        let err = read(&bytes).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let payload = [0u8; 20];
+        let bytes = header(COMMAND_PROXY, FAMILY_TCP6, 20, &payload);
+        let err = read(&bytes).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn length_past_the_end_of_the_stream_is_refused() {
+        let payload = [203, 0, 113, 7, 10, 0, 0, 2, 0xd4, 0x31, 0x00, 0x50];
+        let bytes = header(COMMAND_PROXY, FAMILY_TCP4, u16::MAX, &payload);
+        let err = read(&bytes).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A header cut before the length field.
+        let bytes = header(COMMAND_PROXY, FAMILY_TCP4, 12, &payload);
+        let err = read(&bytes[..14]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn unknown_address_families_have_no_address() {
+        // UDP over IPv4, then AF_UNIX stream.
+        let payload = [203, 0, 113, 7, 10, 0, 0, 2, 0xd4, 0x31, 0x00, 0x50];
+        let bytes = header(COMMAND_PROXY, 0x12, 12, &payload);
+        assert_eq!(read(&bytes).await.unwrap(), None);
+
+        let payload = [0u8; 216];
+        let bytes = header(COMMAND_PROXY, 0x31, 216, &payload);
+        assert_eq!(read(&bytes).await.unwrap(), None);
+
+        let bytes = header(COMMAND_PROXY, 0x00, 0, &[]);
+        assert_eq!(read(&bytes).await.unwrap(), None);
+    }
+}
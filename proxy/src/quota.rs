@@ -0,0 +1,229 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{error::Error, fmt::Display, fs, io};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+use crate::{Consumer, State};
+
+#[derive(Debug)]
+pub enum QuotaError {
+    BandwidthExceeded,
+    DailyRequests,
+    MonthlyRequests,
+}
+impl QuotaError {
+    /// Time left until the window of the exhausted quota resets, for calendar quotas.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let now = Utc::now();
+        let reset = match self {
+            QuotaError::BandwidthExceeded => return None,
+            QuotaError::DailyRequests => now.date_naive().succ_opt()?,
+            QuotaError::MonthlyRequests => next_month(now.date_naive())?,
+        };
+        let reset = reset.and_hms_opt(0, 0, 0)?.and_utc();
+        (reset - now).to_std().ok()
+    }
+}
+impl Display for QuotaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuotaError::BandwidthExceeded => f.write_str("Bandwidth quota exceeded"),
+            QuotaError::DailyRequests => f.write_str("Daily request quota exceeded"),
+            QuotaError::MonthlyRequests => f.write_str("Monthly request quota exceeded"),
+        }
+    }
+}
+impl Error for QuotaError {}
+
+fn next_month(date: NaiveDate) -> Option<NaiveDate> {
+    match date.month() {
+        12 => NaiveDate::from_ymd_opt(date.year() + 1, 1, 1),
+        month => NaiveDate::from_ymd_opt(date.year(), month + 1, 1),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Usage {
+    window_start: Instant,
+    used: u64,
+}
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            window_start: Instant::now(),
+            used: 0,
+        }
+    }
+}
+
+/// Accounts bytes transferred by the consumer in either direction, failing once the bandwidth
+/// quota of the tier is exhausted for the current window.
+pub async fn consume_bandwidth(
+    state: &State,
+    consumer: &Consumer,
+    bytes: usize,
+) -> Result<(), QuotaError> {
+    let quota = match state
+        .consumer_tier(consumer)
+        .await
+        .and_then(|tier| tier.bandwidth)
+    {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let key = state.current_key(consumer).await;
+    let mut bandwidth = state.bandwidth.write().await;
+    let usage = bandwidth.entry(key).or_default();
+
+    if usage.window_start.elapsed() >= quota.interval {
+        *usage = Usage::default();
+    }
+
+    usage.used += bytes as u64;
+    if usage.used > quota.limit {
+        return Err(QuotaError::BandwidthExceeded);
+    }
+
+    Ok(())
+}
+
+/// Bytes used by the consumer in the current bandwidth window, and the time left until it resets.
+pub async fn bandwidth_usage(
+    state: &State,
+    consumer: &Consumer,
+    interval: Duration,
+) -> (u64, Duration) {
+    match state.bandwidth.read().await.get(&consumer.key) {
+        Some(usage) if usage.window_start.elapsed() < interval => {
+            (usage.used, interval - usage.window_start.elapsed())
+        }
+        _ => (0, interval),
+    }
+}
+
+/// Requests of a consumer in the current UTC day and month. Counters of a past window are reset
+/// on the next request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestUsage {
+    day: String,
+    daily: u64,
+    month: String,
+    monthly: u64,
+}
+impl RequestUsage {
+    fn roll(&mut self, now: DateTime<Utc>) {
+        let day = now.format("%Y-%m-%d").to_string();
+        if self.day != day {
+            self.day = day;
+            self.daily = 0;
+        }
+        let month = now.format("%Y-%m").to_string();
+        if self.month != month {
+            self.month = month;
+            self.monthly = 0;
+        }
+    }
+}
+
+/// Counts a request against the daily and monthly quotas of the tier. Refused requests aren't
+/// counted. Usage is kept by consumer name, since the key hashes change on every restart.
+pub async fn consume_request(state: &State, consumer: &Consumer) -> Result<(), QuotaError> {
+    let (daily, monthly) = match state.consumer_tier(consumer).await {
+        Some(tier) => (tier.daily_requests, tier.monthly_requests),
+        None => return Ok(()),
+    };
+    if daily.is_none() && monthly.is_none() {
+        return Ok(());
+    }
+
+    let mut requests = state.requests.write().await;
+    let usage = requests.entry(consumer.to_string()).or_default();
+    usage.roll(Utc::now());
+
+    if daily.is_some_and(|limit| usage.daily >= limit) {
+        return Err(QuotaError::DailyRequests);
+    }
+    if monthly.is_some_and(|limit| usage.monthly >= limit) {
+        return Err(QuotaError::MonthlyRequests);
+    }
+    usage.daily += 1;
+    usage.monthly += 1;
+
+    Ok(())
+}
+
+/// Requests counted for the consumer in the current UTC day and month.
+pub async fn request_usage(state: &State, consumer: &Consumer) -> (u64, u64) {
+    let mut usage = state
+        .requests
+        .read()
+        .await
+        .get(&consumer.to_string())
+        .cloned()
+        .unwrap_or_default();
+    usage.roll(Utc::now());
+    (usage.daily, usage.monthly)
+}
+
+/// Reads the request usage saved by a previous run. A missing file starts from zero.
+pub fn load_requests(path: &Path) -> HashMap<String, RequestUsage> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Default::default(),
+        Err(err) => {
+            error!(error = err.to_string(), "fail to read quota state");
+            return Default::default();
+        }
+    };
+
+    serde_json::from_str(&contents).unwrap_or_else(|err| {
+        warn!(error = err.to_string(), "ignoring invalid quota state");
+        Default::default()
+    })
+}
+
+/// Writes the request usage to `PROXY_QUOTA_STATE_PATH`, through a temporary file so a crash
+/// mid-write doesn't leave a truncated state.
+pub async fn flush_requests(state: &State) {
+    let Some(path) = state.config().proxy_quota_state_path.clone() else {
+        return;
+    };
+
+    let contents = match serde_json::to_vec(&*state.requests.read().await) {
+        Ok(contents) => contents,
+        Err(err) => {
+            error!(error = err.to_string(), "fail to serialize quota state");
+            return;
+        }
+    };
+    let tmp_path = path.with_extension("tmp");
+    let result = match tokio::fs::write(&tmp_path, contents).await {
+        Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        error!(error = err.to_string(), "fail to write quota state");
+    }
+}
+
+/// Flushes the request usage every `PROXY_QUOTA_FLUSH_INTERVAL`, when a state path is set.
+pub fn start(state: Arc<State>) {
+    if state.config().proxy_quota_state_path.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.config().proxy_quota_flush_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            flush_requests(&state).await;
+        }
+    });
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::KeyScope;
+
+struct Session {
+    key: String,
+    scope: KeyScope,
+    instance: String,
+    // Unset while the session is still open, the token only starts expiring once it ends.
+    expires_at: Option<Instant>,
+}
+
+/// Consumer and instance a reconnect token was issued for.
+pub struct Resumed {
+    pub key: String,
+    pub scope: KeyScope,
+    pub instance: String,
+}
+
+/// Tokens handed to websocket clients on connect. A client that drops can present its token to
+/// resume on the same instance, without sending the api key again. Tokens are single use.
+#[derive(Default)]
+pub struct ReconnectTokens(Mutex<HashMap<String, Session>>);
+impl ReconnectTokens {
+    pub fn issue(&self, key: &str, scope: KeyScope, instance: &str) -> String {
+        let token = Uuid::new_v4().to_string();
+        let mut sessions = self.0.lock().unwrap();
+
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires_at.is_none_or(|at| at > now));
+        sessions.insert(
+            token.clone(),
+            Session {
+                key: key.to_string(),
+                scope,
+                instance: instance.to_string(),
+                expires_at: None,
+            },
+        );
+
+        token
+    }
+
+    /// Starts the validity window of the token once its session ended.
+    pub fn release(&self, token: &str, ttl: Duration) {
+        if let Some(session) = self.0.lock().unwrap().get_mut(token) {
+            session.expires_at = Some(Instant::now() + ttl);
+        }
+    }
+
+    /// Consumes a token released less than its ttl ago.
+    pub fn take(&self, token: &str) -> Option<Resumed> {
+        let mut sessions = self.0.lock().unwrap();
+        let expires_at = sessions.get(token)?.expires_at?;
+        let session = sessions.remove(token)?;
+
+        (expires_at > Instant::now()).then_some(Resumed {
+            key: session.key,
+            scope: session.scope,
+            instance: session.instance,
+        })
+    }
+}
@@ -0,0 +1,239 @@
+use hyper::rt::ReadBufCursor;
+use hyper::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use rustls_pki_types::ServerName;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::Instant;
+use tokio_rustls::client::TlsStream;
+use tower_service::Service;
+use tracing::warn;
+
+use crate::tls::UpstreamTls;
+
+/// Consecutive connection failures after which the cached addresses of an instance are dropped.
+const FAILURE_THRESHOLD: usize = 3;
+
+struct Resolved {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Instant,
+    failures: usize,
+}
+
+/// Caches the addresses of the upstream instances. Entries are resolved again once the TTL
+/// expires, or right away after repeated connection failures, so DNS changes are picked up by new
+/// sessions without a restart.
+pub struct Resolver {
+    ttl: Duration,
+    cache: Mutex<HashMap<String, Resolved>>,
+    tls: Option<UpstreamTls>,
+}
+impl Resolver {
+    pub fn new(ttl: Duration, tls: Option<UpstreamTls>) -> Self {
+        Self {
+            ttl,
+            cache: Default::default(),
+            tls,
+        }
+    }
+
+    /// Connects to the instance, completing the TLS handshake when upstream TLS is enabled. The
+    /// certificate is verified for the configured server name, or the host of the instance.
+    pub async fn connect(&self, instance: &str) -> io::Result<UpstreamStream> {
+        let stream = self.connect_tcp(instance).await?;
+
+        let Some(tls) = &self.tls else {
+            return Ok(UpstreamStream::Plain(stream));
+        };
+
+        let host = match &tls.server_name {
+            Some(server_name) => server_name.clone(),
+            None => instance
+                .rsplit_once(':')
+                .map_or(instance, |(host, _)| host)
+                .to_string(),
+        };
+        let server_name = ServerName::try_from(host)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        match tls.connector.connect(server_name, stream).await {
+            Ok(stream) => Ok(UpstreamStream::Tls(Box::new(stream))),
+            Err(err) => {
+                self.record_failure(instance);
+                Err(err)
+            }
+        }
+    }
+
+    async fn connect_tcp(&self, instance: &str) -> io::Result<TcpStream> {
+        let addrs = self.resolve(instance).await?;
+
+        match TcpStream::connect(&addrs[..]).await {
+            Ok(stream) => {
+                if let Some(resolved) = self.cache.lock().unwrap().get_mut(instance) {
+                    resolved.failures = 0;
+                }
+                Ok(stream)
+            }
+            Err(err) => {
+                self.record_failure(instance);
+                Err(err)
+            }
+        }
+    }
+
+    async fn resolve(&self, instance: &str) -> io::Result<Vec<SocketAddr>> {
+        if let Some(resolved) = self.cache.lock().unwrap().get(instance) {
+            if resolved.resolved_at.elapsed() < self.ttl {
+                return Ok(resolved.addrs.clone());
+            }
+        }
+
+        let addrs: Vec<SocketAddr> = lookup_host(instance).await?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address found for {instance}"),
+            ));
+        }
+
+        self.cache.lock().unwrap().insert(
+            instance.to_string(),
+            Resolved {
+                addrs: addrs.clone(),
+                resolved_at: Instant::now(),
+                failures: 0,
+            },
+        );
+
+        Ok(addrs)
+    }
+
+    fn record_failure(&self, instance: &str) {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(resolved) = cache.get_mut(instance) {
+            resolved.failures += 1;
+            if resolved.failures >= FAILURE_THRESHOLD {
+                warn!(
+                    instance,
+                    "repeated connection failures, resolving instance again"
+                );
+                cache.remove(instance);
+            }
+        }
+    }
+}
+
+/// Connection to an instance, plaintext or over TLS.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Adapts an [`UpstreamStream`] to the hyper client.
+pub struct UpstreamIo(TokioIo<UpstreamStream>);
+impl hyper::rt::Read for UpstreamIo {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+impl hyper::rt::Write for UpstreamIo {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+impl Connection for UpstreamIo {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/// Opens the pooled http connections through the resolver, so they share its cache.
+#[derive(Clone)]
+pub struct UpstreamConnector(pub Arc<Resolver>);
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamIo;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let resolver = self.0.clone();
+
+        Box::pin(async move {
+            let instance = uri.authority().map(|a| a.to_string()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "uri without authority")
+            })?;
+            resolver
+                .connect(&instance)
+                .await
+                .map(|stream| UpstreamIo(TokioIo::new(stream)))
+        })
+    }
+}
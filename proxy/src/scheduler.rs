@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Instant};
+
+#[derive(Debug)]
+pub struct UpstreamSaturated;
+impl Display for UpstreamSaturated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Upstream saturated, please retry later")
+    }
+}
+impl Error for UpstreamSaturated {}
+
+struct Waiter {
+    finish: f64,
+    slot: oneshot::Sender<Slot>,
+}
+
+#[derive(Default)]
+struct TierQueue {
+    last_finish: f64,
+    waiters: VecDeque<Waiter>,
+}
+
+#[derive(Default)]
+struct Inner {
+    in_use: usize,
+    virtual_time: f64,
+    queues: HashMap<String, TierQueue>,
+}
+
+/// Caps the requests forwarded to the instances at once. Once the cap is reached, requests wait
+/// in a queue per tier and freed slots go to the queues by weighted fair queuing: each waiter is
+/// tagged with a virtual finish time advancing by `1 / weight` for its tier, and the lowest tag
+/// is served first. Heavier tiers drain faster, and waiters of light tiers are the first to hit
+/// the max wait and be shed.
+pub struct Scheduler {
+    capacity: Option<usize>,
+    max_wait: Duration,
+    inner: Mutex<Inner>,
+}
+impl Scheduler {
+    pub fn new(capacity: Option<usize>, max_wait: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            capacity,
+            max_wait,
+            inner: Default::default(),
+        })
+    }
+
+    /// Waits for a slot, returning how long it waited. No slot is needed when the scheduler is
+    /// disabled.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        tier: &str,
+        weight: u32,
+    ) -> Result<(Option<Slot>, Duration), UpstreamSaturated> {
+        let Some(capacity) = self.capacity else {
+            return Ok((None, Duration::ZERO));
+        };
+
+        let started_at = Instant::now();
+        let receiver = {
+            let mut inner = self.inner.lock().unwrap();
+            // Waiters that gave up don't hold the queue.
+            for queue in inner.queues.values_mut() {
+                queue.waiters.retain(|waiter| !waiter.slot.is_closed());
+            }
+            inner.queues.retain(|_, queue| !queue.waiters.is_empty());
+            let queued = !inner.queues.is_empty();
+            if inner.in_use < capacity && !queued {
+                inner.in_use += 1;
+                return Ok((Some(self.slot()), Duration::ZERO));
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            let virtual_time = inner.virtual_time;
+            let queue = inner.queues.entry(tier.to_string()).or_default();
+            let finish = queue.last_finish.max(virtual_time) + 1.0 / weight.max(1) as f64;
+            queue.last_finish = finish;
+            queue.waiters.push_back(Waiter {
+                finish,
+                slot: sender,
+            });
+            receiver
+        };
+
+        // A slot handed over right as the wait expires is dropped with the receiver, which
+        // releases it again.
+        match timeout(self.max_wait, receiver).await {
+            Ok(Ok(slot)) => Ok((Some(slot), started_at.elapsed())),
+            _ => Err(UpstreamSaturated),
+        }
+    }
+
+    fn slot(self: &Arc<Self>) -> Slot {
+        Slot(Some(self.clone()))
+    }
+
+    /// Hands the slot over to the waiter with the lowest finish tag, or frees it.
+    fn release(self: &Arc<Self>) {
+        let mut inner = self.inner.lock().unwrap();
+        loop {
+            let next = inner
+                .queues
+                .iter()
+                .filter_map(|(tier, queue)| Some((tier, queue.waiters.front()?.finish)))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(tier, _)| tier.clone());
+            let Some(tier) = next else {
+                inner.in_use = inner.in_use.saturating_sub(1);
+                return;
+            };
+
+            let queue = inner.queues.get_mut(&tier).unwrap();
+            let waiter = queue.waiters.pop_front().unwrap();
+            if queue.waiters.is_empty() {
+                inner.queues.remove(&tier);
+            }
+            inner.virtual_time = waiter.finish;
+
+            // Waiters that gave up are skipped, the slot goes to the next one.
+            match waiter.slot.send(self.slot()) {
+                Ok(()) => return,
+                Err(mut slot) => slot.0 = None,
+            }
+        }
+    }
+}
+
+/// A request forwarded to the instances, the slot is freed when dropped.
+pub struct Slot(Option<Arc<Scheduler>>);
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.0.take() {
+            scheduler.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Queues the waiters, tiers with their weight, in order and returns their positions in the
+    /// order they were given the slot held by the test.
+    async fn served(waiters: &[(&'static str, u32)]) -> Vec<usize> {
+        let scheduler = Scheduler::new(Some(1), Duration::from_secs(5));
+        let (held, _) = scheduler.acquire("held", 1).await.unwrap();
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for (position, &(tier, weight)) in waiters.iter().enumerate() {
+            let waiting = scheduler.clone();
+            let order = order.clone();
+            tasks.push(tokio::spawn(async move {
+                let (slot, _) = waiting.acquire(tier, weight).await.unwrap();
+                order.lock().unwrap().push(position);
+                drop(slot);
+            }));
+            // Each waiter is queued before the next one.
+            while queued(&scheduler) < tasks.len() {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    fn queued(scheduler: &Scheduler) -> usize {
+        let inner = scheduler.inner.lock().unwrap();
+        inner.queues.values().map(|queue| queue.waiters.len()).sum()
+    }
+
+    #[tokio::test]
+    async fn free_slots_are_given_right_away() {
+        let scheduler = Scheduler::new(Some(2), Duration::from_secs(5));
+        let (first, waited) = scheduler.acquire("free", 1).await.unwrap();
+        assert!(first.is_some());
+        assert_eq!(waited, Duration::ZERO);
+        let (second, _) = scheduler.acquire("free", 1).await.unwrap();
+        assert!(second.is_some());
+        assert_eq!(scheduler.inner.lock().unwrap().in_use, 2);
+
+        drop(first);
+        drop(second);
+        assert_eq!(scheduler.inner.lock().unwrap().in_use, 0);
+    }
+
+    #[tokio::test]
+    async fn disabled_scheduler_needs_no_slot() {
+        let scheduler = Scheduler::new(None, Duration::from_secs(5));
+        let (slot, _) = scheduler.acquire("free", 1).await.unwrap();
+        assert!(slot.is_none());
+    }
+
+    #[tokio::test]
+    async fn same_tier_is_served_in_order() {
+        let order = served(&[("free", 1), ("free", 1), ("free", 1)]).await;
+        assert_eq!(order, [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn heavier_tiers_are_served_more_often() {
+        // Finish tags: paid 1/7, 2/7, 3/7, 4/7, 5/7 and free 1/2, 1.
+        let mut waiters = vec![("free", 2), ("free", 2)];
+        waiters.extend([("paid", 7); 5]);
+        let order = served(&waiters).await;
+        assert_eq!(order, [2, 3, 4, 0, 5, 6, 1]);
+    }
+
+    #[tokio::test]
+    async fn waiters_are_shed_after_the_max_wait() {
+        let scheduler = Scheduler::new(Some(1), Duration::from_millis(10));
+        let (held, _) = scheduler.acquire("paid", 1).await.unwrap();
+        assert!(scheduler.acquire("free", 1).await.is_err());
+
+        // The waiter that gave up doesn't take the slot once it's freed.
+        drop(held);
+        assert_eq!(scheduler.inner.lock().unwrap().in_use, 0);
+        let (slot, waited) = scheduler.acquire("free", 1).await.unwrap();
+        assert!(slot.is_some());
+        assert_eq!(waited, Duration::ZERO);
+    }
+}
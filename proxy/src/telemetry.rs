@@ -0,0 +1,317 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use opentelemetry::metrics::{MetricsError, Unit};
+use opentelemetry::propagation::Injector;
+use opentelemetry::trace::{TraceContextExt, TraceError};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_sdk::metrics::data::{
+    Aggregation, DataPoint, Exemplar, Gauge, Histogram, HistogramDataPoint, Metric, ScopeMetrics,
+    Sum, Temporality,
+};
+use opentelemetry_sdk::metrics::reader::{
+    DefaultAggregationSelector, DefaultTemporalitySelector, MetricProducer,
+};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, AttributeSet, Resource, Scope};
+use prometheus::proto::{MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::{metrics, State};
+
+const DEFAULT_SERVICE_NAME: &str = "ogmios-proxy";
+const DEFAULT_METRIC_EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+fn resource() -> Resource {
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or(DEFAULT_SERVICE_NAME.to_string());
+    Resource::new(vec![KeyValue::new("service.name", service_name)])
+}
+
+/// Builds the layer exporting spans over OTLP, only when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. The
+/// exporter reads the endpoint and the rest of the standard `OTEL_*` variables by itself.
+pub fn layer<S>() -> Result<Option<impl Layer<S>>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+        return Ok(None);
+    }
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(trace::config().with_resource(resource()))
+        .install_batch(runtime::Tokio)?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Pushes the metrics over OTLP every `OTEL_METRIC_EXPORT_INTERVAL` milliseconds, only when
+/// `OTEL_METRICS_EXPORTER=otlp`. The prometheus endpoint keeps being served, the same families are
+/// read from its registry and converted, so both report the same series.
+pub fn meter_provider(state: Arc<State>) -> Result<Option<SdkMeterProvider>, MetricsError> {
+    if std::env::var("OTEL_METRICS_EXPORTER").as_deref() != Ok("otlp") {
+        return Ok(None);
+    }
+
+    let interval = std::env::var("OTEL_METRIC_EXPORT_INTERVAL")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+        .filter(|interval| *interval > 0)
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_METRIC_EXPORT_INTERVAL);
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .build_metrics_exporter(
+            Box::new(DefaultAggregationSelector::new()),
+            Box::new(DefaultTemporalitySelector::new()),
+        )?;
+    state
+        .metrics
+        .exemplars
+        .enabled
+        .store(true, Ordering::Relaxed);
+    let reader = PeriodicReader::builder(exporter, runtime::Tokio)
+        .with_interval(interval)
+        .with_producer(PrometheusProducer {
+            state: state.clone(),
+            start_time: SystemTime::now(),
+        })
+        .build();
+
+    // The producer can't wait on the state, the computed metrics are updated alongside instead.
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            metrics::update_computed(&state).await;
+        }
+    });
+
+    Ok(Some(
+        SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource())
+            .build(),
+    ))
+}
+
+/// Flushes the spans still buffered by the exporter, and the metrics when they're pushed.
+pub fn shutdown(meter_provider: Option<SdkMeterProvider>) {
+    global::shutdown_tracer_provider();
+    if let Some(meter_provider) = meter_provider {
+        if let Err(err) = meter_provider.shutdown() {
+            tracing::error!(error = err.to_string(), "error flushing metrics");
+        }
+    }
+}
+
+/// Converts the families of the prometheus registry into OTLP metrics: counters into cumulative
+/// monotonic sums, gauges into gauges and histograms into cumulative histograms.
+struct PrometheusProducer {
+    state: Arc<State>,
+    start_time: SystemTime,
+}
+impl std::fmt::Debug for PrometheusProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PrometheusProducer").finish_non_exhaustive()
+    }
+}
+impl MetricProducer for PrometheusProducer {
+    fn produce(&self) -> opentelemetry::metrics::Result<ScopeMetrics> {
+        let now = SystemTime::now();
+        let metrics = self
+            .state
+            .metrics
+            .metrics_collected()
+            .iter()
+            .filter_map(|family| {
+                Some(Metric {
+                    name: family.get_name().to_string().into(),
+                    description: family.get_help().to_string().into(),
+                    unit: Unit::new(""),
+                    data: self.aggregation(family, now)?,
+                })
+            })
+            .collect();
+
+        Ok(ScopeMetrics {
+            scope: Scope::new(
+                DEFAULT_SERVICE_NAME,
+                Some(env!("CARGO_PKG_VERSION")),
+                None::<&'static str>,
+                None,
+            ),
+            metrics,
+        })
+    }
+}
+impl PrometheusProducer {
+    fn aggregation(&self, family: &MetricFamily, now: SystemTime) -> Option<Box<dyn Aggregation>> {
+        let exemplars = &self.state.metrics.exemplars;
+        let data_point = |metric: &prometheus::proto::Metric, value: f64| DataPoint {
+            attributes: attributes(metric),
+            start_time: Some(self.start_time),
+            time: Some(now),
+            value,
+            exemplars: exemplars.take(family.get_name(), metric),
+        };
+
+        match family.get_field_type() {
+            MetricType::COUNTER => Some(Box::new(Sum {
+                data_points: family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| data_point(metric, metric.get_counter().get_value()))
+                    .collect(),
+                temporality: Temporality::Cumulative,
+                is_monotonic: true,
+            })),
+            MetricType::GAUGE => Some(Box::new(Gauge {
+                data_points: family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| data_point(metric, metric.get_gauge().get_value()))
+                    .collect(),
+            })),
+            MetricType::HISTOGRAM => Some(Box::new(Histogram {
+                data_points: family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| {
+                        let histogram = metric.get_histogram();
+                        let buckets = histogram.get_bucket();
+                        // Prometheus buckets count every sample up to their bound, OTLP ones
+                        // only those above the previous bound, with an implied +Inf last.
+                        let mut bucket_counts = Vec::with_capacity(buckets.len() + 1);
+                        let mut previous = 0;
+                        for bucket in buckets {
+                            bucket_counts.push(bucket.get_cumulative_count() - previous);
+                            previous = bucket.get_cumulative_count();
+                        }
+                        bucket_counts.push(histogram.get_sample_count().saturating_sub(previous));
+
+                        HistogramDataPoint {
+                            attributes: attributes(metric),
+                            start_time: self.start_time,
+                            time: now,
+                            count: histogram.get_sample_count(),
+                            bounds: buckets
+                                .iter()
+                                .map(|bucket| bucket.get_upper_bound())
+                                .collect(),
+                            bucket_counts,
+                            min: None,
+                            max: None,
+                            sum: histogram.get_sample_sum(),
+                            exemplars: exemplars.take(family.get_name(), metric),
+                        }
+                    })
+                    .collect(),
+                temporality: Temporality::Cumulative,
+            })),
+            _ => None,
+        }
+    }
+}
+
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// The last sampled trace of each series of the latency histograms and error counters, attached
+/// as an exemplar the next time the metrics are pushed. The prometheus client can't expose
+/// exemplars, so they're only recorded when the metrics are pushed over OTLP.
+#[derive(Debug, Default)]
+pub struct Exemplars {
+    enabled: AtomicBool,
+    last: Mutex<HashMap<SeriesKey, Exemplar<f64>>>,
+}
+impl Exemplars {
+    /// Whether the series of the metrics should be recorded, to skip building their labels.
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Keeps the trace of the current span for the series, when it's sampled.
+    pub fn record(&self, name: &str, labels: &[String], values: &[&str], value: f64) {
+        let context = tracing::Span::current().context();
+        let span = context.span();
+        let span_context = span.span_context();
+        if !span_context.is_sampled() {
+            return;
+        }
+
+        let mut labels: Vec<(String, String)> = labels
+            .iter()
+            .zip(values)
+            .map(|(name, value)| (name.clone(), value.to_string()))
+            .collect();
+        labels.sort();
+        self.last.lock().unwrap().insert(
+            (name.to_string(), labels),
+            Exemplar {
+                filtered_attributes: vec![],
+                time: SystemTime::now(),
+                value,
+                span_id: span_context.span_id().to_bytes(),
+                trace_id: span_context.trace_id().to_bytes(),
+            },
+        );
+    }
+
+    /// The exemplar of the series, each one is only pushed once.
+    fn take(&self, name: &str, metric: &prometheus::proto::Metric) -> Vec<Exemplar<f64>> {
+        // Prometheus keeps the labels sorted by name.
+        let labels = metric
+            .get_label()
+            .iter()
+            .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+            .collect();
+        self.last
+            .lock()
+            .unwrap()
+            .remove(&(name.to_string(), labels))
+            .into_iter()
+            .collect()
+    }
+}
+
+fn attributes(metric: &prometheus::proto::Metric) -> AttributeSet {
+    let labels: Vec<KeyValue> = metric
+        .get_label()
+        .iter()
+        .map(|label| KeyValue::new(label.get_name().to_string(), label.get_value().to_string()))
+        .collect();
+    AttributeSet::from(labels.as_slice())
+}
+
+/// Adds the `traceparent` header of the current span, so the upstream can continue the trace.
+/// Nothing is added when the export is disabled.
+pub fn inject_context(headers: &mut HeaderMap) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
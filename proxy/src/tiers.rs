@@ -1,39 +1,366 @@
-use notify::{Event, PollWatcher, RecursiveMode, Watcher};
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use futures_util::TryStreamExt;
+use notify::{RecursiveMode, Watcher};
+use operator::{
+    kube::{
+        runtime::watcher::{self, Config, Event},
+        Api, Client, ResourceExt,
+    },
+    OgmiosTier, OgmiosTierRate, OgmiosTierSchedule, TierOverrides as TierOverridesSpec,
+};
 use regex::Regex;
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-use std::{error::Error, fs, sync::Arc, time::Duration};
-use tokio::runtime::{Handle, Runtime};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::{collections::HashMap, error::Error, fs, sync::Arc, time::Duration};
+use tokio::pin;
 use tracing::{error, info, instrument, warn};
 
+use crate::utils::handle_legacy_networks;
 use crate::State;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Tier {
     pub name: String,
     pub rates: Vec<TierRate>,
     pub max_connections: usize,
+    /// Whether the key can be sent as the `dmtr-api-key` query parameter, for browser clients
+    /// that can't set headers on websocket handshakes.
+    #[serde(default)]
+    pub allow_query_key: bool,
+    /// When set, only these JSON-RPC methods are forwarded.
+    pub allowed_methods: Option<Vec<String>>,
+    /// JSON-RPC methods never forwarded, checked after the allowlist.
+    #[serde(default)]
+    pub denied_methods: Vec<String>,
+    /// Cap of JSON-RPC requests waiting for a response at once, across all the sessions.
+    pub max_in_flight: Option<usize>,
+    /// Extra rates applied only to the given JSON-RPC methods, keyed by method name.
+    #[serde(default)]
+    pub methods: HashMap<String, Vec<TierRate>>,
+    pub bandwidth: Option<TierBandwidth>,
+    /// Requests allowed in each UTC calendar day, on top of the rates.
+    pub daily_requests: Option<u64>,
+    /// Requests allowed in each UTC calendar month.
+    pub monthly_requests: Option<u64>,
+    /// Weight of the tier when the upstream is saturated, a tier with twice the weight gets its
+    /// requests forwarded twice as often. Defaults to 1.
+    pub priority: Option<u32>,
+    /// When set, only ports of these networks can use the tier, eg to keep mainnet on paid tiers.
+    pub allowed_networks: Option<Vec<String>>,
+    /// Windows in which the rates are multiplied, eg doubled off-peak.
+    #[serde(default)]
+    pub schedules: Vec<TierSchedule>,
+    /// Moves ports created longer ago than the trial to another tier.
+    pub trial: Option<TierTrial>,
+    /// Units charged to the general rates by method, eg more for expensive ledger queries.
+    #[serde(default)]
+    pub costs: HashMap<String, usize>,
+    /// Units charged for other messages, defaults to 1.
+    pub default_cost: Option<usize>,
+    /// Websocket sessions are closed once open for this long, the client can reconnect.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub max_session_duration: Option<Duration>,
+    /// Whether websocket messages are compressed with permessage-deflate, on each leg whose peer
+    /// agrees to it.
+    #[serde(default)]
+    pub compression: bool,
 }
-#[derive(Debug, Clone, Deserialize)]
+impl Tier {
+    /// Messages that aren't JSON-RPC calls are only let through when there's no allowlist.
+    pub fn is_method_allowed(&self, method: Option<&str>) -> bool {
+        let allowed = match (&self.allowed_methods, method) {
+            (None, _) => true,
+            (Some(allowed), Some(method)) => allowed.iter().any(|m| m == method),
+            (Some(_), None) => false,
+        };
+
+        allowed && !method.is_some_and(|method| self.denied_methods.iter().any(|m| m == method))
+    }
+
+    pub fn is_network_allowed(&self, network: &str) -> bool {
+        self.allowed_networks
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|n| handle_legacy_networks(n) == network))
+    }
+
+    /// Units a message takes from the general rates.
+    pub fn cost(&self, method: Option<&str>) -> usize {
+        method
+            .and_then(|method| self.costs.get(method))
+            .copied()
+            .unwrap_or(self.default_cost.unwrap_or(1))
+    }
+
+    /// Product of the multipliers of the windows that contain `now`, 1 outside of them.
+    pub fn multiplier_at(&self, now: DateTime<Utc>) -> f64 {
+        self.schedules
+            .iter()
+            .filter(|schedule| schedule.window.contains(now))
+            .map(|schedule| schedule.multiplier)
+            .product()
+    }
+
+    /// The tier with the parameters overridden by a port.
+    pub fn with_overrides(&self, overrides: &TierOverrides) -> Self {
+        let mut tier = self.clone();
+        if let Some(rates) = &overrides.rates {
+            tier.rates = rates.clone();
+        }
+        if let Some(burst) = overrides.burst {
+            for rate in &mut tier.rates {
+                rate.burst = Some(burst);
+            }
+        }
+        tier.max_connections = overrides.max_connections.unwrap_or(tier.max_connections);
+        tier.max_in_flight = overrides.max_in_flight.or(tier.max_in_flight);
+        tier.daily_requests = overrides.daily_requests.or(tier.daily_requests);
+        tier.monthly_requests = overrides.monthly_requests.or(tier.monthly_requests);
+        tier.allow_query_key = overrides.allow_query_key.unwrap_or(tier.allow_query_key);
+        tier
+    }
+}
+
+/// Parameters of a tier set on a port, merged over the tier it references.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TierOverrides {
+    pub max_connections: Option<usize>,
+    pub max_in_flight: Option<usize>,
+    pub burst: Option<usize>,
+    pub rates: Option<Vec<TierRate>>,
+    pub daily_requests: Option<u64>,
+    pub monthly_requests: Option<u64>,
+    pub allow_query_key: Option<bool>,
+}
+impl TryFrom<&TierOverridesSpec> for TierOverrides {
+    type Error = &'static str;
+
+    fn try_from(spec: &TierOverridesSpec) -> Result<Self, Self::Error> {
+        let rates = match &spec.rates {
+            Some(rates) => Some(
+                rates
+                    .iter()
+                    .map(TierRate::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            max_connections: spec.max_connections.map(|max| max as usize),
+            max_in_flight: spec.max_in_flight.map(|max| max as usize),
+            burst: spec.burst.map(|burst| burst as usize),
+            rates,
+            daily_requests: spec.daily_requests,
+            monthly_requests: spec.monthly_requests,
+            allow_query_key: spec.allow_query_key,
+        })
+    }
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TierBandwidth {
+    /// Bytes allowed in both directions for each interval.
+    pub limit: u64,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct TierRate {
+    /// Requests refilled each interval, the sustained rate.
     pub limit: usize,
+    /// Requests that can be sent at once when the consumer was quiet, defaults to the limit.
+    pub burst: Option<usize>,
     #[serde(deserialize_with = "deserialize_duration")]
     pub interval: Duration,
+    /// Requests added at each refill, to refill in smaller steps than the limit.
+    #[serde(default)]
+    pub refill: Option<usize>,
+    /// Time between refills, defaults to the interval.
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub refill_interval: Option<Duration>,
+    /// Requests in the bucket when it's created, defaults to a full bucket.
+    #[serde(default)]
+    pub initial: Option<usize>,
+}
+impl TierRate {
+    /// Size of the bucket, never below one refill. Without refill steps that's the sustained
+    /// rate.
+    pub fn capacity(&self) -> usize {
+        self.burst.unwrap_or(self.limit).max(self.refill_amount())
+    }
+
+    pub fn refill_amount(&self) -> usize {
+        self.refill.unwrap_or(self.limit)
+    }
+
+    pub fn refill_interval(&self) -> Duration {
+        self.refill_interval.unwrap_or(self.interval)
+    }
+
+    pub fn initial(&self) -> usize {
+        self.initial.unwrap_or(usize::MAX).min(self.capacity())
+    }
+
+    /// The rate with its amounts multiplied, by a schedule of the tier. Amounts are kept at one
+    /// request at least.
+    pub fn scaled(&self, multiplier: f64) -> Self {
+        let scale = |value: usize| ((value as f64 * multiplier).round() as usize).max(1);
+        Self {
+            limit: scale(self.limit),
+            burst: self.burst.map(scale),
+            refill: self.refill.map(scale),
+            initial: self
+                .initial
+                .map(|initial| (initial as f64 * multiplier).round() as usize),
+            ..self.clone()
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TierTrial {
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+    pub post_trial_tier: String,
+}
+impl TierTrial {
+    /// When the trial of a port created at `created_at` ends.
+    pub fn ends_at(&self, created_at: DateTime<Utc>) -> DateTime<Utc> {
+        created_at + self.duration
+    }
+}
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "OgmiosTierSchedule")]
+pub struct TierSchedule {
+    pub multiplier: f64,
+    pub window: TierWindow,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum TierWindow {
+    /// Between two UTC times of the given days, wrapping around midnight when `to` is earlier.
+    Daily {
+        from: NaiveTime,
+        to: NaiveTime,
+        days: Vec<Weekday>,
+    },
+    /// For `duration` every `every`, counting from `anchor`.
+    Periodic {
+        anchor: DateTime<Utc>,
+        every: Duration,
+        duration: Duration,
+    },
+}
+impl TierWindow {
+    fn contains(&self, now: DateTime<Utc>) -> bool {
+        match self {
+            TierWindow::Daily { from, to, days } => {
+                // Past midnight, a window wrapping around it belongs to the day it started on.
+                let time = now.time();
+                let day = match from < to {
+                    true if *from <= time && time < *to => now.weekday(),
+                    false if *from <= time => now.weekday(),
+                    false if time < *to => now.weekday().pred(),
+                    _ => return false,
+                };
+                days.is_empty() || days.contains(&day)
+            }
+            TierWindow::Periodic {
+                anchor,
+                every,
+                duration,
+            } => {
+                let every = every.as_secs() as i64;
+                let elapsed = (now - *anchor).num_seconds().rem_euclid(every.max(1));
+                elapsed < duration.as_secs() as i64
+            }
+        }
+    }
+}
+impl TryFrom<OgmiosTierSchedule> for TierSchedule {
+    type Error = &'static str;
+
+    fn try_from(spec: OgmiosTierSchedule) -> Result<Self, Self::Error> {
+        TierSchedule::try_from(&spec)
+    }
 }
+impl TryFrom<&OgmiosTierSchedule> for TierSchedule {
+    type Error = &'static str;
+
+    fn try_from(spec: &OgmiosTierSchedule) -> Result<Self, Self::Error> {
+        if !spec.multiplier.is_finite() || spec.multiplier <= 0.0 {
+            return Err("Invalid schedule multiplier");
+        }
+        let time = |value: &str| {
+            NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| "Invalid schedule time")
+        };
+
+        let window = match spec {
+            OgmiosTierSchedule {
+                from: Some(from),
+                to: Some(to),
+                every: None,
+                ..
+            } => TierWindow::Daily {
+                from: time(from)?,
+                to: time(to)?,
+                days: spec
+                    .days
+                    .iter()
+                    .flatten()
+                    .map(|day| day.parse().map_err(|_| "Invalid schedule day"))
+                    .collect::<Result<_, _>>()?,
+            },
+            OgmiosTierSchedule {
+                anchor: Some(anchor),
+                every: Some(every),
+                duration: Some(duration),
+                from: None,
+                ..
+            } => TierWindow::Periodic {
+                anchor: DateTime::parse_from_rfc3339(anchor)
+                    .map_err(|_| "Invalid schedule anchor")?
+                    .to_utc(),
+                every: parse_duration(every)?,
+                duration: parse_duration(duration)?,
+            },
+            _ => return Err("A schedule needs either from and to, or anchor, every and duration"),
+        };
+
+        Ok(Self {
+            multiplier: spec.multiplier,
+            window,
+        })
+    }
+}
+
 pub fn deserialize_duration<'de, D: Deserializer<'de>>(
     deserializer: D,
 ) -> Result<Duration, D::Error> {
     let value: String = Deserialize::deserialize(deserializer)?;
-    let regex = Regex::new(r"([\d]+)([\w])").unwrap();
-    let captures = regex.captures(&value);
-    if captures.is_none() {
-        return Err(<D::Error as serde::de::Error>::custom(
-            "Invalid tier interval format",
-        ));
-    }
+    parse_duration(&value).map_err(<D::Error as serde::de::Error>::custom)
+}
+
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let value: Option<String> = Deserialize::deserialize(deserializer)?;
+    value
+        .map(|value| parse_duration(&value).map_err(<D::Error as serde::de::Error>::custom))
+        .transpose()
+}
+
+fn parse_duration(value: &str) -> Result<Duration, &'static str> {
+    let regex = Regex::new(r"^(\d+)([smhd])$").unwrap();
+    let Some(captures) = regex.captures(value) else {
+        return Err("Invalid tier interval format");
+    };
 
-    let captures = captures.unwrap();
-    let number = captures.get(1).unwrap().as_str().parse::<u64>().unwrap();
+    let number = captures
+        .get(1)
+        .unwrap()
+        .as_str()
+        .parse::<u64>()
+        .map_err(|_| "Invalid tier interval format")?;
     let symbol = captures.get(2).unwrap().as_str();
 
     match symbol {
@@ -41,65 +368,338 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
         "m" => Ok(Duration::from_secs(number * 60)),
         "h" => Ok(Duration::from_secs(number * 60 * 60)),
         "d" => Ok(Duration::from_secs(number * 60 * 60 * 24)),
-        _ => Err(<D::Error as serde::de::Error>::custom(
-            "Invalid symbol tier interval",
-        )),
+        _ => Err("Invalid symbol tier interval"),
     }
 }
 
-#[instrument("tiers background service", skip_all)]
-pub fn start(state: Arc<State>) {
-    tokio::spawn(async move {
-        if let Err(err) = update_tiers(state.clone()).await {
-            error!(error = err.to_string(), "error to update tiers");
-            return;
+impl TryFrom<&OgmiosTierRate> for TierRate {
+    type Error = &'static str;
+
+    fn try_from(rate: &OgmiosTierRate) -> Result<Self, Self::Error> {
+        let interval = parse_duration(&rate.interval)?;
+        let refill_interval = rate
+            .refill_interval
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?;
+        // The limiter can't refill a bucket every 0 seconds.
+        if interval.is_zero() || refill_interval.is_some_and(|interval| interval.is_zero()) {
+            return Err("Tier rate interval must be above 0");
+        }
+        if rate.limit == 0 {
+            return Err("Tier rate limit must be above 0");
         }
 
-        let (tx, mut rx) = tokio::sync::mpsc::channel::<Event>(1);
+        Ok(Self {
+            limit: rate.limit as usize,
+            burst: rate.burst.map(|burst| burst as usize),
+            interval,
+            refill: rate.refill.map(|refill| refill as usize),
+            refill_interval,
+            initial: rate.initial.map(|initial| initial as usize),
+        })
+    }
+}
+impl TryFrom<&OgmiosTier> for Tier {
+    type Error = &'static str;
 
-        let watcher_config = notify::Config::default()
-            .with_compare_contents(true)
-            .with_poll_interval(state.config.proxy_tiers_poll_interval);
+    fn try_from(crd: &OgmiosTier) -> Result<Self, Self::Error> {
+        let spec = &crd.spec;
+        let rates = |rates: &Vec<OgmiosTierRate>| {
+            rates
+                .iter()
+                .map(TierRate::try_from)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let methods = spec
+            .methods
+            .iter()
+            .flatten()
+            .map(|(method, method_rates)| Ok((method.clone(), rates(method_rates)?)))
+            .collect::<Result<_, Self::Error>>()?;
+        let bandwidth = match &spec.bandwidth {
+            Some(bandwidth) => Some(TierBandwidth {
+                limit: bandwidth.limit,
+                interval: parse_duration(&bandwidth.interval)?,
+            }),
+            None => None,
+        };
 
-        let watcher_result = PollWatcher::new(
-            move |res| {
-                if let Ok(event) = res {
-                    runtime_handle()
-                        .block_on(async { tx.send(event).await })
-                        .unwrap();
-                }
+        Ok(Self {
+            name: crd.name_any(),
+            rates: rates(spec.rates.as_ref().ok_or("rates is required")?)?,
+            max_connections: spec.max_connections.ok_or("maxConnections is required")? as usize,
+            allow_query_key: spec.allow_query_key.unwrap_or_default(),
+            allowed_methods: spec.allowed_methods.clone(),
+            denied_methods: spec.denied_methods.clone().unwrap_or_default(),
+            max_in_flight: spec.max_in_flight.map(|max| max as usize),
+            methods,
+            bandwidth,
+            daily_requests: spec.daily_requests,
+            monthly_requests: spec.monthly_requests,
+            priority: spec.priority,
+            allowed_networks: spec.allowed_networks.clone(),
+            schedules: spec
+                .schedules
+                .iter()
+                .flatten()
+                .map(TierSchedule::try_from)
+                .collect::<Result<_, _>>()?,
+            costs: spec
+                .costs
+                .iter()
+                .flatten()
+                .map(|(method, cost)| (method.clone(), *cost as usize))
+                .collect(),
+            default_cost: spec.default_cost.map(|cost| cost as usize),
+            max_session_duration: spec
+                .max_session_duration
+                .as_deref()
+                .map(parse_duration)
+                .transpose()?,
+            compression: spec.compression.unwrap_or_default(),
+            trial: match &spec.trial {
+                Some(trial) => Some(TierTrial {
+                    duration: parse_duration(&trial.duration)?,
+                    post_trial_tier: trial.post_trial_tier.clone(),
+                }),
+                None => None,
             },
-            watcher_config,
+        })
+    }
+}
+
+/// Reads the tiers from the `OgmiosTier` resources of the proxy namespace, or from the toml file
+/// at `PROXY_TIERS_PATH` when it's set.
+pub fn start(state: Arc<State>) {
+    match state.config().proxy_tiers_path.clone() {
+        Some(path) => watch_file(state, path),
+        None => watch_crds(state),
+    }
+}
+
+/// Replaces the tiers, the limiters are rebuilt with the new rates. Nothing is reset when the
+/// tiers didn't change, eg on a watcher resync, so consumers keep their limiter balance.
+async fn set_tiers(state: &State, tiers: HashMap<String, Tier>) -> bool {
+    let mut current = state.tiers.write().await;
+    if *current == tiers {
+        return false;
+    }
+
+    *current = tiers;
+    state.limiter.write().await.clear();
+    true
+}
+
+/// Merges `value` over `base`. Objects are merged key by key, unset fields keep the value of the
+/// base and anything else replaces it.
+fn merge(base: &mut Value, value: Value) {
+    match (base, value) {
+        (Value::Object(base), Value::Object(value)) => {
+            for (key, value) in value {
+                match base.get_mut(&key) {
+                    Some(base) if !value.is_null() => merge(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        (_, Value::Null) => {}
+        (base, value) => *base = value,
+    }
+}
+
+fn resolve_extended(
+    name: &str,
+    raw: &HashMap<String, Value>,
+    resolved: &mut HashMap<String, Option<Value>>,
+    path: &mut Vec<String>,
+) -> Option<Value> {
+    if let Some(value) = resolved.get(name) {
+        return value.clone();
+    }
+    if path.iter().any(|tier| tier == name) {
+        error!(
+            tier = name,
+            cycle = format!("{} -> {name}", path.join(" -> ")),
+            "ignoring tiers extending each other"
         );
+        return None;
+    }
+
+    let value = raw.get(name)?;
+    let result = match value.get("extends").and_then(Value::as_str) {
+        Some(base) if !raw.contains_key(base) => {
+            error!(
+                tier = name,
+                extends = base,
+                "ignoring tier extending an unknown tier"
+            );
+            None
+        }
+        Some(base) => {
+            path.push(name.to_string());
+            let base = resolve_extended(base, raw, resolved, path);
+            path.pop();
+            base.map(|mut base| {
+                merge(&mut base, value.clone());
+                base
+            })
+        }
+        None => Some(value.clone()),
+    };
+    resolved.insert(name.to_string(), result.clone());
+    result
+}
+
+/// Resolves the `extends` of the tiers, keyed by name: each tier is merged over the one it
+/// extends, recursively. Tiers extending an unknown tier, or themselves through other tiers,
+/// are logged and left out.
+fn resolve_extends(raw: &HashMap<String, Value>) -> HashMap<String, Value> {
+    let mut resolved = HashMap::new();
+    raw.keys()
+        .filter_map(|name| {
+            let value = resolve_extended(name, raw, &mut resolved, &mut Vec::new())?;
+            Some((name.clone(), value))
+        })
+        .collect()
+}
+
+fn tier_from_crd(crd: &OgmiosTier) -> Option<Tier> {
+    match Tier::try_from(crd) {
+        Ok(tier) => Some(tier),
+        Err(err) => {
+            error!(error = err, tier = crd.name_any(), "ignoring invalid tier");
+            None
+        }
+    }
+}
+
+/// The tiers of the resources, by name, with their `extends` resolved.
+fn tiers_from_crds(crds: &HashMap<String, OgmiosTier>) -> HashMap<String, Tier> {
+    let raw = crds
+        .iter()
+        .filter_map(|(name, crd)| Some((name.clone(), serde_json::to_value(&crd.spec).ok()?)))
+        .collect();
+
+    resolve_extends(&raw)
+        .into_iter()
+        .filter_map(|(name, spec)| {
+            let mut crd = crds.get(&name)?.clone();
+            crd.spec = match serde_json::from_value(spec) {
+                Ok(spec) => spec,
+                Err(err) => {
+                    error!(
+                        error = err.to_string(),
+                        tier = name,
+                        "ignoring invalid tier"
+                    );
+                    return None;
+                }
+            };
+            tier_from_crd(&crd)
+        })
+        .map(|tier| (tier.name.clone(), tier))
+        .collect()
+}
+
+#[instrument("tiers background service", skip_all)]
+fn watch_crds(state: Arc<State>) {
+    tokio::spawn(async move {
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+
+        let api = Api::<OgmiosTier>::namespaced(client, &state.config().proxy_namespace);
+        let stream = watcher::watcher(api, Config::default());
+        pin!(stream);
+
+        // Resources are kept as read, a change to a tier can change the tiers extending it.
+        let mut crds: HashMap<String, OgmiosTier> = HashMap::new();
+        loop {
+            match stream.try_next().await {
+                // Stream restart, also run on startup.
+                Ok(Some(Event::Restarted(restarted))) => {
+                    crds = restarted
+                        .into_iter()
+                        .map(|crd| (crd.name_any(), crd))
+                        .collect();
+                    if set_tiers(&state, tiers_from_crds(&crds)).await {
+                        info!("tiers loaded");
+                    }
+                    state.tiers_synced.store(true, Ordering::Relaxed);
+                }
+                Ok(Some(Event::Applied(crd))) => {
+                    let name = crd.name_any();
+                    crds.insert(name.clone(), crd);
+                    if set_tiers(&state, tiers_from_crds(&crds)).await {
+                        info!(tier = name, "tier modified");
+                    }
+                }
+                Ok(Some(Event::Deleted(crd))) => {
+                    crds.remove(&crd.name_any());
+                    set_tiers(&state, tiers_from_crds(&crds)).await;
+                    info!(tier = crd.name_any(), "tier deleted");
+                }
+                Ok(None) => {
+                    error!("tiers: Empty response from watcher.");
+                    continue;
+                }
+                Err(err) => {
+                    error!(error = err.to_string(), "tiers: Failed to watch tiers.");
+                    std::process::exit(1);
+                }
+            }
+        }
+    });
+}
+
+#[instrument("tiers background service", skip_all)]
+fn watch_file(state: Arc<State>, path: PathBuf) {
+    tokio::spawn(async move {
+        if let Err(err) = update_tiers(&state, &path).await {
+            error!(error = err.to_string(), "error to update tiers");
+            return;
+        }
+        state.tiers_synced.store(true, Ordering::Relaxed);
+
+        // A full channel already has a reload pending, bursts of events are coalesced.
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<notify::Event>(1);
+        let watcher_result = notify::recommended_watcher(move |res| {
+            if let Ok(event) = res {
+                let _ = tx.try_send(event);
+            }
+        });
         if let Err(err) = watcher_result {
             error!(error = err.to_string(), "error to watcher tier");
             return;
         }
 
+        // Mounted ConfigMaps are updated by swapping a symlink in the directory, the file itself
+        // is never written to. A bare file name has an empty parent, the current directory.
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
         let mut watcher = watcher_result.unwrap();
-        let watcher_result =
-            watcher.watch(&state.config.proxy_tiers_path, RecursiveMode::Recursive);
+        let watcher_result = watcher.watch(&dir, RecursiveMode::NonRecursive);
         if let Err(err) = watcher_result {
             error!(error = err.to_string(), "error to watcher tier");
             return;
         }
 
-        loop {
-            let result = rx.recv().await;
-            if result.is_some() {
-                if let Err(err) = update_tiers(state.clone()).await {
-                    error!(error = err.to_string(), "error to update tiers");
-                    continue;
-                }
-
-                info!("tiers modified");
+        while rx.recv().await.is_some() {
+            if let Err(err) = update_tiers(&state, &path).await {
+                error!(error = err.to_string(), "error to update tiers");
             }
         }
     });
 }
 
-async fn update_tiers(state: Arc<State>) -> Result<(), Box<dyn Error>> {
-    let contents = fs::read_to_string(&state.config.proxy_tiers_path)?;
+async fn update_tiers(state: &State, path: &Path) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
 
     let value: Value = toml::from_str(&contents)?;
     let tiers_value: Option<&Value> = value.get("tiers");
@@ -108,24 +708,120 @@ async fn update_tiers(state: Arc<State>) -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    let tiers = serde_json::from_value::<Vec<Tier>>(tiers_value.unwrap().to_owned())?;
+    let raw = serde_json::from_value::<Vec<Value>>(tiers_value.unwrap().to_owned())?
+        .into_iter()
+        .filter_map(|tier| Some((tier.get("name")?.as_str()?.to_string(), tier)))
+        .collect();
 
-    *state.tiers.write().await = tiers
+    let tiers = resolve_extends(&raw)
+        .into_values()
+        .map(serde_json::from_value::<Tier>)
+        .collect::<Result<Vec<_>, _>>()?
         .into_iter()
         .map(|tier| (tier.name.clone(), tier))
         .collect();
-
-    state.limiter.write().await.clear();
+    if set_tiers(state, tiers).await {
+        info!("tiers modified");
+    }
 
     Ok(())
 }
 
-fn runtime_handle() -> Handle {
-    match Handle::try_current() {
-        Ok(h) => h,
-        Err(_) => {
-            let rt = Runtime::new().unwrap();
-            rt.handle().clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daily(from: &str, to: &str, days: &[Weekday]) -> TierWindow {
+        TierWindow::Daily {
+            from: NaiveTime::parse_from_str(from, "%H:%M").unwrap(),
+            to: NaiveTime::parse_from_str(to, "%H:%M").unwrap(),
+            days: days.to_vec(),
         }
     }
+
+    fn at(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    fn rate(limit: u32, interval: &str, refill_interval: Option<&str>) -> OgmiosTierRate {
+        OgmiosTierRate {
+            limit,
+            burst: None,
+            interval: interval.into(),
+            refill: None,
+            refill_interval: refill_interval.map(String::from),
+            initial: None,
+        }
+    }
+
+    #[test]
+    fn durations_are_a_number_and_a_unit() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+
+        for value in [
+            "", "m", "1", "1x", "1ms", "10m30s", "1m ", " 1m", "-1m", "1.5h", "x1m",
+        ] {
+            assert!(parse_duration(value).is_err(), "{value:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn rates_with_a_zero_interval_or_limit_are_refused() {
+        assert!(TierRate::try_from(&rate(10, "1m", None)).is_ok());
+        assert!(TierRate::try_from(&rate(10, "1m", Some("10s"))).is_ok());
+        assert!(TierRate::try_from(&rate(10, "0s", None)).is_err());
+        assert!(TierRate::try_from(&rate(10, "0m", None)).is_err());
+        assert!(TierRate::try_from(&rate(10, "1m", Some("0s"))).is_err());
+        assert!(TierRate::try_from(&rate(0, "1m", None)).is_err());
+    }
+
+    #[test]
+    fn daily_windows_are_checked_on_their_days() {
+        let window = daily("08:00", "18:00", &[Weekday::Sat]);
+
+        // 2026-10-17 is a Saturday.
+        assert!(window.contains(at("2026-10-17T08:00:00Z")));
+        assert!(window.contains(at("2026-10-17T17:59:59Z")));
+        assert!(!window.contains(at("2026-10-17T18:00:00Z")));
+        assert!(!window.contains(at("2026-10-17T07:59:59Z")));
+        assert!(!window.contains(at("2026-10-18T12:00:00Z")));
+    }
+
+    #[test]
+    fn windows_wrapping_around_midnight_belong_to_the_day_they_start() {
+        let window = daily("22:00", "02:00", &[Weekday::Sat]);
+
+        assert!(window.contains(at("2026-10-17T22:00:00Z")));
+        assert!(window.contains(at("2026-10-17T23:59:59Z")));
+        // Sunday past midnight is still the window of Saturday.
+        assert!(window.contains(at("2026-10-18T00:00:00Z")));
+        assert!(window.contains(at("2026-10-18T01:59:59Z")));
+        assert!(!window.contains(at("2026-10-18T02:00:00Z")));
+        assert!(!window.contains(at("2026-10-18T22:00:00Z")));
+        // Saturday past midnight is the window of Friday.
+        assert!(!window.contains(at("2026-10-17T01:00:00Z")));
+        assert!(!window.contains(at("2026-10-17T12:00:00Z")));
+    }
+
+    #[test]
+    fn windows_wrapping_around_midnight_every_day() {
+        let window = daily("22:00", "02:00", &[]);
+
+        assert!(window.contains(at("2026-10-17T01:00:00Z")));
+        assert!(window.contains(at("2026-10-17T23:00:00Z")));
+        assert!(!window.contains(at("2026-10-17T12:00:00Z")));
+    }
+
+    #[test]
+    fn same_from_and_to_cover_the_whole_day() {
+        let window = daily("06:00", "06:00", &[Weekday::Sat]);
+
+        assert!(window.contains(at("2026-10-17T06:00:00Z")));
+        assert!(window.contains(at("2026-10-18T05:59:59Z")));
+        assert!(!window.contains(at("2026-10-18T06:00:00Z")));
+        assert!(!window.contains(at("2026-10-17T05:59:59Z")));
+    }
 }
@@ -0,0 +1,180 @@
+use notify::{PollWatcher, RecursiveMode, Watcher};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::{fs, io};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{error, info};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::config::Config;
+use crate::State;
+
+/// Serves the certificate currently loaded from disk. The certificate is swapped in place when the
+/// files change, so rotations apply to new handshakes without restarting the proxy.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    crt_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+}
+impl CertificateResolver {
+    pub fn try_new(crt_path: &Path, key_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let certified_key = load_certified_key(crt_path, key_path)?;
+
+        Ok(Self {
+            crt_path: crt_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            certified_key: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    pub fn reload(&self) -> Result<(), Box<dyn Error>> {
+        let certified_key = load_certified_key(&self.crt_path, &self.key_path)?;
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.read().unwrap().clone())
+    }
+}
+
+/// Builds the TLS acceptor when a certificate and key are configured. The returned watcher keeps
+/// the certificate files monitored for rotation and must be held for as long as the listener runs.
+pub fn build_tls_acceptor(
+    state: &State,
+) -> Result<Option<(TlsAcceptor, PollWatcher)>, Box<dyn Error>> {
+    let config = state.config();
+    let (crt_path, key_path) = match (&config.ssl_crt_path, &config.ssl_key_path) {
+        (Some(crt_path), Some(key_path)) => (crt_path, key_path),
+        (None, None) => return Ok(None),
+        _ => return Err("SSL_CRT_PATH and SSL_KEY_PATH must be set together".into()),
+    };
+
+    let resolver = Arc::new(CertificateResolver::try_new(crt_path, key_path)?);
+
+    let watcher_config = notify::Config::default()
+        .with_compare_contents(true)
+        .with_poll_interval(config.ssl_poll_interval);
+
+    let watcher_resolver = resolver.clone();
+    let mut watcher = PollWatcher::new(
+        move |res: notify::Result<notify::Event>| {
+            if res.is_ok() {
+                match watcher_resolver.reload() {
+                    Ok(()) => info!("tls certificate reloaded"),
+                    Err(err) => error!(error = err.to_string(), "fail to reload tls certificate"),
+                }
+            }
+        },
+        watcher_config,
+    )?;
+    watcher.watch(crt_path, RecursiveMode::NonRecursive)?;
+    watcher.watch(key_path, RecursiveMode::NonRecursive)?;
+
+    // Client certificates are optional, clients without one authenticate with their api key.
+    let builder = ServerConfig::builder();
+    let server_config = match &config.proxy_client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()?;
+            builder
+                .with_client_cert_verifier(verifier)
+                .with_cert_resolver(resolver)
+        }
+        None => builder.with_no_client_auth().with_cert_resolver(resolver),
+    };
+
+    Ok(Some((TlsAcceptor::from(Arc::new(server_config)), watcher)))
+}
+
+/// Identity of a verified client certificate, its first DNS subject alternative name or else its
+/// common name.
+pub fn client_identity(certs: Option<&[CertificateDer<'_>]>) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(certs?.first()?).ok()?;
+
+    let san = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .and_then(|san| {
+            san.value.general_names.iter().find_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+        });
+
+    san.or_else(|| {
+        cert.subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from)
+    })
+}
+
+/// TLS settings for the connections to the instances.
+pub struct UpstreamTls {
+    pub connector: TlsConnector,
+    pub server_name: Option<String>,
+}
+
+/// Builds the TLS connector for the instances when `OGMIOS_TLS` is enabled. Certificates are
+/// verified against the custom CA when one is configured, otherwise against the webpki roots.
+pub fn build_upstream_tls(config: &Config) -> Result<Option<UpstreamTls>, Box<dyn Error>> {
+    if !config.ogmios_tls {
+        return Ok(None);
+    }
+
+    let mut roots = RootCertStore::empty();
+    match &config.ogmios_tls_ca_path {
+        Some(ca_path) => {
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let client_config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Some(UpstreamTls {
+        connector: TlsConnector::from(Arc::new(client_config)),
+        server_name: config.ogmios_tls_server_name.clone(),
+    }))
+}
+
+fn load_certified_key(crt_path: &Path, key_path: &Path) -> Result<CertifiedKey, Box<dyn Error>> {
+    let certs = load_certs(crt_path)?;
+    let key = load_private_key(key_path)?;
+    let signing_key = any_supported_type(&key)?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let cert_file = fs::File::open(path)?;
+    let mut reader = io::BufReader::new(cert_file);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let key_file = fs::File::open(path)?;
+    let mut reader = io::BufReader::new(key_file);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))
+}
@@ -0,0 +1,54 @@
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::{limiter, Consumer, State};
+
+/// Consumers whose trial ended between `since` and `until`, with their post-trial tier.
+async fn ended_trials(
+    state: &State,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Vec<(Consumer, String)> {
+    let tiers = state.tiers.read().await;
+    let consumers = state.consumers.read().await;
+    consumers
+        .values()
+        .filter_map(|consumer| {
+            let trial = tiers.get(&consumer.tier)?.trial.as_ref()?;
+            let ends_at = trial.ends_at(consumer.created_at?);
+            (since < ends_at && ends_at <= until)
+                .then(|| (consumer.clone(), trial.post_trial_tier.clone()))
+        })
+        .collect()
+}
+
+/// Checks every `PROXY_TRIAL_CHECK_INTERVAL` for trials that ended since the last check. Tiers
+/// are resolved with the trial on every lookup already, this logs and counts the move and swaps
+/// the limiter of the consumer, so its open sessions get the post-trial rates right away. Trials
+/// that ended before the proxy started aren't reported again.
+pub fn start(state: Arc<State>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.config().proxy_trial_check_interval);
+        let mut since = Utc::now();
+        loop {
+            interval.tick().await;
+            let until = Utc::now();
+            for (consumer, post_trial_tier) in ended_trials(&state, since, until).await {
+                info!(
+                    consumer = consumer.to_string(),
+                    tier = consumer.tier,
+                    post_trial_tier,
+                    "trial ended, moving to the post-trial tier"
+                );
+                state.metrics.count_trial_expiration(
+                    &state.config().proxy_namespace,
+                    &consumer,
+                    &post_trial_tier,
+                );
+                limiter::rebuild(&state, &consumer).await;
+            }
+            since = until;
+        }
+    });
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpstreamStrategy {
+    RoundRobin,
+    LeastConnections,
+}
+impl FromStr for UpstreamStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-connections" => Ok(Self::LeastConnections),
+            _ => Err(format!("invalid upstream strategy: {s}")),
+        }
+    }
+}
+
+/// Picks one of the instances serving a version and keeps track of how many connections each
+/// instance is currently handling.
+#[derive(Default)]
+pub struct Upstreams {
+    next: AtomicUsize,
+    connections: Mutex<HashMap<String, usize>>,
+}
+impl Upstreams {
+    pub fn select(&self, strategy: UpstreamStrategy, instances: &[String]) -> String {
+        match strategy {
+            UpstreamStrategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                instances[next % instances.len()].clone()
+            }
+            UpstreamStrategy::LeastConnections => {
+                let connections = self.connections.lock().unwrap();
+                instances
+                    .iter()
+                    .min_by_key(|instance| connections.get(*instance).copied().unwrap_or_default())
+                    .unwrap()
+                    .clone()
+            }
+        }
+    }
+
+    /// Accounts a connection to the instance until the returned guard is dropped.
+    pub fn connect(&self, instance: &str) -> UpstreamConnection<'_> {
+        *self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(instance.to_string())
+            .or_default() += 1;
+
+        UpstreamConnection {
+            upstreams: self,
+            instance: instance.to_string(),
+        }
+    }
+}
+
+pub struct UpstreamConnection<'a> {
+    upstreams: &'a Upstreams,
+    instance: String,
+}
+impl Drop for UpstreamConnection<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.upstreams.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.instance) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
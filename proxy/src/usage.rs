@@ -0,0 +1,258 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::{Consumer, State};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsageSinkFormat {
+    /// One JSON document with every consumer of the period.
+    Json,
+    /// A record per consumer, as expected by the topic endpoint of a Kafka REST proxy.
+    KafkaRest,
+}
+impl FromStr for UsageSinkFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "kafka-rest" => Ok(Self::KafkaRest),
+            _ => Err(format!("invalid usage sink format: {s}")),
+        }
+    }
+}
+
+/// What a consumer used since the last export.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConsumerUsage {
+    consumer: String,
+    namespace: String,
+    port: String,
+    tier: String,
+    network: String,
+    requests: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+    connection_seconds: f64,
+}
+impl ConsumerUsage {
+    fn new(consumer: &Consumer) -> Self {
+        Self {
+            consumer: consumer.to_string(),
+            namespace: consumer.namespace.clone(),
+            port: consumer.port_name.clone(),
+            tier: consumer.tier.clone(),
+            network: consumer.network.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn merge(&mut self, other: ConsumerUsage) {
+        self.requests += other.requests;
+        self.bytes_received += other.bytes_received;
+        self.bytes_sent += other.bytes_sent;
+        self.connection_seconds += other.connection_seconds;
+    }
+}
+
+struct Inner {
+    usage: HashMap<String, ConsumerUsage>,
+    /// Open websocket sessions by request id, with the consumer and since when their time is
+    /// counted.
+    sessions: HashMap<String, (Consumer, Instant)>,
+    /// Start of the period.
+    since: DateTime<Utc>,
+}
+
+/// Requests, bytes and connection time of each consumer, exported to `PROXY_USAGE_SINK_URL` every
+/// `PROXY_USAGE_EXPORT_INTERVAL` and reset. Nothing is counted when no sink is configured.
+pub struct UsageAccounting {
+    enabled: bool,
+    inner: Mutex<Inner>,
+}
+impl UsageAccounting {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            inner: Mutex::new(Inner {
+                usage: Default::default(),
+                sessions: Default::default(),
+                since: Utc::now(),
+            }),
+        }
+    }
+
+    fn update(&self, consumer: &Consumer, update: impl FnOnce(&mut ConsumerUsage)) {
+        if !self.enabled {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner
+            .usage
+            .entry(consumer.to_string())
+            .or_insert_with(|| ConsumerUsage::new(consumer));
+        usage.tier.clone_from(&consumer.tier);
+        update(usage);
+    }
+
+    pub fn add_request(&self, consumer: &Consumer) {
+        self.update(consumer, |usage| usage.requests += 1);
+    }
+
+    pub fn add_bytes_received(&self, consumer: &Consumer, bytes: usize) {
+        self.update(consumer, |usage| usage.bytes_received += bytes as u64);
+    }
+
+    pub fn add_bytes_sent(&self, consumer: &Consumer, bytes: usize) {
+        self.update(consumer, |usage| usage.bytes_sent += bytes as u64);
+    }
+
+    pub fn session_opened(&self, request_id: &str, consumer: &Consumer) {
+        if !self.enabled {
+            return;
+        }
+        self.inner
+            .lock()
+            .unwrap()
+            .sessions
+            .insert(request_id.to_string(), (consumer.clone(), Instant::now()));
+    }
+
+    pub fn session_closed(&self, request_id: &str) {
+        if !self.enabled {
+            return;
+        }
+        let session = self.inner.lock().unwrap().sessions.remove(request_id);
+        if let Some((consumer, since)) = session {
+            let seconds = since.elapsed().as_secs_f64();
+            self.update(&consumer, |usage| usage.connection_seconds += seconds);
+        }
+    }
+
+    /// Ends the period, sessions still open are counted up to now and keep going in the next one.
+    fn take(&self) -> (DateTime<Utc>, DateTime<Utc>, Vec<ConsumerUsage>) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let mut connection_seconds = HashMap::new();
+        for (consumer, since) in inner.sessions.values_mut() {
+            connection_seconds
+                .entry(consumer.to_string())
+                .or_insert_with(|| (consumer.clone(), 0.0))
+                .1 += now.duration_since(*since).as_secs_f64();
+            *since = now;
+        }
+        for (name, (consumer, seconds)) in connection_seconds {
+            inner
+                .usage
+                .entry(name)
+                .or_insert_with(|| ConsumerUsage::new(&consumer))
+                .connection_seconds += seconds;
+        }
+
+        let to = Utc::now();
+        let from = std::mem::replace(&mut inner.since, to);
+        let usage = std::mem::take(&mut inner.usage).into_values().collect();
+        (from, to, usage)
+    }
+
+    /// Puts back the usage of a period that couldn't be exported, it goes with the next one.
+    fn restore(&self, from: DateTime<Utc>, usage: Vec<ConsumerUsage>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.since = from;
+        for usage in usage {
+            match inner.usage.get_mut(&usage.consumer) {
+                Some(current) => current.merge(usage),
+                None => {
+                    inner.usage.insert(usage.consumer.clone(), usage);
+                }
+            }
+        }
+    }
+}
+
+fn body(
+    state: &State,
+    format: UsageSinkFormat,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    usage: &[ConsumerUsage],
+) -> Value {
+    let namespace = &state.config().proxy_namespace;
+    match format {
+        UsageSinkFormat::Json => json!({
+            "namespace": namespace,
+            "from": from.to_rfc3339(),
+            "to": to.to_rfc3339(),
+            "consumers": usage,
+        }),
+        UsageSinkFormat::KafkaRest => json!({
+            "records": usage
+                .iter()
+                .map(|usage| {
+                    let mut value = serde_json::to_value(usage).unwrap_or_default();
+                    value["from"] = json!(from.to_rfc3339());
+                    value["to"] = json!(to.to_rfc3339());
+                    json!({ "key": usage.consumer, "value": value })
+                })
+                .collect::<Vec<_>>(),
+        }),
+    }
+}
+
+/// Sends the usage of the period to the sink. When the call fails, the usage is kept and sent
+/// with the next period.
+pub async fn flush(state: &State) {
+    let config = state.config();
+    let Some(url) = &config.proxy_usage_sink_url else {
+        return;
+    };
+
+    let (from, to, usage) = state.usage.take();
+    if usage.is_empty() {
+        return;
+    }
+
+    let content_type = match config.proxy_usage_sink_format {
+        UsageSinkFormat::Json => "application/json",
+        UsageSinkFormat::KafkaRest => "application/vnd.kafka.json.v2+json",
+    };
+    let mut request = state
+        .webhook_client
+        .post(url)
+        .timeout(config.proxy_usage_sink_timeout)
+        .header("content-type", content_type)
+        .body(body(state, config.proxy_usage_sink_format, from, to, &usage).to_string());
+    if let Some(token) = &config.proxy_usage_sink_token {
+        request = request.bearer_auth(token);
+    }
+
+    match request.send().await.and_then(|r| r.error_for_status()) {
+        Ok(_) => info!(consumers = usage.len(), "usage exported"),
+        Err(err) => {
+            error!(error = err.to_string(), "fail to export usage");
+            state.usage.restore(from, usage);
+        }
+    }
+}
+
+/// Exports the usage every `PROXY_USAGE_EXPORT_INTERVAL`, when a sink is set.
+pub fn start(state: Arc<State>) {
+    if state.config().proxy_usage_sink_url.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.config().proxy_usage_export_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            flush(&state).await;
+        }
+    });
+}
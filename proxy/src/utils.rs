@@ -1,10 +1,22 @@
 use bytes::Bytes;
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::{body::Incoming, Request, Response};
+use hyper::header::FORWARDED;
+use hyper::{body::Incoming, HeaderMap, Request, Response};
+use ipnet::IpNet;
 use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use url::form_urlencoded;
 
 pub const DMTR_API_KEY: &str = "dmtr-api-key";
+pub const DMTR_REQUEST_ID: &str = "x-dmtr-request-id";
+pub const DMTR_RECONNECT_TOKEN: &str = "x-dmtr-reconnect-token";
+pub const DMTR_IMPERSONATE: &str = "x-dmtr-impersonate";
+pub const DMTR_IMPERSONATION_TOKEN: &str = "x-dmtr-impersonation-token";
+pub const DMTR_OPERATOR: &str = "x-dmtr-operator";
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
 pub type Body = BoxBody<Bytes, hyper::Error>;
 pub type ProxyResponse = Response<Body>;
 
@@ -16,6 +28,29 @@ lazy_static! {
         m.insert("preview", "cardano-preview".into());
         m
     };
+    // Generated on startup, the hashes are only compared inside this process.
+    static ref KEY_SALT: [u8; 32] = rand::random();
+}
+
+/// Compares secrets in a time that doesn't depend on where they differ, both sides are hashed
+/// first so their lengths don't leak either.
+pub fn secret_eq(sent: impl AsRef<[u8]>, expected: impl AsRef<[u8]>) -> bool {
+    let sent = Sha256::digest(sent);
+    let expected = Sha256::digest(expected);
+    sent.iter()
+        .zip(expected.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+/// Salted hash of an api key. Consumers are stored by the hash of their keys, so the keys
+/// themselves aren't kept in memory once the ports are loaded.
+pub fn hash_key(key: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(*KEY_SALT)
+        .chain_update(key)
+        .finalize();
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
 }
 
 pub fn handle_legacy_networks(network: &str) -> String {
@@ -23,6 +58,109 @@ pub fn handle_legacy_networks(network: &str) -> String {
     LEGACY_NETWORKS.get(network).unwrap_or(&default).to_string()
 }
 
+/// Extracts the network and version from the `{network}-v{version}` label of a port hostname,
+/// eg: `dmtr_ogmios1xxx.cardano-mainnet-v6.ogmios-m1.demeter.run`.
+pub fn parse_host_route(host: &str) -> Option<(String, String)> {
+    host.split('.').find_map(|label| {
+        let (network, version) = label.rsplit_once("-v")?;
+        if network.is_empty() || version.is_empty() || !version.chars().all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        Some((handle_legacy_networks(network), version.to_string()))
+    })
+}
+
+/// Splits a `/{dmtr_key}/rest` path into the key and the path to forward, for clients behind
+/// proxies that rewrite the Host header. The key segment is percent-decoded.
+pub fn split_path_key(path: &str) -> Option<(String, String)> {
+    let path = path.strip_prefix('/')?;
+    let (key, rest) = path.split_once('/').unwrap_or((path, ""));
+    let key = percent_decode_str(key).decode_utf8().ok()?;
+    if key.len() <= "dmtr_".len() || !key.starts_with("dmtr_") {
+        return None;
+    }
+
+    Some((key.into_owned(), format!("/{rest}")))
+}
+
+/// Takes the `dmtr-api-key` parameter out of a query string, returning the key and the query to
+/// forward. When it's repeated the first value that isn't empty is the key, and all of them are
+/// removed.
+pub fn split_query_key(query: &str) -> Option<(String, String)> {
+    let mut key = None;
+    let rest: Vec<&str> = query
+        .split('&')
+        .filter(
+            |pair| match form_urlencoded::parse(pair.as_bytes()).next() {
+                Some((name, value)) if name == DMTR_API_KEY => {
+                    if key.is_none() && !value.is_empty() {
+                        key = Some(value.into_owned());
+                    }
+                    false
+                }
+                _ => true,
+            },
+        )
+        .collect();
+
+    key.map(|key| (key, rest.join("&")))
+}
+
+/// Resolves the address of the client when the peer is a trusted proxy, from `X-Forwarded-For`
+/// or else `Forwarded`. The chain is walked from the nearest hop and the first address that isn't
+/// a trusted proxy is the client, so clients can't spoof it by sending the header themselves.
+/// Returns whether the address was taken from the headers.
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> (IpAddr, bool) {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return (peer, false);
+    }
+
+    let forwarded_for: Vec<IpAddr> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    let chain = if forwarded_for.is_empty() {
+        headers
+            .get_all(FORWARDED)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(parse_forwarded_for)
+            .collect()
+    } else {
+        forwarded_for
+    };
+
+    match chain.iter().rev().find(|ip| !is_trusted(ip)) {
+        Some(ip) => (*ip, true),
+        // Every hop is trusted, the farthest one is the client.
+        None => (chain.first().copied().unwrap_or(peer), !chain.is_empty()),
+    }
+}
+
+// Extracts the address of a `for=` pair, eg: `for=192.0.2.60`, `for="[2001:db8::1]:4711"`.
+fn parse_forwarded_for(element: &str) -> Option<IpAddr> {
+    let value = element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("for").then_some(value)
+    })?;
+    let value = value.trim_matches('"');
+
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.rsplit_once(':')?.0.parse().ok())
+}
+
 pub fn full<T: Into<Bytes>>(chunk: T) -> Body {
     Full::new(chunk.into())
         .map_err(|never| match never {})
@@ -34,3 +172,199 @@ pub fn get_header(req: &Request<Incoming>, key: &str) -> Option<String> {
         .get(key)
         .and_then(|h| h.to_str().ok().map(|v| v.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    fn resolve(peer: &str, headers: &[(&'static str, &'static str)]) -> (IpAddr, bool) {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        resolve_client_ip(peer.parse().unwrap(), &map, &trusted)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn host_route_is_read_from_the_network_label() {
+        let route = |network: &str, version: &str| Some((network.into(), version.into()));
+        assert_eq!(
+            parse_host_route("dmtr_ogmios1xxx.cardano-mainnet-v6.ogmios-m1.demeter.run"),
+            route("cardano-mainnet", "6")
+        );
+        assert_eq!(
+            parse_host_route("preprod-v5.ogmios-m1.demeter.run"),
+            route("cardano-preprod", "5")
+        );
+        assert_eq!(
+            parse_host_route("dmtr_ogmios1xxx.ogmios-m1.demeter.run"),
+            None
+        );
+        assert_eq!(parse_host_route("cardano-mainnet-vx.demeter.run"), None);
+        assert_eq!(parse_host_route("-v6.demeter.run"), None);
+        assert_eq!(parse_host_route("cardano-mainnet-v.demeter.run"), None);
+        assert_eq!(parse_host_route(""), None);
+    }
+
+    #[test]
+    fn path_key_is_split_from_the_path() {
+        let split = |key: &str, path: &str| Some((key.into(), path.into()));
+        assert_eq!(
+            split_path_key("/dmtr_ogmios1xxx"),
+            split("dmtr_ogmios1xxx", "/")
+        );
+        assert_eq!(
+            split_path_key("/dmtr_ogmios1xxx/health"),
+            split("dmtr_ogmios1xxx", "/health")
+        );
+        assert_eq!(
+            split_path_key("/dmtr%5Fogmios1xxx/"),
+            split("dmtr_ogmios1xxx", "/")
+        );
+    }
+
+    #[test]
+    fn paths_without_a_key_segment_are_left_alone() {
+        assert_eq!(split_path_key("/"), None);
+        assert_eq!(split_path_key(""), None);
+        assert_eq!(split_path_key("//dmtr_ogmios1xxx"), None);
+        assert_eq!(split_path_key("/health"), None);
+        assert_eq!(split_path_key("/v6/dmtr_ogmios1xxx"), None);
+        assert_eq!(split_path_key("/dmtr_"), None);
+        assert_eq!(split_path_key("/dmtr_ogmios%FF"), None);
+    }
+
+    #[test]
+    fn query_key_is_taken_out_of_the_query() {
+        let split = |key: &str, query: &str| Some((key.into(), query.into()));
+        assert_eq!(
+            split_query_key("dmtr-api-key=dmtr_ogmios1xxx"),
+            split("dmtr_ogmios1xxx", "")
+        );
+        assert_eq!(
+            split_query_key("a=1&dmtr-api-key=dmtr_ogmios1xxx&b=2"),
+            split("dmtr_ogmios1xxx", "a=1&b=2")
+        );
+        assert_eq!(
+            split_query_key("dmtr-api-key=dmtr%5Fogmios1xxx&b=%20"),
+            split("dmtr_ogmios1xxx", "b=%20")
+        );
+        assert_eq!(
+            split_query_key("dmtr%2Dapi%2Dkey=dmtr_ogmios1xxx"),
+            split("dmtr_ogmios1xxx", "")
+        );
+    }
+
+    #[test]
+    fn repeated_query_keys_are_all_removed() {
+        let split = |key: &str, query: &str| Some((key.into(), query.into()));
+        assert_eq!(
+            split_query_key("dmtr-api-key=dmtr_first&dmtr-api-key=dmtr_second&a=1"),
+            split("dmtr_first", "a=1")
+        );
+        assert_eq!(
+            split_query_key("dmtr-api-key=&dmtr-api-key=dmtr_ogmios1xxx"),
+            split("dmtr_ogmios1xxx", "")
+        );
+    }
+
+    #[test]
+    fn empty_query_keys_are_no_key() {
+        assert_eq!(split_query_key("dmtr-api-key="), None);
+        assert_eq!(split_query_key("dmtr-api-key"), None);
+        assert_eq!(split_query_key(""), None);
+        assert_eq!(split_query_key("a=1&dmtr-api-keys=dmtr_ogmios1xxx"), None);
+    }
+
+    #[test]
+    fn forwarded_headers_of_untrusted_peers_are_ignored() {
+        let headers = [
+            (X_FORWARDED_FOR, "203.0.113.7"),
+            ("forwarded", "for=203.0.113.8"),
+        ];
+        assert_eq!(
+            resolve("198.51.100.1", &headers),
+            (ip("198.51.100.1"), false)
+        );
+        assert_eq!(resolve("10.0.0.1", &[]), (ip("10.0.0.1"), false));
+    }
+
+    #[test]
+    fn chain_is_walked_past_the_trusted_hops() {
+        let headers = [(X_FORWARDED_FOR, "203.0.113.7, 10.0.0.5")];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("203.0.113.7"), true));
+
+        // Addresses the client prepended itself are left behind the first untrusted hop.
+        let headers = [(X_FORWARDED_FOR, "192.0.2.1, 203.0.113.7, 10.0.0.5")];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("203.0.113.7"), true));
+
+        // Hops of several headers form a single chain.
+        let headers = [
+            (X_FORWARDED_FOR, "203.0.113.7"),
+            (X_FORWARDED_FOR, "10.0.0.6, 10.0.0.5"),
+        ];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("203.0.113.7"), true));
+
+        // When every hop is trusted the farthest one is the client.
+        let headers = [(X_FORWARDED_FOR, "10.0.0.7, 10.0.0.5")];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("10.0.0.7"), true));
+    }
+
+    #[test]
+    fn forwarded_is_used_without_x_forwarded_for() {
+        let headers = [(
+            "forwarded",
+            r#"for="[2001:db8::1]:443";proto=https, for=10.0.0.5"#,
+        )];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("2001:db8::1"), true));
+
+        let headers = [("forwarded", "for=203.0.113.7:8080")];
+        assert_eq!(resolve("fd00::1", &headers), (ip("203.0.113.7"), true));
+
+        let headers = [
+            (X_FORWARDED_FOR, "203.0.113.7"),
+            ("forwarded", "for=203.0.113.8"),
+        ];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("203.0.113.7"), true));
+    }
+
+    #[test]
+    fn garbage_entries_are_skipped() {
+        let headers = [(X_FORWARDED_FOR, "unknown, 203.0.113.7, , 10.0.0.300")];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("203.0.113.7"), true));
+
+        let headers = [(X_FORWARDED_FOR, "garbage"), ("forwarded", "for=_hidden")];
+        assert_eq!(resolve("10.0.0.1", &headers), (ip("10.0.0.1"), false));
+    }
+
+    #[test]
+    fn forwarded_for_pairs_are_parsed() {
+        assert_eq!(
+            parse_forwarded_for("for=192.0.2.60"),
+            Some(ip("192.0.2.60"))
+        );
+        assert_eq!(
+            parse_forwarded_for("proto=http; For=192.0.2.60:4711"),
+            Some(ip("192.0.2.60"))
+        );
+        assert_eq!(
+            parse_forwarded_for(r#"for="[2001:db8::1]:443""#),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(
+            parse_forwarded_for(r#"for="[2001:db8::1]""#),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(parse_forwarded_for(r#"for="[2001:db8::1""#), None);
+        assert_eq!(parse_forwarded_for("for=unknown"), None);
+        assert_eq!(parse_forwarded_for("for=_hidden"), None);
+        assert_eq!(parse_forwarded_for("by=192.0.2.60;proto=https"), None);
+        assert_eq!(parse_forwarded_for(""), None);
+    }
+}
@@ -0,0 +1,192 @@
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{error, info};
+
+use crate::limiter;
+use crate::utils::parse_host_route;
+use crate::{Consumer, Revocation, State};
+
+/// Answer of the auth webhook. Allowed credentials get a consumer of their own, named by the
+/// namespace and port of the answer, with the limits of its tier.
+#[derive(Debug, Deserialize)]
+struct Decision {
+    allow: bool,
+    #[serde(default)]
+    namespace: Option<String>,
+    #[serde(default)]
+    port: Option<String>,
+    #[serde(default)]
+    tier: Option<String>,
+    /// Network and version default to the ones named by the hostname.
+    #[serde(default)]
+    network: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+}
+
+/// Decisions of the webhook by credential hash, allowed or denied, until they expire.
+#[derive(Default)]
+pub struct WebhookDecisions(Mutex<HashMap<String, (Instant, Option<Consumer>)>>);
+impl WebhookDecisions {
+    fn get(&self, key_hash: &str) -> Option<Option<Consumer>> {
+        let decisions = self.0.lock().unwrap();
+        let (expires_at, consumer) = decisions.get(key_hash)?;
+        (*expires_at > Instant::now()).then(|| consumer.clone())
+    }
+
+    fn insert(&self, key_hash: &str, consumer: Option<Consumer>, expires_at: Instant) {
+        let mut decisions = self.0.lock().unwrap();
+        let now = Instant::now();
+        decisions.retain(|_, (expires_at, _)| *expires_at > now);
+        decisions.insert(key_hash.to_string(), (expires_at, consumer));
+    }
+}
+
+/// Failed calls are denied for this long, or for `PROXY_AUTH_WEBHOOK_CACHE_TTL` when shorter, so
+/// a webhook that's down isn't called on every request.
+const FAILURE_TTL: Duration = Duration::from_secs(5);
+
+/// Evicts the webhook consumers whose decision expired once their sessions are closed, they're
+/// registered again by the next call.
+pub fn start(state: Arc<State>) {
+    tokio::spawn(async move {
+        let period = state
+            .config()
+            .proxy_auth_webhook_cache_ttl
+            .max(Duration::from_secs(1));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            let mut consumers = state.consumers.write().await;
+            let mut expired = Vec::new();
+            consumers.retain(|key, consumer| {
+                let keep = !consumer.external
+                    || consumer.active_connections > 0
+                    || state.webhook.get(key).is_some();
+                if !keep {
+                    expired.push(key.clone());
+                }
+                keep
+            });
+            drop(consumers);
+            for key in &expired {
+                state.limiter.write().await.remove(key);
+                state.bandwidth.write().await.remove(key);
+                state.in_flight.write().await.remove(key);
+            }
+        }
+    });
+}
+
+async fn call(state: &State, url: &str, credential: &str, host: &str) -> reqwest::Result<Decision> {
+    state
+        .webhook_client
+        .post(url)
+        .timeout(state.config().proxy_auth_webhook_timeout)
+        .json(&json!({ "credential": credential, "host": host }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+/// Asks `PROXY_AUTH_WEBHOOK_URL` whether the credential is allowed on the host. Decisions are
+/// cached for `PROXY_AUTH_WEBHOOK_CACHE_TTL`, failed calls are denied for `FAILURE_TTL` without
+/// closing the sessions of the credential.
+pub async fn authenticate(
+    state: &State,
+    credential: &str,
+    key_hash: &str,
+    host: &str,
+) -> Option<Consumer> {
+    let config = state.config();
+    let url = config.proxy_auth_webhook_url.as_ref()?;
+    if let Some(consumer) = state.webhook.get(key_hash) {
+        return consumer.map(|consumer| consumer.with_key(key_hash));
+    }
+    if credential.is_empty() {
+        return None;
+    }
+
+    let decision = match call(state, url, credential, host).await {
+        Ok(decision) => decision,
+        Err(err) => {
+            error!(
+                error = err.to_string(),
+                "auth: Failed to call the auth webhook."
+            );
+            let ttl = config.proxy_auth_webhook_cache_ttl.min(FAILURE_TTL);
+            state.webhook.insert(key_hash, None, Instant::now() + ttl);
+            return None;
+        }
+    };
+
+    let route = parse_host_route(host);
+    let consumer = match decision {
+        Decision {
+            allow: true,
+            namespace: Some(namespace),
+            port: Some(port_name),
+            tier: Some(tier),
+            network,
+            version,
+        } => {
+            let route = network.zip(version).or(route);
+            route.map(|(network, version)| Consumer {
+                namespace,
+                port_name,
+                tier,
+                key: key_hash.to_string(),
+                network,
+                version,
+                external: true,
+                ..Default::default()
+            })
+        }
+        _ => None,
+    };
+
+    let expires_at = Instant::now() + config.proxy_auth_webhook_cache_ttl;
+    state.webhook.insert(key_hash, consumer.clone(), expires_at);
+    register(state, key_hash, consumer.as_ref()).await;
+
+    consumer.map(|consumer| consumer.with_key(key_hash))
+}
+
+/// Keeps the consumers map in line with the decision, so the limiters and the connection cap
+/// apply to webhook consumers like to ports. Sessions of a credential denied now are closed.
+async fn register(state: &State, key_hash: &str, consumer: Option<&Consumer>) {
+    let mut consumers = state.consumers.write().await;
+    match consumer {
+        Some(consumer) => {
+            let mut consumer = consumer.clone();
+            let mut tier_changed = false;
+            if let Some(current) = consumers.get(key_hash) {
+                consumer.active_connections = current.active_connections;
+                tier_changed = current.tier != consumer.tier;
+            }
+            consumers.insert(key_hash.to_string(), consumer.clone());
+            drop(consumers);
+            if tier_changed {
+                limiter::rebuild(state, &consumer).await;
+            }
+        }
+        None => {
+            if let Some(previous) = consumers.remove(key_hash) {
+                info!(
+                    consumer = previous.to_string(),
+                    "auth: Credential denied by the webhook."
+                );
+                state.limiter.write().await.remove(key_hash);
+                state.bandwidth.write().await.remove(key_hash);
+                state.in_flight.write().await.remove(key_hash);
+                let _ = state.revoke.send(Revocation::Key(key_hash.to_string()));
+            }
+        }
+    }
+}